@@ -100,8 +100,99 @@ fn check_coverage_supported() {
     }
 }
 
+/// The `detour` feature only has an x86 implementation for now.
+fn check_detour_supported() {
+    if var("CARGO_FEATURE_DETOUR").is_err() {
+        return;
+    }
+
+    let arch = var("CARGO_CFG_TARGET_ARCH").unwrap();
+    if !["x86_64", "x86"].contains(&arch.as_str()) {
+        println!(
+            "cargo::error=the 'detour' feature of closure-ffi is not supported on the '{arch}' \
+            target architecture."
+        );
+    }
+}
+
+/// The entry stub of the `dyn_bare_fn` feature only implements the x86_64 System V calling
+/// convention for now.
+fn check_dyn_bare_fn_supported() {
+    if var("CARGO_FEATURE_DYN_BARE_FN").is_err() {
+        return;
+    }
+
+    let arch = var("CARGO_CFG_TARGET_ARCH").unwrap();
+    if arch != "x86_64" || var("CARGO_CFG_WINDOWS").is_ok() {
+        println!(
+            "cargo::error=the 'dyn_bare_fn' feature of closure-ffi is only supported on x86_64 \
+            System V targets."
+        );
+    }
+}
+
+/// The `memfd_jit_alloc` feature relies on Linux-specific system calls.
+fn check_memfd_supported() {
+    if var("CARGO_FEATURE_MEMFD_JIT_ALLOC").is_err() {
+        return;
+    }
+
+    let os = var("CARGO_CFG_TARGET_OS").unwrap();
+    if os != "linux" {
+        println!(
+            "cargo::error=the 'memfd_jit_alloc' feature of closure-ffi is only supported on Linux \
+            targets, not '{os}'."
+        );
+    }
+}
+
+/// The `perf_map` and `gdb_jit` features register thunks with Linux tools.
+fn check_jit_symbols_supported() {
+    let os = var("CARGO_CFG_TARGET_OS").unwrap();
+    if os == "linux" {
+        return;
+    }
+
+    for feature in ["perf_map", "gdb_jit"] {
+        if var(format!("CARGO_FEATURE_{}", feature.to_uppercase())).is_ok() {
+            println!(
+                "cargo::error=the '{feature}' feature of closure-ffi is only supported on Linux \
+                targets, not '{os}'."
+            );
+        }
+    }
+}
+
+/// The `unwind_info` feature registers DWARF call frame information with `__register_frame`, which
+/// ARM targets do not use for unwinding.
+fn check_unwind_info_supported() {
+    if var("CARGO_FEATURE_UNWIND_INFO").is_err() {
+        return;
+    }
+
+    let os = var("CARGO_CFG_TARGET_OS").unwrap();
+    if os != "linux" {
+        println!(
+            "cargo::error=the 'unwind_info' feature of closure-ffi is only supported on Linux \
+            targets, not '{os}'."
+        );
+    }
+    let arch = var("CARGO_CFG_TARGET_ARCH").unwrap();
+    if arch == "arm" {
+        println!(
+            "cargo::error=the 'unwind_info' feature of closure-ffi is not supported on the 'arm' \
+            target architecture."
+        );
+    }
+}
+
 fn main() {
     check_supported_archs();
+    check_detour_supported();
+    check_dyn_bare_fn_supported();
+    check_memfd_supported();
+    check_jit_symbols_supported();
+    check_unwind_info_supported();
     no_safe_jit_warn();
     check_coverage_supported();
     set_thumb_mode_cfg();
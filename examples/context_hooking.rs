@@ -1,8 +1,8 @@
 //! This example shows how one can design the user-facing interface function hooking library with
 //! excellent type inference and support for capturing closures using `closure-ffi`.
 
-// This example will not compile without the default JIT allocator.
-#![cfg(feature = "default_jit_alloc")]
+// This example builds on the detour module, which requires the `detour` feature.
+#![cfg(all(feature = "detour", any(target_arch = "x86", target_arch = "x86_64")))]
 #![cfg_attr(feature = "tuple_trait", feature(unboxed_closures))]
 #![cfg_attr(feature = "tuple_trait", feature(tuple_trait))]
 #![cfg_attr(feature = "tuple_trait", feature(fn_traits))]
@@ -10,8 +10,8 @@
 use core::marker::PhantomData;
 
 use closure_ffi::{
+    detour::{Detour, DetourError},
     traits::{FnPtr, FnThunk, ToBoxedDyn},
-    BareFnAny,
 };
 
 /// Context object storing information about the current hook.
@@ -122,135 +122,206 @@ impl<'a, B: FnPtr> HookCtx<'a, B> {
 ///   doesn't get assigned to an already-typed field. In this example, [`Hook::with_cc`] and
 ///   [`Hook::with_cc_ctx`] follow this idiom.
 pub struct Hook<'a, B: FnPtr> {
-    // We use `BareFnAny` to specify stronger bounds (`Send + Sync`) on the type-erased closure.
-    // By doing this, `Self` automatically implements `Send` and `Sync`, no need for unsafe impls!
-    bare_wrapper: BareFnAny<B, dyn Send + Sync + 'a>,
-    // Here we would also store a handle to the executable memory we allocated for the trampoline,
-    // the target function's address, etc.
+    // `Detour` stores the hook closure as a `BareFnAny` with stronger bounds (`Send + Sync`) on
+    // the type-erased closure. By doing this, `Self` automatically implements `Send` and `Sync`,
+    // no need for unsafe impls!
+    //
+    // It also owns the executable memory allocated for the trampoline and remembers the target
+    // function's address.
+    detour: Detour<'a, B>,
 }
 
 impl<'a, B: FnPtr> Hook<'a, B> {
-    /// Internals not relevant for this example
-    fn make_context() -> HookCtx<'a, B> {
-        // For the purpose of type inference tests, give out a dangling original `B`
-        // (We will not call it)
+    /// Builds the context object given to hook closures from the trampoline of the detour.
+    fn make_context(original: B) -> HookCtx<'a, B> {
         HookCtx {
-            original: unsafe { B::from_ptr(core::ptr::dangling()) },
+            original,
             phantom: PhantomData,
         }
     }
 
-    /// Create a hook that will invoke `fun` when the original function is called.
-    pub fn new<F>(fun: F) -> Self
+    /// Create a hook that will invoke `fun` when `target` is called.
+    ///
+    /// # Safety
+    /// `target` must be a function of signature `B`. See also [`Detour::new`].
+    pub unsafe fn new<F>(target: *const (), fun: F) -> Result<Self, DetourError>
     where
         F: ToBoxedDyn<dyn Send + Sync + 'a>,
         (B::CC, F): FnThunk<B>,
     {
-        Self {
-            bare_wrapper: BareFnAny::new(fun),
-        }
+        Ok(Self {
+            detour: unsafe { Detour::new(B::from_ptr(target), |_| fun)? },
+        })
     }
 
-    /// Create a hook to a function of calling convention `cc`, inferring the signature
+    /// Create a hook to `target`, a function of calling convention `cc`, inferring the signature
     /// based on the provided closure.
-    pub fn with_cc<CC, F>(cc: CC, fun: F) -> Self
+    ///
+    /// # Safety
+    /// `target` must be a function of signature `B`. See also [`Detour::new`].
+    pub unsafe fn with_cc<CC, F>(target: *const (), cc: CC, fun: F) -> Result<Self, DetourError>
     where
         F: ToBoxedDyn<dyn Send + Sync + 'a>,
         (CC, F): FnThunk<B>,
     {
-        Self {
-            bare_wrapper: BareFnAny::with_cc(cc, fun),
-        }
+        Ok(Self {
+            detour: unsafe { Detour::with_cc(B::from_ptr(target), cc, |_| fun)? },
+        })
     }
 
-    /// Create a hook that will invoke a closure when the original function is called.
+    /// Create a hook that will invoke a closure when `target` is called.
     ///
     /// Unlike [`Hook::new`], takes a closure-generating function that is given a hook context
     /// object to capture.
-    pub fn with_ctx<F>(ctx_binder: impl FnOnce(HookCtx<'a, B>) -> F) -> Self
+    ///
+    /// # Safety
+    /// `target` must be a function of signature `B`. See also [`Detour::new`].
+    pub unsafe fn with_ctx<F>(
+        target: *const (),
+        ctx_binder: impl FnOnce(HookCtx<'a, B>) -> F,
+    ) -> Result<Self, DetourError>
     where
         F: ToBoxedDyn<dyn Send + Sync + 'a>,
         (B::CC, F): FnThunk<B>,
     {
-        let ctx = Self::make_context();
-        let closure = ctx_binder(ctx);
-        Self {
-            bare_wrapper: BareFnAny::new(closure),
-        }
+        Ok(Self {
+            detour: unsafe {
+                Detour::new(B::from_ptr(target), |original| {
+                    ctx_binder(Self::make_context(original))
+                })?
+            },
+        })
     }
 
-    /// Create a hook that will invoke a closure when the original function is called.
+    /// Create a hook that will invoke a closure when `target` is called.
     ///
     /// The signature of the hooked function is inferred from the calling convention marker type and
     /// the closure's annotations.
     ///
     /// Unlike [`Hook::new`], takes a closure-generating function that is given a hook context
     /// object to capture.
-    pub fn with_cc_ctx<CC, F>(cc: CC, ctx_binder: impl FnOnce(HookCtx<'a, B>) -> F) -> Self
+    ///
+    /// # Safety
+    /// `target` must be a function of signature `B`. See also [`Detour::new`].
+    pub unsafe fn with_cc_ctx<CC, F>(
+        target: *const (),
+        cc: CC,
+        ctx_binder: impl FnOnce(HookCtx<'a, B>) -> F,
+    ) -> Result<Self, DetourError>
     where
         F: ToBoxedDyn<dyn Send + Sync + 'a>,
         (CC, F): FnThunk<B>,
     {
-        let ctx = Self::make_context();
-        let closure = ctx_binder(ctx);
-        Self {
-            bare_wrapper: BareFnAny::with_cc(cc, closure),
-        }
+        Ok(Self {
+            detour: unsafe {
+                Detour::with_cc(B::from_ptr(target), cc, |original| {
+                    ctx_binder(Self::make_context(original))
+                })?
+            },
+        })
     }
 
     /// Returns the bare function wrapping the hook closure.
     pub fn hook(&self) -> B {
-        self.bare_wrapper.bare()
+        self.detour.hook()
+    }
+
+    /// Redirects the target function to the hook closure.
+    ///
+    /// # Safety
+    /// See [`Detour::enable`].
+    pub unsafe fn enable(&mut self) -> Result<(), DetourError> {
+        unsafe { self.detour.enable() }
     }
 }
 
 /// Showcases the type inference abilities of an API build around `closure-ffi`.
 #[test]
 fn test_inference() {
+    use std::hint::black_box;
+
     use closure_ffi::cc;
 
+    // Functions to hook. They must be large enough for the jump to the hook to fit
+    #[inline(never)]
+    extern "C" fn double(x: usize) -> usize {
+        black_box(x).wrapping_mul(black_box(2)).wrapping_add(black_box(0))
+    }
+    #[inline(never)]
+    extern "C" fn truncate(x: usize) -> u32 {
+        black_box(x).wrapping_add(black_box(0)) as u32
+    }
+    #[inline(never)]
+    #[allow(improper_ctypes_definitions)]
+    extern "C" fn len(s: String) -> usize {
+        black_box(s.len()).wrapping_add(black_box(0))
+    }
+    #[inline(never)]
+    extern "C" fn add(x: usize, y: u32) -> u32 {
+        black_box(x as u32).wrapping_add(black_box(y))
+    }
+    #[inline(never)]
+    extern "C" fn add_wide(x: usize, y: u32) -> usize {
+        black_box(x).wrapping_add(black_box(y as usize))
+    }
+
     let borrowed = Box::new(42usize);
 
     // Infer `F` from `B`
 
-    let hook: Hook<unsafe extern "C" fn(usize) -> usize> = Hook::new(|arg| arg + *borrowed);
+    let hook: Hook<unsafe extern "C" fn(usize) -> usize> =
+        unsafe { Hook::new(double as *const (), |arg| arg + *borrowed) }.unwrap();
     assert_eq!(unsafe { hook.hook()(4) }, 46);
 
     // Infer `B` from `CC` and `F`
     // hook is Hook<'_, extern "C" fn(usize) -> usize>
-    let hook = Hook::with_cc(cc::C, |arg: usize| *borrowed * arg);
+    let hook =
+        unsafe { Hook::with_cc(double as *const (), cc::C, |arg: usize| *borrowed * arg) }.unwrap();
     assert_eq!(unsafe { hook.hook()(2) }, 84);
 
     // Infer 'F` from `B` with unused context
 
-    let hook: Hook<unsafe extern "C" fn(usize) -> u32> = Hook::with_ctx(|_ctx| move |arg| arg as _);
+    let hook: Hook<unsafe extern "C" fn(usize) -> u32> =
+        unsafe { Hook::with_ctx(truncate as *const (), |_ctx| move |arg| arg as _) }.unwrap();
     assert_eq!(unsafe { hook.hook()(42) }, 42);
 
     // Infer `B` from `CC` and `F` with unused context
-    let hook = Hook::with_cc_ctx(cc::C, |_ctx| move |s: String| s.len());
+    let hook =
+        unsafe { Hook::with_cc_ctx(len as *const (), cc::C, |_ctx| move |s: String| s.len()) }
+            .unwrap();
     assert_eq!(unsafe { hook.hook()("abc".to_string()) }, 3);
 
     // Infer `F` from `B` with used context
 
-    let _hook: Hook<unsafe extern "C" fn(usize, u32) -> u32> = Hook::with_ctx(|ctx| {
-        move |x, y| unsafe {
-            let result = ctx.call_original((x, y));
-            result + 42
-        }
-    });
+    let mut hook: Hook<unsafe extern "C" fn(usize, u32) -> u32> = unsafe {
+        Hook::with_ctx(add as *const (), |ctx| {
+            move |x, y| {
+                let result = ctx.call_original((x, y));
+                result + 42
+            }
+        })
+    }
+    .unwrap();
+    unsafe { hook.enable() }.unwrap();
+    assert_eq!(black_box(add)(1, 2), 45);
 
     // Infer `B` from `CC` and `F` with used context
-    let _hook = Hook::with_cc_ctx(cc::C, |ctx| {
-        move |x: usize, y: u32| -> usize {
-            // stable API. Must pass the args as a tuple
-            #[cfg(not(feature = "tuple_trait"))]
-            let result = unsafe { ctx.original()((x, y)) };
-
-            // nightly-only API with tuple_trait feature: call like a normal function
-            #[cfg(feature = "tuple_trait")]
-            let result = unsafe { ctx.original()(x, y) };
-
-            result + 42
-        }
-    });
+    let mut hook = unsafe {
+        Hook::with_cc_ctx(add_wide as *const (), cc::C, |ctx| {
+            move |x: usize, y: u32| -> usize {
+                // stable API. Must pass the args as a tuple
+                #[cfg(not(feature = "tuple_trait"))]
+                let result = ctx.original()((x, y));
+
+                // nightly-only API with tuple_trait feature: call like a normal function
+                #[cfg(feature = "tuple_trait")]
+                let result = ctx.original()(x, y);
+
+                result + 42
+            }
+        })
+    }
+    .unwrap();
+    unsafe { hook.enable() }.unwrap();
+    assert_eq!(black_box(add_wide)(1, 2), 45);
 }
@@ -0,0 +1,227 @@
+use proc_macro2 as pm2;
+use quote::{format_ident, quote};
+use syn::visit::Visit;
+
+pub struct FfiInterfaceArgs {
+    crate_path: syn::Path,
+    vtable_ident: Option<syn::Ident>,
+    abi: syn::LitStr,
+}
+
+impl Default for FfiInterfaceArgs {
+    fn default() -> Self {
+        Self {
+            crate_path: syn::parse_quote!(::closure_ffi),
+            vtable_ident: None,
+            abi: syn::LitStr::new("C", pm2::Span::call_site()),
+        }
+    }
+}
+
+impl FfiInterfaceArgs {
+    pub fn parse_meta(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("crate") {
+            self.crate_path = meta.value()?.parse()?;
+        }
+        else if meta.path.is_ident("vtable") {
+            self.vtable_ident = Some(meta.value()?.parse()?);
+        }
+        else if meta.path.is_ident("abi") {
+            self.abi = meta.value()?.parse()?;
+        }
+        else {
+            return Err(meta.error("Expected one of `crate`, `vtable` or `abi`"));
+        }
+        Ok(())
+    }
+}
+
+fn combine_err(acc: &mut Option<syn::Error>, err: syn::Error) {
+    match acc.as_mut() {
+        None => *acc = Some(err),
+        Some(e) => e.combine(err),
+    }
+}
+
+// Types containing lifetimes would make the bare function higher-ranked, which is not supported
+struct HasLifetimes(Vec<pm2::Span>);
+
+impl<'a> Visit<'a> for HasLifetimes {
+    fn visit_lifetime(&mut self, i: &'a syn::Lifetime) {
+        self.0.push(i.span());
+    }
+
+    fn visit_type_reference(&mut self, i: &'a syn::TypeReference) {
+        self.0.push(i.and_token.span);
+    }
+
+    fn visit_type_impl_trait(&mut self, i: &'a syn::TypeImplTrait) {
+        self.0.push(i.impl_token.span);
+    }
+}
+
+struct VtableEntry<'a> {
+    attrs: Vec<&'a syn::Attribute>,
+    ident: &'a syn::Ident,
+    arg_idents: Vec<syn::Ident>,
+    arg_tys: Vec<&'a syn::Type>,
+    output: &'a syn::ReturnType,
+}
+
+fn vtable_entry(method: &syn::TraitItemFn) -> syn::Result<VtableEntry<'_>> {
+    let sig = &method.sig;
+    let mut err = None;
+
+    if let Some(asyncness) = &sig.asyncness {
+        combine_err(
+            &mut err,
+            syn::Error::new_spanned(asyncness, "Async methods are not supported"),
+        );
+    }
+    if let Some(variadic) = &sig.variadic {
+        combine_err(
+            &mut err,
+            syn::Error::new_spanned(variadic, "Variadic methods are not supported"),
+        );
+    }
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        combine_err(
+            &mut err,
+            syn::Error::new_spanned(&sig.generics, "Generic methods are not supported"),
+        );
+    }
+
+    let is_ref_self = sig.receiver().is_some_and(|r| {
+        r.reference.as_ref().is_some_and(|(_, lt)| lt.is_none())
+            && r.mutability.is_none()
+            && r.colon_token.is_none()
+    });
+    if !is_ref_self {
+        combine_err(
+            &mut err,
+            syn::Error::new_spanned(sig, "Methods must take `&self` as their receiver"),
+        );
+    }
+
+    let mut arg_tys = Vec::new();
+    let mut lifetime_check = HasLifetimes(Vec::new());
+    for input in &sig.inputs {
+        if let syn::FnArg::Typed(pat_type) = input {
+            lifetime_check.visit_type(&pat_type.ty);
+            arg_tys.push(&*pat_type.ty);
+        }
+    }
+    lifetime_check.visit_return_type(&sig.output);
+    for span in lifetime_check.0 {
+        combine_err(
+            &mut err,
+            syn::Error::new(
+                span,
+                "Types with lifetimes or `impl Trait` are not supported in vtable methods",
+            ),
+        );
+    }
+
+    match err {
+        Some(err) => Err(err),
+        None => Ok(VtableEntry {
+            attrs: method.attrs.iter().filter(|a| a.path().is_ident("doc")).collect(),
+            ident: &sig.ident,
+            arg_idents: (0..arg_tys.len()).map(|i| format_ident!("a{i}")).collect(),
+            arg_tys,
+            output: &sig.output,
+        }),
+    }
+}
+
+pub fn ffi_interface_impl(
+    args: FfiInterfaceArgs,
+    item: syn::ItemTrait,
+) -> syn::Result<pm2::TokenStream> {
+    if !item.generics.params.is_empty() || item.generics.where_clause.is_some() {
+        return Err(syn::Error::new_spanned(
+            &item.generics,
+            "Generic traits are not supported",
+        ));
+    }
+
+    let mut err = None;
+    let mut entries = Vec::new();
+    for trait_item in &item.items {
+        // Only methods get a vtable entry
+        if let syn::TraitItem::Fn(method) = trait_item {
+            match vtable_entry(method) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => combine_err(&mut err, e),
+            }
+        }
+    }
+    if let Some(err) = err {
+        return Err(err);
+    }
+
+    let crate_path = &args.crate_path;
+    let abi = &args.abi;
+    let trait_ident = &item.ident;
+    let vis = &item.vis;
+    let vtable_ident = args.vtable_ident.unwrap_or_else(|| format_ident!("{trait_ident}Vtable"));
+    let vtable_doc =
+        format!(" C-compatible function pointer table for the [`{trait_ident}`] trait.");
+
+    let fields = entries.iter().map(|e| {
+        let VtableEntry {
+            attrs,
+            ident,
+            arg_tys,
+            output,
+            ..
+        } = e;
+        quote! {
+            #(#attrs)*
+            pub #ident: unsafe extern #abi fn(#(#arg_tys),*) #output
+        }
+    });
+
+    let inits = entries.iter().map(|e| {
+        let VtableEntry {
+            ident,
+            arg_idents,
+            arg_tys,
+            output,
+            ..
+        } = e;
+        quote! {
+            #ident: thunks.thunk::<unsafe extern #abi fn(#(#arg_tys),*) #output, _>(
+                move |#(#arg_idents: #arg_tys),*| unsafe {
+                    <__T as #trait_ident>::#ident(&*imp, #(#arg_idents),*)
+                },
+            )?
+        }
+    });
+
+    Ok(quote! {
+        #item
+
+        #[doc = #vtable_doc]
+        #[repr(C)]
+        #[derive(::core::clone::Clone, ::core::marker::Copy, ::core::fmt::Debug)]
+        #vis struct #vtable_ident {
+            #(#fields,)*
+        }
+
+        unsafe impl<__T: #trait_ident> #crate_path::ffi_interface::FfiVtable<__T> for #vtable_ident {
+            #[allow(unused_variables)]
+            unsafe fn build<'__a, __A: #crate_path::JitAlloc + ::core::clone::Clone>(
+                imp: *const __T,
+                thunks: &mut #crate_path::ffi_interface::VtableThunks<'__a, __A>,
+            ) -> ::core::result::Result<Self, #crate_path::ThunkError>
+            where
+                __T: '__a,
+            {
+                ::core::result::Result::Ok(Self {
+                    #(#inits,)*
+                })
+            }
+        }
+    })
+}
@@ -5,6 +5,8 @@ use proc_macro2 as pm2;
 use quote::{quote, ToTokens};
 use syn::{parse_macro_input, visit::Visit, visit_mut::VisitMut};
 
+mod ffi_interface;
+
 struct MacroInput {
     thunk_attrs: Vec<syn::Attribute>,
     crate_path: syn::Path,
@@ -475,3 +477,27 @@ pub fn bare_hrtb(tokens: TokenStream) -> TokenStream {
     }
     .into()
 }
+
+/// Generates a `#[repr(C)]` table of bare function pointers from a trait, along with an
+/// implementation of `closure_ffi::ffi_interface::FfiVtable` filling it with thunks calling into
+/// an implementor of the trait.
+///
+/// See the `closure_ffi::ffi_interface` module for usage.
+///
+/// Every method of the trait must take `&self` as its receiver, must not be generic, and must not
+/// use lifetimes in its signature. The attribute accepts the following optional arguments:
+/// - `vtable = Ident`: The name of the generated table. Defaults to the trait name followed by
+///   `Vtable`.
+/// - `abi = "C"`: The calling convention of the table entries. Defaults to `"C"`.
+/// - `crate = path`: The path to the `closure_ffi` crate. Defaults to `::closure_ffi`.
+#[proc_macro_attribute]
+pub fn ffi_interface(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut macro_args = ffi_interface::FfiInterfaceArgs::default();
+    let args_parser = syn::meta::parser(|meta| macro_args.parse_meta(meta));
+    parse_macro_input!(args with args_parser);
+    let item = parse_macro_input!(item as syn::ItemTrait);
+
+    ffi_interface::ffi_interface_impl(macro_args, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -3,9 +3,23 @@
 //!
 //! While parts of this module are public for macro reasons, they should not be used directly.
 
-use crate::jit_alloc::{JitAlloc, JitAllocError, ProtectJitAccess};
+use alloc::borrow::Cow;
+#[cfg(feature = "safe_jit")]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "safe_jit")]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+#[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+use crate::jit_symbols::{self, ThunkSymbol};
 #[cfg(feature = "safe_jit")]
 use crate::safe_jit::RelocThunk;
+use crate::{
+    error::{PrologueError, ThunkError},
+    jit_alloc::{JitAlloc, ProtectJitAccess},
+    thunk_info::{ThunkInfo, ThunkKind},
+};
+#[cfg(feature = "unwind_info")]
+use crate::{safe_jit::cfi::CfiOp, unwind_info::FrameRegistration};
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[doc(hidden)]
@@ -162,15 +176,339 @@ macro_rules! _thunk_asm {
     };
 }
 
+/// The prologue and `asm!` block of a thunk template, ready to be copied to JIT memory.
+#[derive(Debug)]
+struct ThunkPrologue {
+    /// Offset of the magic number from the start of the thunk template.
+    template_magic_offset: usize,
+    /// The (possibly relocated) prologue code, up to the end of the `asm!` block.
+    thunk: Cow<'static, [u8]>,
+    /// Offset of the magic number in `thunk`.
+    magic_offset: usize,
+    /// Ranges of `thunk` which were rewritten by the relocator.
+    #[cfg(feature = "safe_jit")]
+    rewritten: alloc::vec::Vec<core::ops::Range<usize>>,
+    /// The prologue and `asm!` block of the thunk template, as compiled.
+    #[cfg(feature = "safe_jit")]
+    template: &'static [u8],
+    /// Offsets of the 32-bit IP-relative displacements in `template`, which must be adjusted when
+    /// copying it verbatim to a thunk close enough to it.
+    #[cfg(feature = "safe_jit")]
+    rel32_fixups: alloc::vec::Vec<usize>,
+    /// Set if the prologue could not be relocated, so that `template` can only be copied close to
+    /// it.
+    #[cfg(feature = "safe_jit")]
+    far_error: Option<PrologueError>,
+    /// Unwinding rules for the code of `thunk`, if it could be analyzed.
+    #[cfg(feature = "unwind_info")]
+    cfi: Option<alloc::vec::Vec<CfiOp>>,
+    /// Unwinding rules for the code of `template`, if it could be analyzed. Only computed when it
+    /// can be copied close to the template.
+    #[cfg(feature = "unwind_info")]
+    template_cfi: Option<alloc::vec::Vec<CfiOp>>,
+}
+
+/// Relocation results, including failures, so that a template which cannot be relocated is not
+/// disassembled again on every attempt.
+#[cfg(feature = "safe_jit")]
+type PrologueCache = BTreeMap<usize, Result<&'static ThunkPrologue, PrologueError>>;
+
+#[cfg(all(feature = "safe_jit", not(feature = "std")))]
+static PROLOGUE_CACHE: spin::RwLock<PrologueCache> = spin::RwLock::new(BTreeMap::new());
+#[cfg(all(feature = "safe_jit", feature = "std"))]
+static PROLOGUE_CACHE: std::sync::RwLock<PrologueCache> = std::sync::RwLock::new(BTreeMap::new());
+
+/// An entry of [`PROLOGUE_INDEX`].
+#[cfg(feature = "safe_jit")]
+struct PrologueIndexEntry {
+    key: usize,
+    prologue: Result<&'static ThunkPrologue, PrologueError>,
+}
+
+#[cfg(feature = "safe_jit")]
+const PROLOGUE_INDEX_SIZE: usize = 256;
+
+/// Lock-free open addressing index of the first [`PROLOGUE_INDEX_SIZE`] entries of the relocation
+/// cache, so that creating a thunk from a template which was already relocated does not take the
+/// cache lock. Entries are leaked and only inserted while holding the cache's write lock.
+#[cfg(feature = "safe_jit")]
+static PROLOGUE_INDEX: [AtomicPtr<PrologueIndexEntry>; PROLOGUE_INDEX_SIZE] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; PROLOGUE_INDEX_SIZE];
+
+/// Returns the slots of [`PROLOGUE_INDEX`] to probe for `key`, in order.
+#[cfg(feature = "safe_jit")]
+fn prologue_index_probe(
+    key: usize,
+) -> impl Iterator<Item = &'static AtomicPtr<PrologueIndexEntry>> {
+    // Fibonacci hashing of the template address
+    let start = (key as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 56;
+    (0..PROLOGUE_INDEX_SIZE)
+        .map(move |i| &PROLOGUE_INDEX[(start as usize + i) % PROLOGUE_INDEX_SIZE])
+}
+
+impl ThunkPrologue {
+    /// Locates the `asm!` block in the thunk template and relocates the prologue if the `safe_jit`
+    /// feature is enabled.
+    ///
+    /// # Errors
+    /// If the `safe_jit` feature is enabled and the prologue cannot be relocated.
+    ///
+    /// # Safety
+    /// `thunk_template_ptr` must point to a thunk template for a non-ZST closure, with the thumb
+    /// bit cleared.
+    unsafe fn new(thunk_template_ptr: *const u8) -> Result<Self, PrologueError> {
+        const MAGIC_ALIGN: usize = align_of::<consts::Magic>();
+
+        // Align to pointer size and search for the magic number to be replaced by the
+        // closure address
+        let mut template_magic_offset = thunk_template_ptr.align_offset(MAGIC_ALIGN);
+        while thunk_template_ptr.add(template_magic_offset).cast::<consts::Magic>().read()
+            != consts::CLOSURE_ADDR_MAGIC
+        {
+            template_magic_offset += MAGIC_ALIGN;
+        }
+
+        let template_size = template_magic_offset.wrapping_add_signed(consts::THUNK_EXTRA_SIZE);
+        // SAFETY: The thunk template is code in the binary, so lives for 'static
+        let thunk_template: &'static [u8] =
+            unsafe { core::slice::from_raw_parts(thunk_template_ptr, template_size) };
+
+        #[cfg(not(feature = "safe_jit"))]
+        let (thunk, magic_offset) = (thunk_template.into(), template_magic_offset);
+
+        #[cfg(feature = "safe_jit")]
+        let RelocThunk {
+            thunk,
+            magic_offset,
+            rewritten,
+            rel32_fixups,
+            far_error,
+        } = crate::safe_jit::reloc_thunk_template(
+            thunk_template,
+            thunk_template_ptr as usize,
+            template_magic_offset,
+        )?;
+
+        #[cfg(feature = "unwind_info")]
+        let cfi =
+            crate::safe_jit::cfi::thunk_cfi(&thunk[..magic_offset], thunk_template_ptr as usize);
+        #[cfg(feature = "unwind_info")]
+        let template_cfi = match rel32_fixups.is_empty() {
+            true => None,
+            false => crate::safe_jit::cfi::thunk_cfi(
+                &thunk_template[..template_magic_offset],
+                thunk_template_ptr as usize,
+            ),
+        };
+
+        Ok(Self {
+            template_magic_offset,
+            thunk,
+            magic_offset,
+            #[cfg(feature = "safe_jit")]
+            rewritten,
+            #[cfg(feature = "safe_jit")]
+            template: thunk_template,
+            #[cfg(feature = "safe_jit")]
+            rel32_fixups,
+            #[cfg(feature = "safe_jit")]
+            far_error,
+            #[cfg(feature = "unwind_info")]
+            cfi,
+            #[cfg(feature = "unwind_info")]
+            template_cfi,
+        })
+    }
+
+    /// The kind of thunks created from the (possibly relocated) prologue.
+    fn far_kind(&self) -> ThunkKind {
+        match self.thunk {
+            Cow::Borrowed(_) => ThunkKind::Copied,
+            Cow::Owned(_) => ThunkKind::Relocated,
+        }
+    }
+
+    /// Size of the JIT allocation required to align the magic number in a copy of the prologue.
+    fn alloc_size(&self) -> usize {
+        self.thunk.len() + align_of::<consts::Magic>() - 1
+    }
+
+    /// Tries to allocate a copy of the unrelocated template close enough to it for the
+    /// IP-relative displacements in its prologue to still reach their targets. This avoids the
+    /// larger relocated prologue, and is the only option if it could not be relocated.
+    ///
+    /// Returns `None` if the allocation is too far for the adjusted displacements to fit in 32
+    /// bits. `extra_size` bytes are allocated past the end of the copy.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails, including when it does not support near allocations.
+    #[cfg(feature = "safe_jit")]
+    fn alloc_near<J: JitAlloc>(
+        &self,
+        jit: &J,
+        extra_size: usize,
+    ) -> Result<Option<ThunkPlacement<'_>>, crate::jit_alloc::JitAllocError> {
+        /// Keeps every byte of the thunk within 1 GiB of the template, so that displacements to
+        /// the same binary almost always stay within the ±2 GiB reach of a rel32.
+        const NEAR_RANGE: usize = 1 << 30;
+        const MAGIC_ALIGN: usize = align_of::<consts::Magic>();
+
+        let template = self.template.as_ptr();
+        let size = self.template.len() + MAGIC_ALIGN - 1 + extra_size;
+        let (rx, rw) = jit.alloc_near(template, NEAR_RANGE, size)?;
+        // SAFETY: The magic number is within the template, so within the allocation
+        let align_offset = unsafe { rw.add(self.template_magic_offset) }.align_offset(MAGIC_ALIGN);
+        let (thunk_rx, thunk_rw) = (rx.wrapping_add(align_offset), rw.wrapping_add(align_offset));
+
+        let delta = (template as isize).wrapping_sub(thunk_rx as isize);
+        let rel32_patches = self
+            .rel32_fixups
+            .iter()
+            .map(|&offset| {
+                let disp =
+                    i32::from_le_bytes(self.template[offset..offset + 4].try_into().unwrap());
+                Some((
+                    offset,
+                    i32::try_from((disp as isize).checked_add(delta)?).ok()?,
+                ))
+            })
+            .collect::<Option<alloc::vec::Vec<_>>>();
+
+        let Some(rel32_patches) = rel32_patches
+        else {
+            // SAFETY: `rx` was just allocated from `jit`
+            let _ = unsafe { jit.release(rx) };
+            return Ok(None);
+        };
+        Ok(Some(ThunkPlacement {
+            alloc_base: rx,
+            thunk_rx,
+            thunk_rw,
+            code: self.template,
+            magic_offset: self.template_magic_offset,
+            rel32_patches,
+            #[cfg(feature = "unwind_info")]
+            cfi: self.template_cfi.as_deref(),
+        }))
+    }
+
+    /// Gets the prologue of the thunk template from the relocation cache, relocating it and adding
+    /// it to the cache if not present.
+    ///
+    /// Since thunk templates are only ever instantiated by the compiler, the cache is bounded and
+    /// its entries are never freed. Looking up a cached prologue is lock-free, unless many
+    /// templates were cached before it.
+    ///
+    /// # Errors
+    /// If the prologue cannot be relocated.
+    ///
+    /// # Safety
+    /// See [`ThunkPrologue::new`].
+    #[cfg(feature = "safe_jit")]
+    unsafe fn cached(thunk_template_ptr: *const u8) -> Result<&'static Self, PrologueError> {
+        let key = thunk_template_ptr as usize;
+
+        for slot in prologue_index_probe(key) {
+            // SAFETY: Entries are leaked, so live for 'static
+            match unsafe { slot.load(Ordering::Acquire).as_ref() } {
+                Some(entry) if entry.key == key => return entry.prologue,
+                Some(_) => continue,
+                None => break,
+            }
+        }
+
+        #[cfg(not(feature = "std"))]
+        let cached = PROLOGUE_CACHE.read().get(&key).copied();
+        #[cfg(feature = "std")]
+        let cached = PROLOGUE_CACHE.read().unwrap().get(&key).copied();
+
+        if let Some(prologue) = cached {
+            return prologue;
+        }
+
+        // Relocate without holding the lock, as this is relatively expensive
+        let prologue = Self::new(thunk_template_ptr);
+
+        #[cfg(not(feature = "std"))]
+        let mut cache = PROLOGUE_CACHE.write();
+        #[cfg(feature = "std")]
+        let mut cache = PROLOGUE_CACHE.write().unwrap();
+
+        // Another thread may have beaten us to it
+        let prologue = *cache
+            .entry(key)
+            .or_insert_with(|| prologue.map(|p| &*alloc::boxed::Box::leak(p.into())));
+
+        // Index the entry unless it already is. Holding the write lock makes us the only writer
+        for slot in prologue_index_probe(key) {
+            // SAFETY: Entries are leaked, so live for 'static
+            match unsafe { slot.load(Ordering::Relaxed).as_ref() } {
+                Some(entry) if entry.key == key => break,
+                Some(_) => continue,
+                None => {
+                    let entry = PrologueIndexEntry { key, prologue };
+                    slot.store(
+                        alloc::boxed::Box::leak(alloc::boxed::Box::new(entry)),
+                        Ordering::Release,
+                    );
+                    break;
+                }
+            }
+        }
+        prologue
+    }
+}
+
+/// Where the closure invoked by a thunk is stored.
+#[derive(Clone, Copy)]
+enum ClosureLocation {
+    /// The closure is stored elsewhere, and the thunk points to it.
+    Ptr(*const ()),
+    /// The closure is copied to the JIT allocation, after the thunk's code.
+    Inline {
+        bytes: *const u8,
+        layout: core::alloc::Layout,
+    },
+}
+
+/// A JIT allocation for a thunk, along with the prologue code to copy to it.
+struct ThunkPlacement<'a> {
+    alloc_base: *const u8,
+    thunk_rx: *const u8,
+    thunk_rw: *mut u8,
+    code: &'a [u8],
+    /// Offset of the magic number in `code`.
+    magic_offset: usize,
+    /// 32-bit displacements to write to the copy of `code`, as `(offset, value)` pairs.
+    #[cfg(feature = "safe_jit")]
+    rel32_patches: alloc::vec::Vec<(usize, i32)>,
+    /// Unwinding rules for the copy of `code`, if it could be analyzed.
+    #[cfg(feature = "unwind_info")]
+    cfi: Option<&'a [CfiOp]>,
+}
+
 #[derive(Debug)]
 pub(crate) struct AllocatedThunk<J: JitAlloc> {
     alloc_base: *const u8,
     thunk: *const (),
+    closure: *const (),
+    template: *const u8,
+    kind: ThunkKind,
+    #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+    symbol: Option<ThunkSymbol>,
+    #[cfg(feature = "unwind_info")]
+    frame: Option<FrameRegistration>,
     jit: J,
 }
 
 impl<J: JitAlloc> Drop for AllocatedThunk<J> {
     fn drop(&mut self) {
+        // Unregister the symbol before the memory can be reused by another thunk
+        #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+        drop(self.symbol.take());
+        #[cfg(feature = "unwind_info")]
+        drop(self.frame.take());
+
         if !self.alloc_base.is_null() {
             let _ = unsafe { self.jit.release(self.alloc_base) };
         }
@@ -178,18 +516,185 @@ impl<J: JitAlloc> Drop for AllocatedThunk<J> {
 }
 
 impl<J: JitAlloc> AllocatedThunk<J> {
+    /// Relocates the prologue of a thunk template and stores the result in the relocation cache, so
+    /// that future calls to [`AllocatedThunk::new`] with the same template only copy it.
+    ///
+    /// Does nothing if the `safe_jit` feature is disabled or if `closure_size` is zero.
+    ///
+    /// # Errors
+    /// If the `safe_jit` feature is enabled and the thunk template prologue cannot be relocated.
+    ///
+    /// # Safety
+    /// The same requirements on `thunk_template_ptr` and `closure_size` as
+    /// [`AllocatedThunk::new`] apply.
+    #[cfg_attr(not(feature = "safe_jit"), allow(unused_variables))]
+    pub unsafe fn prewarm(
+        thunk_template_ptr: *const u8,
+        closure_size: usize,
+    ) -> Result<(), PrologueError> {
+        #[cfg(feature = "safe_jit")]
+        if closure_size != 0 {
+            #[cfg(thumb_mode)]
+            let thunk_template_ptr = thunk_template_ptr.map_addr(|a| a & !1);
+
+            ThunkPrologue::cached(thunk_template_ptr)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the JIT allocation [`AllocatedThunk::new`] makes for a thunk template,
+    /// or zero if `closure_size` is zero.
+    ///
+    /// If the `safe_jit` feature is enabled, this relocates the prologue and caches it like
+    /// [`AllocatedThunk::prewarm`].
+    ///
+    /// # Errors
+    /// If the `safe_jit` feature is enabled and the thunk template prologue cannot be relocated.
+    ///
+    /// # Safety
+    /// The same requirements on `thunk_template_ptr` and `closure_size` as
+    /// [`AllocatedThunk::new`] apply.
+    pub unsafe fn alloc_size(
+        thunk_template_ptr: *const u8,
+        closure_size: usize,
+    ) -> Result<usize, PrologueError> {
+        if closure_size == 0 {
+            return Ok(0);
+        }
+
+        #[cfg(thumb_mode)]
+        let thunk_template_ptr = thunk_template_ptr.map_addr(|a| a & !1);
+
+        #[cfg(not(feature = "safe_jit"))]
+        let prologue = &ThunkPrologue::new(thunk_template_ptr)?;
+        #[cfg(feature = "safe_jit")]
+        let prologue = ThunkPrologue::cached(thunk_template_ptr)?;
+
+        Ok(prologue.alloc_size())
+    }
+
+    /// Returns the size of the JIT allocation [`AllocatedThunk::new_inline`] makes for a thunk
+    /// template and a closure of layout `closure_layout`, or zero if the closure is a ZST.
+    ///
+    /// # Errors
+    /// Same as [`AllocatedThunk::alloc_size`].
+    ///
+    /// # Safety
+    /// The same requirements on `thunk_template_ptr` as [`AllocatedThunk::new_inline`] apply.
+    pub unsafe fn alloc_size_inline(
+        thunk_template_ptr: *const u8,
+        closure_layout: core::alloc::Layout,
+    ) -> Result<usize, PrologueError> {
+        let size = Self::alloc_size(thunk_template_ptr, closure_layout.size())?;
+        Ok(match size {
+            0 => 0,
+            size => size + closure_layout.size() + closure_layout.align() - 1,
+        })
+    }
+
     /// Gets a pointer to the JITed bare function thunk.
     pub fn thunk_ptr(&self) -> *const () {
         self.thunk
     }
 
+    /// Gets the closure pointer the thunk was created with.
+    pub fn closure_ptr(&self) -> *const () {
+        self.closure
+    }
+
+    /// Returns `true` if the thunk was emitted to JIT memory, i.e. the closure is not a ZST.
+    pub fn is_jit(&self) -> bool {
+        !self.alloc_base.is_null()
+    }
+
+    /// Registers the thunk with profilers and debuggers under a symbol naming the closure type `T`
+    /// and bare function type `B`, if the `perf_map` or `gdb_jit` features are enabled.
+    ///
+    /// Does nothing if the thunk was not emitted to JIT memory, as the thunk template already has
+    /// a symbol.
+    #[inline]
+    #[cfg_attr(
+        not(any(feature = "perf_map", feature = "gdb_jit")),
+        allow(unused_mut, clippy::extra_unused_type_parameters)
+    )]
+    pub fn with_symbol<B, T: ?Sized>(mut self) -> Self {
+        #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+        if self.is_jit() {
+            let info = self.info();
+            let symbol = ThunkSymbol::register(
+                info.thunk_addr(),
+                info.code().len(),
+                &jit_symbols::thunk_name::<B, T>(),
+            );
+            self.symbol = Some(symbol);
+        }
+        self
+    }
+
+    /// Returns debug information about the code of the thunk.
+    pub fn info(&self) -> ThunkInfo<'_> {
+        let mut info = ThunkInfo {
+            kind: self.kind,
+            thunk_addr: self.thunk as usize,
+            template_addr: self.template as usize,
+            code: &[],
+            magic_offset: None,
+            closure_ptr_offset: None,
+            return_addr_offset: None,
+            rewritten: alloc::vec::Vec::new(),
+        };
+        if self.kind == ThunkKind::Template {
+            return info;
+        }
+
+        // When in thumb mode, the thunk and template pointers have the lower bit set to 1
+        #[cfg(thumb_mode)]
+        let (template, thunk) = (
+            self.template.map_addr(|a| a & !1),
+            self.thunk.map_addr(|a| a & !1),
+        );
+        #[cfg(not(thumb_mode))]
+        let (template, thunk) = (self.template, self.thunk);
+
+        // SAFETY: The prologue was successfully created from this template by `new`
+        #[cfg(not(feature = "safe_jit"))]
+        let prologue = &unsafe { ThunkPrologue::new(template) }.unwrap();
+        #[cfg(feature = "safe_jit")]
+        let prologue = unsafe { ThunkPrologue::cached(template) }.unwrap();
+
+        let (len, magic_offset) = match self.kind {
+            #[cfg(feature = "safe_jit")]
+            ThunkKind::Near => (prologue.template.len(), prologue.template_magic_offset),
+            _ => (prologue.thunk.len(), prologue.magic_offset),
+        };
+
+        // SAFETY: The thunk's code is live for as long as `self`
+        info.code = unsafe { core::slice::from_raw_parts(thunk.cast::<u8>(), len) };
+        info.magic_offset = Some(magic_offset);
+        info.closure_ptr_offset =
+            Some(magic_offset.wrapping_add_signed(consts::CLOSURE_ADDR_OFFSET));
+        info.return_addr_offset =
+            Some(magic_offset.wrapping_add_signed(consts::THUNK_RETURN_ADDR_OFFSET));
+
+        #[cfg(feature = "safe_jit")]
+        {
+            info.rewritten = match self.kind {
+                ThunkKind::Relocated => prologue.rewritten.clone(),
+                ThunkKind::Near => prologue.rel32_fixups.iter().map(|&o| o..o + 4).collect(),
+                _ => alloc::vec::Vec::new(),
+            };
+        }
+        info
+    }
+
     /// JITs a thunk to a closure from a thunk template.
     ///
     /// Note that if the closure is a ZST, no JIT allocation occurs as the thunk template is a valid
     /// thunk for all instances of the closure.
     ///
-    /// # Panics
-    /// If the `safe_jit` feature is enabled and the thunk template prologue cannot be relocated.
+    /// # Errors
+    /// If the JIT allocator fails, or if the `safe_jit` feature is enabled and the thunk template
+    /// prologue cannot be relocated.
     ///
     /// # Safety
     /// Given a closure of type `F`, the following must hold:
@@ -206,57 +711,145 @@ impl<J: JitAlloc> AllocatedThunk<J> {
         closure_ptr: *const (),
         closure_size: usize,
         jit: J,
-    ) -> Result<Self, JitAllocError> {
+    ) -> Result<Self, ThunkError> {
         if closure_size == 0 {
             return Ok(AllocatedThunk {
                 alloc_base: core::ptr::null(),
                 thunk: thunk_template_ptr.cast(),
+                closure: closure_ptr,
+                template: thunk_template_ptr,
+                kind: ThunkKind::Template,
+                #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+                symbol: None,
+                #[cfg(feature = "unwind_info")]
+                frame: None,
                 jit,
             });
         }
 
-        const MAGIC_ALIGN: usize = align_of::<consts::Magic>();
+        Self::emit(thunk_template_ptr, ClosureLocation::Ptr(closure_ptr), jit)
+    }
+
+    /// JITs a thunk to a closure from a thunk template, moving the closure into the executable
+    /// memory right after the thunk's code instead of pointing to it.
+    ///
+    /// This avoids a separate allocation for small closures, but the closure is read-only and
+    /// is never dropped. It must thus only be called by shared reference.
+    ///
+    /// # Errors
+    /// Same as [`AllocatedThunk::new`].
+    ///
+    /// # Safety
+    /// `thunk_template` must be a pointer obtained via the associated const of the
+    /// `crate::thunk::FnThunk<B>` trait implemented on `F`.
+    pub unsafe fn new_inline<F>(
+        thunk_template_ptr: *const u8,
+        closure: F,
+        jit: J,
+    ) -> Result<Self, ThunkError> {
+        const { assert!(!core::mem::needs_drop::<F>()) };
+
+        // The thunk template of a ZST closure is already a valid thunk
+        if size_of::<F>() == 0 {
+            return Self::new(thunk_template_ptr, core::ptr::dangling(), 0, jit);
+        }
+
+        let closure = core::mem::ManuallyDrop::new(closure);
+        let location = ClosureLocation::Inline {
+            bytes: (&raw const closure).cast(),
+            layout: core::alloc::Layout::new::<F>(),
+        };
+        Self::emit(thunk_template_ptr, location, jit)
+    }
 
+    /// JITs a thunk invoking the closure at `closure` from a thunk template for a non-ZST
+    /// closure.
+    unsafe fn emit(
+        thunk_template_ptr: *const u8,
+        closure: ClosureLocation,
+        jit: J,
+    ) -> Result<Self, ThunkError> {
         // When in thumb mode, the thunk pointer will have the lower bit set to 1. Clear it
         #[cfg(thumb_mode)]
         let thunk_template_ptr = thunk_template_ptr.map_addr(|a| a & !1);
 
-        // Align to pointer size and search for the magic number to be replaced by the
-        // closure address
-        let mut template_magic_offset = thunk_template_ptr.align_offset(MAGIC_ALIGN);
-        while thunk_template_ptr.add(template_magic_offset).cast::<consts::Magic>().read()
-            != consts::CLOSURE_ADDR_MAGIC
-        {
-            template_magic_offset += MAGIC_ALIGN;
-        }
+        #[cfg(not(feature = "safe_jit"))]
+        let prologue = &ThunkPrologue::new(thunk_template_ptr)?;
+        #[cfg(feature = "safe_jit")]
+        let prologue = ThunkPrologue::cached(thunk_template_ptr)?;
 
-        let template_size = template_magic_offset.wrapping_add_signed(consts::THUNK_EXTRA_SIZE);
-        let thunk_template =
-            unsafe { core::slice::from_raw_parts(thunk_template_ptr, template_size) };
+        // Inline closures may need padding to be aligned
+        let extra_size = match closure {
+            ClosureLocation::Ptr(_) => 0,
+            ClosureLocation::Inline { layout, .. } => layout.size() + layout.align() - 1,
+        };
 
-        #[cfg(not(feature = "safe_jit"))]
-        let (thunk, magic_offset) = (thunk_template, template_magic_offset);
+        let template_magic_offset = prologue.template_magic_offset;
 
+        // If the prologue has IP-relative operands, prefer copying it verbatim close to the
+        // template over using the relocated prologue
         #[cfg(feature = "safe_jit")]
-        let RelocThunk {
-            thunk,
-            magic_offset,
-        } = crate::safe_jit::reloc_thunk_template(
-            thunk_template,
-            thunk_template_ptr as usize,
-            template_magic_offset,
+        let near = match prologue.rel32_fixups.is_empty() {
+            true => None,
+            false => match (prologue.alloc_near(&jit, extra_size), prologue.far_error) {
+                (Ok(Some(near)), _) => Some(near),
+                (Ok(None), None) | (Err(_), None) => None,
+                // Without a relocated prologue, the allocator error is more relevant than the
+                // reason the prologue was not relocated, unless near allocations are unsupported
+                (Err(e), Some(_)) if e != crate::jit_alloc::JitAllocError::Unsupported => {
+                    return Err(e.into())
+                }
+                (_, Some(far_error)) => return Err(far_error.into()),
+            },
+        };
+
+        #[cfg(feature = "safe_jit")]
+        let (placement, kind) = match near {
+            Some(near) => (near, ThunkKind::Near),
+            None => (
+                Self::alloc_far(&jit, prologue, extra_size)?,
+                prologue.far_kind(),
+            ),
+        };
+        #[cfg(not(feature = "safe_jit"))]
+        let (placement, kind) = (
+            Self::alloc_far(&jit, prologue, extra_size)?,
+            prologue.far_kind(),
         );
 
-        // Skip initial bytes for proper alignment
-        let (rx, rw) = jit.alloc(thunk.len() + MAGIC_ALIGN - 1)?;
-        let align_offset = rw.add(magic_offset).align_offset(MAGIC_ALIGN);
-        let (thunk_rx, rw) = (rx.add(align_offset), rw.add(align_offset));
+        let ThunkPlacement {
+            alloc_base: rx,
+            thunk_rx,
+            thunk_rw: rw,
+            code: thunk,
+            magic_offset,
+            ..
+        } = placement;
+
+        let (closure_ptr, len) = match closure {
+            ClosureLocation::Ptr(ptr) => (ptr, thunk.len()),
+            ClosureLocation::Inline { layout, .. } => {
+                let offset = thunk.len() + thunk_rx.add(thunk.len()).align_offset(layout.align());
+                (thunk_rx.add(offset).cast(), offset + layout.size())
+            }
+        };
 
-        jit.protect_jit_memory(thunk_rx, thunk.len(), ProtectJitAccess::ReadWrite);
+        jit.protect_jit_memory(thunk_rx, len, ProtectJitAccess::ReadWrite);
 
         // Copy the prologue + asm block from the compiler-generated thunk
         core::ptr::copy_nonoverlapping(thunk.as_ptr(), rw, thunk.len());
 
+        if let ClosureLocation::Inline { bytes, layout } = closure {
+            let offset = closure_ptr as usize - thunk_rx as usize;
+            core::ptr::copy_nonoverlapping(bytes, rw.add(offset), layout.size());
+        }
+
+        // Point the IP-relative operands of a verbatim copy of the template back to their targets
+        #[cfg(feature = "safe_jit")]
+        for &(offset, disp) in &placement.rel32_patches {
+            rw.add(offset).cast::<i32>().write_unaligned(disp);
+        }
+
         // Write the closure pointer
         rw.add(magic_offset.wrapping_add_signed(consts::CLOSURE_ADDR_OFFSET))
             .cast::<*const ()>()
@@ -272,8 +865,15 @@ impl<J: JitAlloc> AllocatedThunk<J> {
             .cast::<*const u8>()
             .write_unaligned(thunk_return);
 
-        jit.protect_jit_memory(thunk_rx, thunk.len(), ProtectJitAccess::ReadExecute);
-        jit.flush_instruction_cache(thunk_rx, thunk.len());
+        jit.protect_jit_memory(thunk_rx, len, ProtectJitAccess::ReadExecute);
+        jit.flush_instruction_cache(thunk_rx, len);
+
+        // Describe the prologue to unwinders now that the code is in place. The rest of the thunk
+        // is data
+        #[cfg(feature = "unwind_info")]
+        let frame = placement
+            .cfi
+            .and_then(|cfi| FrameRegistration::register(thunk_rx as usize, magic_offset, cfi));
 
         // When in thumb mode, set the lower bit to one so we don't switch to A32 mode
         #[cfg(thumb_mode)]
@@ -282,9 +882,41 @@ impl<J: JitAlloc> AllocatedThunk<J> {
         Ok(AllocatedThunk {
             alloc_base: rx,
             thunk: thunk_rx.cast(),
+            closure: closure_ptr,
+            template: thunk_template_ptr,
+            kind,
+            #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+            symbol: None,
+            #[cfg(feature = "unwind_info")]
+            frame,
             jit,
         })
     }
+
+    /// Allocates memory anywhere for the (possibly relocated) prologue, with `extra_size` bytes
+    /// past the end of the copy.
+    unsafe fn alloc_far<'a>(
+        jit: &J,
+        prologue: &'a ThunkPrologue,
+        extra_size: usize,
+    ) -> Result<ThunkPlacement<'a>, ThunkError> {
+        const MAGIC_ALIGN: usize = align_of::<consts::Magic>();
+
+        // Skip initial bytes for proper alignment
+        let (rx, rw) = jit.alloc(prologue.alloc_size() + extra_size)?;
+        let align_offset = rw.add(prologue.magic_offset).align_offset(MAGIC_ALIGN);
+        Ok(ThunkPlacement {
+            alloc_base: rx,
+            thunk_rx: rx.add(align_offset),
+            thunk_rw: rw.add(align_offset),
+            code: &prologue.thunk,
+            magic_offset: prologue.magic_offset,
+            #[cfg(feature = "safe_jit")]
+            rel32_patches: alloc::vec::Vec::new(),
+            #[cfg(feature = "unwind_info")]
+            cfi: prologue.cfi.as_deref(),
+        })
+    }
 }
 
 /// Runs the provided closure and returns the result.
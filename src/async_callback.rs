@@ -0,0 +1,457 @@
+//! Bridges between callback-driven foreign APIs and async Rust.
+//!
+//! - [`CallbackFuture`] owns a one-shot [`BareFnMutSync`] callback and is a [`Future`] that
+//!   resolves to the item produced when the callback is first called, e.g. for completion
+//!   callbacks.
+//! - [`CallbackStream`] owns a [`BareFnMutSync`] callback and yields an item each time it is
+//!   called, e.g. for event notifications or enumerators.
+//!
+//! The arguments of the callback are converted to an owned item by a closure, which is then
+//! pushed through a waker-aware channel. No executor, async runtime or `std` is required:
+//!
+//! ```
+//! # #[cfg(feature = "default_jit_alloc")] {
+//! use core::{
+//!     future::Future,
+//!     pin::pin,
+//!     task::{Context, Poll, Waker},
+//! };
+//!
+//! use closure_ffi::async_callback::CallbackFuture;
+//!
+//! // A foreign function reporting its result through a completion callback
+//! unsafe extern "C" fn compute(on_done: unsafe extern "C" fn(u32, i32)) {
+//!     unsafe { on_done(42, 0) };
+//! }
+//!
+//! let future =
+//!     CallbackFuture::<unsafe extern "C" fn(u32, i32), _>::new(|(value, status)| (value, status));
+//! unsafe { compute(future.bare()) };
+//!
+//! let mut cx = Context::from_waker(Waker::noop());
+//! assert_eq!(pin!(future).poll(&mut cx), Poll::Ready((42, 0)));
+//! # }
+//! ```
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    ops::DerefMut,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+#[cfg(feature = "global_jit_alloc")]
+use crate::jit_alloc::GlobalJitAlloc;
+use crate::{
+    bare_closure::BareFnMutSync, error::ThunkError, jit_alloc::JitAlloc, thunk_factory,
+    traits::FnPtr,
+};
+
+struct ChannelState<T> {
+    items: VecDeque<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+#[cfg(feature = "std")]
+type Lock<T> = std::sync::Mutex<T>;
+// Without `std`, the `spin` dependency may not be enabled. Critical sections only push or pop an
+// item and swap the waker, so a minimal spin lock is enough.
+#[cfg(not(feature = "std"))]
+type Lock<T> = spin_lock::SpinLock<T>;
+
+// A multi-producer, single-consumer queue which wakes the consumer when an item is pushed
+struct Channel<T> {
+    state: Lock<ChannelState<T>>,
+}
+
+impl<T> Channel<T> {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Lock::new(ChannelState {
+                items: VecDeque::new(),
+                waker: None,
+                closed: false,
+            }),
+        })
+    }
+
+    fn lock(&self) -> impl DerefMut<Target = ChannelState<T>> + '_ {
+        // The state is always consistent, even if a panic occurred while the lock was held
+        #[cfg(feature = "std")]
+        return self.state.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        #[cfg(not(feature = "std"))]
+        return self.state.lock();
+    }
+
+    fn push(&self, item: T) {
+        let mut state = self.lock();
+        if state.closed {
+            return;
+        }
+        state.items.push_back(item);
+        let waker = state.waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        let waker = state.waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        self.lock().items.pop_front()
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.lock();
+        if let Some(item) = state.items.pop_front() {
+            return Poll::Ready(Some(item));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        match &mut state.waker {
+            Some(waker) => waker.clone_from(cx.waker()),
+            None => state.waker = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod spin_lock {
+    use core::{
+        cell::UnsafeCell,
+        ops::{Deref, DerefMut},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    pub struct SpinLock<T> {
+        locked: AtomicBool,
+        value: UnsafeCell<T>,
+    }
+
+    // SAFETY: The value is only accessed by the thread holding the lock
+    unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+    impl<T> SpinLock<T> {
+        pub const fn new(value: T) -> Self {
+            Self {
+                locked: AtomicBool::new(false),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        pub fn lock(&self) -> SpinLockGuard<'_, T> {
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            SpinLockGuard(self)
+        }
+    }
+
+    pub struct SpinLockGuard<'a, T>(&'a SpinLock<T>);
+
+    impl<T> Deref for SpinLockGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            // SAFETY: The lock is held
+            unsafe { &*self.0.value.get() }
+        }
+    }
+
+    impl<T> DerefMut for SpinLockGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            // SAFETY: The lock is held
+            unsafe { &mut *self.0.value.get() }
+        }
+    }
+
+    impl<T> Drop for SpinLockGuard<'_, T> {
+        fn drop(&mut self) {
+            self.0.locked.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// A [`Future`] resolving to the item produced by a one-shot callback.
+///
+/// The future owns the [`BareFnMutSync`] callback, which is released along with the closure when
+/// the future is dropped, whether or not the callback was called. The future resolves once the
+/// callback is called. Later calls are ignored.
+#[cfg(feature = "global_jit_alloc")]
+pub struct CallbackFuture<'a, B: FnPtr, T, A: JitAlloc = GlobalJitAlloc> {
+    callback: BareFnMutSync<'a, B, A>,
+    channel: Arc<Channel<T>>,
+}
+
+/// A [`Future`] resolving to the item produced by a one-shot callback.
+///
+/// The future owns the [`BareFnMutSync`] callback, which is released along with the closure when
+/// the future is dropped, whether or not the callback was called. The future resolves once the
+/// callback is called. Later calls are ignored.
+#[cfg(not(feature = "global_jit_alloc"))]
+pub struct CallbackFuture<'a, B: FnPtr, T, A: JitAlloc> {
+    callback: BareFnMutSync<'a, B, A>,
+    channel: Arc<Channel<T>>,
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<'a, B: FnPtr + 'a, T: Send + 'a> CallbackFuture<'a, B, T, GlobalJitAlloc> {
+    /// Creates a one-shot callback of signature `B` along with a future resolving to the item
+    /// `convert` produces from its arguments.
+    ///
+    /// `convert` receives the arguments of the callback as a tuple. The callback returns
+    /// `Default::default()`, e.g. `()` or `0`.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new<F>(convert: F) -> Self
+    where
+        F: Send + 'a + for<'x, 'y, 'z> FnOnce(B::Args<'x, 'y, 'z>) -> T,
+        for<'x, 'y, 'z> B::Ret<'x, 'y, 'z>: Default,
+    {
+        Self::new_in(convert, Default::default())
+    }
+}
+
+impl<'a, B: FnPtr + 'a, T: Send + 'a, A: JitAlloc> CallbackFuture<'a, B, T, A> {
+    /// Creates a one-shot callback of signature `B` along with a future resolving to the item
+    /// `convert` produces from its arguments. See [`CallbackFuture::new`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_new_in<F>(convert: F, jit_alloc: A) -> Result<Self, ThunkError>
+    where
+        F: Send + 'a + for<'x, 'y, 'z> FnOnce(B::Args<'x, 'y, 'z>) -> T,
+        for<'x, 'y, 'z> B::Ret<'x, 'y, 'z>: Default,
+    {
+        let channel = Channel::new();
+        let sender = channel.clone();
+        let mut convert = Some(convert);
+        let thunk = thunk_factory::make_mut_send::<B, _>(move |args| {
+            if let Some(convert) = convert.take() {
+                sender.push(convert(args));
+            }
+            Default::default()
+        });
+
+        let callback = BareFnMutSync::try_with_thunk_in(thunk, jit_alloc)?;
+        Ok(Self { callback, channel })
+    }
+
+    /// Creates a one-shot callback of signature `B` along with a future resolving to the item
+    /// `convert` produces from its arguments. See [`CallbackFuture::new`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_new_in`].
+    #[inline]
+    pub fn new_in<F>(convert: F, jit_alloc: A) -> Self
+    where
+        F: Send + 'a + for<'x, 'y, 'z> FnOnce(B::Args<'x, 'y, 'z>) -> T,
+        for<'x, 'y, 'z> B::Ret<'x, 'y, 'z>: Default,
+    {
+        Self::try_new_in(convert, jit_alloc).unwrap()
+    }
+}
+
+impl<B: FnPtr, T, A: JitAlloc> CallbackFuture<'_, B, T, A> {
+    /// Returns the bare function pointer of the callback.
+    ///
+    /// # Safety
+    /// While this method is safe, the returned function pointer is not. In particular, it
+    /// must not be called when:
+    /// - The lifetime of `self` has expired, or `self` has been dropped.
+    /// - A previous call is still in progress (e.g. through recursion) or concurrent with the
+    ///   current one.
+    #[inline]
+    pub fn bare(&self) -> B {
+        self.callback.bare()
+    }
+}
+
+impl<B: FnPtr, T, A: JitAlloc> Future for CallbackFuture<'_, B, T, A> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The channel is never closed, so it is only ready once the callback was called
+        match self.channel.poll_recv(cx) {
+            Poll::Ready(Some(item)) => Poll::Ready(item),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// A stream of the items produced each time a callback is called.
+///
+/// The stream owns the [`BareFnMutSync`] callback, which is released when the stream is
+/// dropped. It ends once [`close`](CallbackStream::close) is called and the items produced
+/// before have been received.
+///
+/// As `core` has no `Stream` trait, items are received through [`poll_next`], which matches
+/// the signature of `Stream::poll_next` from the `futures` crate, or through [`recv`].
+///
+/// [`poll_next`]: CallbackStream::poll_next
+/// [`recv`]: CallbackStream::recv
+#[cfg(feature = "global_jit_alloc")]
+pub struct CallbackStream<'a, B: FnPtr, T, A: JitAlloc = GlobalJitAlloc> {
+    callback: BareFnMutSync<'a, B, A>,
+    channel: Arc<Channel<T>>,
+}
+
+/// A stream of the items produced each time a callback is called.
+///
+/// The stream owns the [`BareFnMutSync`] callback, which is released when the stream is
+/// dropped. It ends once [`close`](CallbackStream::close) is called and the items produced
+/// before have been received.
+///
+/// As `core` has no `Stream` trait, items are received through [`poll_next`], which matches
+/// the signature of `Stream::poll_next` from the `futures` crate, or through [`recv`].
+///
+/// [`poll_next`]: CallbackStream::poll_next
+/// [`recv`]: CallbackStream::recv
+#[cfg(not(feature = "global_jit_alloc"))]
+pub struct CallbackStream<'a, B: FnPtr, T, A: JitAlloc> {
+    callback: BareFnMutSync<'a, B, A>,
+    channel: Arc<Channel<T>>,
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<'a, B: FnPtr + 'a, T: Send + 'a> CallbackStream<'a, B, T, GlobalJitAlloc> {
+    /// Creates a callback of signature `B` along with a stream of the items `convert` produces
+    /// from its arguments each time it is called.
+    ///
+    /// `convert` receives the arguments of the callback as a tuple. The callback returns
+    /// `Default::default()`, e.g. `()` or `0`.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new<F>(convert: F) -> Self
+    where
+        F: Send + 'a + for<'x, 'y, 'z> FnMut(B::Args<'x, 'y, 'z>) -> T,
+        for<'x, 'y, 'z> B::Ret<'x, 'y, 'z>: Default,
+    {
+        Self::new_in(convert, Default::default())
+    }
+}
+
+impl<'a, B: FnPtr + 'a, T: Send + 'a, A: JitAlloc> CallbackStream<'a, B, T, A> {
+    /// Creates a callback of signature `B` along with a stream of the items `convert` produces
+    /// from its arguments each time it is called. See [`CallbackStream::new`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_new_in<F>(mut convert: F, jit_alloc: A) -> Result<Self, ThunkError>
+    where
+        F: Send + 'a + for<'x, 'y, 'z> FnMut(B::Args<'x, 'y, 'z>) -> T,
+        for<'x, 'y, 'z> B::Ret<'x, 'y, 'z>: Default,
+    {
+        let channel = Channel::new();
+        let sender = channel.clone();
+        let thunk = thunk_factory::make_mut_send::<B, _>(move |args| {
+            sender.push(convert(args));
+            Default::default()
+        });
+
+        let callback = BareFnMutSync::try_with_thunk_in(thunk, jit_alloc)?;
+        Ok(Self { callback, channel })
+    }
+
+    /// Creates a callback of signature `B` along with a stream of the items `convert` produces
+    /// from its arguments each time it is called. See [`CallbackStream::new`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_new_in`].
+    #[inline]
+    pub fn new_in<F>(convert: F, jit_alloc: A) -> Self
+    where
+        F: Send + 'a + for<'x, 'y, 'z> FnMut(B::Args<'x, 'y, 'z>) -> T,
+        for<'x, 'y, 'z> B::Ret<'x, 'y, 'z>: Default,
+    {
+        Self::try_new_in(convert, jit_alloc).unwrap()
+    }
+}
+
+impl<B: FnPtr, T, A: JitAlloc> CallbackStream<'_, B, T, A> {
+    /// Returns the bare function pointer of the callback.
+    ///
+    /// # Safety
+    /// While this method is safe, the returned function pointer is not. In particular, it
+    /// must not be called when:
+    /// - The lifetime of `self` has expired, or `self` has been dropped.
+    /// - A previous call is still in progress (e.g. through recursion) or concurrent with the
+    ///   current one.
+    #[inline]
+    pub fn bare(&self) -> B {
+        self.callback.bare()
+    }
+
+    /// Ends the stream. Items produced by calls to the callback after this are discarded.
+    pub fn close(&self) {
+        self.channel.close();
+    }
+
+    /// Receives the next item if one is available, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.channel.try_recv()
+    }
+
+    /// Polls for the next item of the stream, registering the current task to be woken when
+    /// the callback is called.
+    ///
+    /// Returns `Poll::Ready(None)` once the stream has been [closed](Self::close) and all
+    /// items have been received.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.channel.poll_recv(cx)
+    }
+
+    /// Returns a future resolving to the next item of the stream, or to `None` once the stream
+    /// has been [closed](Self::close) and all items have been received.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv {
+            channel: &self.channel,
+        }
+    }
+}
+
+/// Future returned by [`CallbackStream::recv`].
+pub struct Recv<'s, T> {
+    channel: &'s Channel<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.channel.poll_recv(cx)
+    }
+}
@@ -5,6 +5,9 @@
 //! provided. This can be useful when a type needs to own wrappers for functions of different
 //! signatures.
 //!
+//! [`SharedBareFn`] and [`SharedBareFnSync`] are reference-counted handles to a [`BareFn`], which
+//! can be cloned to share the closure and its bare function between several owners.
+//!
 //! # Thread Safety
 //!
 //! The closure wrapper types provided by this module are (trivially) [`Send`] if and only if both
@@ -12,7 +15,7 @@
 //!
 //! Canonically, they should also always be [`Sync`], as the invariants required for soundly
 //! calling the wrapped closure across threads are encoded in the `unsafe`ness of the
-//! bare function pointer returned by [`BareFnOnce::leak`], [`BareFnMut::bare`] and
+//! bare function pointer returned by [`BareFnOnce::bare`], [`BareFnMut::bare`] and
 //! [`BareFn::bare`], and documented on these (safe) functions.
 //!
 //! However, such a [`Sync`] impl makes it very easy to call the bare function in situations where
@@ -42,9 +45,16 @@
 //! - for [`BareFnMutAny`]: When the closure is [`Send`]. The user is still responsible for guarding
 //!   against unsynchronized calls.
 //! - for [`BareFnAny`]: When the closure is [`Sync`].
-
-use alloc::boxed::Box;
-use core::{marker::PhantomData, mem::ManuallyDrop};
+//!
+//! [`SharedBareFnAny`] handles share a [`BareFnAny`] through an [`Arc`], so they are [`Send`] and
+//! [`Sync`] only when the shared [`BareFnAny`] is both. Since any clone may drop the closure, it
+//! must then be [`Send`] in addition to [`Sync`].
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    marker::PhantomData,
+    mem::{ManuallyDrop, MaybeUninit},
+};
 
 #[cfg(feature = "proc_macros")]
 #[doc(hidden)]
@@ -52,12 +62,16 @@ pub use closure_ffi_proc_macros::bare_hrtb as bare_hrtb_impl;
 
 #[cfg(feature = "global_jit_alloc")]
 use crate::jit_alloc::GlobalJitAlloc;
+#[cfg(feature = "std")]
+use crate::thunk_factory::{thread_bound, ThreadAffinityPolicy};
 #[allow(unused_imports)]
 use crate::{
     arch::AllocatedThunk,
     cc,
-    jit_alloc::{JitAlloc, JitAllocError},
-    traits::{Any, FnMutThunk, FnOnceThunk, FnPtr, FnThunk, ToBoxedDyn},
+    error::ThunkError,
+    jit_alloc::JitAlloc,
+    thunk_info::ThunkInfo,
+    traits::{Any, FnMutThunk, FnOnceThunk, FnPtr, FnThunk, ToBoxedDyn, TupleRemove},
 };
 
 #[cfg(not(feature = "coverage"))]
@@ -264,8 +278,7 @@ macro_rules! bare_closure_impl {
         sync_alias_bound: $sync_alias_bound: ty,
         trait_ident: $trait_ident:ident,
         thunk_template: $thunk_template:ident,
-        bare_toggle: $bare_toggle:meta,
-        bare_receiver: $bare_receiver:ty,
+        self_freeing: $self_freeing:meta,
         fn_trait_doc: $fn_trait_doc:literal,
         ty_name_doc: $ty_name_doc:literal,
         with_cc_doc: $with_cc_doc:literal,
@@ -340,7 +353,7 @@ macro_rules! bare_closure_impl {
         unsafe impl<S: $($sync_bounds+)* ?Sized, A: JitAlloc> Sync for $erased_ty_name<S, A> {}
 
         impl<S: ?Sized, A: JitAlloc> $erased_ty_name<S, A> {
-            #[$bare_toggle]
+            #[cfg(not($self_freeing))]
             /// Return a type-erased pointer to the bare function thunk wrapping the closure.
             ///
             /// # Safety
@@ -350,10 +363,47 @@ macro_rules! bare_closure_impl {
             /// - The lifetime of `self` has expired, or `self` has been dropped.
             #[doc = $safety_doc]
             #[inline]
-            pub fn bare(self: $bare_receiver) -> *const () {
+            pub fn bare(&self) -> *const () {
                 self.thunk.thunk_ptr()
             }
 
+            #[cfg($self_freeing)]
+            /// Consume `self`, returning a type-erased pointer to the bare function thunk wrapping
+            /// the closure.
+            ///
+            /// Ownership of the closure and the executable memory is transferred to the thunk,
+            /// which frees both and drops the JIT allocator when it is called. If it is never
+            /// called, they are leaked.
+            ///
+            /// # Safety
+            /// While this method is safe, using the returned pointer is very much not. In
+            /// particular, the only safe thing to do with it is casting it to the exact bare
+            /// function signature it had before erasure. Even then, it must not be called when:
+            /// - The lifetime of the closure has expired.
+            #[doc = $safety_doc]
+            #[inline]
+            pub fn bare(self) -> *const () {
+                let this = ManuallyDrop::new(self);
+                let thunk_ptr = this.thunk.thunk_ptr();
+                if this.thunk.is_jit() {
+                    let cell = this.thunk.closure_ptr() as *mut OnceThunkCell<S, A>;
+                    // SAFETY: JIT thunks of self-freeing types are created by
+                    // `OnceThunkCell::alloc_thunk`, so `cell` is valid and its owner is uninit.
+                    // `this` is never used again so ownership is moved to the cell.
+                    unsafe {
+                        (*cell).owner.write(core::ptr::read(&*this));
+                    }
+                }
+                else {
+                    // The thunk template conjures zero-sized closures, so only the allocator
+                    // has to be dropped here. Note that the closure must not be dropped as the
+                    // thunk will consume it.
+                    // SAFETY: `this` is never used again
+                    drop(unsafe { core::ptr::read(&this.thunk) });
+                }
+                thunk_ptr
+            }
+
             /// Leak the underlying closure, returning the unsafe bare function pointer that invokes
             /// it.
             ///
@@ -369,6 +419,9 @@ macro_rules! bare_closure_impl {
             where
                 Self: 'static,
             {
+                #[cfg($self_freeing)]
+                return self.bare();
+                #[cfg(not($self_freeing))]
                 ManuallyDrop::new(self).thunk.thunk_ptr()
             }
 
@@ -387,6 +440,13 @@ macro_rules! bare_closure_impl {
                 // (and the foreseeable future).
                 unsafe { core::mem::transmute_copy(&ManuallyDrop::new(self)) }
             }
+
+            /// Returns debug information about the code of the bare function thunk, such as its
+            /// bytes, the address of its thunk template and the instructions rewritten by the
+            /// relocator. See [`ThunkInfo`].
+            pub fn thunk_info(&self) -> ThunkInfo<'_> {
+                self.thunk.info()
+            }
         }
 
         impl<B: FnPtr, S: ?Sized, U: ?Sized, A: JitAlloc> From<$ty_name<B, S, A>> for $erased_ty_name<U, A>
@@ -403,7 +463,16 @@ macro_rules! bare_closure_impl {
                 // SAFETY:
                 // - The caller of `bare()` promised not to call through the thunk after
                 // the lifetime of self expires, so no borrow on closure exists
-                drop(unsafe { Box::from_raw(self.storage) })
+                drop(unsafe { Box::from_raw(self.storage) });
+
+                // Free the cell holding the self-freeing closure, which was never called
+                #[cfg($self_freeing)]
+                if self.thunk.is_jit() {
+                    // SAFETY: The cell was allocated by `OnceThunkCell::alloc_thunk`
+                    drop(unsafe {
+                        Box::from_raw(self.thunk.closure_ptr() as *mut OnceThunkCell<S, A>)
+                    });
+                }
             }
         }
 
@@ -522,7 +591,7 @@ macro_rules! bare_closure_impl {
         }
 
         impl<B: FnPtr, S: ?Sized, A: JitAlloc> $ty_name<B, S, A> {
-            #[$bare_toggle]
+            #[cfg(not($self_freeing))]
             /// Return a bare function pointer that invokes the underlying closure.
             ///
             /// # Safety
@@ -531,7 +600,26 @@ macro_rules! bare_closure_impl {
             /// - The lifetime of `self` has expired, or `self` has been dropped.
             #[doc = $safety_doc]
             #[inline]
-            pub fn bare(self: $bare_receiver) -> B {
+            pub fn bare(&self) -> B {
+                // SAFETY: B is a bare function pointer
+                unsafe { B::from_ptr(self.untyped.bare()) }
+            }
+
+            #[cfg($self_freeing)]
+            /// Consume `self`, returning a bare function pointer that invokes the underlying
+            /// closure.
+            ///
+            /// Ownership of the closure and the executable memory is transferred to the thunk,
+            /// which frees both and drops the JIT allocator when it is called. If it is never
+            /// called, they are leaked.
+            ///
+            /// # Safety
+            /// While this method is safe, the returned function pointer is not. In particular, it
+            /// must not be called when:
+            /// - The lifetime of the closure has expired.
+            #[doc = $safety_doc]
+            #[inline]
+            pub fn bare(self) -> B {
                 // SAFETY: B is a bare function pointer
                 unsafe { B::from_ptr(self.untyped.bare()) }
             }
@@ -563,6 +651,21 @@ macro_rules! bare_closure_impl {
                 self.untyped
             }
 
+            /// Returns debug information about the code of the bare function thunk, such as its
+            /// bytes, the address of its thunk template and the instructions rewritten by the
+            /// relocator. See [`ThunkInfo`].
+            ///
+            /// With the `thunk_disasm` feature, `ThunkInfo::disassembly` formats an annotated
+            /// disassembly of the thunk:
+            ///
+            /// ```ignore
+            /// let bare_closure = BareFn::new_c(move |n: usize| n + offset);
+            /// println!("{}", bare_closure.thunk_info().disassembly());
+            /// ```
+            pub fn thunk_info(&self) -> ThunkInfo<'_> {
+                self.untyped.thunk_info()
+            }
+
             /// Weaken the bounds of the type-erased storage.
             ///
             /// For example, a [`BareFnAny<B, dyn Send + Sync>`] may be upcast into a [`BareFnAny<B, dyn Send>`].
@@ -582,12 +685,16 @@ macro_rules! bare_closure_impl {
             /// Wraps `fun`, producing a bare function with calling convention `cconv`.
             ///
             /// Uses the provided JIT allocator to allocate the W^X memory used to create the thunk.
+            ///
+            /// # Errors
+            /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+            /// cannot be relocated. See [`ThunkError`].
             #[allow(unused_variables)]
             pub fn try_with_cc_in<CC, F>(
                 cconv: CC,
                 fun: F,
                 jit_alloc: A,
-            ) -> Result<Self, JitAllocError>
+            ) -> Result<Self, ThunkError>
             where
                 F: ToBoxedDyn<S>,
                 (CC, F): $trait_ident<B>,
@@ -595,15 +702,10 @@ macro_rules! bare_closure_impl {
                 let storage = Box::into_raw(F::to_boxed_unsize(fun));
 
                 // SAFETY:
-                // - thunk_template pointer obtained from the correct source
-                // - `closure` is a valid pointer to `fun`
-                let thunk = unsafe {
-                    AllocatedThunk::new(
-                        <(CC, F)>::$thunk_template,
-                        storage as *const _, size_of::<F>(),
-                        jit_alloc
-                    )?
-                };
+                // - `storage` is a valid pointer to `fun`
+                // - `(CC, F)` has the same layout as `F`
+                let thunk = unsafe { Self::alloc_thunk::<(CC, F)>(storage, jit_alloc)? }
+                    .with_symbol::<B, F>();
                 Ok(Self {
                     untyped: $erased_ty_name {
                         thunk,
@@ -618,8 +720,8 @@ macro_rules! bare_closure_impl {
             /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
             ///
             /// # Panics
-            /// If the provided JIT allocator fails to allocate memory. For a non-panicking
-            /// version, see
+            /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+            /// allocate memory. For a non-panicking version, see
             #[doc = $try_with_cc_in_doc]
             #[allow(unused_variables)]
             #[inline]
@@ -642,8 +744,8 @@ macro_rules! bare_closure_impl {
             /// instead.
             ///
             /// # Panics
-            /// If the provided JIT allocator fails to allocate memory. For a non-panicking
-            /// version, see
+            /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+            /// allocate memory. For a non-panicking version, see
             #[doc = $try_with_cc_in_doc]
             #[inline]
             pub fn new_in<F>(fun: F, jit_alloc: A) -> Self
@@ -660,23 +762,19 @@ macro_rules! bare_closure_impl {
             #[doc = concat!("[`", stringify!($trait_ident), "`].")]
             ///
             /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
-            pub fn try_with_thunk_in<T>(thunk: T, jit_alloc: A) -> Result<Self, JitAllocError>
+            ///
+            /// # Errors
+            /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+            /// cannot be relocated. See [`ThunkError`].
+            pub fn try_with_thunk_in<T>(thunk: T, jit_alloc: A) -> Result<Self, ThunkError>
             where T: $trait_ident<B> + ToBoxedDyn<S>
             {
                 // SAFETY: All implementors of `Fn*Thunk` are #[repr(transparent)] with the closure
                 let storage = Box::into_raw(T::to_boxed_unsize(thunk));
 
-                // SAFETY:
-                // - thunk_template pointer obtained from the correct source
-                // - `closure` is a valid pointer to `fun`
-                // - `size_of::<T>()` equals the size of the closure
-                let thunk = unsafe {
-                    AllocatedThunk::new(
-                        T::$thunk_template,
-                        storage as *const _, size_of::<T>(),
-                        jit_alloc
-                    )?
-                };
+                // SAFETY: `storage` is a valid pointer to `thunk`
+                let thunk = unsafe { Self::alloc_thunk::<T>(storage, jit_alloc)? }
+                    .with_symbol::<B, T>();
                 Ok(Self {
                     untyped: $erased_ty_name {
                         thunk,
@@ -694,14 +792,117 @@ macro_rules! bare_closure_impl {
             /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
             ///
             /// # Panics
-            /// If the provided JIT allocator fails to allocate memory. For a non-panicking
-            /// version, see [`Self::try_with_thunk_in`].
+            /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+            /// allocate memory. For a non-panicking version, see [`Self::try_with_thunk_in`].
             #[inline]
             pub fn with_thunk_in<T>(thunk: T, jit_alloc: A) -> Self
             where T: $trait_ident<B> + ToBoxedDyn<S>
             {
                 Self::try_with_thunk_in(thunk, jit_alloc).unwrap()
             }
+
+            /// Relocates and caches the thunk template prologue used for closures of type `F`,
+            /// so that constructing a
+            #[doc = $ty_name_doc]
+            /// from such a closure only has to copy it.
+            ///
+            /// Otherwise, this is done on the first construction. Calling this at startup moves
+            /// this cost out of latency-sensitive code paths.
+            ///
+            /// Does nothing if the `safe_jit` feature is disabled.
+            ///
+            /// # Panics
+            /// If the thunk template prologue cannot be relocated. This is a bug and the
+            /// constructors of
+            #[doc = $ty_name_doc]
+            /// would fail as well.
+            #[inline]
+            pub fn prewarm<F>()
+            where
+                (B::CC, F): $trait_ident<B>,
+            {
+                Self::prewarm_thunk::<(B::CC, F)>()
+            }
+
+            /// Relocates and caches the thunk template prologue used for the
+            #[doc = concat!("[`", stringify!($trait_ident), "`]")]
+            /// implementation `T`. See [`Self::prewarm`].
+            pub fn prewarm_thunk<T>()
+            where
+                T: $trait_ident<B>,
+            {
+                let (template, closure_size) = Self::thunk_template::<T>();
+                // SAFETY: thunk_template pointer and closure size obtained from the correct source
+                unsafe { AllocatedThunk::<A>::prewarm(template, closure_size) }
+                    .unwrap_or_else(|e| panic!("{e}"))
+            }
+
+            /// Returns the size of the executable memory requested from the JIT allocator when
+            /// wrapping a closure of type `F`, or zero if no allocation is needed.
+            ///
+            /// This can be used to size the slots of a [`ThunkPool`](crate::jit_alloc::ThunkPool).
+            /// If the `safe_jit` feature is enabled, this also does the work of [`Self::prewarm`].
+            ///
+            /// # Panics
+            /// If the thunk template prologue cannot be relocated. This is a bug and the
+            /// constructors of
+            #[doc = $ty_name_doc]
+            /// would fail as well.
+            #[inline]
+            pub fn jit_alloc_size<F>() -> usize
+            where
+                (B::CC, F): $trait_ident<B>,
+            {
+                Self::jit_alloc_size_thunk::<(B::CC, F)>()
+            }
+
+            /// Returns the size of the executable memory requested from the JIT allocator for the
+            #[doc = concat!("[`", stringify!($trait_ident), "`]")]
+            /// implementation `T`. See [`Self::jit_alloc_size`].
+            pub fn jit_alloc_size_thunk<T>() -> usize
+            where
+                T: $trait_ident<B>,
+            {
+                let (template, closure_size) = Self::thunk_template::<T>();
+                // SAFETY: thunk_template pointer and closure size obtained from the correct source
+                unsafe { AllocatedThunk::<A>::alloc_size(template, closure_size) }
+                    .unwrap_or_else(|e| panic!("{e}"))
+            }
+
+            /// Gets the thunk template and closure size used by [`Self::alloc_thunk`] for `T`.
+            #[inline]
+            fn thunk_template<T>() -> (*const u8, usize)
+            where
+                T: $trait_ident<B>,
+            {
+                #[cfg($self_freeing)]
+                return OnceThunkCell::<S, A>::thunk_template::<B, T>();
+
+                #[cfg(not($self_freeing))]
+                (T::$thunk_template, size_of::<T>())
+            }
+
+            /// JITs a thunk invoking the
+            #[doc = concat!("[`", stringify!($trait_ident), "`]")]
+            /// implementation stored at `storage`.
+            ///
+            /// # Safety
+            /// `storage` must be a valid pointer to an instance of `T` (or of a closure `T` is
+            /// `#[repr(transparent)]` with).
+            #[inline]
+            unsafe fn alloc_thunk<T>(storage: *mut S, jit_alloc: A) -> Result<AllocatedThunk<A>, ThunkError>
+            where T: $trait_ident<B>
+            {
+                #[cfg($self_freeing)]
+                return OnceThunkCell::alloc_thunk::<B, T>(storage, jit_alloc);
+
+                // SAFETY:
+                // - thunk_template pointer obtained from the correct source
+                // - `storage` is a valid pointer to the closure
+                // - `size_of::<T>()` equals the size of the closure
+                #[cfg(not($self_freeing))]
+                AllocatedThunk::new(T::$thunk_template, storage as *const _, size_of::<T>(), jit_alloc)
+            }
         }
 
         #[cfg(feature = "global_jit_alloc")]
@@ -911,14 +1112,6 @@ macro_rules! bare_closure_impl {
     };
 }
 
-// TODO:
-// BareFnOnce still needs work.
-// In particular, to avoid leaks we need to have the compiler generated thunk
-// call `release` on the allocator after it's done running, then drop the allocator.
-// Then, to avoid double frees we need `bare` to be taken by value.
-//
-// At the moment, we simply force leaking for `BareFnOnce` by omitting `bare()`.
-
 bare_closure_impl!(
     ty_name: BareFnOnceAny,
     erased_ty_name: UntypedBareFnOnce,
@@ -928,8 +1121,7 @@ bare_closure_impl!(
     sync_alias_bound: dyn Send + 'a,
     trait_ident: FnOnceThunk,
     thunk_template: THUNK_TEMPLATE_ONCE,
-    bare_toggle: cfg(any()),
-    bare_receiver: Self,
+    self_freeing: all(),
     fn_trait_doc: "[`FnOnce`]",
     ty_name_doc: "[`BareFnOnceAny`]",
     with_cc_doc: "[`with_cc`](BareFnOnceAny::with_cc)",
@@ -951,8 +1143,7 @@ bare_closure_impl!(
     sync_alias_bound: dyn Send + 'a,
     trait_ident: FnMutThunk,
     thunk_template: THUNK_TEMPLATE_MUT,
-    bare_toggle: cfg(all()),
-    bare_receiver: &Self,
+    self_freeing: any(),
     fn_trait_doc: "[`FnMut`]",
     ty_name_doc: "[`BareFnMutAny`]",
     with_cc_doc: "[`with_cc`](BareFnMutAny::with_cc)",
@@ -974,8 +1165,7 @@ bare_closure_impl!(
     sync_alias_bound: dyn Send + Sync + 'a,
     trait_ident: FnThunk,
     thunk_template: THUNK_TEMPLATE,
-    bare_toggle: cfg(all()),
-    bare_receiver: &Self,
+    self_freeing: any(),
     fn_trait_doc: "[`Fn`]",
     ty_name_doc: "[`BareFnAny`]",
     with_cc_doc: "[`with_cc`](BareFnAny::with_cc)",
@@ -986,3 +1176,613 @@ bare_closure_impl!(
     sync_alias_bound_doc: "[`S = dyn Send + Sync + 'a`](Sync)",
     safety_doc: "- The closure is not `Sync`, if calling from a different thread than the current one."
 );
+
+#[cfg(feature = "global_jit_alloc")]
+impl<B: FnPtr, S: ?Sized> BareFnAny<B, S, GlobalJitAlloc> {
+    /// Wraps a foreign function pointer taking an opaque context as its first argument,
+    /// producing a bare function of signature `B` which takes the remaining arguments and always
+    /// passes `data` as the context.
+    ///
+    /// This is the reverse of the usual C callback pattern, and is useful to forward a
+    /// `(callback, userdata)` pair to an API which only takes a function pointer.
+    ///
+    /// No closure is heap allocated: `fun` and `data` are stored in the W^X memory allocated for
+    /// the thunk using the global JIT allocator.
+    ///
+    /// The bare function type `B` cannot be inferred from the type annotation of the result, so
+    /// it must be specified.
+    ///
+    /// ```
+    /// # #[cfg(feature = "default_jit_alloc")] {
+    /// use core::ffi::c_void;
+    ///
+    /// use closure_ffi::BareFn;
+    ///
+    /// unsafe extern "C" fn add(user: *mut c_void, n: u32) -> u32 {
+    ///     *user.cast::<u32>() + n
+    /// }
+    ///
+    /// let mut offset = 5u32;
+    /// let user = (&raw mut offset).cast::<c_void>();
+    /// let bare_closure = BareFn::<unsafe extern "C" fn(u32) -> u32>::from_fn_with_data(
+    ///     add as unsafe extern "C" fn(*mut c_void, u32) -> u32,
+    ///     user,
+    /// );
+    ///
+    /// assert_eq!(unsafe { bare_closure.bare()(1) }, 6);
+    /// # }
+    /// ```
+    #[inline]
+    pub fn from_fn_with_data<F, D>(fun: F, data: D) -> Self
+    where
+        F: FnPtr,
+        D: Copy,
+        PhantomData<D>: ToBoxedDyn<S>,
+        for<'a, 'b, 'c> F::Args<'a, 'b, 'c>: TupleRemove<0, B::Args<'a, 'b, 'c>, Removed = D>,
+        for<'a, 'b, 'c> F: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+    {
+        Self::from_fn_with_data_in(fun, data, Default::default())
+    }
+
+    /// Wraps `fun`, copying it to the W^X memory allocated for the thunk instead of moving it to
+    /// the heap. See [`BareFnAny::new_inline_in`].
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new_inline<F>(fun: F) -> Self
+    where
+        F: Copy,
+        PhantomData<F>: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        Self::new_inline_in(fun, Default::default())
+    }
+}
+
+impl<B: FnPtr, S: ?Sized, A: JitAlloc> BareFnAny<B, S, A> {
+    /// Wraps a foreign function pointer taking an opaque context as its first argument. See
+    /// [`BareFnAny::from_fn_with_data`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_from_fn_with_data_in`].
+    #[inline]
+    pub fn from_fn_with_data_in<F, D>(fun: F, data: D, jit_alloc: A) -> Self
+    where
+        F: FnPtr,
+        D: Copy,
+        PhantomData<D>: ToBoxedDyn<S>,
+        for<'a, 'b, 'c> F::Args<'a, 'b, 'c>: TupleRemove<0, B::Args<'a, 'b, 'c>, Removed = D>,
+        for<'a, 'b, 'c> F: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+    {
+        Self::try_from_fn_with_data_in(fun, data, jit_alloc).unwrap()
+    }
+
+    /// Wraps a foreign function pointer taking an opaque context as its first argument. See
+    /// [`BareFnAny::from_fn_with_data`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_from_fn_with_data_in<F, D>(fun: F, data: D, jit_alloc: A) -> Result<Self, ThunkError>
+    where
+        F: FnPtr,
+        D: Copy,
+        PhantomData<D>: ToBoxedDyn<S>,
+        for<'a, 'b, 'c> F::Args<'a, 'b, 'c>: TupleRemove<0, B::Args<'a, 'b, 'c>, Removed = D>,
+        for<'a, 'b, 'c> F: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+    {
+        #[inline(always)]
+        unsafe fn alloc_inline<B: FnPtr, T: FnThunk<B>, A: JitAlloc>(
+            thunk: T,
+            jit_alloc: A,
+        ) -> Result<AllocatedThunk<A>, ThunkError> {
+            AllocatedThunk::new_inline(T::THUNK_TEMPLATE, thunk, jit_alloc)
+        }
+
+        let thunk =
+            B::make_thunk(move |args| unsafe { fun.call(TupleRemove::<0, _>::insert(args, data)) });
+
+        // SAFETY: The thunk template was obtained from the `FnThunk` implementation of the
+        // closure it is given, which only captures `Copy` values and is thus not a ZST
+        let thunk = unsafe { alloc_inline(thunk, jit_alloc)? }.with_symbol::<B, F>();
+
+        // The storage only carries the auto traits of `data`, and does not allocate
+        let storage = Box::into_raw(PhantomData::<D>::to_boxed_unsize(PhantomData));
+        Ok(Self {
+            untyped: UntypedBareFn { thunk, storage },
+            phantom: PhantomData,
+        })
+    }
+
+    /// Wraps `fun`, copying it to the W^X memory allocated for the thunk instead of moving it to
+    /// the heap.
+    ///
+    /// Since executable memory is read-only, this is limited to [`Copy`] closures, which have no
+    /// destructor. If `fun` is zero-sized, no memory is allocated at all. Together with a
+    /// [`ThunkPool`](crate::jit_alloc::ThunkPool) and [`Self::prewarm`], this creates bare
+    /// functions without taking locks or allocating, unless the `perf_map` or `gdb_jit` features
+    /// register a symbol for the thunk. Use [`Self::jit_alloc_size_inline`] to size the slots of
+    /// the pool.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_new_inline_in<F>(fun: F, jit_alloc: A) -> Result<Self, ThunkError>
+    where
+        F: Copy,
+        PhantomData<F>: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        // SAFETY: The thunk template was obtained from the `FnThunk` implementation of the
+        // closure it is given
+        let thunk = unsafe {
+            AllocatedThunk::new_inline(
+                <(B::CC, F)>::THUNK_TEMPLATE,
+                (B::CC::default(), fun),
+                jit_alloc,
+            )?
+        }
+        .with_symbol::<B, F>();
+
+        // The storage only carries the auto traits of `fun`, and does not allocate
+        let storage = Box::into_raw(PhantomData::<F>::to_boxed_unsize(PhantomData));
+        Ok(Self {
+            untyped: UntypedBareFn { thunk, storage },
+            phantom: PhantomData,
+        })
+    }
+
+    /// Wraps `fun`, copying it to the W^X memory allocated for the thunk instead of moving it to
+    /// the heap. See [`Self::try_new_inline_in`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_new_inline_in`].
+    #[inline]
+    pub fn new_inline_in<F>(fun: F, jit_alloc: A) -> Self
+    where
+        F: Copy,
+        PhantomData<F>: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        Self::try_new_inline_in(fun, jit_alloc).unwrap()
+    }
+
+    /// Returns the size of the executable memory requested from the JIT allocator when
+    /// wrapping a closure of type `F` with [`Self::new_inline_in`], or zero if no allocation is
+    /// needed.
+    ///
+    /// # Panics
+    /// If the thunk template prologue cannot be relocated. This is a bug and the constructors
+    /// of [`BareFnAny`] would fail as well.
+    pub fn jit_alloc_size_inline<F>() -> usize
+    where
+        F: Copy,
+        (B::CC, F): FnThunk<B>,
+    {
+        // SAFETY: thunk_template pointer obtained from the correct source
+        unsafe {
+            AllocatedThunk::<A>::alloc_size_inline(
+                <(B::CC, F)>::THUNK_TEMPLATE,
+                core::alloc::Layout::new::<(B::CC, F)>(),
+            )
+        }
+        .unwrap_or_else(|e| panic!("{e}"))
+    }
+}
+
+#[cfg(all(feature = "std", feature = "global_jit_alloc"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+impl<'a, B: FnPtr + 'a> BareFnAny<B, dyn Any + 'a, GlobalJitAlloc> {
+    /// Wraps `fun`, producing a bare function which may only be called from the current thread.
+    ///
+    /// Calls from other threads never touch the closure. Instead, they are handled by `policy`,
+    /// which can for example abort with a diagnostic message
+    /// ([`AbortOnForeignThread`](crate::thunk_factory::AbortOnForeignThread)). This turns
+    /// calling the bare function of a non-[`Send`] closure from the wrong thread into a
+    /// detectable error rather than undefined behavior.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    ///
+    /// See [`thunk_factory::thread_bound`](crate::thunk_factory::thread_bound) for details.
+    ///
+    /// ```
+    /// # #[cfg(feature = "default_jit_alloc")] {
+    /// use std::rc::Rc;
+    ///
+    /// use closure_ffi::{thunk_factory::DefaultOnForeignThread, BareFn};
+    ///
+    /// let rc = Rc::new(42);
+    /// let bare_closure: BareFn<unsafe extern "C" fn() -> u32> =
+    ///     BareFn::new_thread_bound(move || *rc, DefaultOnForeignThread);
+    /// let bare = bare_closure.bare();
+    ///
+    /// assert_eq!(unsafe { bare() }, 42);
+    /// assert_eq!(std::thread::spawn(move || unsafe { bare() }).join().unwrap(), 0);
+    /// # }
+    /// ```
+    #[inline]
+    pub fn new_thread_bound<F, P>(fun: F, policy: P) -> Self
+    where
+        F: 'a,
+        (B::CC, F): FnThunk<B>,
+        P: ThreadAffinityPolicy<B> + Sync + 'a,
+    {
+        Self::new_thread_bound_in(fun, policy, Default::default())
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+impl<'a, B: FnPtr + 'a, A: JitAlloc> BareFnAny<B, dyn Any + 'a, A> {
+    /// Wraps `fun`, producing a bare function which may only be called from the current thread.
+    /// See [`BareFnAny::new_thread_bound`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_new_thread_bound_in`].
+    #[inline]
+    pub fn new_thread_bound_in<F, P>(fun: F, policy: P, jit_alloc: A) -> Self
+    where
+        F: 'a,
+        (B::CC, F): FnThunk<B>,
+        P: ThreadAffinityPolicy<B> + Sync + 'a,
+    {
+        Self::try_new_thread_bound_in(fun, policy, jit_alloc).unwrap()
+    }
+
+    /// Wraps `fun`, producing a bare function which may only be called from the current thread.
+    /// See [`BareFnAny::new_thread_bound`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_new_thread_bound_in<F, P>(
+        fun: F,
+        policy: P,
+        jit_alloc: A,
+    ) -> Result<Self, ThunkError>
+    where
+        F: 'a,
+        (B::CC, F): FnThunk<B>,
+        P: ThreadAffinityPolicy<B> + Sync + 'a,
+    {
+        let thunk = thread_bound((B::CC::default(), fun), policy);
+        Self::try_with_thunk_in(thunk, jit_alloc)
+    }
+}
+
+#[cfg(feature = "global_jit_alloc")]
+#[cfg_attr(docsrs, doc(cfg(all())))]
+/// Reference-counted handle to a [`BareFnAny`], which can be cheaply cloned to share one closure
+/// and bare function thunk between several owners.
+///
+/// The closure and its executable memory are freed when the last clone is dropped.
+///
+/// # Thread Safety
+/// Like [`Arc`], this is only [`Send`] and [`Sync`] if the shared [`BareFnAny`] is both, i.e.
+/// if the closure is [`Send`] and [`Sync`] and the JIT allocator is [`Send`] and [`Sync`]. This is
+/// the case for [`SharedBareFnSync`], but never for [`SharedBareFn`].
+///
+/// # Type parameters
+/// Same as for [`BareFnAny`].
+pub struct SharedBareFnAny<B: FnPtr, S: ?Sized, A: JitAlloc = GlobalJitAlloc> {
+    inner: Arc<BareFnAny<B, S, A>>,
+}
+
+#[cfg(not(feature = "global_jit_alloc"))]
+/// Reference-counted handle to a [`BareFnAny`], which can be cheaply cloned to share one closure
+/// and bare function thunk between several owners.
+///
+/// The closure and its executable memory are freed when the last clone is dropped.
+///
+/// # Thread Safety
+/// Like [`Arc`], this is only [`Send`] and [`Sync`] if the shared [`BareFnAny`] is both, i.e.
+/// if the closure is [`Send`] and [`Sync`] and the JIT allocator is [`Send`] and [`Sync`]. This is
+/// the case for [`SharedBareFnSync`], but never for [`SharedBareFn`].
+///
+/// # Type parameters
+/// Same as for [`BareFnAny`].
+pub struct SharedBareFnAny<B: FnPtr, S: ?Sized, A: JitAlloc> {
+    inner: Arc<BareFnAny<B, S, A>>,
+}
+
+impl<B: FnPtr, S: ?Sized, A: JitAlloc> Clone for SharedBareFnAny<B, S, A> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: FnPtr, S: ?Sized, A: JitAlloc> From<BareFnAny<B, S, A>> for SharedBareFnAny<B, S, A> {
+    #[inline]
+    fn from(value: BareFnAny<B, S, A>) -> Self {
+        Self {
+            inner: Arc::new(value),
+        }
+    }
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<B: FnPtr, S: ?Sized> SharedBareFnAny<B, S, GlobalJitAlloc> {
+    /// Wraps `fun`, producing a shared bare function of signature `B`. See [`BareFnAny::new`].
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new<F>(fun: F) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        BareFnAny::new(fun).into()
+    }
+
+    /// Wraps `fun`, producing a shared bare function with calling convention `cconv`. See
+    /// [`BareFnAny::with_cc`].
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn with_cc<CC, F>(cconv: CC, fun: F) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (CC, F): FnThunk<B>,
+    {
+        BareFnAny::with_cc(cconv, fun).into()
+    }
+
+    cc_shorthand!(new_rust, FnThunk, cc::Rust, "Rust");
+
+    cc_shorthand!(new_c, FnThunk, cc::C, "C");
+
+    cc_shorthand!(new_system, FnThunk, cc::System, "system");
+}
+
+impl<B: FnPtr, S: ?Sized, A: JitAlloc> SharedBareFnAny<B, S, A> {
+    /// Wraps `fun`, producing a shared bare function with calling convention `cconv`. See
+    /// [`BareFnAny::try_with_cc_in`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    #[inline]
+    pub fn try_with_cc_in<CC, F>(cconv: CC, fun: F, jit_alloc: A) -> Result<Self, ThunkError>
+    where
+        F: ToBoxedDyn<S>,
+        (CC, F): FnThunk<B>,
+    {
+        BareFnAny::try_with_cc_in(cconv, fun, jit_alloc).map(Into::into)
+    }
+
+    /// Wraps `fun`, producing a shared bare function with calling convention `cconv`. See
+    /// [`BareFnAny::with_cc_in`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_with_cc_in`].
+    #[inline]
+    pub fn with_cc_in<CC, F>(cconv: CC, fun: F, jit_alloc: A) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (CC, F): FnThunk<B>,
+    {
+        BareFnAny::with_cc_in(cconv, fun, jit_alloc).into()
+    }
+
+    /// Wraps `fun`, producing a shared bare function of signature `B`. See
+    /// [`BareFnAny::new_in`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunk.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_with_cc_in`].
+    #[inline]
+    pub fn new_in<F>(fun: F, jit_alloc: A) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        BareFnAny::new_in(fun, jit_alloc).into()
+    }
+
+    /// Return a bare function pointer that invokes the underlying closure.
+    ///
+    /// All clones return the same pointer.
+    ///
+    /// # Safety
+    /// While this method is safe, the returned function pointer is not. In particular, it
+    /// must not be called when:
+    /// - The lifetime of the closure has expired, or all clones of `self` have been dropped.
+    /// - The closure is not `Sync`, if calling from a different thread than the current one.
+    #[inline]
+    pub fn bare(&self) -> B {
+        self.inner.bare()
+    }
+
+    /// Returns debug information about the code of the bare function thunk. See
+    /// [`BareFnAny::thunk_info`].
+    #[inline]
+    pub fn thunk_info(&self) -> ThunkInfo<'_> {
+        self.inner.thunk_info()
+    }
+
+    /// Returns the number of clones of this handle, including itself.
+    #[inline]
+    pub fn strong_count(this: &Self) -> usize {
+        Arc::strong_count(&this.inner)
+    }
+
+    /// Returns `true` if both handles share the same closure and thunk.
+    #[inline]
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Arc::ptr_eq(&this.inner, &other.inner)
+    }
+
+    /// Returns the shared [`BareFnAny`] if `this` is its last handle. Otherwise, `this` is
+    /// returned back.
+    ///
+    /// # Errors
+    /// If there are other clones of `this`.
+    #[inline]
+    pub fn try_unwrap(this: Self) -> Result<BareFnAny<B, S, A>, Self> {
+        Arc::try_unwrap(this.inner).map_err(|inner| Self { inner })
+    }
+}
+
+#[cfg(feature = "global_jit_alloc")]
+#[cfg_attr(docsrs, doc(cfg(all())))]
+/// Reference-counted handle to a [`BareFn`], which can be cheaply cloned.
+///
+/// This is a type alias only. Additional details and methods are described on the
+/// [`SharedBareFnAny`] type.
+pub type SharedBareFn<'a, B, A = GlobalJitAlloc> = SharedBareFnAny<B, dyn Any + 'a, A>;
+
+#[cfg(not(feature = "global_jit_alloc"))]
+/// Reference-counted handle to a [`BareFn`], which can be cheaply cloned.
+///
+/// This is a type alias only. Additional details and methods are described on the
+/// [`SharedBareFnAny`] type.
+pub type SharedBareFn<'a, B, A> = SharedBareFnAny<B, dyn Any + 'a, A>;
+
+#[cfg(feature = "global_jit_alloc")]
+#[cfg_attr(docsrs, doc(cfg(all())))]
+/// Reference-counted handle to a [`BareFnSync`], which can be cheaply cloned and shared between
+/// threads.
+///
+/// This is a type alias only. Additional details and methods are described on the
+/// [`SharedBareFnAny`] type.
+pub type SharedBareFnSync<'a, B, A = GlobalJitAlloc> = SharedBareFnAny<B, dyn Send + Sync + 'a, A>;
+
+#[cfg(not(feature = "global_jit_alloc"))]
+/// Reference-counted handle to a [`BareFnSync`], which can be cheaply cloned and shared between
+/// threads.
+///
+/// This is a type alias only. Additional details and methods are described on the
+/// [`SharedBareFnAny`] type.
+pub type SharedBareFnSync<'a, B, A> = SharedBareFnAny<B, dyn Send + Sync + 'a, A>;
+
+/// Heap-allocated cell holding the closure invoked by the thunk of a [`BareFnOnceAny`].
+///
+/// The closure captures a pointer to the cell. When [`BareFnOnceAny::bare`] is called, the cell
+/// takes ownership of the [`UntypedBareFnOnce`] so that the closure can free it after moving the
+/// wrapped [`FnOnceThunk`] out of its storage.
+#[repr(C)]
+struct OnceThunkCell<S: ?Sized, A: JitAlloc> {
+    closure: MaybeUninit<*const ()>,
+    owner: MaybeUninit<UntypedBareFnOnce<S, A>>,
+}
+
+impl<S: ?Sized, A: JitAlloc> OnceThunkCell<S, A> {
+    /// JITs a self-freeing thunk invoking the [`FnOnceThunk`] stored at `storage`.
+    ///
+    /// Zero-sized closures are conjured by the thunk template, so they do not need a cell.
+    ///
+    /// # Safety
+    /// `storage` must be a valid pointer to an instance of `T` (or of a closure `T` is
+    /// `#[repr(transparent)]` with).
+    unsafe fn alloc_thunk<B: FnPtr, T: FnOnceThunk<B>>(
+        storage: *mut S,
+        jit_alloc: A,
+    ) -> Result<AllocatedThunk<A>, ThunkError> {
+        if size_of::<T>() == 0 {
+            return AllocatedThunk::new(T::THUNK_TEMPLATE_ONCE, storage as *const _, 0, jit_alloc);
+        }
+
+        let cell = Box::into_raw(Box::new(Self {
+            closure: MaybeUninit::uninit(),
+            owner: MaybeUninit::uninit(),
+        }));
+
+        #[inline(always)]
+        unsafe fn store_closure<B: FnPtr, C: FnOnceThunk<B>, S: ?Sized, A: JitAlloc>(
+            cell: *mut OnceThunkCell<S, A>,
+            closure: C,
+            jit_alloc: A,
+        ) -> Result<AllocatedThunk<A>, ThunkError> {
+            const {
+                assert!(size_of::<C>() == size_of::<*const ()>());
+                assert!(align_of::<C>() <= align_of::<*const ()>());
+            }
+            (*cell).closure.as_mut_ptr().cast::<C>().write(closure);
+
+            // SAFETY:
+            // - thunk_template pointer obtained from the correct source
+            // - `cell` starts with a valid instance of `C`
+            AllocatedThunk::new(
+                C::THUNK_TEMPLATE_ONCE,
+                cell as *const _,
+                size_of::<C>(),
+                jit_alloc,
+            )
+            .inspect_err(|_| drop(Box::from_raw(cell)))
+        }
+
+        store_closure(cell, Self::closure::<B, T>(cell), jit_alloc)
+    }
+
+    /// Gets the thunk template and closure size used by [`OnceThunkCell::alloc_thunk`] for `T`.
+    fn thunk_template<B: FnPtr, T: FnOnceThunk<B>>() -> (*const u8, usize) {
+        #[inline(always)]
+        fn template_of<B: FnPtr, C: FnOnceThunk<B>>(_closure: &C) -> (*const u8, usize) {
+            (C::THUNK_TEMPLATE_ONCE, size_of::<C>())
+        }
+
+        if size_of::<T>() == 0 {
+            (T::THUNK_TEMPLATE_ONCE, 0)
+        }
+        else {
+            // The closure is never called, so the dangling cell pointer is fine
+            template_of(&Self::closure::<B, T>(core::ptr::null_mut()))
+        }
+    }
+
+    /// The closure stored in `cell`.
+    #[inline(always)]
+    fn closure<B: FnPtr, T: FnOnceThunk<B>>(cell: *mut Self) -> impl FnOnceThunk<B> {
+        B::make_once_thunk(move |args| unsafe { Self::call::<B, T>(cell, args) })
+    }
+
+    /// Frees `cell` and the [`UntypedBareFnOnce`] it owns, then calls the wrapped closure.
+    ///
+    /// # Safety
+    /// [`UntypedBareFnOnce::bare`] must have moved the owner into `cell`, and this function must
+    /// not have been called on `cell` before.
+    #[inline(always)]
+    unsafe fn call<'a, 'b, 'c, B: FnPtr, T: FnOnceThunk<B>>(
+        cell: *mut Self,
+        args: B::Args<'a, 'b, 'c>,
+    ) -> B::Ret<'a, 'b, 'c> {
+        let cell = Box::from_raw(cell);
+        let owner = ManuallyDrop::new(cell.owner.assume_init_read());
+        drop(cell);
+
+        // Move the closure out of its storage and free the latter without dropping the former
+        let fun = owner.storage.cast::<T>().read();
+        drop(Box::from_raw(owner.storage.cast::<MaybeUninit<T>>()));
+
+        // Release the executable memory and drop the allocator. This is fine as by now, the thunk
+        // has jumped back to the compiler-generated thunk template.
+        drop(core::ptr::read(&owner.thunk));
+
+        fun.call_once(args)
+    }
+}
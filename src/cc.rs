@@ -202,6 +202,7 @@ macro_rules! cc_trait_impl_recursive {
     };
 }
 
+#[cfg(not(feature = "extended_arity"))]
 macro_rules! cc_trait_impl {
     ($cconv:ty, $cconv_lit:literal, $impl_macro:ident) => {
         cc_trait_impl_recursive!(
@@ -216,6 +217,27 @@ macro_rules! cc_trait_impl {
     };
 }
 
+// Same as above, but up to 24 arguments. Kept behind a feature as this doubles the number of trait
+// impls the compiler has to process.
+#[cfg(feature = "extended_arity")]
+macro_rules! cc_trait_impl {
+    ($cconv:ty, $cconv_lit:literal, $impl_macro:ident) => {
+        cc_trait_impl_recursive!(
+            $cconv,
+            $cconv_lit,
+            $impl_macro,
+            [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,],
+            [T0,T1,T2,T3,T4,T5,T6,T7,T8,T9,T10,T11,T12,T13,T14,T15,T16,T17,T18,T19,T20,T21,T22,T23,](),
+            [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,](),
+            [
+                a0:T0,a1:T1,a2:T2,a3:T3,a4:T4,a5:T5,a6:T6,a7:T7,a8:T8,a9:T9,a10:T10,a11:T11,
+                a12:T12,a13:T13,a14:T14,a15:T15,a16:T16,a17:T17,a18:T18,a19:T19,a20:T20,a21:T21,
+                a22:T22,a23:T23,
+            ]()
+        );
+    };
+}
+
 macro_rules! cc_impl {
     ($ty_name:tt, $lit_name:literal $(,$cfg:meta)?) => {
         #[doc = "Marker type representing the"]
@@ -0,0 +1,539 @@
+//! Reference-counted, binary compatible COM-style objects whose methods are bare closure thunks.
+//!
+//! A COM object is a pointer to a structure whose first field points to a table of function
+//! pointers (a "vtable"). The table starts with the `QueryInterface`, `AddRef` and `Release`
+//! methods of `IUnknown`, and every method takes the object pointer as its first argument.
+//!
+//! [`ComObject`] allocates such an object. The `IUnknown` methods are provided, while the other
+//! methods are the fields of a `#[repr(C)]` struct `V`, usually filled with thunks created using
+//! [`VtableThunks::thunk`].
+//!
+//! The object can also hold a state of type `S`. Since the object does not exist yet when its
+//! methods are created, they receive it as their first argument instead of capturing it. By
+//! declaring that argument as a [`ComThis<S>`], which has the same ABI as the object pointer,
+//! methods can access the state without any pointer casts:
+//!
+//! ```
+//! # #[cfg(feature = "default_jit_alloc")] {
+//! use core::cell::Cell;
+//!
+//! use closure_ffi::com::{ComObject, ComThis, Guid, IID_IUNKNOWN};
+//!
+//! const IID_ICOUNTER: Guid = Guid::new(0x12345678, 0x9abc, 0xdef0, [1, 2, 3, 4, 5, 6, 7, 8]);
+//!
+//! struct Counter {
+//!     count: Cell<u32>,
+//! }
+//!
+//! #[repr(C)]
+//! struct ICounterVtbl {
+//!     increment: unsafe extern "system" fn(ComThis<Counter>) -> u32,
+//! }
+//!
+//! let state = Counter { count: Cell::new(0) };
+//! let counter = ComObject::with_state(&[IID_ICOUNTER], state, |thunks| {
+//!     Ok(ICounterVtbl {
+//!         increment: thunks.thunk(|this: ComThis<Counter>| {
+//!             this.count.set(this.count.get() + 1);
+//!             this.count.get()
+//!         })?,
+//!     })
+//! });
+//! assert_eq!(counter.state().count.get(), 0);
+//!
+//! // What a foreign caller would do with the object pointer
+//! let this = counter.into_raw();
+//! unsafe {
+//!     let vtable = &**this.cast::<*const closure_ffi::com::ComVtable<ICounterVtbl>>();
+//!     assert_eq!((vtable.methods.increment)(ComThis::from_raw(this)), 1);
+//!
+//!     let mut unknown = core::ptr::null_mut();
+//!     assert_eq!((vtable.unknown.query_interface)(this, &IID_IUNKNOWN, &mut unknown), 0);
+//!     assert_eq!((vtable.unknown.release)(unknown), 1);
+//!
+//!     // Frees the thunks and the closures they own
+//!     assert_eq!((vtable.unknown.release)(this), 0);
+//! }
+//! # }
+//! ```
+
+use alloc::boxed::Box;
+use core::{
+    ffi::c_void,
+    fmt,
+    marker::PhantomData,
+    ops::Deref,
+    ptr::NonNull,
+    sync::atomic::{self, AtomicU32, Ordering},
+};
+
+#[cfg(feature = "global_jit_alloc")]
+use crate::jit_alloc::GlobalJitAlloc;
+use crate::{error::ThunkError, ffi_interface::VtableThunks, jit_alloc::JitAlloc};
+
+/// A COM globally unique identifier, used to identify interfaces.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    /// Creates a GUID from its fields.
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        Self {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+}
+
+/// The interface identifier of `IUnknown`, `{00000000-0000-0000-C000-000000000046}`.
+pub const IID_IUNKNOWN: Guid = Guid::new(0, 0, 0, [0xC0, 0, 0, 0, 0, 0, 0, 0x46]);
+
+/// A COM status code.
+pub type HResult = i32;
+
+/// The operation succeeded.
+pub const S_OK: HResult = 0;
+/// The object does not implement the requested interface.
+pub const E_NOINTERFACE: HResult = 0x80004002u32 as i32;
+/// A null pointer was passed.
+pub const E_POINTER: HResult = 0x80004003u32 as i32;
+
+/// The `IUnknown` methods at the start of every COM vtable.
+///
+/// These use the `system` calling convention, which is the one used by COM on Windows and is
+/// the same as `C` on other platforms.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IUnknownVtbl {
+    /// Writes the object to `*ppv` and increments its reference count if it implements the
+    /// interface identified by `*riid`. Otherwise, writes null and returns [`E_NOINTERFACE`].
+    pub query_interface: unsafe extern "system" fn(
+        this: *mut c_void,
+        riid: *const Guid,
+        ppv: *mut *mut c_void,
+    ) -> HResult,
+    /// Increments the reference count of the object, returning the new count.
+    pub add_ref: unsafe extern "system" fn(this: *mut c_void) -> u32,
+    /// Decrements the reference count of the object, returning the new count. The object is
+    /// freed when it reaches zero.
+    pub release: unsafe extern "system" fn(this: *mut c_void) -> u32,
+}
+
+/// The vtable of a [`ComObject`]: the `IUnknown` methods followed by the methods `V`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ComVtable<V> {
+    pub unknown: IUnknownVtbl,
+    pub methods: V,
+}
+
+#[repr(C)]
+struct ComHeader {
+    vtable: *const c_void,
+    ref_count: AtomicU32,
+    iids: Box<[Guid]>,
+}
+
+// The start of the object, which can be accessed from the object pointer knowing only `S`.
+#[repr(C)]
+struct ComPrefix<S> {
+    header: ComHeader,
+    state: S,
+}
+
+// The object foreign code sees. The header must come first, so that the object pointer can be
+// used to access it without knowing `V`, `A` or `S`.
+#[repr(C)]
+struct ComObjectInner<'a, V, A: JitAlloc, S> {
+    prefix: ComPrefix<S>,
+    vtable: ComVtable<V>,
+    thunks: VtableThunks<'a, A>,
+}
+
+/// The object pointer received by the methods of a [`ComObject`] with state `S`.
+///
+/// This has the same ABI as `*mut c_void`, so it can be used as the type of the first argument of
+/// the methods in the vtable. It dereferences to the state of the object.
+#[repr(transparent)]
+pub struct ComThis<S> {
+    ptr: NonNull<c_void>,
+    phantom: PhantomData<*const S>,
+}
+
+impl<S> ComThis<S> {
+    /// Wraps an object pointer.
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object created by a [`ComObject`] with state `S`, and the
+    /// object must outlive the returned value.
+    #[inline]
+    pub unsafe fn from_raw(ptr: *mut c_void) -> Self {
+        Self {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the object pointer.
+    #[inline]
+    pub fn as_raw(&self) -> *mut c_void {
+        self.ptr.as_ptr()
+    }
+}
+
+impl<S> Deref for ComThis<S> {
+    type Target = S;
+
+    #[inline]
+    fn deref(&self) -> &S {
+        // SAFETY: The caller of the method guarantees the pointer is a live object with state `S`
+        unsafe { &(*self.ptr.as_ptr().cast::<ComPrefix<S>>()).state }
+    }
+}
+
+impl<S> Clone for ComThis<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for ComThis<S> {}
+
+impl<S> fmt::Debug for ComThis<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ComThis").field(&self.ptr).finish()
+    }
+}
+
+unsafe extern "system" fn query_interface(
+    this: *mut c_void,
+    riid: *const Guid,
+    ppv: *mut *mut c_void,
+) -> HResult {
+    if ppv.is_null() {
+        return E_POINTER;
+    }
+    if riid.is_null() {
+        unsafe { ppv.write(core::ptr::null_mut()) };
+        return E_POINTER;
+    }
+
+    // SAFETY: The caller guarantees `this` is a live COM object created by this module
+    let header = unsafe { &*this.cast::<ComHeader>() };
+    let iid = unsafe { riid.read_unaligned() };
+    if iid == IID_IUNKNOWN || header.iids.contains(&iid) {
+        header.ref_count.fetch_add(1, Ordering::Relaxed);
+        unsafe { ppv.write(this) };
+        S_OK
+    }
+    else {
+        unsafe { ppv.write(core::ptr::null_mut()) };
+        E_NOINTERFACE
+    }
+}
+
+unsafe extern "system" fn add_ref(this: *mut c_void) -> u32 {
+    // SAFETY: The caller guarantees `this` is a live COM object created by this module
+    let header = unsafe { &*this.cast::<ComHeader>() };
+    header.ref_count.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+unsafe extern "system" fn release<O>(this: *mut c_void) -> u32 {
+    // SAFETY: The caller guarantees `this` is a live COM object created by this module
+    let header = unsafe { &*this.cast::<ComHeader>() };
+    let count = header.ref_count.fetch_sub(1, Ordering::Release) - 1;
+    if count == 0 {
+        // Synchronize with the other releases before freeing, like `Arc`
+        atomic::fence(Ordering::Acquire);
+        // SAFETY: This was the last reference to the object, which was allocated as a `Box<O>`
+        drop(unsafe { Box::from_raw(this.cast::<O>()) });
+    }
+    count
+}
+
+/// An owned reference to a COM-style object whose vtable is the `IUnknown` methods followed by
+/// the methods `V`. See the [module documentation](self).
+///
+/// Cloning a [`ComObject`] increments the object's reference count, and dropping it decrements
+/// it. The object, its methods and the closures they own are freed when the count reaches zero.
+///
+/// Since a method's closure is freed along with the object, a method must not release the last
+/// reference to the object it is called on.
+///
+/// # Type parameters
+/// - `V`: The `#[repr(C)]` table of methods following the `IUnknown` ones. Each method should take
+///   the object pointer as its first argument, either as a [`ComThis<S>`] or a `*mut c_void`.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free executable memory.
+/// - `S`: The state of the object, which methods can access through [`ComThis<S>`].
+#[cfg(feature = "global_jit_alloc")]
+pub struct ComObject<'a, V, A: JitAlloc = GlobalJitAlloc, S = ()> {
+    ptr: NonNull<ComObjectInner<'a, V, A, S>>,
+    phantom: PhantomData<ComObjectInner<'a, V, A, S>>,
+}
+
+/// An owned reference to a COM-style object whose vtable is the `IUnknown` methods followed by
+/// the methods `V`. See the [module documentation](self).
+///
+/// Cloning a [`ComObject`] increments the object's reference count, and dropping it decrements
+/// it. The object, its methods and the closures they own are freed when the count reaches zero.
+///
+/// Since a method's closure is freed along with the object, a method must not release the last
+/// reference to the object it is called on.
+///
+/// # Type parameters
+/// - `V`: The `#[repr(C)]` table of methods following the `IUnknown` ones. Each method should take
+///   the object pointer as its first argument, either as a [`ComThis<S>`] or a `*mut c_void`.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free executable memory.
+/// - `S`: The state of the object, which methods can access through [`ComThis<S>`].
+#[cfg(not(feature = "global_jit_alloc"))]
+pub struct ComObject<'a, V, A: JitAlloc, S = ()> {
+    ptr: NonNull<ComObjectInner<'a, V, A, S>>,
+    phantom: PhantomData<ComObjectInner<'a, V, A, S>>,
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<'a, V> ComObject<'a, V, GlobalJitAlloc> {
+    /// Creates a COM object implementing the interfaces `iids`, in addition to `IUnknown`.
+    ///
+    /// `build` creates the methods of the object, usually through [`VtableThunks::thunk`].
+    /// The thunks it adds are freed along with the object.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    ///
+    /// # Panics
+    /// If `build` returns an error.
+    #[inline]
+    pub fn new(
+        iids: &[Guid],
+        build: impl FnOnce(&mut VtableThunks<'a, GlobalJitAlloc>) -> Result<V, ThunkError>,
+    ) -> Self {
+        Self::new_in(iids, build, Default::default())
+    }
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<'a, V, S> ComObject<'a, V, GlobalJitAlloc, S> {
+    /// Creates a COM object implementing the interfaces `iids`, in addition to `IUnknown`, and
+    /// holding `state`.
+    ///
+    /// `build` creates the methods of the object, usually through [`VtableThunks::thunk`].
+    /// The thunks it adds are freed along with the object.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    ///
+    /// # Panics
+    /// If `build` returns an error.
+    #[inline]
+    pub fn with_state(
+        iids: &[Guid],
+        state: S,
+        build: impl FnOnce(&mut VtableThunks<'a, GlobalJitAlloc>) -> Result<V, ThunkError>,
+    ) -> Self {
+        Self::with_state_in(iids, state, build, Default::default())
+    }
+}
+
+impl<'a, V, A: JitAlloc + Clone> ComObject<'a, V, A> {
+    /// Creates a COM object implementing the interfaces `iids`, in addition to `IUnknown`.
+    ///
+    /// `build` creates the methods of the object, usually through [`VtableThunks::thunk`].
+    /// The thunks it adds are freed along with the object.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks.
+    ///
+    /// # Errors
+    /// If `build` returns an error.
+    #[inline]
+    pub fn try_new_in(
+        iids: &[Guid],
+        build: impl FnOnce(&mut VtableThunks<'a, A>) -> Result<V, ThunkError>,
+        jit_alloc: A,
+    ) -> Result<Self, ThunkError> {
+        Self::try_with_state_in(iids, (), build, jit_alloc)
+    }
+
+    /// Creates a COM object implementing the interfaces `iids`, in addition to `IUnknown`.
+    ///
+    /// `build` creates the methods of the object, usually through [`VtableThunks::thunk`].
+    /// The thunks it adds are freed along with the object.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks.
+    ///
+    /// # Panics
+    /// If `build` returns an error. For a non-panicking version, see [`Self::try_new_in`].
+    #[inline]
+    pub fn new_in(
+        iids: &[Guid],
+        build: impl FnOnce(&mut VtableThunks<'a, A>) -> Result<V, ThunkError>,
+        jit_alloc: A,
+    ) -> Self {
+        Self::try_new_in(iids, build, jit_alloc).unwrap()
+    }
+}
+
+impl<'a, V, A: JitAlloc + Clone, S> ComObject<'a, V, A, S> {
+    /// Creates a COM object implementing the interfaces `iids`, in addition to `IUnknown`, and
+    /// holding `state`.
+    ///
+    /// `build` creates the methods of the object, usually through [`VtableThunks::thunk`].
+    /// The thunks it adds are freed along with the object.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks.
+    ///
+    /// # Errors
+    /// If `build` returns an error.
+    pub fn try_with_state_in(
+        iids: &[Guid],
+        state: S,
+        build: impl FnOnce(&mut VtableThunks<'a, A>) -> Result<V, ThunkError>,
+        jit_alloc: A,
+    ) -> Result<Self, ThunkError> {
+        let mut thunks = VtableThunks::new(jit_alloc);
+        let methods = build(&mut thunks)?;
+
+        let inner = Box::new(ComObjectInner {
+            prefix: ComPrefix {
+                header: ComHeader {
+                    vtable: core::ptr::null(),
+                    ref_count: AtomicU32::new(1),
+                    iids: iids.into(),
+                },
+                state,
+            },
+            vtable: ComVtable {
+                unknown: IUnknownVtbl {
+                    query_interface,
+                    add_ref,
+                    release: release::<ComObjectInner<'a, V, A, S>>,
+                },
+                methods,
+            },
+            thunks,
+        });
+
+        let ptr = Box::into_raw(inner);
+        // SAFETY: `ptr` was just allocated. The vtable is part of the same allocation, so it
+        // stays in place for as long as the object lives
+        unsafe { (*ptr).prefix.header.vtable = (&raw const (*ptr).vtable).cast() };
+        Ok(Self {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            phantom: PhantomData,
+        })
+    }
+
+    /// Creates a COM object implementing the interfaces `iids`, in addition to `IUnknown`, and
+    /// holding `state`.
+    ///
+    /// `build` creates the methods of the object, usually through [`VtableThunks::thunk`].
+    /// The thunks it adds are freed along with the object.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks.
+    ///
+    /// # Panics
+    /// If `build` returns an error. For a non-panicking version, see
+    /// [`Self::try_with_state_in`].
+    #[inline]
+    pub fn with_state_in(
+        iids: &[Guid],
+        state: S,
+        build: impl FnOnce(&mut VtableThunks<'a, A>) -> Result<V, ThunkError>,
+        jit_alloc: A,
+    ) -> Self {
+        Self::try_with_state_in(iids, state, build, jit_alloc).unwrap()
+    }
+}
+
+impl<'a, V, A: JitAlloc, S> ComObject<'a, V, A, S> {
+    /// Returns the object pointer, to be passed to foreign code.
+    ///
+    /// This does not increment the reference count of the object, so the pointer is only valid
+    /// while `self` (or another reference to the object) is alive.
+    #[inline]
+    pub fn as_raw(&self) -> *mut c_void {
+        self.ptr.as_ptr().cast()
+    }
+
+    /// Consumes the reference to the object, returning the object pointer.
+    ///
+    /// The reference count is not decremented, so the object must eventually be released by
+    /// calling its `Release` method or by using [`Self::from_raw`].
+    #[inline]
+    pub fn into_raw(self) -> *mut c_void {
+        let ptr = self.as_raw();
+        core::mem::forget(self);
+        ptr
+    }
+
+    /// Takes ownership of a reference to the object `ptr`, without incrementing its reference
+    /// count.
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object created by a [`ComObject<'a, V, A, S>`] with the same
+    /// type parameters, and the caller must own one of its references.
+    #[inline]
+    pub unsafe fn from_raw(ptr: *mut c_void) -> Self {
+        Self {
+            ptr: unsafe { NonNull::new_unchecked(ptr.cast()) },
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns a new reference to the object `ptr`, incrementing its reference count.
+    ///
+    /// This is useful to obtain a reference to the object from the pointer its methods receive.
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object created by a [`ComObject<'a, V, A, S>`] with the same
+    /// type parameters.
+    #[inline]
+    pub unsafe fn clone_from_raw(ptr: *mut c_void) -> Self {
+        unsafe {
+            add_ref(ptr);
+            Self::from_raw(ptr)
+        }
+    }
+
+    /// Returns the vtable of the object.
+    #[inline]
+    pub fn vtable(&self) -> &ComVtable<V> {
+        // SAFETY: The object is alive while we hold a reference to it
+        unsafe { &self.ptr.as_ref().vtable }
+    }
+
+    /// Returns the state of the object.
+    #[inline]
+    pub fn state(&self) -> &S {
+        // SAFETY: The object is alive while we hold a reference to it
+        unsafe { &self.ptr.as_ref().prefix.state }
+    }
+
+    /// Returns the current reference count of the object.
+    #[inline]
+    pub fn ref_count(this: &Self) -> u32 {
+        // SAFETY: The object is alive while we hold a reference to it
+        unsafe { this.ptr.as_ref().prefix.header.ref_count.load(Ordering::Relaxed) }
+    }
+}
+
+impl<V, A: JitAlloc, S> Clone for ComObject<'_, V, A, S> {
+    fn clone(&self) -> Self {
+        // SAFETY: The object is alive while we hold a reference to it
+        unsafe { Self::clone_from_raw(self.as_raw()) }
+    }
+}
+
+impl<V, A: JitAlloc, S> Drop for ComObject<'_, V, A, S> {
+    fn drop(&mut self) {
+        let release = self.vtable().unknown.release;
+        // SAFETY: We own one reference to the object
+        unsafe { release(self.as_raw()) };
+    }
+}
@@ -0,0 +1,587 @@
+//! Inline function hooking ("detours") built on top of the `safe_jit` relocator.
+//!
+//! A [`Detour`] overwrites the first instructions of a target function with a jump to a bare
+//! closure thunk. The overwritten instructions are relocated to an executable *trampoline*, which
+//! the closure can call to run the original function:
+//!
+//! ```
+//! use closure_ffi::detour::Detour;
+//!
+//! #[inline(never)]
+//! extern "C" fn add(a: u32, b: u32) -> u32 {
+//!     // Make sure the function is large enough to be hooked
+//!     std::hint::black_box(a).wrapping_add(std::hint::black_box(b))
+//! }
+//!
+//! let target: unsafe extern "C" fn(u32, u32) -> u32 = add;
+//! let mut detour = unsafe { Detour::new(target, |original| move |a, b| original(a, b) * 10) }
+//!     .expect("failed to create detour");
+//!
+//! unsafe { detour.enable().unwrap() };
+//! assert_eq!(std::hint::black_box(add)(1, 2), 30);
+//!
+//! unsafe { detour.disable().unwrap() };
+//! assert_eq!(std::hint::black_box(add)(1, 2), 3);
+//! ```
+//!
+//! Only x86 and x86_64 are supported at the moment.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use jit_allocator2::virtual_memory;
+
+#[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+use crate::jit_symbols::{self, ThunkSymbol};
+use crate::{
+    bare_closure::BareFnAny,
+    error::ThunkError,
+    jit_alloc::{GlobalJitAlloc, JitAlloc, JitAllocError, ProtectJitAccess},
+    safe_jit::{
+        x86_detour::{self, StolenPrologue},
+        JitError,
+    },
+    traits::{FnPtr, FnThunk, ToBoxedDyn},
+};
+
+/// Size of the JIT allocation made for trampolines. Large enough for the worst case, where every
+/// stolen instruction is a relative branch or IP-relative load that must be expanded.
+const TRAMPOLINE_SIZE: usize = 256;
+
+/// Error returned when creating or toggling a [`Detour`] fails.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DetourError {
+    /// The target function ends before enough bytes could be stolen to fit the jump to the hook.
+    FunctionTooSmall,
+    /// The start of the target function contains instructions that cannot be relocated to the
+    /// trampoline.
+    UnsupportedPrologue,
+    /// The JIT allocator failed to allocate memory for the trampoline.
+    JitAlloc(JitAllocError),
+    /// The thunk of the hook closure could not be created.
+    Thunk(ThunkError),
+    /// Changing the memory protection of the target function failed.
+    Protect,
+}
+
+impl fmt::Display for DetourError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FunctionTooSmall => f.write_str("target function is too small to be hooked"),
+            Self::UnsupportedPrologue => {
+                f.write_str("target function prologue cannot be relocated")
+            }
+            Self::JitAlloc(e) => write!(f, "failed to allocate trampoline memory: {e}"),
+            Self::Thunk(e) => write!(f, "failed to create hook thunk: {e}"),
+            Self::Protect => f.write_str("failed to change target function memory protection"),
+        }
+    }
+}
+
+impl From<JitError> for DetourError {
+    fn from(value: JitError) -> Self {
+        match value {
+            JitError::PrologueTooSmall => Self::FunctionTooSmall,
+            _ => Self::UnsupportedPrologue,
+        }
+    }
+}
+
+impl core::error::Error for DetourError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::JitAlloc(e) => Some(e),
+            Self::Thunk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<JitAllocError> for DetourError {
+    fn from(value: JitAllocError) -> Self {
+        Self::JitAlloc(value)
+    }
+}
+
+impl From<ThunkError> for DetourError {
+    fn from(value: ThunkError) -> Self {
+        Self::Thunk(value)
+    }
+}
+
+/// Untyped inline hook redirecting a function to an arbitrary address.
+///
+/// See [`Detour`] for a typed version that also owns the hook closure.
+pub struct RawDetour<A: JitAlloc> {
+    target: *mut u8,
+    hook: *const u8,
+    trampoline: *const u8,
+    patch: Vec<u8>,
+    original: Vec<u8>,
+    enabled: bool,
+    #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+    symbol: Option<ThunkSymbol>,
+    jit: A,
+}
+
+// SAFETY: The raw pointers are to code, which is not mutated except through `&mut self`
+unsafe impl<A: JitAlloc + Send> Send for RawDetour<A> {}
+unsafe impl<A: JitAlloc + Sync> Sync for RawDetour<A> {}
+
+impl<A: JitAlloc> fmt::Debug for RawDetour<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawDetour")
+            .field("target", &self.target)
+            .field("hook", &self.hook)
+            .field("trampoline", &self.trampoline)
+            .field("enabled", &self.enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<A: JitAlloc> RawDetour<A> {
+    /// Prepares a detour from `target` to `hook`, allocating the trampoline with `jit`.
+    ///
+    /// The detour is initially disabled.
+    ///
+    /// # Safety
+    /// `target` must point to the start of a function.
+    pub unsafe fn new_in(target: *const u8, hook: *const u8, jit: A) -> Result<Self, DetourError> {
+        Self::with_hook_in(target, jit, |_| Ok(hook))
+    }
+
+    /// Like [`RawDetour::new_in`], but the hook is created by `make_hook` after the trampoline has
+    /// been allocated, so that it can know its address.
+    ///
+    /// # Safety
+    /// `target` must point to the start of a function.
+    unsafe fn with_hook_in(
+        target: *const u8,
+        jit: A,
+        make_hook: impl FnOnce(*const u8) -> Result<*const u8, DetourError>,
+    ) -> Result<Self, DetourError> {
+        let (rx, rw) = jit.alloc(TRAMPOLINE_SIZE)?;
+
+        let result = (|| {
+            let hook = make_hook(rx)?;
+
+            let patch_size = x86_detour::jump_size(target as usize, hook as usize);
+            let page_size = virtual_memory::info().page_size as usize;
+            // SAFETY: The decoder only reads past the end of a page when the instruction it is
+            // decoding continues on the next one
+            let stolen =
+                StolenPrologue::decode(target as usize, patch_size, page_size, |addr, len| {
+                    core::slice::from_raw_parts(addr as *const u8, len)
+                })?;
+
+            let trampoline = stolen.encode_trampoline(rx as usize)?;
+            if trampoline.len() > TRAMPOLINE_SIZE {
+                return Err(DetourError::UnsupportedPrologue);
+            }
+
+            jit.protect_jit_memory(rx, trampoline.len(), ProtectJitAccess::ReadWrite);
+            core::ptr::copy_nonoverlapping(trampoline.as_ptr(), rw, trampoline.len());
+            jit.protect_jit_memory(rx, trampoline.len(), ProtectJitAccess::ReadExecute);
+            jit.flush_instruction_cache(rx, trampoline.len());
+
+            // Pad with int3 so that the leftover bytes of the last stolen instruction trap
+            let mut patch = x86_detour::encode_jump(target as usize, hook as usize);
+            patch.resize(stolen.len(), 0xCC);
+
+            Ok((hook, patch, stolen.code().to_vec(), trampoline.len()))
+        })();
+
+        match result {
+            #[cfg_attr(
+                not(any(feature = "perf_map", feature = "gdb_jit")),
+                allow(unused_variables)
+            )]
+            Ok((hook, patch, original, trampoline_len)) => Ok(Self {
+                target: target.cast_mut(),
+                hook,
+                trampoline: rx,
+                patch,
+                original,
+                enabled: false,
+                #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+                symbol: Some(ThunkSymbol::register(
+                    rx as usize,
+                    trampoline_len,
+                    &jit_symbols::trampoline_name(target as usize),
+                )),
+                jit,
+            }),
+            Err(err) => {
+                let _ = jit.release(rx);
+                Err(err)
+            }
+        }
+    }
+
+    /// The address of the hooked function.
+    pub fn target(&self) -> *const u8 {
+        self.target
+    }
+
+    /// The address the hooked function is redirected to.
+    pub fn hook(&self) -> *const u8 {
+        self.hook
+    }
+
+    /// The address of the trampoline, which behaves like the hooked function did before the detour
+    /// was enabled.
+    pub fn trampoline(&self) -> *const u8 {
+        self.trampoline
+    }
+
+    /// Returns `true` if the detour is currently enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Overwrites the start of the target function with a jump to the hook.
+    ///
+    /// # Safety
+    /// - No thread may be executing the first [`RawDetour::patch_len`] bytes of the target function
+    ///   while this is called.
+    /// - The target function must not already be hooked by another enabled detour.
+    /// - Calling the hook in place of the target function must be sound.
+    pub unsafe fn enable(&mut self) -> Result<(), DetourError> {
+        if !self.enabled {
+            patch_code(self.target, &self.patch)?;
+            self.enabled = true;
+        }
+        Ok(())
+    }
+
+    /// Restores the start of the target function.
+    ///
+    /// # Safety
+    /// No thread may be executing the first [`RawDetour::patch_len`] bytes of the target function
+    /// while this is called.
+    pub unsafe fn disable(&mut self) -> Result<(), DetourError> {
+        if self.enabled {
+            patch_code(self.target, &self.original)?;
+            self.enabled = false;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes at the start of the target function which are overwritten when the
+    /// detour is enabled.
+    pub fn patch_len(&self) -> usize {
+        self.patch.len()
+    }
+}
+
+impl<A: JitAlloc> Drop for RawDetour<A> {
+    fn drop(&mut self) {
+        // SAFETY: Same requirements as `disable`, which callers of `enable` accepted
+        let _ = unsafe { self.disable() };
+
+        // Unregister the symbol before the memory can be reused by another thunk
+        #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+        drop(self.symbol.take());
+        let _ = unsafe { self.jit.release(self.trampoline) };
+    }
+}
+
+/// Writes `bytes` to code memory at `dst`, restoring the original protection of the modified
+/// pages afterwards.
+unsafe fn patch_code(dst: *mut u8, bytes: &[u8]) -> Result<(), DetourError> {
+    let page_size = virtual_memory::info().page_size as usize;
+
+    // The patch may straddle a page boundary, and both pages can have different protections
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = dst.wrapping_add(offset);
+        let page = addr.map_addr(|a| a & !(page_size - 1));
+        let chunk_len = (page_size - (addr as usize - page as usize)).min(bytes.len() - offset);
+
+        os::with_writable_page(page, page_size, || {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr().add(offset), addr, chunk_len);
+        })?;
+        offset += chunk_len;
+    }
+
+    virtual_memory::flush_instruction_cache(dst, bytes.len());
+    Ok(())
+}
+
+#[cfg(unix)]
+mod os {
+    use jit_allocator2::virtual_memory::{self, MemoryFlags};
+
+    use super::DetourError;
+
+    /// Makes `page` writable while `write` runs, then restores its original protection.
+    pub unsafe fn with_writable_page(
+        page: *mut u8,
+        page_size: usize,
+        write: impl FnOnce(),
+    ) -> Result<(), DetourError> {
+        let original = protection(page as usize).unwrap_or(MemoryFlags::ACCESS_RX);
+
+        // Use RWX instead of RW, in case the page also contains code that is currently running
+        virtual_memory::protect(page, page_size, MemoryFlags(MemoryFlags::ACCESS_RWX))
+            .map_err(|_| DetourError::Protect)?;
+        write();
+        virtual_memory::protect(page, page_size, MemoryFlags(original))
+            .map_err(|_| DetourError::Protect)
+    }
+
+    /// Looks up the protection of the mapping containing `addr` in `/proc/self/maps`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn protection(addr: usize) -> Option<u32> {
+        let maps = read_proc_maps()?;
+        maps.split(|&b| b == b'\n').find_map(|line| {
+            let line = core::str::from_utf8(line).ok()?;
+            let mut fields = line.split_ascii_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            if !(start..end).contains(&addr) {
+                return None;
+            }
+
+            let perms = fields.next()?.as_bytes();
+            let mut flags = 0;
+            for (i, (c, flag)) in [
+                (b'r', MemoryFlags::ACCESS_READ),
+                (b'w', MemoryFlags::ACCESS_WRITE),
+                (b'x', MemoryFlags::ACCESS_EXECUTE),
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                if perms.get(i) == Some(&c) {
+                    flags |= flag;
+                }
+            }
+            Some(flags)
+        })
+    }
+
+    /// There is no portable way to query the protection of a page on other Unix systems, so code
+    /// pages are assumed to be read-execute.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn protection(_addr: usize) -> Option<u32> {
+        None
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn read_proc_maps() -> Option<alloc::vec::Vec<u8>> {
+        // SAFETY: The path is nul-terminated and the buffer is valid for `spare` bytes
+        unsafe {
+            let fd = libc::open(
+                c"/proc/self/maps".as_ptr(),
+                libc::O_RDONLY | libc::O_CLOEXEC,
+            );
+            if fd < 0 {
+                return None;
+            }
+
+            let mut buf = alloc::vec::Vec::<u8>::with_capacity(4096);
+            let result = loop {
+                if buf.capacity() == buf.len() {
+                    buf.reserve(4096);
+                }
+                let spare = buf.capacity() - buf.len();
+                let n = libc::read(fd, buf.as_mut_ptr().add(buf.len()).cast(), spare);
+                match n {
+                    0 => break Some(buf),
+                    n if n < 0 => break None,
+                    n => buf.set_len(buf.len() + n as usize),
+                }
+            };
+            libc::close(fd);
+            result
+        }
+    }
+}
+
+#[cfg(windows)]
+mod os {
+    use winapi::um::{memoryapi::VirtualProtect, winnt::PAGE_EXECUTE_READWRITE};
+
+    use super::DetourError;
+
+    /// Makes `page` writable while `write` runs, then restores its original protection.
+    pub unsafe fn with_writable_page(
+        page: *mut u8,
+        page_size: usize,
+        write: impl FnOnce(),
+    ) -> Result<(), DetourError> {
+        // Use RWX instead of RW, in case the page also contains code that is currently running
+        let mut original = 0;
+        if VirtualProtect(
+            page.cast(),
+            page_size,
+            PAGE_EXECUTE_READWRITE,
+            &mut original,
+        ) == 0
+        {
+            return Err(DetourError::Protect);
+        }
+        write();
+        let mut previous = 0;
+        match VirtualProtect(page.cast(), page_size, original, &mut previous) {
+            0 => Err(DetourError::Protect),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Inline hook redirecting a function of type `B` to a closure.
+///
+/// The closure is created from the trampoline to the original function, which it may capture
+/// to call the original. See the [module-level documentation](self) for an example.
+///
+/// The detour is created disabled. It is disabled again when dropped.
+pub struct Detour<'a, B: FnPtr, A: JitAlloc = GlobalJitAlloc> {
+    // Declared first so that the target function is restored before the hook is freed
+    raw: RawDetour<A>,
+    hook: BareFnAny<B, dyn Send + Sync + 'a, A>,
+}
+
+impl<B: FnPtr, A: JitAlloc> fmt::Debug for Detour<'_, B, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Detour").field("raw", &self.raw).finish_non_exhaustive()
+    }
+}
+
+impl<'a, B: FnPtr> Detour<'a, B, GlobalJitAlloc> {
+    /// Prepares a detour from `target` to the closure returned by `hook_binder`, which is given a
+    /// function pointer to the trampoline calling the original function.
+    ///
+    /// # Safety
+    /// `target` must be a function defined in this process, not a pointer to an import stub or
+    /// another thunk the detour could be unaware of.
+    pub unsafe fn new<F>(target: B, hook_binder: impl FnOnce(B) -> F) -> Result<Self, DetourError>
+    where
+        F: ToBoxedDyn<dyn Send + Sync + 'a>,
+        (B::CC, F): FnThunk<B>,
+    {
+        Self::with_cc_in(target, B::CC::default(), hook_binder, GlobalJitAlloc)
+    }
+
+    /// Prepares a detour from `target` to the closure returned by `hook_binder`, which is given a
+    /// function pointer to the trampoline calling the original function.
+    ///
+    /// The signature of the target function is inferred from the calling convention marker type
+    /// and the closure's annotations.
+    ///
+    /// # Safety
+    /// See [`Detour::new`].
+    pub unsafe fn with_cc<CC, F>(
+        target: B,
+        cconv: CC,
+        hook_binder: impl FnOnce(B) -> F,
+    ) -> Result<Self, DetourError>
+    where
+        F: ToBoxedDyn<dyn Send + Sync + 'a>,
+        (CC, F): FnThunk<B>,
+    {
+        Self::with_cc_in(target, cconv, hook_binder, GlobalJitAlloc)
+    }
+}
+
+impl<'a, B: FnPtr, A: JitAlloc + Clone> Detour<'a, B, A> {
+    /// Prepares a detour from `target` to the closure returned by `hook_binder`, which is given a
+    /// function pointer to the trampoline calling the original function.
+    ///
+    /// Uses `jit_alloc` to allocate the trampoline and the hook thunk.
+    ///
+    /// # Safety
+    /// See [`Detour::new`].
+    pub unsafe fn new_in<F>(
+        target: B,
+        hook_binder: impl FnOnce(B) -> F,
+        jit_alloc: A,
+    ) -> Result<Self, DetourError>
+    where
+        F: ToBoxedDyn<dyn Send + Sync + 'a>,
+        (B::CC, F): FnThunk<B>,
+    {
+        Self::with_cc_in(target, B::CC::default(), hook_binder, jit_alloc)
+    }
+
+    /// Prepares a detour from `target` to the closure returned by `hook_binder`, which is given a
+    /// function pointer to the trampoline calling the original function.
+    ///
+    /// Uses `jit_alloc` to allocate the trampoline and the hook thunk.
+    ///
+    /// # Safety
+    /// See [`Detour::new`].
+    pub unsafe fn with_cc_in<CC, F>(
+        target: B,
+        cconv: CC,
+        hook_binder: impl FnOnce(B) -> F,
+        jit_alloc: A,
+    ) -> Result<Self, DetourError>
+    where
+        F: ToBoxedDyn<dyn Send + Sync + 'a>,
+        (CC, F): FnThunk<B>,
+    {
+        let mut hook = None;
+        let raw =
+            RawDetour::with_hook_in(target.to_ptr().cast(), jit_alloc.clone(), |trampoline| {
+                let original = B::from_ptr(trampoline.cast());
+                let bare_fn = BareFnAny::try_with_cc_in(cconv, hook_binder(original), jit_alloc)?;
+                let hook_ptr = bare_fn.bare().to_ptr();
+                hook = Some(bare_fn);
+                Ok(hook_ptr.cast())
+            })?;
+
+        Ok(Self {
+            raw,
+            // Cannot fail since `with_hook_in` succeeded
+            hook: hook.unwrap(),
+        })
+    }
+
+    /// The hooked function.
+    pub fn target(&self) -> B {
+        // SAFETY: The target was created from a `B`
+        unsafe { B::from_ptr(self.raw.target().cast()) }
+    }
+
+    /// The bare function thunk invoking the hook closure.
+    pub fn hook(&self) -> B {
+        self.hook.bare()
+    }
+
+    /// A function pointer to the trampoline, which behaves like the hooked function did before the
+    /// detour was enabled.
+    pub fn original(&self) -> B {
+        // SAFETY: The trampoline has the same signature as the target
+        unsafe { B::from_ptr(self.raw.trampoline().cast()) }
+    }
+
+    /// Returns `true` if the detour is currently enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.raw.is_enabled()
+    }
+
+    /// Redirects the target function to the hook closure.
+    ///
+    /// # Safety
+    /// See [`RawDetour::enable`].
+    pub unsafe fn enable(&mut self) -> Result<(), DetourError> {
+        self.raw.enable()
+    }
+
+    /// Restores the target function.
+    ///
+    /// # Safety
+    /// See [`RawDetour::disable`].
+    pub unsafe fn disable(&mut self) -> Result<(), DetourError> {
+        self.raw.disable()
+    }
+
+    /// Returns the underlying [`RawDetour`].
+    pub fn as_raw(&self) -> &RawDetour<A> {
+        &self.raw
+    }
+}
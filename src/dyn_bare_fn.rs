@@ -0,0 +1,620 @@
+//! Bare functions whose signature is only known at runtime.
+//!
+//! The bare closure types require the signature of the bare function to be known at compile time
+//! through a [`FnPtr`](crate::traits::FnPtr) type. A [`DynBareFn`] instead takes a
+//! [`DynSignature`] describing the argument and return types, and a closure receiving the
+//! arguments as a slice of [`DynValue`]s:
+//!
+//! ```
+//! use closure_ffi::dyn_bare_fn::{DynBareFn, DynValue};
+//!
+//! let scale = 10.0;
+//! let bare_fn = DynBareFn::new("f64(i32, f64)".parse().unwrap(), move |args| {
+//!     let (DynValue::I32(a), DynValue::F64(b)) = (args[0], args[1])
+//!     else {
+//!         unreachable!()
+//!     };
+//!     DynValue::F64(scale * (a as f64 + b))
+//! });
+//!
+//! let bare: unsafe extern "C" fn(i32, f64) -> f64 = unsafe { core::mem::transmute(bare_fn.bare()) };
+//! assert_eq!(unsafe { bare(1, 0.5) }, 15.0);
+//! ```
+//!
+//! Rather than relying on a compiler-generated thunk template, the bare function is a small stub
+//! emitted to JIT memory which spills the argument registers and passes them to a dispatcher
+//! decoding them according to the signature. Only scalar arguments and return values are
+//! supported, and only for the C calling convention of x86_64 System V targets (e.g. Linux and
+//! macOS).
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{ffi::c_void, fmt, str::FromStr};
+
+#[cfg(feature = "global_jit_alloc")]
+use crate::jit_alloc::GlobalJitAlloc;
+#[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+use crate::jit_symbols::{self, ThunkSymbol};
+use crate::{
+    error::ThunkError,
+    jit_alloc::{JitAlloc, ProtectJitAccess},
+};
+
+/// Calling convention of a [`DynSignature`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DynCallConv {
+    /// The C calling convention of the target, i.e. `extern "C"`.
+    #[default]
+    C,
+    /// The System V AMD64 calling convention, i.e. `extern "sysv64"`. Same as
+    /// [`DynCallConv::C`] on the supported targets.
+    Sysv64,
+}
+
+/// Type of an argument or return value of a [`DynSignature`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DynType {
+    /// No value. Only valid as a return type.
+    Void,
+    /// [`bool`].
+    Bool,
+    /// [`i8`].
+    I8,
+    /// [`u8`].
+    U8,
+    /// [`i16`].
+    I16,
+    /// [`u16`].
+    U16,
+    /// [`i32`].
+    I32,
+    /// [`u32`].
+    U32,
+    /// [`i64`].
+    I64,
+    /// [`u64`].
+    U64,
+    /// [`isize`].
+    Isize,
+    /// [`usize`].
+    Usize,
+    /// [`f32`].
+    F32,
+    /// [`f64`].
+    F64,
+    /// A raw pointer.
+    Ptr,
+}
+
+impl DynType {
+    const NAMES: [(&'static str, Self); 15] = [
+        ("void", Self::Void),
+        ("bool", Self::Bool),
+        ("i8", Self::I8),
+        ("u8", Self::U8),
+        ("i16", Self::I16),
+        ("u16", Self::U16),
+        ("i32", Self::I32),
+        ("u32", Self::U32),
+        ("i64", Self::I64),
+        ("u64", Self::U64),
+        ("isize", Self::Isize),
+        ("usize", Self::Usize),
+        ("f32", Self::F32),
+        ("f64", Self::F64),
+        ("ptr", Self::Ptr),
+    ];
+
+    /// Returns the name of the type in signature strings.
+    pub fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, t)| *t == self).unwrap().0
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, Self::F32 | Self::F64)
+    }
+}
+
+impl fmt::Display for DynType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DynType {
+    type Err = DynSignatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::NAMES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|&(_, t)| t)
+            .ok_or(DynSignatureError::UnknownType)
+    }
+}
+
+/// An argument or return value passed to the closure of a [`DynBareFn`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum DynValue {
+    /// No value, for [`DynType::Void`].
+    Void,
+    /// A value of type [`DynType::Bool`].
+    Bool(bool),
+    /// A value of type [`DynType::I8`].
+    I8(i8),
+    /// A value of type [`DynType::U8`].
+    U8(u8),
+    /// A value of type [`DynType::I16`].
+    I16(i16),
+    /// A value of type [`DynType::U16`].
+    U16(u16),
+    /// A value of type [`DynType::I32`].
+    I32(i32),
+    /// A value of type [`DynType::U32`].
+    U32(u32),
+    /// A value of type [`DynType::I64`].
+    I64(i64),
+    /// A value of type [`DynType::U64`].
+    U64(u64),
+    /// A value of type [`DynType::Isize`].
+    Isize(isize),
+    /// A value of type [`DynType::Usize`].
+    Usize(usize),
+    /// A value of type [`DynType::F32`].
+    F32(f32),
+    /// A value of type [`DynType::F64`].
+    F64(f64),
+    /// A value of type [`DynType::Ptr`].
+    Ptr(*mut c_void),
+}
+
+impl DynValue {
+    /// Returns the type of the value.
+    pub fn ty(&self) -> DynType {
+        match self {
+            Self::Void => DynType::Void,
+            Self::Bool(_) => DynType::Bool,
+            Self::I8(_) => DynType::I8,
+            Self::U8(_) => DynType::U8,
+            Self::I16(_) => DynType::I16,
+            Self::U16(_) => DynType::U16,
+            Self::I32(_) => DynType::I32,
+            Self::U32(_) => DynType::U32,
+            Self::I64(_) => DynType::I64,
+            Self::U64(_) => DynType::U64,
+            Self::Isize(_) => DynType::Isize,
+            Self::Usize(_) => DynType::Usize,
+            Self::F32(_) => DynType::F32,
+            Self::F64(_) => DynType::F64,
+            Self::Ptr(_) => DynType::Ptr,
+        }
+    }
+
+    /// Decodes a value of type `ty` from the 64-bit register or stack slot it was passed in.
+    fn from_bits(ty: DynType, bits: u64) -> Self {
+        match ty {
+            DynType::Void => Self::Void,
+            DynType::Bool => Self::Bool(bits as u8 != 0),
+            DynType::I8 => Self::I8(bits as i8),
+            DynType::U8 => Self::U8(bits as u8),
+            DynType::I16 => Self::I16(bits as i16),
+            DynType::U16 => Self::U16(bits as u16),
+            DynType::I32 => Self::I32(bits as i32),
+            DynType::U32 => Self::U32(bits as u32),
+            DynType::I64 => Self::I64(bits as i64),
+            DynType::U64 => Self::U64(bits),
+            DynType::Isize => Self::Isize(bits as isize),
+            DynType::Usize => Self::Usize(bits as usize),
+            DynType::F32 => Self::F32(f32::from_bits(bits as u32)),
+            DynType::F64 => Self::F64(f64::from_bits(bits)),
+            DynType::Ptr => Self::Ptr(bits as *mut c_void),
+        }
+    }
+
+    /// Encodes the value into the low bits of a 64-bit register.
+    fn to_bits(self) -> u64 {
+        match self {
+            Self::Void => 0,
+            Self::Bool(v) => v as u64,
+            Self::I8(v) => v as u64,
+            Self::U8(v) => v as u64,
+            Self::I16(v) => v as u64,
+            Self::U16(v) => v as u64,
+            Self::I32(v) => v as u64,
+            Self::U32(v) => v as u64,
+            Self::I64(v) => v as u64,
+            Self::U64(v) => v,
+            Self::Isize(v) => v as u64,
+            Self::Usize(v) => v as u64,
+            Self::F32(v) => v.to_bits() as u64,
+            Self::F64(v) => v.to_bits(),
+            Self::Ptr(v) => v as u64,
+        }
+    }
+}
+
+/// Error returned when a [`DynSignature`] is invalid or cannot be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DynSignatureError {
+    /// The signature string is not of the form `ret(arg, ...)`, optionally preceded by
+    /// `extern "C"` or `extern "sysv64"`.
+    InvalidSyntax,
+    /// The signature string contains an unknown type or calling convention name.
+    UnknownType,
+    /// An argument has type [`DynType::Void`].
+    VoidArgument,
+}
+
+impl fmt::Display for DynSignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidSyntax => "invalid signature syntax",
+            Self::UnknownType => "unknown type or calling convention",
+            Self::VoidArgument => "argument of type void",
+        })
+    }
+}
+
+impl core::error::Error for DynSignatureError {}
+
+/// Runtime description of the signature of a [`DynBareFn`].
+///
+/// Signatures can be parsed from strings of the form `ret(arg, ...)`, such as
+/// `"i32(f64, ptr, u8)"`, optionally preceded by `extern "C"` or `extern "sysv64"`. The type
+/// names are those of [`DynType::name`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DynSignature {
+    cc: DynCallConv,
+    args: Vec<DynType>,
+    ret: DynType,
+}
+
+impl DynSignature {
+    /// Creates a signature from its calling convention, argument types and return type.
+    ///
+    /// # Errors
+    /// If an argument has type [`DynType::Void`].
+    pub fn new(
+        cc: DynCallConv,
+        args: impl Into<Vec<DynType>>,
+        ret: DynType,
+    ) -> Result<Self, DynSignatureError> {
+        let args = args.into();
+        if args.contains(&DynType::Void) {
+            return Err(DynSignatureError::VoidArgument);
+        }
+        Ok(Self { cc, args, ret })
+    }
+
+    /// The calling convention of the signature.
+    pub fn cc(&self) -> DynCallConv {
+        self.cc
+    }
+
+    /// The argument types of the signature.
+    pub fn args(&self) -> &[DynType] {
+        &self.args
+    }
+
+    /// The return type of the signature.
+    pub fn ret(&self) -> DynType {
+        self.ret
+    }
+}
+
+impl fmt::Display for DynSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cc {
+            DynCallConv::C => write!(f, "extern \"C\" ")?,
+            DynCallConv::Sysv64 => write!(f, "extern \"sysv64\" ")?,
+        }
+        write!(f, "{}(", self.ret)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{arg}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+impl FromStr for DynSignature {
+    type Err = DynSignatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (cc, s) = match s.strip_prefix("extern") {
+            Some(rest) => {
+                let rest =
+                    rest.trim_start().strip_prefix('"').ok_or(DynSignatureError::InvalidSyntax)?;
+                let (name, rest) = rest.split_once('"').ok_or(DynSignatureError::InvalidSyntax)?;
+                let cc = match name {
+                    "C" => DynCallConv::C,
+                    "sysv64" => DynCallConv::Sysv64,
+                    _ => return Err(DynSignatureError::UnknownType),
+                };
+                (cc, rest)
+            }
+            None => (DynCallConv::C, s),
+        };
+
+        let (ret, rest) = s.split_once('(').ok_or(DynSignatureError::InvalidSyntax)?;
+        let args = rest.trim_end().strip_suffix(')').ok_or(DynSignatureError::InvalidSyntax)?;
+        let ret = ret.trim().parse()?;
+        let args = match args.trim() {
+            "" => Vec::new(),
+            args => args.split(',').map(|a| a.trim().parse()).collect::<Result<_, _>>()?,
+        };
+        Self::new(cc, args, ret)
+    }
+}
+
+/// Machine code of the entry stub. The closure context and dispatcher addresses are written to
+/// the `movabs` immediates at [`CTX_OFFSET`] and [`DISPATCH_OFFSET`].
+const STUB: [u8; 116] = [
+    0x55, // push rbp
+    0x48, 0x89, 0xe5, // mov rbp, rsp
+    0x48, 0x83, 0xec, 0x70, // sub rsp, 0x70
+    0x48, 0x89, 0x3c, 0x24, // mov [rsp], rdi
+    0x48, 0x89, 0x74, 0x24, 0x08, // mov [rsp + 0x08], rsi
+    0x48, 0x89, 0x54, 0x24, 0x10, // mov [rsp + 0x10], rdx
+    0x48, 0x89, 0x4c, 0x24, 0x18, // mov [rsp + 0x18], rcx
+    0x4c, 0x89, 0x44, 0x24, 0x20, // mov [rsp + 0x20], r8
+    0x4c, 0x89, 0x4c, 0x24, 0x28, // mov [rsp + 0x28], r9
+    0xf2, 0x0f, 0x11, 0x44, 0x24, 0x30, // movsd [rsp + 0x30], xmm0
+    0xf2, 0x0f, 0x11, 0x4c, 0x24, 0x38, // movsd [rsp + 0x38], xmm1
+    0xf2, 0x0f, 0x11, 0x54, 0x24, 0x40, // movsd [rsp + 0x40], xmm2
+    0xf2, 0x0f, 0x11, 0x5c, 0x24, 0x48, // movsd [rsp + 0x48], xmm3
+    0xf2, 0x0f, 0x11, 0x64, 0x24, 0x50, // movsd [rsp + 0x50], xmm4
+    0xf2, 0x0f, 0x11, 0x6c, 0x24, 0x58, // movsd [rsp + 0x58], xmm5
+    0xf2, 0x0f, 0x11, 0x74, 0x24, 0x60, // movsd [rsp + 0x60], xmm6
+    0xf2, 0x0f, 0x11, 0x7c, 0x24, 0x68, // movsd [rsp + 0x68], xmm7
+    0x48, 0xbf, 0, 0, 0, 0, 0, 0, 0, 0, // movabs rdi, ctx
+    0x48, 0x89, 0xe6, // mov rsi, rsp
+    0x48, 0x8d, 0x55, 0x10, // lea rdx, [rbp + 0x10]
+    0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, // movabs rax, dispatch
+    0xff, 0xd0, // call rax
+    0xc9, // leave
+    0xc3, // ret
+];
+
+/// Offset of the closure context immediate in [`STUB`].
+const CTX_OFFSET: usize = 87;
+
+/// Offset of the dispatcher address immediate in [`STUB`].
+const DISPATCH_OFFSET: usize = 104;
+
+/// Argument registers spilled by the entry stub.
+#[repr(C)]
+struct SpilledRegs {
+    gp: [u64; 6],
+    xmm: [u64; 8],
+}
+
+/// Returned in `rax` and `xmm0` by the dispatcher, so that it covers both integer and floating
+/// point return values.
+#[repr(C)]
+struct RawRet {
+    rax: u64,
+    xmm0: f64,
+}
+
+/// Maximum number of arguments decoded without allocating.
+const INLINE_ARGS: usize = 16;
+
+type DynClosure<'a> = dyn Fn(&[DynValue]) -> DynValue + 'a;
+
+struct Context<'a> {
+    sig: DynSignature,
+    closure: Box<DynClosure<'a>>,
+}
+
+/// Decodes the arguments spilled by the entry stub and calls the closure with them.
+///
+/// # Safety
+/// `regs` and `stack` must be the pointers set up by the entry stub of the [`DynBareFn`] which
+/// owns `ctx`, and the caller must have passed arguments matching its signature.
+unsafe extern "C" fn dispatch(
+    ctx: *const Context<'_>,
+    regs: *const SpilledRegs,
+    mut stack: *const u64,
+) -> RawRet {
+    let ctx = &*ctx;
+    let regs = &*regs;
+
+    let (mut gp, mut xmm) = (regs.gp.iter(), regs.xmm.iter());
+    let mut next_arg = |ty: DynType| {
+        let reg = if ty.is_float() { xmm.next() } else { gp.next() };
+        let bits = reg.copied().unwrap_or_else(|| {
+            let bits = stack.read();
+            stack = stack.add(1);
+            bits
+        });
+        DynValue::from_bits(ty, bits)
+    };
+
+    let args = ctx.sig.args();
+    let ret = if args.len() <= INLINE_ARGS {
+        let mut values = [DynValue::Void; INLINE_ARGS];
+        for (value, &ty) in values.iter_mut().zip(args) {
+            *value = next_arg(ty);
+        }
+        (ctx.closure)(&values[..args.len()])
+    }
+    else {
+        let values: Vec<_> = args.iter().map(|&ty| next_arg(ty)).collect();
+        (ctx.closure)(&values)
+    };
+
+    assert_eq!(
+        ret.ty(),
+        ctx.sig.ret(),
+        "DynBareFn closure returned a value of the wrong type"
+    );
+    let bits = ret.to_bits();
+    match ret.ty().is_float() {
+        true => RawRet {
+            rax: 0,
+            xmm0: f64::from_bits(bits),
+        },
+        false => RawRet {
+            rax: bits,
+            xmm0: 0.0,
+        },
+    }
+}
+
+/// A closure exposed as a bare function whose signature is described at runtime by a
+/// [`DynSignature`].
+///
+/// See the [module documentation](self) for more information.
+///
+/// # Type parameters
+/// - `'a`: The lifetime of the closure.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free the executable memory of the
+///   entry stub.
+#[cfg(feature = "global_jit_alloc")]
+pub struct DynBareFn<'a, A: JitAlloc = GlobalJitAlloc> {
+    stub: *const u8,
+    ctx: *mut Context<'a>,
+    #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+    symbol: Option<ThunkSymbol>,
+    jit_alloc: A,
+}
+
+/// A closure exposed as a bare function whose signature is described at runtime by a
+/// [`DynSignature`].
+///
+/// See the [module documentation](self) for more information.
+///
+/// # Type parameters
+/// - `'a`: The lifetime of the closure.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free the executable memory of the
+///   entry stub.
+#[cfg(not(feature = "global_jit_alloc"))]
+pub struct DynBareFn<'a, A: JitAlloc> {
+    stub: *const u8,
+    ctx: *mut Context<'a>,
+    #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+    symbol: Option<ThunkSymbol>,
+    jit_alloc: A,
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<'a> DynBareFn<'a, GlobalJitAlloc> {
+    /// Wraps `fun`, producing a bare function with signature `sig`.
+    ///
+    /// `fun` is called with one [`DynValue`] per argument of `sig`, and must return a value of
+    /// its return type ([`DynValue::Void`] if it has none).
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new<F>(sig: DynSignature, fun: F) -> Self
+    where
+        F: Fn(&[DynValue]) -> DynValue + 'a,
+    {
+        Self::new_in(sig, fun, Default::default())
+    }
+}
+
+impl<'a, A: JitAlloc> DynBareFn<'a, A> {
+    /// Wraps `fun`, producing a bare function with signature `sig`. See [`DynBareFn::new`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the entry stub.
+    ///
+    /// # Panics
+    /// If the JIT allocator fails to allocate memory. For a non-panicking version, see
+    /// [`Self::try_new_in`].
+    #[inline]
+    pub fn new_in<F>(sig: DynSignature, fun: F, jit_alloc: A) -> Self
+    where
+        F: Fn(&[DynValue]) -> DynValue + 'a,
+    {
+        Self::try_new_in(sig, fun, jit_alloc).unwrap()
+    }
+
+    /// Wraps `fun`, producing a bare function with signature `sig`. See [`DynBareFn::new`].
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the entry stub.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory.
+    pub fn try_new_in<F>(sig: DynSignature, fun: F, jit_alloc: A) -> Result<Self, ThunkError>
+    where
+        F: Fn(&[DynValue]) -> DynValue + 'a,
+    {
+        let (rx, rw) = jit_alloc.alloc(STUB.len())?;
+        let ctx = Box::into_raw(Box::new(Context {
+            sig,
+            closure: Box::new(fun),
+        }));
+
+        let mut stub = STUB;
+        stub[CTX_OFFSET..CTX_OFFSET + 8].copy_from_slice(&(ctx as u64).to_le_bytes());
+        stub[DISPATCH_OFFSET..DISPATCH_OFFSET + 8]
+            .copy_from_slice(&(dispatch as *const () as u64).to_le_bytes());
+
+        // SAFETY: `rx` and `rw` were just allocated with the size of the stub
+        unsafe {
+            jit_alloc.protect_jit_memory(rx, stub.len(), ProtectJitAccess::ReadWrite);
+            core::ptr::copy_nonoverlapping(stub.as_ptr(), rw, stub.len());
+            jit_alloc.protect_jit_memory(rx, stub.len(), ProtectJitAccess::ReadExecute);
+            jit_alloc.flush_instruction_cache(rx, stub.len());
+        }
+
+        Ok(Self {
+            stub: rx,
+            ctx,
+            #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+            symbol: Some(ThunkSymbol::register(
+                rx as usize,
+                STUB.len(),
+                &jit_symbols::dyn_stub_name::<F>(),
+            )),
+            jit_alloc,
+        })
+    }
+
+    /// Return a pointer to the bare function, which must be cast to a function pointer type
+    /// matching the signature before being called.
+    ///
+    /// # Safety
+    /// While this method is safe, the returned function pointer is not. In particular, it
+    /// must not be called when:
+    /// - The lifetime of `self` has expired, or `self` has been dropped.
+    /// - The arguments passed to it do not match the signature.
+    /// - The closure is not `Sync`, if calling from a different thread than the current one.
+    ///
+    /// The process is aborted if the closure panics or returns a value which does not match the
+    /// return type of the signature.
+    #[inline]
+    pub fn bare(&self) -> *const () {
+        self.stub.cast()
+    }
+
+    /// The signature of the bare function.
+    #[inline]
+    pub fn signature(&self) -> &DynSignature {
+        // SAFETY: `ctx` is valid until `self` is dropped
+        unsafe { &(*self.ctx).sig }
+    }
+}
+
+impl<A: JitAlloc> Drop for DynBareFn<'_, A> {
+    fn drop(&mut self) {
+        // Unregister the symbol before the memory can be reused by another thunk
+        #[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+        drop(self.symbol.take());
+
+        // SAFETY: `stub` was allocated by `jit_alloc` and `ctx` by `Box`, and neither can be used
+        // once `self` is dropped
+        unsafe {
+            let _ = self.jit_alloc.release(self.stub);
+            drop(Box::from_raw(self.ctx));
+        }
+    }
+}
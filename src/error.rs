@@ -0,0 +1,183 @@
+//! Errors returned by the fallible constructors of the bare closure types.
+//!
+//! See [`ThunkError`] for more information.
+
+use core::fmt;
+
+use crate::jit_alloc::JitAllocError;
+
+/// Error returned when a bare closure thunk cannot be created, e.g. by
+/// [`BareFnAny::try_with_cc_in`](crate::bare_closure::BareFnAny::try_with_cc_in).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ThunkError {
+    /// The JIT allocator failed to provide executable memory for the thunk.
+    JitAlloc(JitAllocError),
+    /// The prologue of the compiler-generated thunk template could not be relocated by the
+    /// `safe_jit` feature.
+    Prologue(PrologueError),
+}
+
+impl fmt::Display for ThunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::JitAlloc(e) => write!(f, "failed to allocate thunk memory: {e}"),
+            Self::Prologue(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl core::error::Error for ThunkError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::JitAlloc(e) => Some(e),
+            Self::Prologue(e) => Some(e),
+        }
+    }
+}
+
+impl From<JitAllocError> for ThunkError {
+    fn from(value: JitAllocError) -> Self {
+        Self::JitAlloc(value)
+    }
+}
+
+impl From<PrologueError> for ThunkError {
+    fn from(value: PrologueError) -> Self {
+        Self::Prologue(value)
+    }
+}
+
+/// The reason why a thunk template prologue could not be relocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PrologueErrorKind {
+    /// The prologue contains an invalid instruction.
+    InvalidInstruction,
+    /// The prologue contains an instruction that depends on its address in a way that cannot be
+    /// relocated.
+    UnsupportedInstruction,
+    /// The prologue contains a branch that cannot be relocated.
+    UnsupportedControlFlow,
+    /// No scratch register is available to hold the address of an IP-relative operand.
+    NoAvailableRegister,
+    /// A relocated instruction could not be encoded.
+    EncodingError,
+    /// The closure address load emitted by the thunk template could not be found.
+    NoThunkAsm,
+}
+
+impl fmt::Display for PrologueErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidInstruction => "invalid instruction",
+            Self::UnsupportedInstruction => "unsupported instruction",
+            Self::UnsupportedControlFlow => "unsupported branch",
+            Self::NoAvailableRegister => "no available scratch register",
+            Self::EncodingError => "failed to encode relocated instruction",
+            Self::NoThunkAsm => "closure address load not found",
+        })
+    }
+}
+
+#[cfg(feature = "safe_jit")]
+impl From<crate::safe_jit::JitError> for PrologueErrorKind {
+    fn from(value: crate::safe_jit::JitError) -> Self {
+        use crate::safe_jit::JitError;
+        match value {
+            JitError::InvalidInstruction | JitError::PrologueTooSmall => Self::InvalidInstruction,
+            JitError::UnsupportedInstruction => Self::UnsupportedInstruction,
+            JitError::UnsupportedControlFlow => Self::UnsupportedControlFlow,
+            JitError::NoAvailableRegister => Self::NoAvailableRegister,
+            JitError::EncodingError => Self::EncodingError,
+            JitError::NoThunkAsm => Self::NoThunkAsm,
+        }
+    }
+}
+
+/// Error returned when the prologue of a thunk template cannot be relocated.
+///
+/// Thunk templates are generated by the compiler, so this is a bug in `closure-ffi`. Please report
+/// it along with the information provided by this error, and your binary with debug info if
+/// possible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrologueError {
+    kind: PrologueErrorKind,
+    template: usize,
+    offset: Option<usize>,
+    bytes: [u8; Self::MAX_BYTES],
+    bytes_len: u8,
+}
+
+impl PrologueError {
+    /// Maximum number of instruction bytes stored in the error.
+    const MAX_BYTES: usize = 16;
+
+    /// Creates an error for the thunk template at address `template`, caused by the instruction
+    /// with the given offset and bytes if there is one.
+    #[cfg_attr(not(feature = "safe_jit"), allow(dead_code))]
+    pub(crate) fn new(
+        kind: PrologueErrorKind,
+        template: usize,
+        instruction: Option<(usize, &[u8])>,
+    ) -> Self {
+        let mut bytes = [0; Self::MAX_BYTES];
+        let (offset, bytes_len) = match instruction {
+            Some((offset, instruction_bytes)) => {
+                let len = instruction_bytes.len().min(Self::MAX_BYTES);
+                bytes[..len].copy_from_slice(&instruction_bytes[..len]);
+                (Some(offset), len as u8)
+            }
+            None => (None, 0),
+        };
+
+        Self {
+            kind,
+            template,
+            offset,
+            bytes,
+            bytes_len,
+        }
+    }
+
+    /// The reason why the prologue could not be relocated.
+    pub fn kind(&self) -> PrologueErrorKind {
+        self.kind
+    }
+
+    /// The address of the thunk template.
+    pub fn template_addr(&self) -> usize {
+        self.template
+    }
+
+    /// The offset of the offending instruction from the start of the thunk template, if the error
+    /// was caused by a specific instruction.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// The bytes of the offending instruction, if the error was caused by a specific instruction.
+    pub fn instruction_bytes(&self) -> Option<&[u8]> {
+        self.offset.map(|_| &self.bytes[..self.bytes_len as usize])
+    }
+}
+
+impl fmt::Display for PrologueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to relocate thunk template prologue at {:#x}: {}",
+            self.template, self.kind
+        )?;
+        if let (Some(offset), Some(bytes)) = (self.offset, self.instruction_bytes()) {
+            write!(f, " at offset {offset:#x} (bytes:")?;
+            for byte in bytes {
+                write!(f, " {byte:02x}")?;
+            }
+            f.write_str(")")?;
+        }
+        f.write_str(". This is a bug, please report it")
+    }
+}
+
+impl core::error::Error for PrologueError {}
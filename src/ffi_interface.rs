@@ -0,0 +1,242 @@
+//! C function pointer tables ("vtables") whose entries are bare closure thunks calling into a
+//! Rust trait implementation.
+//!
+//! C plugin interfaces are often structs of function pointers without a context argument. The
+//! [`ffi_interface`](macro@crate::ffi_interface) attribute macro (requires the `proc_macros`
+//! feature) generates such a `#[repr(C)]` table from a trait, along with an implementation of
+//! [`FfiVtable`] for it. An [`FfiInterface`] then owns an implementor of the trait, the table
+//! and the thunks its entries point to:
+//!
+//! ```
+//! # #[cfg(all(feature = "proc_macros", feature = "default_jit_alloc"))] {
+//! use core::cell::Cell;
+//!
+//! use closure_ffi::{ffi_interface, ffi_interface::FfiInterface};
+//!
+//! #[ffi_interface]
+//! trait Counter {
+//!     fn add(&self, n: u32);
+//!     fn get(&self) -> u32;
+//! }
+//!
+//! struct MyCounter(Cell<u32>);
+//!
+//! impl Counter for MyCounter {
+//!     fn add(&self, n: u32) {
+//!         self.0.set(self.0.get() + n);
+//!     }
+//!     fn get(&self) -> u32 {
+//!         self.0.get()
+//!     }
+//! }
+//!
+//! let interface = FfiInterface::<CounterVtable, _>::new(MyCounter(Cell::new(0)));
+//! let vtable: &CounterVtable = interface.vtable();
+//! unsafe {
+//!     (vtable.add)(2);
+//!     (vtable.add)(3);
+//!     assert_eq!((vtable.get)(), 5);
+//! }
+//! # }
+//! ```
+
+use alloc::{boxed::Box, vec::Vec};
+
+#[cfg(feature = "global_jit_alloc")]
+use crate::jit_alloc::GlobalJitAlloc;
+use crate::{
+    bare_closure::{BareFn, UntypedBareFn},
+    error::ThunkError,
+    jit_alloc::JitAlloc,
+    traits::{Any, FnPtr, FnThunk, ToBoxedDyn},
+};
+
+/// A table of bare function pointers which can be filled with thunks calling into an
+/// implementation of `T`.
+///
+/// This is usually implemented by the [`ffi_interface`](macro@crate::ffi_interface) attribute
+/// macro.
+///
+/// # Safety
+/// [`FfiVtable::build`] must only use `imp` from the thunks it creates, and only as a shared
+/// reference.
+pub unsafe trait FfiVtable<T>: Sized {
+    /// Creates the thunks of the table entries, which call into the implementation at `imp`,
+    /// adding them to `thunks` so that they live as long as the table.
+    ///
+    /// # Errors
+    /// If a thunk cannot be created. See [`ThunkError`].
+    ///
+    /// # Safety
+    /// `imp` must remain valid for as long as the thunks added to `thunks` are called.
+    unsafe fn build<'a, A: JitAlloc + Clone>(
+        imp: *const T,
+        thunks: &mut VtableThunks<'a, A>,
+    ) -> Result<Self, ThunkError>
+    where
+        T: 'a;
+}
+
+/// The thunks referenced by the entries of an [`FfiVtable`].
+pub struct VtableThunks<'a, A: JitAlloc> {
+    thunks: Vec<UntypedBareFn<dyn Any + 'a, A>>,
+    jit_alloc: A,
+}
+
+impl<'a, A: JitAlloc> VtableThunks<'a, A> {
+    pub(crate) fn new(jit_alloc: A) -> Self {
+        Self {
+            thunks: Vec::new(),
+            jit_alloc,
+        }
+    }
+}
+
+impl<'a, A: JitAlloc + Clone> VtableThunks<'a, A> {
+    /// Returns a clone of the JIT allocator to use for the thunks.
+    #[inline]
+    pub fn jit_alloc(&self) -> A {
+        self.jit_alloc.clone()
+    }
+
+    /// Takes ownership of `bare_fn`, returning its bare function pointer.
+    #[inline]
+    pub fn push<B: FnPtr>(&mut self, bare_fn: BareFn<'a, B, A>) -> B {
+        let bare = bare_fn.bare();
+        self.thunks.push(bare_fn.into_untyped());
+        bare
+    }
+
+    /// Wraps `fun`, returning a bare function of signature `B` which lives as long as the
+    /// thunks.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if a thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn thunk<B: FnPtr, F>(&mut self, fun: F) -> Result<B, ThunkError>
+    where
+        F: ToBoxedDyn<dyn Any + 'a>,
+        (B::CC, F): FnThunk<B>,
+    {
+        let bare_fn = BareFn::<'a, B, A>::try_with_cc_in(B::CC::default(), fun, self.jit_alloc())?;
+        Ok(self.push(bare_fn))
+    }
+}
+
+/// Owns an implementation of `T`, along with a table of bare function pointers `V` calling into
+/// it. See the [module documentation](self).
+///
+/// # Type parameters
+/// - `V`: The table of bare function pointers, usually generated by the
+///   [`ffi_interface`](macro@crate::ffi_interface) macro.
+/// - `T`: The implementation the table calls into.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free executable memory.
+#[cfg(feature = "global_jit_alloc")]
+pub struct FfiInterface<'a, V, T: 'a, A: JitAlloc = GlobalJitAlloc> {
+    vtable: Box<V>,
+    thunks: VtableThunks<'a, A>,
+    // Like bare closures, we can't own the implementation directly as the thunks borrow it.
+    // So we reclaim the pointer in the Drop impl.
+    imp: *mut T,
+}
+
+/// Owns an implementation of `T`, along with a table of bare function pointers `V` calling into
+/// it. See the [module documentation](self).
+///
+/// # Type parameters
+/// - `V`: The table of bare function pointers, usually generated by the
+///   [`ffi_interface`](macro@crate::ffi_interface) macro.
+/// - `T`: The implementation the table calls into.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free executable memory.
+#[cfg(not(feature = "global_jit_alloc"))]
+pub struct FfiInterface<'a, V, T: 'a, A: JitAlloc> {
+    vtable: Box<V>,
+    thunks: VtableThunks<'a, A>,
+    imp: *mut T,
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<'a, V: FfiVtable<T>, T: 'a> FfiInterface<'a, V, T, GlobalJitAlloc> {
+    /// Takes ownership of `imp`, creating a table of bare functions calling into it.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new(imp: T) -> Self {
+        Self::new_in(imp, Default::default())
+    }
+}
+
+impl<'a, V: FfiVtable<T>, T: 'a, A: JitAlloc + Clone> FfiInterface<'a, V, T, A> {
+    /// Takes ownership of `imp`, creating a table of bare functions calling into it.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if a thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_new_in(imp: T, jit_alloc: A) -> Result<Self, ThunkError> {
+        let imp = Box::into_raw(Box::new(imp));
+        let mut thunks = VtableThunks::new(jit_alloc);
+
+        // SAFETY: `imp` is freed when `self` is dropped, after which the thunks can't be called
+        let vtable = match unsafe { V::build(imp, &mut thunks) } {
+            Ok(vtable) => vtable,
+            Err(err) => {
+                drop(thunks);
+                // SAFETY: `imp` was allocated above, and the thunks using it were freed
+                drop(unsafe { Box::from_raw(imp) });
+                return Err(err);
+            }
+        };
+        Ok(Self {
+            vtable: Box::new(vtable),
+            thunks,
+            imp,
+        })
+    }
+
+    /// Takes ownership of `imp`, creating a table of bare functions calling into it.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks.
+    ///
+    /// # Panics
+    /// If a thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_new_in`].
+    #[inline]
+    pub fn new_in(imp: T, jit_alloc: A) -> Self {
+        Self::try_new_in(imp, jit_alloc).unwrap()
+    }
+}
+
+impl<'a, V, T: 'a, A: JitAlloc> FfiInterface<'a, V, T, A> {
+    /// Returns the table of bare functions.
+    ///
+    /// The table is boxed, so its address does not change when `self` is moved.
+    ///
+    /// # Safety
+    /// While this method is safe, the bare functions in the table are not. In particular, they
+    /// must not be called when:
+    /// - The lifetime of `self` has expired, or `self` has been dropped.
+    /// - The implementation is not `Sync`, if calling from a different thread than the current one.
+    #[inline]
+    pub fn vtable(&self) -> &V {
+        &self.vtable
+    }
+
+    /// Returns the implementation the bare functions call into.
+    #[inline]
+    pub fn implementor(&self) -> &T {
+        // SAFETY: `imp` is valid until `self` is dropped
+        unsafe { &*self.imp }
+    }
+}
+
+impl<V, T, A: JitAlloc> Drop for FfiInterface<'_, V, T, A> {
+    fn drop(&mut self) {
+        // Free the thunks first, then the implementation they call into
+        self.thunks.thunks.clear();
+        // SAFETY: The caller of the table's bare functions promised not to call them after the
+        // lifetime of self expires, so no borrow on the implementation exists
+        drop(unsafe { Box::from_raw(self.imp) });
+    }
+}
@@ -8,11 +8,60 @@
 
 #[allow(unused_imports)]
 use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-/// Anonymous error that may be returned by [`JitAlloc`] implementations when [`JitAlloc::alloc`] or
+#[cfg(any(
+    all(feature = "memfd_jit_alloc", target_os = "linux"),
+    all(feature = "default_jit_alloc", target_arch = "x86_64")
+))]
+mod granules;
+#[cfg(all(feature = "memfd_jit_alloc", target_os = "linux"))]
+mod memfd;
+#[cfg(all(feature = "default_jit_alloc", target_arch = "x86_64"))]
+mod near;
+#[cfg(all(feature = "memfd_jit_alloc", target_os = "linux"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "memfd_jit_alloc")))]
+pub use memfd::MemfdJitAlloc;
+
+/// Error that may be returned by [`JitAlloc`] implementations when [`JitAlloc::alloc`] or
 /// [`JitAlloc::release`] fail.
-#[derive(Debug)]
-pub struct JitAllocError;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JitAllocError {
+    /// Not enough executable memory is available to satisfy the request.
+    OutOfMemory,
+    /// The requested size is not supported by the allocator.
+    InvalidSize,
+    /// The pointer passed to [`JitAlloc::release`] was not allocated by this allocator, or was
+    /// already released.
+    InvalidPointer,
+    /// The operating system refused to map memory with the required protection, e.g. because of a
+    /// security policy forbidding executable memory.
+    ProtectionFailed,
+    /// A system call failed with the given OS error code (`errno` on Unix, `GetLastError` on
+    /// Windows).
+    Os(i32),
+    /// The allocator does not support the requested operation, e.g. [`JitAlloc::alloc_near`].
+    Unsupported,
+    /// The allocator failed for another reason.
+    Other,
+}
+
+impl core::fmt::Display for JitAllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::OutOfMemory => f.write_str("out of executable memory"),
+            Self::InvalidSize => f.write_str("unsupported allocation size"),
+            Self::InvalidPointer => f.write_str("pointer was not allocated by this allocator"),
+            Self::ProtectionFailed => f.write_str("failed to map executable memory"),
+            Self::Os(code) => write!(f, "OS error {code}"),
+            Self::Unsupported => f.write_str("operation not supported by the allocator"),
+            Self::Other => f.write_str("JIT allocator error"),
+        }
+    }
+}
+
+impl core::error::Error for JitAllocError {}
 
 /// Values to use with [`JitAlloc::protect_jit_memory`].
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -33,10 +82,29 @@ pub trait JitAlloc {
     /// All code writes *must* go to the Read-Write mapping.
     fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError>;
 
+    /// Allocates `size` bytes in the executable memory region, such that every byte of the
+    /// allocation is within `range` bytes of `hint`.
+    ///
+    /// This lets thunks be placed close enough to their template for IP-relative operands to be
+    /// reached with their original 32-bit displacements, which avoids relocating the template's
+    /// prologue. Allocators that cannot honor the request return [`JitAllocError::Unsupported`],
+    /// which is what the default implementation does.
+    ///
+    /// Memory returned by this method must be released with [`JitAlloc::release`].
+    fn alloc_near(
+        &self,
+        hint: *const u8,
+        range: usize,
+        size: usize,
+    ) -> Result<(*const u8, *mut u8), JitAllocError> {
+        let _ = (hint, range, size);
+        Err(JitAllocError::Unsupported)
+    }
+
     /// Releases the memory allocated by `alloc`.
     ///
     /// # Safety
-    /// - `rx_ptr` must have been returned from `alloc`
+    /// - `rx_ptr` must have been returned from `alloc` or `alloc_near`
     /// - `rx_ptr` must have been allocated from this allocator
     /// - `rx_ptr` must not have been passed to `release` before
     /// - `rx_ptr` must point to read-execute part of memory returned from `alloc`.
@@ -68,6 +136,15 @@ impl<J: JitAlloc, D: Deref<Target = J>> JitAlloc for D {
         (**self).alloc(size)
     }
 
+    fn alloc_near(
+        &self,
+        hint: *const u8,
+        range: usize,
+        size: usize,
+    ) -> Result<(*const u8, *mut u8), JitAllocError> {
+        (**self).alloc_near(hint, range, size)
+    }
+
     unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
         (**self).release(rx_ptr)
     }
@@ -83,6 +160,621 @@ impl<J: JitAlloc, D: Deref<Target = J>> JitAlloc for D {
     }
 }
 
+/// A [`JitAlloc`] handing out fixed-size slots from executable memory reserved up front.
+///
+/// All slots are obtained from the backing allocator with a single call to [`JitAlloc::alloc`]
+/// when the pool is created. After that, allocating and releasing slots is lock-free and never
+/// calls into the backing allocator. This makes it possible to create bare closures in contexts
+/// where taking the [`GlobalJitAlloc`] lock is not acceptable, by passing a reference to the pool
+/// to the `*_in` constructors.
+///
+/// The [`JitAlloc::protect_jit_memory`] and [`JitAlloc::flush_instruction_cache`] calls made for
+/// each slot are forwarded to the backing allocator. When creating many thunks at once, they can
+/// be created from within [`ThunkPool::write`] instead, which makes these calls once over the whole
+/// reservation and skips the per-slot calls made by the current thread in the meantime (this
+/// requires the `std` feature; without it, the per-slot calls are always made).
+///
+/// The bare closure types still move the closure to the heap, unless it is zero-sized. To avoid
+/// this, the `new_inline_in` constructor of [`BareFn`](crate::BareFn) copies a [`Copy`] closure to
+/// the slot instead. Together with `prewarm`, which caches the relocated thunk template ahead of
+/// time, creating the thunk is then free of locks and heap allocations:
+///
+/// ```
+/// # #[cfg(feature = "default_jit_alloc")] {
+/// use closure_ffi::{
+///     jit_alloc::{GlobalJitAlloc, ThunkPool},
+///     BareFn,
+/// };
+///
+/// type Bare = unsafe extern "C" fn(u32) -> u32;
+///
+/// fn make_adder(n: u32) -> impl Fn(u32) -> u32 + Copy {
+///     move |x| x + n
+/// }
+///
+/// fn pool_for<F: Fn(u32) -> u32 + Copy>(_: &F, count: usize) -> ThunkPool<GlobalJitAlloc> {
+///     BareFn::<Bare>::prewarm::<F>();
+///     ThunkPool::new(count, BareFn::<Bare>::jit_alloc_size_inline::<F>())
+/// }
+///
+/// let pool = pool_for(&make_adder(0), 4);
+///
+/// // No lock is taken and no memory is allocated here
+/// let bare_closure: BareFn<Bare, _> = pool.write(|| BareFn::new_inline_in(make_adder(1), &pool));
+/// assert_eq!(unsafe { bare_closure.bare()(1) }, 2);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ThunkPool<A: JitAlloc> {
+    rx: *const u8,
+    rw: *mut u8,
+    slot_size: usize,
+    slot_count: usize,
+    used: alloc::boxed::Box<[AtomicUsize]>,
+    jit: A,
+}
+
+// SAFETY: The pool only hands out memory through atomic operations on `used`
+unsafe impl<A: JitAlloc + Send> Send for ThunkPool<A> {}
+unsafe impl<A: JitAlloc + Sync> Sync for ThunkPool<A> {}
+
+impl<A: JitAlloc> ThunkPool<A> {
+    /// Slots are aligned to this many bytes.
+    const SLOT_ALIGN: usize = 16;
+
+    /// Reserves executable memory for `slot_count` thunks of at most `slot_size` bytes each from
+    /// `jit`.
+    ///
+    /// The size of the thunks of a particular closure type can be obtained with the
+    /// `jit_alloc_size` associated function of the bare closure types.
+    pub fn try_new_in(slot_count: usize, slot_size: usize, jit: A) -> Result<Self, JitAllocError> {
+        const BITS: usize = usize::BITS as usize;
+
+        let slot_size = slot_size.max(1).next_multiple_of(Self::SLOT_ALIGN);
+        let total_size = slot_count.checked_mul(slot_size).ok_or(JitAllocError::InvalidSize)?;
+        let (rx, rw) = match total_size {
+            0 => (core::ptr::null(), core::ptr::null_mut()),
+            _ => jit.alloc(total_size)?,
+        };
+
+        // Mark the bits past the last slot as used so they are never handed out
+        let used = (0..slot_count.div_ceil(BITS))
+            .map(|i| {
+                let remaining = slot_count - i * BITS;
+                AtomicUsize::new(if remaining >= BITS { 0 } else { usize::MAX << remaining })
+            })
+            .collect();
+
+        Ok(Self {
+            rx,
+            rw,
+            slot_size,
+            slot_count,
+            used,
+            jit,
+        })
+    }
+
+    /// Reserves executable memory for `slot_count` thunks of at most `slot_size` bytes each from
+    /// `jit`.
+    ///
+    /// # Panics
+    /// If the backing allocator fails to allocate memory. For a non-panicking version, see
+    /// [`Self::try_new_in`].
+    #[inline]
+    pub fn new_in(slot_count: usize, slot_size: usize, jit: A) -> Self {
+        Self::try_new_in(slot_count, slot_size, jit).unwrap()
+    }
+
+    /// The size of each slot in the pool, in bytes.
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// The total number of slots in the pool.
+    pub fn capacity(&self) -> usize {
+        self.slot_count
+    }
+
+    /// The number of slots which are not currently allocated.
+    pub fn available(&self) -> usize {
+        self.used.iter().map(|w| w.load(Ordering::Relaxed).count_zeros() as usize).sum()
+    }
+
+    /// Calls `f`, which creates thunks in the pool, with the whole reservation writable by the
+    /// current thread. The reservation is then made executable and the instruction cache is
+    /// flushed for it, even if `f` panics.
+    ///
+    /// Thunks created from the pool cannot be called before this returns.
+    ///
+    /// If the backing allocator does not dual map its memory, making the reservation writable may
+    /// also make it non-executable. Thunks already created from the pool must then not be running
+    /// on other threads while this is called.
+    pub fn write<R>(&self, f: impl FnOnce() -> R) -> R {
+        let size = self.slot_count * self.slot_size;
+        if size == 0 {
+            return f();
+        }
+
+        // SAFETY: The reservation is `size` bytes of JIT memory allocated from `jit`
+        unsafe { self.jit.protect_jit_memory(self.rx, size, ProtectJitAccess::ReadWrite) };
+        let _guard = WriteGuard {
+            pool: self,
+            size,
+            #[cfg(feature = "std")]
+            outer: pool_writer::enter(self.rx),
+        };
+        f()
+    }
+
+    /// Whether the current thread is inside [`Self::write`] for this pool.
+    #[inline(always)]
+    fn is_writing(&self) -> bool {
+        #[cfg(feature = "std")]
+        return pool_writer::current() == self.rx;
+        #[cfg(not(feature = "std"))]
+        false
+    }
+}
+
+/// Makes the reservation executable again when [`ThunkPool::write`] returns or unwinds.
+struct WriteGuard<'a, A: JitAlloc> {
+    pool: &'a ThunkPool<A>,
+    size: usize,
+    #[cfg(feature = "std")]
+    outer: *const u8,
+}
+
+impl<A: JitAlloc> Drop for WriteGuard<'_, A> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        pool_writer::leave(self.outer);
+
+        // SAFETY: The reservation is `size` bytes of JIT memory allocated from `jit`
+        unsafe {
+            let pool = self.pool;
+            pool.jit.protect_jit_memory(pool.rx, self.size, ProtectJitAccess::ReadExecute);
+            pool.jit.flush_instruction_cache(pool.rx, self.size);
+        }
+    }
+}
+
+/// Tracks the [`ThunkPool`] the current thread is writing to, by the address of its reservation.
+#[cfg(feature = "std")]
+mod pool_writer {
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static CURRENT: Cell<*const u8> = const { Cell::new(core::ptr::null()) };
+    }
+
+    /// Returns the reservation of the pool previously written to, to be restored by [`leave`].
+    pub fn enter(rx: *const u8) -> *const u8 {
+        CURRENT.with(|c| c.replace(rx))
+    }
+
+    pub fn leave(outer: *const u8) {
+        CURRENT.with(|c| c.set(outer));
+    }
+
+    pub fn current() -> *const u8 {
+        CURRENT.with(|c| c.get())
+    }
+}
+
+#[cfg(feature = "global_jit_alloc")]
+impl ThunkPool<GlobalJitAlloc> {
+    /// Reserves executable memory for `slot_count` thunks of at most `slot_size` bytes each from
+    /// the global JIT allocator.
+    ///
+    /// # Panics
+    /// If the global JIT allocator fails to allocate memory. For a non-panicking version, see
+    /// [`Self::try_new_in`].
+    #[inline]
+    pub fn new(slot_count: usize, slot_size: usize) -> Self {
+        Self::new_in(slot_count, slot_size, GlobalJitAlloc)
+    }
+}
+
+impl<A: JitAlloc> JitAlloc for ThunkPool<A> {
+    fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError> {
+        if size > self.slot_size {
+            return Err(JitAllocError::InvalidSize);
+        }
+
+        for (i, word) in self.used.iter().enumerate() {
+            let mut bits = word.load(Ordering::Relaxed);
+            while bits != usize::MAX {
+                let bit = bits.trailing_ones() as usize;
+                match word.compare_exchange_weak(
+                    bits,
+                    bits | (1 << bit),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let offset = (i * usize::BITS as usize + bit) * self.slot_size;
+                        // SAFETY: The slot index is less than `slot_count`
+                        return Ok(unsafe { (self.rx.add(offset), self.rw.add(offset)) });
+                    }
+                    Err(new_bits) => bits = new_bits,
+                }
+            }
+        }
+        Err(JitAllocError::OutOfMemory)
+    }
+
+    unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
+        const BITS: usize = usize::BITS as usize;
+
+        let offset = (rx_ptr as usize).wrapping_sub(self.rx as usize);
+        if !offset.is_multiple_of(self.slot_size) || offset / self.slot_size >= self.slot_count {
+            return Err(JitAllocError::InvalidPointer);
+        }
+        let slot = offset / self.slot_size;
+
+        let mask = 1 << (slot % BITS);
+        match self.used[slot / BITS].fetch_and(!mask, Ordering::Release) & mask {
+            0 => Err(JitAllocError::InvalidPointer),
+            _ => Ok(()),
+        }
+    }
+
+    // Done once for the whole reservation by `ThunkPool::write` when inside it
+    #[inline]
+    unsafe fn flush_instruction_cache(&self, rx_ptr: *const u8, size: usize) {
+        if !self.is_writing() {
+            self.jit.flush_instruction_cache(rx_ptr, size);
+        }
+    }
+
+    #[inline]
+    unsafe fn protect_jit_memory(&self, ptr: *const u8, size: usize, access: ProtectJitAccess) {
+        if !self.is_writing() {
+            self.jit.protect_jit_memory(ptr, size, access);
+        }
+    }
+}
+
+impl<A: JitAlloc> Drop for ThunkPool<A> {
+    fn drop(&mut self) {
+        if !self.rx.is_null() {
+            // SAFETY: `rx` was allocated by `jit`
+            let _ = unsafe { self.jit.release(self.rx) };
+        }
+    }
+}
+
+/// Where an allocation made through a [`TrackingJitAlloc`] originates from.
+///
+/// Allocations are attributed to a site by making them through a [`TaggedJitAlloc`] handle.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AllocSite {
+    /// The allocation was made through the [`TrackingJitAlloc`] itself.
+    Untagged,
+    /// The allocation was made through a handle obtained with [`TrackingJitAlloc::tagged`] or
+    /// [`TrackingJitAlloc::for_signature`].
+    Label(&'static str),
+    /// The allocation was made through a handle obtained with [`TrackingJitAlloc::at_caller`].
+    CallSite(&'static core::panic::Location<'static>),
+}
+
+/// Usage statistics collected by a [`TrackingJitAlloc`].
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JitAllocStats {
+    /// Number of allocations which have not been released yet.
+    pub live_allocations: usize,
+    /// Total size of the allocations which have not been released yet, in bytes.
+    pub live_bytes: usize,
+    /// Highest value reached by `live_allocations`.
+    pub peak_allocations: usize,
+    /// Highest value reached by `live_bytes`.
+    pub peak_bytes: usize,
+    /// Number of successful allocations made so far.
+    pub total_allocations: usize,
+    /// Number of allocations which failed.
+    pub failed_allocations: usize,
+}
+
+#[cfg(feature = "std")]
+impl JitAllocStats {
+    fn record_alloc(&mut self, size: usize) {
+        self.live_allocations += 1;
+        self.live_bytes += size;
+        self.total_allocations += 1;
+        self.peak_allocations = self.peak_allocations.max(self.live_allocations);
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+    }
+
+    fn record_release(&mut self, size: usize) {
+        self.live_allocations -= 1;
+        self.live_bytes -= size;
+    }
+}
+
+/// An allocation made through a [`TrackingJitAlloc`] which has not been released yet.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutstandingAlloc {
+    /// Pointer to the Read-Execute view of the allocation.
+    pub rx_ptr: *const u8,
+    /// Requested size of the allocation, in bytes.
+    pub size: usize,
+    /// Where the allocation originates from.
+    pub site: AllocSite,
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct TrackingState {
+    stats: JitAllocStats,
+    sites: alloc::collections::BTreeMap<AllocSite, JitAllocStats>,
+    live: alloc::collections::BTreeMap<usize, (usize, AllocSite)>,
+}
+
+/// A [`JitAlloc`] wrapping another one to collect usage statistics and list the allocations
+/// which have not been released yet.
+///
+/// Allocations can be attributed to a label, a bare function signature or a call site by making
+/// them through a [`TaggedJitAlloc`] handle. This makes it possible to see which callbacks use
+/// executable memory, or to check that no bare closure was leaked at the end of a test:
+///
+/// ```
+/// # #[cfg(feature = "default_jit_alloc")] {
+/// use closure_ffi::{
+///     jit_alloc::{GlobalJitAlloc, TrackingJitAlloc},
+///     BareFn,
+/// };
+///
+/// static TRACKER: TrackingJitAlloc<GlobalJitAlloc> = TrackingJitAlloc::new(GlobalJitAlloc);
+///
+/// type Callback = unsafe extern "C" fn(u32) -> u32;
+///
+/// let offset = 10;
+/// let bare_closure: BareFn<Callback, _> =
+///     BareFn::new_in(move |x| x + offset, TRACKER.for_signature::<Callback>());
+///
+/// let stats = TRACKER.stats();
+/// assert_eq!(stats.live_allocations, 1);
+/// assert_eq!(TRACKER.stats_by_site()[0].1, stats);
+///
+/// drop(bare_closure);
+/// assert!(TRACKER.outstanding().is_empty());
+/// # }
+/// ```
+///
+/// Requires the `std` feature.
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug)]
+pub struct TrackingJitAlloc<A: JitAlloc> {
+    state: std::sync::Mutex<TrackingState>,
+    inner: A,
+}
+
+#[cfg(feature = "std")]
+impl<A: JitAlloc> TrackingJitAlloc<A> {
+    /// Wraps `inner`, tracking the allocations made through the returned allocator.
+    pub const fn new(inner: A) -> Self {
+        Self {
+            state: std::sync::Mutex::new(TrackingState {
+                stats: JitAllocStats {
+                    live_allocations: 0,
+                    live_bytes: 0,
+                    peak_allocations: 0,
+                    peak_bytes: 0,
+                    total_allocations: 0,
+                    failed_allocations: 0,
+                },
+                sites: alloc::collections::BTreeMap::new(),
+                live: alloc::collections::BTreeMap::new(),
+            }),
+            inner,
+        }
+    }
+
+    /// The wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns a handle attributing the allocations made through it to `label`.
+    pub fn tagged(&self, label: &'static str) -> TaggedJitAlloc<'_, A> {
+        TaggedJitAlloc {
+            tracker: self,
+            site: AllocSite::Label(label),
+        }
+    }
+
+    /// Returns a handle attributing the allocations made through it to the bare function
+    /// signature `B`.
+    pub fn for_signature<B: crate::traits::FnPtr>(&self) -> TaggedJitAlloc<'_, A> {
+        self.tagged(core::any::type_name::<B>())
+    }
+
+    /// Returns a handle attributing the allocations made through it to the location this
+    /// function is called from.
+    #[track_caller]
+    pub fn at_caller(&self) -> TaggedJitAlloc<'_, A> {
+        TaggedJitAlloc {
+            tracker: self,
+            site: AllocSite::CallSite(core::panic::Location::caller()),
+        }
+    }
+
+    /// Usage statistics for all allocations made through this allocator.
+    pub fn stats(&self) -> JitAllocStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Usage statistics for each [`AllocSite`] allocations were made from, in ascending order of
+    /// site.
+    pub fn stats_by_site(&self) -> alloc::vec::Vec<(AllocSite, JitAllocStats)> {
+        let state = self.state.lock().unwrap();
+        state.sites.iter().map(|(&site, &stats)| (site, stats)).collect()
+    }
+
+    /// Lists the allocations which have not been released yet, in ascending order of address.
+    pub fn outstanding(&self) -> alloc::vec::Vec<OutstandingAlloc> {
+        let state = self.state.lock().unwrap();
+        state
+            .live
+            .iter()
+            .map(|(&rx_ptr, &(size, site))| OutstandingAlloc {
+                rx_ptr: rx_ptr as *const u8,
+                size,
+                site,
+            })
+            .collect()
+    }
+
+    fn alloc_at(
+        &self,
+        size: usize,
+        site: AllocSite,
+        near: Option<(*const u8, usize)>,
+    ) -> Result<(*const u8, *mut u8), JitAllocError> {
+        let result = match near {
+            Some((hint, range)) => self.inner.alloc_near(hint, range, size),
+            None => self.inner.alloc(size),
+        };
+        // Not a failure, as callers fall back to `alloc` when near placement is unsupported
+        if result == Err(JitAllocError::Unsupported) {
+            return result;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let site_stats = state.sites.entry(site).or_default();
+        match result {
+            Ok((rx, _)) => {
+                state.stats.record_alloc(size);
+                site_stats.record_alloc(size);
+                state.live.insert(rx as usize, (size, site));
+            }
+            Err(_) => {
+                state.stats.failed_allocations += 1;
+                site_stats.failed_allocations += 1;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A: JitAlloc> JitAlloc for TrackingJitAlloc<A> {
+    fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError> {
+        self.alloc_at(size, AllocSite::Untagged, None)
+    }
+
+    fn alloc_near(
+        &self,
+        hint: *const u8,
+        range: usize,
+        size: usize,
+    ) -> Result<(*const u8, *mut u8), JitAllocError> {
+        self.alloc_at(size, AllocSite::Untagged, Some((hint, range)))
+    }
+
+    unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
+        // Hold the lock while releasing, as another thread may be handed the same address by the
+        // inner allocator as soon as it is released
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let entry = state.live.remove(&(rx_ptr as usize));
+
+        if let Err(err) = self.inner.release(rx_ptr) {
+            if let Some(entry) = entry {
+                state.live.insert(rx_ptr as usize, entry);
+            }
+            return Err(err);
+        }
+
+        if let Some((size, site)) = entry {
+            state.stats.record_release(size);
+            if let Some(site_stats) = state.sites.get_mut(&site) {
+                site_stats.record_release(size);
+            }
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    unsafe fn flush_instruction_cache(&self, rx_ptr: *const u8, size: usize) {
+        self.inner.flush_instruction_cache(rx_ptr, size);
+    }
+
+    #[inline(always)]
+    unsafe fn protect_jit_memory(&self, ptr: *const u8, size: usize, access: ProtectJitAccess) {
+        self.inner.protect_jit_memory(ptr, size, access);
+    }
+}
+
+/// Handle to a [`TrackingJitAlloc`] attributing the allocations made through it to an
+/// [`AllocSite`].
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug)]
+pub struct TaggedJitAlloc<'a, A: JitAlloc> {
+    tracker: &'a TrackingJitAlloc<A>,
+    site: AllocSite,
+}
+
+#[cfg(feature = "std")]
+impl<A: JitAlloc> Clone for TaggedJitAlloc<'_, A> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A: JitAlloc> Copy for TaggedJitAlloc<'_, A> {}
+
+#[cfg(feature = "std")]
+impl<'a, A: JitAlloc> TaggedJitAlloc<'a, A> {
+    /// The tracking allocator this handle allocates from.
+    pub fn tracker(&self) -> &'a TrackingJitAlloc<A> {
+        self.tracker
+    }
+
+    /// The site allocations made through this handle are attributed to.
+    pub fn site(&self) -> AllocSite {
+        self.site
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A: JitAlloc> JitAlloc for TaggedJitAlloc<'_, A> {
+    fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError> {
+        self.tracker.alloc_at(size, self.site, None)
+    }
+
+    fn alloc_near(
+        &self,
+        hint: *const u8,
+        range: usize,
+        size: usize,
+    ) -> Result<(*const u8, *mut u8), JitAllocError> {
+        self.tracker.alloc_at(size, self.site, Some((hint, range)))
+    }
+
+    unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
+        self.tracker.release(rx_ptr)
+    }
+
+    #[inline(always)]
+    unsafe fn flush_instruction_cache(&self, rx_ptr: *const u8, size: usize) {
+        self.tracker.flush_instruction_cache(rx_ptr, size);
+    }
+
+    #[inline(always)]
+    unsafe fn protect_jit_memory(&self, ptr: *const u8, size: usize, access: ProtectJitAccess) {
+        self.tracker.protect_jit_memory(ptr, size, access);
+    }
+}
+
 #[cfg(feature = "global_jit_alloc")]
 /// The default, global JIT allocator.
 ///
@@ -109,6 +801,21 @@ mod default_jit_alloc {
         }
     }
 
+    /// Converts an error returned by [`JitAllocator::alloc`].
+    fn convert_alloc_error(error: jit_allocator2::Error) -> JitAllocError {
+        use jit_allocator2::Error;
+
+        match error {
+            Error::OutOfMemory => JitAllocError::OutOfMemory,
+            Error::TooLarge | Error::InvalidArgument => JitAllocError::InvalidSize,
+            // Raised for most OS errors when mapping memory, not only EACCES and EPERM. The error
+            // code is not kept and `errno` may have been overwritten since, so it is not reported.
+            Error::InvalidState | Error::TooManyHandles | Error::FailedToOpenAnonymousMemory => {
+                JitAllocError::Other
+            }
+        }
+    }
+
     fn flush_instruction_cache(rx_ptr: *const u8, size: usize) {
         #[cfg(all(target_arch = "arm", target_os = "linux"))]
         unsafe {
@@ -142,11 +849,29 @@ mod default_jit_alloc {
     #[cfg_attr(docsrs, doc(cfg(feature = "global_jit_alloc")))]
     impl JitAlloc for super::GlobalJitAlloc {
         fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError> {
-            self.use_alloc(|a| a.alloc(size)).map_err(|_| JitAllocError)
+            self.use_alloc(|a| a.alloc(size).map_err(convert_alloc_error))
+        }
+
+        /// Near allocations are only supported on x86_64, where they are served in whole pages
+        /// from separate chunks mapped close to `hint`. These pages are made executable by
+        /// [`JitAlloc::protect_jit_memory`] and are never writable at the same time.
+        #[cfg(target_arch = "x86_64")]
+        fn alloc_near(
+            &self,
+            hint: *const u8,
+            range: usize,
+            size: usize,
+        ) -> Result<(*const u8, *mut u8), JitAllocError> {
+            let ptr = super::near::alloc_near(hint as usize, range, size)?;
+            Ok((ptr.cast_const(), ptr))
         }
 
         unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
-            self.use_alloc(|a| a.release(rx_ptr)).map_err(|_| JitAllocError)
+            #[cfg(target_arch = "x86_64")]
+            if super::near::release(rx_ptr) {
+                return Ok(());
+            }
+            self.use_alloc(|a| a.release(rx_ptr)).map_err(|_| JitAllocError::InvalidPointer)
         }
 
         #[inline(always)]
@@ -155,12 +880,13 @@ mod default_jit_alloc {
         }
 
         #[inline(always)]
-        unsafe fn protect_jit_memory(
-            &self,
-            _ptr: *const u8,
-            _size: usize,
-            access: ProtectJitAccess,
-        ) {
+        unsafe fn protect_jit_memory(&self, ptr: *const u8, size: usize, access: ProtectJitAccess) {
+            #[cfg(target_arch = "x86_64")]
+            if super::near::protect(ptr, size, access) {
+                return;
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            let _ = (ptr, size);
             jit_allocator2::protect_jit_memory(convert_access(access));
         }
     }
@@ -189,14 +915,13 @@ mod default_jit_alloc {
         impl JitAlloc for ThreadJitAlloc {
             fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError> {
                 THREAD_JIT_ALLOC
-                    .with(|a| unsafe { &mut *a.get() }.alloc(size))
-                    .map_err(|_| JitAllocError)
+                    .with(|a| unsafe { &mut *a.get() }.alloc(size).map_err(convert_alloc_error))
             }
 
             unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
                 THREAD_JIT_ALLOC
                     .with(|a| unsafe { &mut *a.get() }.release(rx_ptr))
-                    .map_err(|_| JitAllocError)
+                    .map_err(|_| JitAllocError::InvalidPointer)
             }
 
             #[inline(always)]
@@ -282,6 +1007,15 @@ mod custom_jit_alloc {
             get_global_jit_alloc().alloc(size)
         }
 
+        fn alloc_near(
+            &self,
+            hint: *const u8,
+            range: usize,
+            size: usize,
+        ) -> Result<(*const u8, *mut u8), JitAllocError> {
+            get_global_jit_alloc().alloc_near(hint, range, size)
+        }
+
         unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
             get_global_jit_alloc().release(rx_ptr)
         }
@@ -0,0 +1,95 @@
+//! Bitmap sub-allocator shared by the allocators which map their own executable memory.
+
+use alloc::vec::Vec;
+
+/// Allocations are made in multiples of this many bytes, which is also their alignment.
+#[cfg(all(feature = "memfd_jit_alloc", target_os = "linux"))]
+pub const GRANULE: usize = 16;
+
+/// Tracks which granules of a region of memory are allocated.
+#[derive(Debug)]
+pub struct Granules {
+    /// Bitmap of the granules which are allocated.
+    used: Vec<u64>,
+    total: usize,
+    live: usize,
+}
+
+impl Granules {
+    /// Creates an empty bitmap for a region of `size` bytes.
+    #[cfg(all(feature = "memfd_jit_alloc", target_os = "linux"))]
+    pub fn new(size: usize) -> Self {
+        Self::with_len(size / GRANULE)
+    }
+
+    /// Creates an empty bitmap of `total` units, for callers allocating in units other than
+    /// [`GRANULE`].
+    pub fn with_len(total: usize) -> Self {
+        Self {
+            used: alloc::vec![0; total.div_ceil(64)],
+            total,
+            live: 0,
+        }
+    }
+
+    /// Returns the number of granules needed to hold `size` bytes.
+    #[cfg(all(feature = "memfd_jit_alloc", target_os = "linux"))]
+    pub fn count_for(size: usize) -> Result<usize, super::JitAllocError> {
+        let count = size.div_ceil(GRANULE).max(1);
+        count.checked_mul(GRANULE).ok_or(super::JitAllocError::InvalidSize)?;
+        Ok(count)
+    }
+
+    /// Whether no allocations are live.
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// Finds `count` consecutive free granules and marks them as used, returning the index of the
+    /// first one.
+    pub fn alloc(&mut self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        let mut i = 0;
+        while i < self.total {
+            // Skip fully used words
+            if i % 64 == 0 && self.used[i / 64] == u64::MAX {
+                run = 0;
+                i += 64;
+                continue;
+            }
+
+            if self.used[i / 64] & (1 << (i % 64)) != 0 {
+                run = 0;
+            }
+            else {
+                run += 1;
+                if run == count {
+                    let start = i + 1 - count;
+                    self.set_used(start, count, true);
+                    self.live += 1;
+                    return Some(start);
+                }
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Marks the `count` granules starting at `start`, previously returned by
+    /// [`Granules::alloc`], as free.
+    pub fn free(&mut self, start: usize, count: usize) {
+        self.set_used(start, count, false);
+        self.live -= 1;
+    }
+
+    fn set_used(&mut self, start: usize, count: usize, used: bool) {
+        for i in start..start + count {
+            if used {
+                self.used[i / 64] |= 1 << (i % 64);
+            }
+            else {
+                self.used[i / 64] &= !(1 << (i % 64));
+            }
+        }
+    }
+}
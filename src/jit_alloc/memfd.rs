@@ -0,0 +1,278 @@
+//! Linux [`JitAlloc`] implementation backed by `memfd_create`, with separate RW and RX views.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ptr::null_mut;
+
+use super::{
+    granules::{Granules, GRANULE},
+    JitAlloc, JitAllocError, ProtectJitAccess,
+};
+
+/// Size of the chunks of executable memory mapped when using regular pages.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Size of huge pages, and of the chunks of executable memory mapped when using them.
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// A region of memory mapped twice: once as Read-Write and once as Read-Execute.
+#[derive(Debug)]
+struct Chunk {
+    rx: usize,
+    rw: usize,
+    size: usize,
+    granules: Granules,
+}
+
+impl Chunk {
+    /// Maps a new chunk of `size` bytes, which must be a multiple of the page size.
+    fn map(size: usize, huge_pages: bool) -> Result<Self, JitAllocError> {
+        let mut flags = libc::MFD_CLOEXEC;
+        if huge_pages {
+            flags |= libc::MFD_HUGETLB;
+        }
+
+        // SAFETY: FFI calls with valid arguments. The mappings keep the memory alive after the file
+        // descriptor is closed.
+        unsafe {
+            let fd = libc::memfd_create(c"closure-ffi".as_ptr(), flags);
+            if fd < 0 {
+                return Err(last_os_error());
+            }
+            let result = Self::map_fd(fd, size);
+            libc::close(fd);
+            result
+        }
+    }
+
+    /// Maps the RW and RX views of the memory file `fd`, resizing it to `size` bytes.
+    unsafe fn map_fd(fd: libc::c_int, size: usize) -> Result<Self, JitAllocError> {
+        if libc::ftruncate(fd, size as libc::off_t) != 0 {
+            return Err(last_os_error());
+        }
+
+        let map = |prot| libc::mmap(null_mut(), size, prot, libc::MAP_SHARED, fd, 0);
+
+        let rw = map(libc::PROT_READ | libc::PROT_WRITE);
+        if rw == libc::MAP_FAILED {
+            return Err(last_os_error());
+        }
+
+        let rx = map(libc::PROT_READ | libc::PROT_EXEC);
+        if rx == libc::MAP_FAILED {
+            let err = last_os_error();
+            libc::munmap(rw, size);
+            return Err(err);
+        }
+
+        Ok(Self {
+            rx: rx as usize,
+            rw: rw as usize,
+            size,
+            granules: Granules::new(size),
+        })
+    }
+
+    fn contains(&self, rx: usize) -> bool {
+        (self.rx..self.rx + self.size).contains(&rx)
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        // SAFETY: Both views were mapped with this size in `Chunk::map`
+        unsafe {
+            libc::munmap(self.rx as *mut _, self.size);
+            libc::munmap(self.rw as *mut _, self.size);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MemfdState {
+    chunks: Vec<Chunk>,
+    /// Maps the RX address of live allocations to their size in granules.
+    allocs: BTreeMap<usize, usize>,
+}
+
+/// A [`JitAlloc`] for Linux which maps executable memory twice from a `memfd_create` file
+/// descriptor: once as Read-Write, and once as Read-Execute.
+///
+/// Unlike the `jit-allocator2` based default allocator, no memory is ever mapped as both writable
+/// and executable, and protections are never changed. This makes it usable on systems where
+/// security policies forbid `PROT_WRITE | PROT_EXEC` mappings or making writable memory executable,
+/// such as SELinux denying `execmem`. Both views appear as `/memfd:closure-ffi` in
+/// `/proc/self/maps`.
+///
+/// The allocator can be used with the `*_in` constructors, or selected as the global allocator
+/// with the [`global_jit_alloc`](crate::global_jit_alloc) macro when the `default_jit_alloc`
+/// feature is disabled:
+///
+/// ```ignore
+/// use closure_ffi::jit_alloc::MemfdJitAlloc;
+///
+/// static JIT_ALLOC: MemfdJitAlloc = MemfdJitAlloc::new();
+/// closure_ffi::global_jit_alloc!(JIT_ALLOC);
+/// ```
+///
+/// Memory is mapped in chunks of 64 KiB, or 2 MiB when huge pages are enabled with
+/// [`MemfdJitAlloc::with_huge_pages`]. Chunks are unmapped once all of their allocations are
+/// released, unless they are the only chunk left.
+#[derive(Debug)]
+pub struct MemfdJitAlloc {
+    #[cfg(feature = "std")]
+    state: std::sync::Mutex<MemfdState>,
+    #[cfg(not(feature = "std"))]
+    state: spin::Mutex<MemfdState>,
+    huge_pages: bool,
+}
+
+impl Default for MemfdJitAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemfdJitAlloc {
+    /// Creates an allocator backed by regular pages. No memory is mapped until the first
+    /// allocation.
+    pub const fn new() -> Self {
+        let state = MemfdState {
+            chunks: Vec::new(),
+            allocs: BTreeMap::new(),
+        };
+        Self {
+            #[cfg(feature = "std")]
+            state: std::sync::Mutex::new(state),
+            #[cfg(not(feature = "std"))]
+            state: spin::Mutex::new(state),
+            huge_pages: false,
+        }
+    }
+
+    /// Creates an allocator which maps memory backed by huge pages when possible.
+    ///
+    /// If a huge page backed chunk cannot be mapped, e.g. because no huge pages are reserved on
+    /// the system, the allocator falls back to regular pages.
+    pub const fn with_huge_pages() -> Self {
+        let mut this = Self::new();
+        this.huge_pages = true;
+        this
+    }
+
+    fn map_chunk(&self, min_size: usize) -> Result<Chunk, JitAllocError> {
+        if self.huge_pages {
+            let size = min_size.max(HUGE_PAGE_SIZE).next_multiple_of(HUGE_PAGE_SIZE);
+            if let Ok(chunk) = Chunk::map(size, true) {
+                return Ok(chunk);
+            }
+        }
+
+        // SAFETY: FFI call without preconditions
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        Chunk::map(min_size.max(CHUNK_SIZE).next_multiple_of(page_size), false)
+    }
+}
+
+impl JitAlloc for MemfdJitAlloc {
+    fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError> {
+        let count = Granules::count_for(size)?;
+        let size = count * GRANULE;
+
+        #[cfg(feature = "std")]
+        let mut state = self.state.lock().unwrap();
+        #[cfg(not(feature = "std"))]
+        let mut state = self.state.lock();
+
+        let found = state
+            .chunks
+            .iter_mut()
+            .enumerate()
+            .find_map(|(i, chunk)| chunk.granules.alloc(count).map(|granule| (i, granule)));
+
+        let (chunk_index, granule) = match found {
+            Some(found) => found,
+            None => {
+                let mut chunk = self.map_chunk(size)?;
+                // Cannot fail as the chunk is empty and large enough
+                let granule = chunk.granules.alloc(count).unwrap();
+                state.chunks.push(chunk);
+                (state.chunks.len() - 1, granule)
+            }
+        };
+
+        let chunk = &state.chunks[chunk_index];
+        let (rx, rw) = (chunk.rx + granule * GRANULE, chunk.rw + granule * GRANULE);
+        state.allocs.insert(rx, count);
+        Ok((rx as *const u8, rw as *mut u8))
+    }
+
+    unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
+        let rx = rx_ptr as usize;
+
+        #[cfg(feature = "std")]
+        let mut state = self.state.lock().unwrap();
+        #[cfg(not(feature = "std"))]
+        let mut state = self.state.lock();
+
+        let count = state.allocs.remove(&rx).ok_or(JitAllocError::InvalidPointer)?;
+        // Cannot fail as the allocation was made from one of the chunks
+        let chunk_index = state.chunks.iter().position(|c| c.contains(rx)).unwrap();
+
+        let chunk = &mut state.chunks[chunk_index];
+        chunk.granules.free((rx - chunk.rx) / GRANULE, count);
+
+        if chunk.granules.is_empty() && state.chunks.len() > 1 {
+            state.chunks.swap_remove(chunk_index);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    unsafe fn protect_jit_memory(&self, _ptr: *const u8, _size: usize, _access: ProtectJitAccess) {
+        // The RW and RX views are separate mappings, so there is nothing to do
+    }
+
+    unsafe fn flush_instruction_cache(&self, rx_ptr: *const u8, size: usize) {
+        flush_instruction_cache(rx_ptr, size);
+    }
+}
+
+/// Converts the current `errno` into a [`JitAllocError`].
+fn last_os_error() -> JitAllocError {
+    // SAFETY: `__errno_location` always returns a valid pointer to the thread's errno
+    match unsafe { *libc::__errno_location() } {
+        libc::ENOMEM => JitAllocError::OutOfMemory,
+        libc::EACCES | libc::EPERM => JitAllocError::ProtectionFailed,
+        code => JitAllocError::Os(code),
+    }
+}
+
+#[allow(unused_variables)]
+unsafe fn flush_instruction_cache(rx_ptr: *const u8, size: usize) {
+    // Instruction caches are coherent with data caches on x86
+    #[cfg(target_arch = "arm")]
+    {
+        const __ARM_NR_CACHEFLUSH: libc::c_long = 0x0f0002;
+        libc::syscall(__ARM_NR_CACHEFLUSH, rx_ptr, rx_ptr.byte_add(size), 0);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        use core::arch::asm;
+
+        let ctr_el0: usize;
+        asm!("mrs {}, ctr_el0", out(reg) ctr_el0, options(nomem, nostack, preserves_flags));
+        let dcache_line = 4 << ((ctr_el0 >> 16) & 0xF);
+        let icache_line = 4 << (ctr_el0 & 0xF);
+
+        let (start, end) = (rx_ptr as usize, rx_ptr as usize + size);
+        for addr in (start & !(dcache_line - 1)..end).step_by(dcache_line) {
+            asm!("dc cvau, {}", in(reg) addr, options(nostack, preserves_flags));
+        }
+        asm!("dsb ish", options(nostack, preserves_flags));
+        for addr in (start & !(icache_line - 1)..end).step_by(icache_line) {
+            asm!("ic ivau, {}", in(reg) addr, options(nostack, preserves_flags));
+        }
+        asm!("dsb ish", "isb", options(nostack, preserves_flags));
+    }
+}
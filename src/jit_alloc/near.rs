@@ -0,0 +1,318 @@
+//! Executable memory mapped close to a given address, backing [`JitAlloc::alloc_near`] for the
+//! default [`GlobalJitAlloc`](super::GlobalJitAlloc).
+//!
+//! `jit-allocator2` has no way to place its blocks, so near allocations are served from separate
+//! chunks which are mapped at addresses probed outwards from the hint. These are single mappings,
+//! so allocations are made in whole pages which are switched between Read-Write and Read-Execute
+//! by [`protect`] instead. Memory is never writable and executable at the same time.
+//!
+//! [`JitAlloc::alloc_near`]: super::JitAlloc::alloc_near
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::{granules::Granules, JitAllocError, ProtectJitAccess};
+
+/// Size and alignment of the chunks of near memory. This is the allocation granularity on Windows.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Allocations are made in multiples of this many bytes, so that each page belongs to a single
+/// allocation and can be protected independently of the others.
+const PAGE_SIZE: usize = 4096;
+
+/// Maximum number of addresses probed when mapping a new chunk.
+const MAX_PROBES: usize = 256;
+
+#[derive(Debug)]
+struct Chunk {
+    base: usize,
+    size: usize,
+    pages: Granules,
+}
+
+impl Chunk {
+    fn contains(&self, ptr: usize) -> bool {
+        (self.base..self.base + self.size).contains(&ptr)
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        // SAFETY: The chunk was mapped with this base and size by `map_at`
+        unsafe { os::unmap(self.base, self.size) };
+    }
+}
+
+#[derive(Debug)]
+struct NearState {
+    chunks: Vec<Chunk>,
+    /// Maps the address of live allocations to their size in pages.
+    allocs: BTreeMap<usize, usize>,
+}
+
+#[cfg(not(feature = "std"))]
+static NEAR_STATE: spin::Mutex<NearState> = spin::Mutex::new(NearState {
+    chunks: Vec::new(),
+    allocs: BTreeMap::new(),
+});
+#[cfg(feature = "std")]
+static NEAR_STATE: std::sync::Mutex<NearState> = std::sync::Mutex::new(NearState {
+    chunks: Vec::new(),
+    allocs: BTreeMap::new(),
+});
+
+fn lock() -> impl core::ops::DerefMut<Target = NearState> {
+    #[cfg(not(feature = "std"))]
+    return NEAR_STATE.lock();
+    #[cfg(feature = "std")]
+    return NEAR_STATE.lock().unwrap();
+}
+
+/// Whether the `len` bytes starting at `start` are all within `range` bytes of `hint`.
+fn within(hint: usize, range: usize, start: usize, len: usize) -> bool {
+    start.abs_diff(hint) <= range
+        && start.checked_add(len).is_some_and(|end| end.abs_diff(hint) <= range)
+}
+
+/// Allocates `size` bytes of Read-Write memory, all of which is within `range` bytes of `hint`.
+///
+/// The memory must be made executable with [`protect`] before running code from it.
+pub fn alloc_near(hint: usize, range: usize, size: usize) -> Result<*mut u8, JitAllocError> {
+    let size = size
+        .max(1)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(JitAllocError::InvalidSize)?;
+    let count = size / PAGE_SIZE;
+
+    let mut state = lock();
+    let found = state.chunks.iter_mut().find_map(|chunk| {
+        let page = chunk.pages.alloc(count)?;
+        let ptr = chunk.base + page * PAGE_SIZE;
+        if within(hint, range, ptr, size) {
+            return Some(ptr);
+        }
+        chunk.pages.free(page, count);
+        None
+    });
+    let ptr = match found {
+        Some(ptr) => ptr,
+        None => {
+            // Probe without holding the lock, as it can take many system calls
+            drop(state);
+            let mut chunk = map_near(hint, range, size.next_multiple_of(CHUNK_SIZE))?;
+            // Cannot fail as the chunk is empty and large enough
+            let ptr = chunk.base + chunk.pages.alloc(count).unwrap() * PAGE_SIZE;
+
+            state = lock();
+            state.chunks.push(chunk);
+            ptr
+        }
+    };
+    state.allocs.insert(ptr, count);
+    drop(state);
+
+    // Chunks are mapped Read-Execute and released pages are left that way
+    // SAFETY: The pages were just allocated, so no thunk is running from them
+    if let Err(e) = unsafe { os::protect(ptr, size, ProtectJitAccess::ReadWrite) } {
+        release(ptr as *const u8);
+        return Err(e);
+    }
+    Ok(ptr as *mut u8)
+}
+
+/// Changes the protection of the pages overlapping the `size` bytes at `ptr`, which must be
+/// within an allocation made by [`alloc_near`]. Returns `false` if `ptr` is not within such an
+/// allocation.
+///
+/// # Safety
+/// If `access` is [`ProtectJitAccess::ReadWrite`], no code may be running from these pages.
+pub unsafe fn protect(ptr: *const u8, size: usize, access: ProtectJitAccess) -> bool {
+    let ptr = ptr as usize;
+    let state = lock();
+
+    let Some((&start, &count)) = state.allocs.range(..=ptr).next_back()
+    else {
+        return false;
+    };
+    let end = start + count * PAGE_SIZE;
+    if ptr >= end {
+        return false;
+    }
+
+    let page_start = ptr & !(PAGE_SIZE - 1);
+    let page_end = ptr.saturating_add(size.max(1)).next_multiple_of(PAGE_SIZE).min(end);
+    // The trait gives no way to report the error. Failing to make the memory writable is caught
+    // by `alloc_near`, and failing to make it executable faults when the thunk is called
+    let _ = os::protect(page_start, page_end - page_start, access);
+    true
+}
+
+/// Releases an allocation made by [`alloc_near`]. Returns `false` if `ptr` is not such an
+/// allocation.
+pub fn release(ptr: *const u8) -> bool {
+    let ptr = ptr as usize;
+    let mut state = lock();
+
+    let Some(count) = state.allocs.remove(&ptr)
+    else {
+        return false;
+    };
+    // Cannot fail as the allocation was made from one of the chunks
+    let chunk_index = state.chunks.iter().position(|c| c.contains(ptr)).unwrap();
+
+    let chunk = &mut state.chunks[chunk_index];
+    chunk.pages.free((ptr - chunk.base) / PAGE_SIZE, count);
+    if chunk.pages.is_empty() {
+        state.chunks.swap_remove(chunk_index);
+    }
+    true
+}
+
+/// Maps a chunk of `size` bytes within `range` of `hint`, probing candidate addresses alternately
+/// below and above it.
+fn map_near(hint: usize, range: usize, size: usize) -> Result<Chunk, JitAllocError> {
+    let step = (range / (MAX_PROBES / 2)).next_multiple_of(CHUNK_SIZE).max(CHUNK_SIZE);
+    let origin = hint & !(CHUNK_SIZE - 1);
+
+    for i in 0..MAX_PROBES {
+        let distance = (i / 2 + 1) * step;
+        let candidate = match i % 2 {
+            0 => origin.checked_sub(distance),
+            _ => origin.checked_add(distance),
+        };
+        let Some(candidate) = candidate.filter(|&c| c != 0 && within(hint, range, c, size))
+        else {
+            continue;
+        };
+
+        // SAFETY: Mapping at a hint never replaces existing mappings
+        if unsafe { os::map_at(candidate, size)? } {
+            return Ok(Chunk {
+                base: candidate,
+                size,
+                pages: Granules::with_len(size / PAGE_SIZE),
+            });
+        }
+    }
+    Err(JitAllocError::OutOfMemory)
+}
+
+#[cfg(unix)]
+mod os {
+    use core::ffi::c_void;
+
+    use super::{JitAllocError, ProtectJitAccess};
+
+    /// Converts the error of the system call which just failed.
+    fn last_error() -> JitAllocError {
+        #[cfg(feature = "std")]
+        return match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::EACCES | libc::EPERM) => JitAllocError::ProtectionFailed,
+            Some(libc::ENOMEM) => JitAllocError::OutOfMemory,
+            Some(code) => JitAllocError::Os(code),
+            None => JitAllocError::Other,
+        };
+        #[cfg(not(feature = "std"))]
+        JitAllocError::Other
+    }
+
+    /// Tries to map `size` bytes of Read-Execute memory at `addr`. Returns `Ok(false)` if the
+    /// address is not available.
+    ///
+    /// Mapping the memory executable right away fails early on systems which forbid executable
+    /// anonymous memory.
+    pub unsafe fn map_at(addr: usize, size: usize) -> Result<bool, JitAllocError> {
+        // As `addr` is only a hint, the kernel picks another address instead of failing when it is
+        // not available, so any error is fatal
+        let ptr = libc::mmap(
+            addr as *mut c_void,
+            size,
+            libc::PROT_READ | libc::PROT_EXEC,
+            libc::MAP_PRIVATE | libc::MAP_ANON,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(last_error());
+        }
+        // The address is only a hint, so the kernel may have placed the mapping elsewhere
+        if ptr as usize != addr {
+            libc::munmap(ptr, size);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    pub unsafe fn protect(
+        addr: usize,
+        size: usize,
+        access: ProtectJitAccess,
+    ) -> Result<(), JitAllocError> {
+        let prot = match access {
+            ProtectJitAccess::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+            ProtectJitAccess::ReadExecute => libc::PROT_READ | libc::PROT_EXEC,
+        };
+        match libc::mprotect(addr as *mut c_void, size, prot) {
+            0 => Ok(()),
+            _ => Err(last_error()),
+        }
+    }
+
+    pub unsafe fn unmap(addr: usize, size: usize) {
+        libc::munmap(addr as *mut c_void, size);
+    }
+}
+
+#[cfg(windows)]
+mod os {
+    use winapi::{
+        shared::winerror::{ERROR_ACCESS_DENIED, ERROR_INVALID_ADDRESS},
+        um::{
+            errhandlingapi::GetLastError,
+            memoryapi::{VirtualAlloc, VirtualFree, VirtualProtect},
+            winnt::{MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READ, PAGE_READWRITE},
+        },
+    };
+
+    use super::{JitAllocError, ProtectJitAccess};
+
+    /// Tries to map `size` bytes of Read-Execute memory at `addr`. Returns `Ok(false)` if the
+    /// address is not available.
+    ///
+    /// Mapping the memory executable right away fails early when dynamic code is prohibited for
+    /// the process.
+    pub unsafe fn map_at(addr: usize, size: usize) -> Result<bool, JitAllocError> {
+        let ptr = VirtualAlloc(addr as _, size, MEM_RESERVE | MEM_COMMIT, PAGE_EXECUTE_READ);
+        if ptr.is_null() {
+            return match GetLastError() {
+                // The address range is already in use
+                ERROR_INVALID_ADDRESS => Ok(false),
+                ERROR_ACCESS_DENIED => Err(JitAllocError::ProtectionFailed),
+                code => Err(JitAllocError::Os(code as i32)),
+            };
+        }
+        Ok(true)
+    }
+
+    pub unsafe fn protect(
+        addr: usize,
+        size: usize,
+        access: ProtectJitAccess,
+    ) -> Result<(), JitAllocError> {
+        let protect = match access {
+            ProtectJitAccess::ReadWrite => PAGE_READWRITE,
+            ProtectJitAccess::ReadExecute => PAGE_EXECUTE_READ,
+        };
+        let mut old = 0;
+        if VirtualProtect(addr as _, size, protect, &mut old) == 0 {
+            return Err(match GetLastError() {
+                ERROR_ACCESS_DENIED => JitAllocError::ProtectionFailed,
+                code => JitAllocError::Os(code as i32),
+            });
+        }
+        Ok(())
+    }
+
+    pub unsafe fn unmap(addr: usize, _size: usize) {
+        VirtualFree(addr as _, 0, MEM_RELEASE);
+    }
+}
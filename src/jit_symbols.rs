@@ -0,0 +1,52 @@
+//! Registration of thunks with profilers and debuggers under a symbol name, for the `perf_map`
+//! and `gdb_jit` features.
+
+use alloc::string::String;
+
+#[cfg(feature = "gdb_jit")]
+mod gdb_jit;
+#[cfg(feature = "perf_map")]
+mod perf_map;
+
+/// Returns the symbol name of a thunk invoking a closure of type `T` through the bare function
+/// type `B`.
+pub fn thunk_name<B, T: ?Sized>() -> String {
+    alloc::format!(
+        "closure_ffi::thunk<{}, {}>",
+        core::any::type_name::<T>(),
+        core::any::type_name::<B>()
+    )
+}
+
+/// Returns the symbol name of a [`DynBareFn`](crate::dyn_bare_fn::DynBareFn) entry stub invoking a
+/// closure of type `T`.
+#[cfg(feature = "dyn_bare_fn")]
+pub fn dyn_stub_name<T: ?Sized>() -> String {
+    alloc::format!("closure_ffi::dyn_bare_fn<{}>", core::any::type_name::<T>())
+}
+
+/// Returns the symbol name of the trampoline of a detour hooking the function at `target`.
+#[cfg(feature = "detour")]
+pub fn trampoline_name(target: usize) -> String {
+    alloc::format!("closure_ffi::trampoline<{target:#x}>")
+}
+
+/// The symbol of a thunk. Registrations which can be undone are undone on drop.
+#[derive(Debug)]
+pub struct ThunkSymbol {
+    #[cfg(feature = "gdb_jit")]
+    _gdb_jit: gdb_jit::Registration,
+}
+
+impl ThunkSymbol {
+    /// Registers `name` as the symbol of the `size` bytes of code at `addr`.
+    pub fn register(addr: usize, size: usize, name: &str) -> Self {
+        #[cfg(feature = "perf_map")]
+        perf_map::add_entry(addr, size, name);
+
+        Self {
+            #[cfg(feature = "gdb_jit")]
+            _gdb_jit: gdb_jit::Registration::new(addr, size, name),
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Registration of thunks with debuggers through the GDB JIT interface, by describing each of them
+//! in an in-memory ELF object.
+//!
+//! See <https://sourceware.org/gdb/current/onlinedocs/gdb.html/JIT-Interface.html>. LLDB supports
+//! the same interface.
+
+use alloc::boxed::Box;
+use core::ptr::{addr_of_mut, null_mut};
+use std::sync::{Mutex, MutexGuard};
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+// The interface symbols are defined as weak, so that the definitions of another JIT in the process
+// (or of another version of this crate) take precedence instead of failing to link. Rust has no
+// stable weak linkage, so they are defined in assembly.
+macro_rules! define_jit_interface {
+    ($ret:literal) => {
+        core::arch::global_asm!(
+            ".pushsection .text.__jit_debug_register_code,\"ax\",%progbits",
+            ".weak __jit_debug_register_code",
+            ".type __jit_debug_register_code,%function",
+            "__jit_debug_register_code:",
+            $ret,
+            ".size __jit_debug_register_code, . - __jit_debug_register_code",
+            ".popsection",
+            ".pushsection .data.__jit_debug_descriptor,\"aw\",%progbits",
+            ".weak __jit_debug_descriptor",
+            ".type __jit_debug_descriptor,%object",
+            ".balign 8",
+            "__jit_debug_descriptor:",
+            // version = 1, action_flag = JIT_NOACTION, relevant_entry = first_entry = null
+            ".4byte 1",
+            ".4byte 0",
+            ".dc.a 0",
+            ".dc.a 0",
+            ".size __jit_debug_descriptor, . - __jit_debug_descriptor",
+            ".popsection",
+        );
+    };
+}
+
+#[cfg(not(target_arch = "arm"))]
+define_jit_interface!("ret");
+#[cfg(target_arch = "arm")]
+define_jit_interface!("bx lr");
+
+extern "C" {
+    /// Debuggers set a breakpoint on this function to be notified of changes to
+    /// [`__jit_debug_descriptor`].
+    fn __jit_debug_register_code();
+
+    /// The list of registered objects, read by debuggers.
+    static mut __jit_debug_descriptor: JitDescriptor;
+}
+
+/// Serializes modifications of [`__jit_debug_descriptor`].
+static DESCRIPTOR_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    DESCRIPTOR_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Notifies the debugger that `entry` was registered or unregistered.
+///
+/// # Safety
+/// The descriptor lock must be held.
+unsafe fn notify(action: u32, entry: *mut JitCodeEntry) {
+    let descriptor = addr_of_mut!(__jit_debug_descriptor);
+    (*descriptor).action_flag = action;
+    (*descriptor).relevant_entry = entry;
+    __jit_debug_register_code();
+    (*descriptor).action_flag = JIT_NOACTION;
+    (*descriptor).relevant_entry = null_mut();
+}
+
+/// An ELF object registered with the debugger, which is unregistered on drop.
+#[derive(Debug)]
+pub struct Registration {
+    entry: *mut JitCodeEntry,
+    _symfile: Box<[u8]>,
+}
+
+impl Registration {
+    /// Registers an ELF object defining `name` as a function spanning the `size` bytes of code at
+    /// `addr`.
+    pub fn new(addr: usize, size: usize, name: &str) -> Self {
+        let symfile = elf::build(addr, size, name).into_boxed_slice();
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: null_mut(),
+            prev_entry: null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        }));
+
+        let _guard = lock();
+        // SAFETY: The lock is held, and all entries of the list are live
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            let first = (*descriptor).first_entry;
+            (*entry).next_entry = first;
+            if !first.is_null() {
+                (*first).prev_entry = entry;
+            }
+            (*descriptor).first_entry = entry;
+            notify(JIT_REGISTER_FN, entry);
+        }
+
+        Self {
+            entry,
+            _symfile: symfile,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _guard = lock();
+        // SAFETY: The lock is held, and all entries of the list are live
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            let JitCodeEntry {
+                next_entry: next,
+                prev_entry: prev,
+                ..
+            } = *self.entry;
+            if prev.is_null() {
+                (*descriptor).first_entry = next;
+            }
+            else {
+                (*prev).next_entry = next;
+            }
+            if !next.is_null() {
+                (*next).prev_entry = prev;
+            }
+            notify(JIT_UNREGISTER_FN, self.entry);
+            drop(Box::from_raw(self.entry));
+        }
+    }
+}
+
+/// A minimal ELF relocatable object file for the native target, holding a single function symbol.
+mod elf {
+    use alloc::vec::Vec;
+
+    const IS_64: bool = size_of::<usize>() == 8;
+    const EHDR_SIZE: usize = if IS_64 { 64 } else { 52 };
+    const SHDR_SIZE: usize = if IS_64 { 64 } else { 40 };
+    const SYM_SIZE: usize = if IS_64 { 24 } else { 16 };
+    const WORD: usize = size_of::<usize>();
+
+    #[cfg(target_arch = "x86_64")]
+    const MACHINE: u16 = 62;
+    #[cfg(target_arch = "x86")]
+    const MACHINE: u16 = 3;
+    #[cfg(target_arch = "aarch64")]
+    const MACHINE: u16 = 183;
+    #[cfg(target_arch = "arm")]
+    const MACHINE: u16 = 40;
+
+    /// EABI version 5 on ARM.
+    const FLAGS: u32 = if cfg!(target_arch = "arm") { 0x0500_0000 } else { 0 };
+
+    const SHT_SYMTAB: u32 = 2;
+    const SHT_STRTAB: u32 = 3;
+    const SHT_NOBITS: u32 = 8;
+    const SHF_ALLOC: usize = 0x2;
+    const SHF_EXECINSTR: usize = 0x4;
+    const STB_GLOBAL_STT_FUNC: u8 = 0x12;
+
+    /// Section names, and the offset of each in the section name table.
+    const SHSTRTAB: &[u8] = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+    const TEXT_NAME: u32 = 1;
+    const SYMTAB_NAME: u32 = 7;
+    const STRTAB_NAME: u32 = 15;
+    const SHSTRTAB_NAME: u32 = 23;
+
+    const TEXT_INDEX: u16 = 1;
+    const STRTAB_INDEX: u32 = 3;
+    const SHSTRTAB_INDEX: u16 = 4;
+
+    #[derive(Default)]
+    struct Writer(Vec<u8>);
+
+    impl Writer {
+        fn bytes(&mut self, bytes: &[u8]) {
+            self.0.extend_from_slice(bytes);
+        }
+
+        fn u8(&mut self, value: u8) {
+            self.0.push(value);
+        }
+
+        fn u16(&mut self, value: u16) {
+            self.bytes(&value.to_ne_bytes());
+        }
+
+        fn u32(&mut self, value: u32) {
+            self.bytes(&value.to_ne_bytes());
+        }
+
+        /// Writes an address, offset or size field, whose size depends on the ELF class.
+        fn word(&mut self, value: usize) {
+            self.bytes(&value.to_ne_bytes());
+        }
+
+        fn align(&mut self, align: usize) {
+            self.0.resize(self.0.len().next_multiple_of(align), 0);
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn section_header(
+            &mut self,
+            name: u32,
+            kind: u32,
+            flags: usize,
+            addr: usize,
+            (offset, size): (usize, usize),
+            link: u32,
+            info: u32,
+            entsize: usize,
+        ) {
+            self.u32(name);
+            self.u32(kind);
+            self.word(flags);
+            self.word(addr);
+            self.word(offset);
+            self.word(size);
+            self.u32(link);
+            self.u32(info);
+            self.word(match kind {
+                SHT_NOBITS => 16,
+                SHT_SYMTAB => WORD,
+                _ => 1,
+            });
+            self.word(entsize);
+        }
+    }
+
+    /// Builds an object whose `.text` section spans the `size` bytes of code at `addr`, and
+    /// defines `name` as a function covering all of it. The code itself is not part of the
+    /// object, as the debugger can read it from memory.
+    pub fn build(addr: usize, size: usize, name: &str) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.0.resize(EHDR_SIZE, 0);
+
+        // Symbol values are relative to their section in relocatable objects. Thumb functions
+        // have the lowest bit set
+        let value = if cfg!(thumb_mode) { 1 } else { 0 };
+
+        w.align(WORD);
+        let symtab = w.0.len();
+        w.0.resize(symtab + SYM_SIZE, 0);
+        w.u32(1);
+        if IS_64 {
+            w.u8(STB_GLOBAL_STT_FUNC);
+            w.u8(0);
+            w.u16(TEXT_INDEX);
+            w.word(value);
+            w.word(size);
+        }
+        else {
+            w.word(value);
+            w.word(size);
+            w.u8(STB_GLOBAL_STT_FUNC);
+            w.u8(0);
+            w.u16(TEXT_INDEX);
+        }
+        let symtab = (symtab, w.0.len() - symtab);
+
+        let strtab = w.0.len();
+        w.u8(0);
+        w.bytes(name.as_bytes());
+        w.u8(0);
+        let strtab = (strtab, w.0.len() - strtab);
+
+        let shstrtab = w.0.len();
+        w.bytes(SHSTRTAB);
+        let shstrtab = (shstrtab, SHSTRTAB.len());
+
+        w.align(WORD);
+        let shoff = w.0.len();
+        w.0.resize(shoff + SHDR_SIZE, 0);
+        let text = (EHDR_SIZE, size);
+        w.section_header(
+            TEXT_NAME,
+            SHT_NOBITS,
+            SHF_ALLOC | SHF_EXECINSTR,
+            addr,
+            text,
+            0,
+            0,
+            0,
+        );
+        // The first non-local symbol is the function
+        w.section_header(
+            SYMTAB_NAME,
+            SHT_SYMTAB,
+            0,
+            0,
+            symtab,
+            STRTAB_INDEX,
+            1,
+            SYM_SIZE,
+        );
+        w.section_header(STRTAB_NAME, SHT_STRTAB, 0, 0, strtab, 0, 0, 0);
+        w.section_header(SHSTRTAB_NAME, SHT_STRTAB, 0, 0, shstrtab, 0, 0, 0);
+
+        let mut header = Writer::default();
+        header.bytes(b"\x7fELF");
+        header.u8(if IS_64 { 2 } else { 1 });
+        header.u8(if cfg!(target_endian = "little") { 1 } else { 2 });
+        header.u8(1); // EV_CURRENT
+        header.0.resize(16, 0);
+        header.u16(1); // ET_REL
+        header.u16(MACHINE);
+        header.u32(1); // EV_CURRENT
+        header.word(0); // e_entry
+        header.word(0); // e_phoff
+        header.word(shoff);
+        header.u32(FLAGS);
+        header.u16(EHDR_SIZE as u16);
+        header.u16(0); // e_phentsize
+        header.u16(0); // e_phnum
+        header.u16(SHDR_SIZE as u16);
+        header.u16(SHSTRTAB_INDEX + 1);
+        header.u16(SHSTRTAB_INDEX);
+        w.0[..EHDR_SIZE].copy_from_slice(&header.0);
+
+        w.0
+    }
+}
@@ -0,0 +1,32 @@
+//! Entries of the `/tmp/perf-<pid>.map` file, which `perf` and other profilers read to symbolize
+//! JIT code.
+
+use std::{fs::File, io::Write, string::String, sync::Mutex};
+
+/// The perf map of the process, along with the pid it was opened for.
+static PERF_MAP: Mutex<Option<(u32, File)>> = Mutex::new(None);
+
+/// Appends an entry for the `size` bytes of code at `addr` to the perf map of the process.
+///
+/// Errors are ignored, as symbols are only a debugging aid. The format has no way to remove
+/// entries, so they outlive the thunk.
+pub fn add_entry(addr: usize, size: usize, name: &str) {
+    let pid = std::process::id();
+    let mut perf_map = PERF_MAP.lock().unwrap_or_else(|e| e.into_inner());
+
+    // The map is per process, so a forked child must not append to the one of its parent
+    if !matches!(&*perf_map, Some((map_pid, _)) if *map_pid == pid) {
+        *perf_map = File::options()
+            .create(true)
+            .append(true)
+            .open(std::format!("/tmp/perf-{pid}.map"))
+            .ok()
+            .map(|file| (pid, file));
+    }
+
+    if let Some((_, file)) = perf_map.as_mut() {
+        // Written in one go so that the line is not interleaved with other writers
+        let line: String = std::format!("{addr:x} {size:x} {name}\n");
+        let _ = file.write_all(line.as_bytes());
+    }
+}
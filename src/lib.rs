@@ -19,24 +19,49 @@ mod safe_jit;
 #[doc(hidden)]
 pub mod arch;
 
+pub mod async_callback;
 pub mod bare_closure;
 pub mod cc;
+pub mod com;
+#[cfg(feature = "detour")]
+#[cfg_attr(docsrs, doc(cfg(feature = "detour")))]
+pub mod detour;
+#[cfg(feature = "dyn_bare_fn")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dyn_bare_fn")))]
+pub mod dyn_bare_fn;
+pub mod error;
+pub mod ffi_interface;
 pub mod jit_alloc;
+#[cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+mod jit_symbols;
+pub mod retargetable;
 pub mod thunk_factory;
+pub mod thunk_info;
 pub mod traits;
+#[cfg(feature = "unwind_info")]
+mod unwind_info;
 
 /// Common imports required to use `closure-ffi`.
 pub mod prelude {
     #[doc(inline)]
     pub use super::bare_closure::{
         BareFn, BareFnAny, BareFnMut, BareFnMutAny, BareFnMutSync, BareFnOnce, BareFnOnceAny,
-        BareFnOnceSync, BareFnSync, UntypedBareFn, UntypedBareFnMut, UntypedBareFnOnce,
+        BareFnOnceSync, BareFnSync, SharedBareFn, SharedBareFnAny, SharedBareFnSync, UntypedBareFn,
+        UntypedBareFnMut, UntypedBareFnOnce,
     };
     #[doc(inline)]
     pub use super::cc;
     #[doc(inline)]
+    pub use super::error::ThunkError;
+    #[doc(inline)]
     pub use super::jit_alloc::{JitAlloc, JitAllocError};
 }
 
+#[cfg(feature = "proc_macros")]
+#[cfg_attr(docsrs, doc(cfg(feature = "proc_macros")))]
+pub use closure_ffi_proc_macros::ffi_interface;
 #[doc(inline)]
 pub use prelude::*;
+#[cfg(feature = "safe_jit")]
+#[cfg_attr(docsrs, doc(cfg(feature = "safe_jit")))]
+pub use safe_jit::{relocate, InstructionBoundary, RelocError, Relocation};
@@ -0,0 +1,364 @@
+//! Bare closures whose closure can be replaced while their bare function pointer stays the same.
+//!
+//! Once the bare function pointer of a bare closure has been handed to foreign code, its behavior
+//! can only be changed by registering a new one, which some APIs do not support. A
+//! [`RetargetableBareFnAny`] instead exposes a bare function which stays valid for its whole
+//! lifetime, and forwards calls to a closure that can be replaced at any time:
+//!
+//! ```
+//! # #[cfg(feature = "default_jit_alloc")] {
+//! use closure_ffi::retargetable::RetargetableBareFn;
+//!
+//! let mut bare_closure = RetargetableBareFn::new_c(|x: u32| x + 1);
+//! let bare = bare_closure.bare();
+//! assert_eq!(unsafe { bare(1) }, 2);
+//!
+//! bare_closure.replace(|x: u32| x * 10);
+//! assert_eq!(unsafe { bare(1) }, 10);
+//! # }
+//! ```
+//!
+//! Each closure is wrapped in its own [`BareFnAny`], and the stable bare function atomically loads
+//! the current one before calling it. Replaced closures may still be running on other threads, so
+//! they are only dropped once no call is in progress.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "global_jit_alloc")]
+use crate::jit_alloc::GlobalJitAlloc;
+#[allow(unused_imports)]
+use crate::{
+    arch::AllocatedThunk,
+    bare_closure::BareFnAny,
+    cc,
+    error::ThunkError,
+    jit_alloc::JitAlloc,
+    traits::{Any, FnPtr, FnThunk, ToBoxedDyn},
+};
+
+/// State shared between a [`RetargetableBareFnAny`] and its stable bare function.
+struct Target {
+    /// Bare function of the current closure.
+    current: AtomicPtr<()>,
+    /// Number of calls through the stable bare function in progress.
+    in_flight: AtomicUsize,
+}
+
+/// JITs `thunk`, moving it into the executable memory after its code.
+///
+/// # Safety
+/// `T` must not be a ZST.
+#[inline(always)]
+unsafe fn alloc_inline<B: FnPtr, T: FnThunk<B>, A: JitAlloc>(
+    thunk: T,
+    jit_alloc: A,
+) -> Result<AllocatedThunk<A>, ThunkError> {
+    Ok(AllocatedThunk::new_inline(T::THUNK_TEMPLATE, thunk, jit_alloc)?.with_symbol::<B, T>())
+}
+
+/// Decrements the in-flight call count when a call returns or unwinds.
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "global_jit_alloc")]
+#[cfg_attr(docsrs, doc(cfg(all())))]
+/// Wrapper around a [`Fn`] closure which exposes a bare function thunk that can invoke it without
+/// additional arguments, and whose closure can be replaced without changing the thunk.
+///
+/// See the [module documentation](self) for more information.
+///
+/// # Note
+/// This is a generic implementation which allows customizing the closure's
+/// type erased storage, which allows enforcing trait bounds like `Send` and `Sync` when
+/// needed. However, this plays poorly with type inference. Consider using the
+/// [`RetargetableBareFn`] and [`RetargetableBareFnSync`] type aliases for the common cases of
+/// [`S = dyn Any + 'a`](Any) (no thread safety constraints) and
+/// [`S = dyn Send + Sync + 'a`](Sync) (minimum required to safely store/call the closure from other
+/// threads), respectively.
+///
+/// # Type parameters
+/// - `B`: The bare function pointer to expose the closure as.
+/// - `S`: The dynamically-sized type to use to type-erase the closures. Use this to enforce
+///   lifetime bounds and marker traits which the closures must satisfy, e.g. `S = dyn Send + 'a`.
+///   Without the `unstable` feature, this is limited to [`dyn Any`](Any) and combinations of
+///   [`Send`] and [`Sync`] marker types.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free executable memory.
+pub struct RetargetableBareFnAny<B: FnPtr, S: ?Sized, A: JitAlloc = GlobalJitAlloc> {
+    // Fields are dropped in declaration order, so the stable thunk is freed before the state it
+    // points to and the closures it calls
+    stable: AllocatedThunk<A>,
+    target: Box<Target>,
+    current: BareFnAny<B, S, A>,
+    retired: Vec<BareFnAny<B, S, A>>,
+    jit_alloc: A,
+    phantom: PhantomData<B>,
+}
+
+#[cfg(not(feature = "global_jit_alloc"))]
+/// Wrapper around a [`Fn`] closure which exposes a bare function thunk that can invoke it without
+/// additional arguments, and whose closure can be replaced without changing the thunk.
+///
+/// See the [module documentation](self) for more information.
+///
+/// # Note
+/// This is a generic implementation which allows customizing the closure's
+/// type erased storage, which allows enforcing trait bounds like `Send` and `Sync` when
+/// needed. However, this plays poorly with type inference. Consider using the
+/// [`RetargetableBareFn`] and [`RetargetableBareFnSync`] type aliases for the common cases of
+/// [`S = dyn Any + 'a`](Any) (no thread safety constraints) and
+/// [`S = dyn Send + Sync + 'a`](Sync) (minimum required to safely store/call the closure from other
+/// threads), respectively.
+///
+/// # Type parameters
+/// - `B`: The bare function pointer to expose the closure as.
+/// - `S`: The dynamically-sized type to use to type-erase the closures. Use this to enforce
+///   lifetime bounds and marker traits which the closures must satisfy, e.g. `S = dyn Send + 'a`.
+///   Without the `unstable` feature, this is limited to [`dyn Any`](Any) and combinations of
+///   [`Send`] and [`Sync`] marker types.
+/// - `A`: The [`JitAlloc`] implementation used to allocate and free executable memory.
+pub struct RetargetableBareFnAny<B: FnPtr, S: ?Sized, A: JitAlloc> {
+    stable: AllocatedThunk<A>,
+    target: Box<Target>,
+    current: BareFnAny<B, S, A>,
+    retired: Vec<BareFnAny<B, S, A>>,
+    jit_alloc: A,
+    phantom: PhantomData<B>,
+}
+
+// SAFETY: The stable thunk only accesses the atomic target state, and the closures and allocator
+// can be moved to other threads
+unsafe impl<B: FnPtr, S: ?Sized + Send, A: JitAlloc + Send> Send
+    for RetargetableBareFnAny<B, S, A>
+{
+}
+// SAFETY: No method taking `&self` accesses the closures or the allocator
+unsafe impl<B: FnPtr, S: ?Sized + Sync, A: JitAlloc> Sync for RetargetableBareFnAny<B, S, A> {}
+
+#[cfg(feature = "global_jit_alloc")]
+impl<B: FnPtr, S: ?Sized> RetargetableBareFnAny<B, S, GlobalJitAlloc> {
+    /// Wraps `fun`, producing a bare function of signature `B` whose closure can be replaced.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new<F>(fun: F) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        Self::new_in(fun, Default::default())
+    }
+
+    /// Wraps `fun`, producing a bare function with calling convention `cconv` whose closure can
+    /// be replaced.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn with_cc<CC, F>(cconv: CC, fun: F) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (CC, F): FnThunk<B>,
+    {
+        Self::with_cc_in(cconv, fun, Default::default())
+    }
+
+    /// Wraps `fun`, producing a bare function with the C calling convention whose closure can be
+    /// replaced.
+    ///
+    /// The W^X memory required is allocated using the global JIT allocator.
+    #[inline]
+    pub fn new_c<F>(fun: F) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (cc::C, F): FnThunk<B>,
+    {
+        Self::with_cc(cc::C, fun)
+    }
+}
+
+impl<B: FnPtr, S: ?Sized, A: JitAlloc + Clone> RetargetableBareFnAny<B, S, A> {
+    /// Wraps `fun`, producing a bare function with calling convention `cconv` whose closure can
+    /// be replaced.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks. A clone of it is
+    /// kept to allocate the thunks of the closures replacing `fun`.
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_with_cc_in<CC, F>(cconv: CC, fun: F, jit_alloc: A) -> Result<Self, ThunkError>
+    where
+        F: ToBoxedDyn<S>,
+        (CC, F): FnThunk<B>,
+    {
+        let current = BareFnAny::try_with_cc_in(cconv, fun, jit_alloc.clone())?;
+        let target = Box::new(Target {
+            current: AtomicPtr::new(current.bare().to_ptr().cast_mut()),
+            in_flight: AtomicUsize::new(0),
+        });
+
+        let target_ptr = &raw const *target;
+        let stable_thunk = B::make_thunk(move |args| {
+            // SAFETY: The stable thunk is freed before the target
+            let target = unsafe { &*target_ptr };
+
+            // Announce the call before loading the current closure, so that `replace` cannot miss
+            // it after swapping the closure out
+            target.in_flight.fetch_add(1, Ordering::SeqCst);
+            let _guard = InFlightGuard(&target.in_flight);
+
+            // SAFETY: `current` is always the bare function of a closure which is only dropped
+            // once no call is in progress
+            unsafe {
+                let bare = B::from_ptr(target.current.load(Ordering::SeqCst));
+                bare.call(args)
+            }
+        });
+
+        // The thunk only captures a pointer, so it is stored in the executable memory along with
+        // its code
+        // SAFETY: The thunk template is obtained from the `FnThunk` implementation of the thunk
+        let stable = unsafe { alloc_inline(stable_thunk, jit_alloc.clone())? };
+
+        Ok(Self {
+            stable,
+            target,
+            current,
+            retired: Vec::new(),
+            jit_alloc,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Wraps `fun`, producing a bare function with calling convention `cconv` whose closure can
+    /// be replaced.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks. A clone of it is
+    /// kept to allocate the thunks of the closures replacing `fun`.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_with_cc_in`].
+    #[inline]
+    pub fn with_cc_in<CC, F>(cconv: CC, fun: F, jit_alloc: A) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (CC, F): FnThunk<B>,
+    {
+        Self::try_with_cc_in(cconv, fun, jit_alloc).unwrap()
+    }
+
+    /// Wraps `fun`, producing a bare function of signature `B` whose closure can be replaced.
+    ///
+    /// Uses `jit_alloc` to allocate the W^X memory used to create the thunks. A clone of it is
+    /// kept to allocate the thunks of the closures replacing `fun`.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the provided JIT allocator fails to
+    /// allocate memory. For a non-panicking version, see [`Self::try_with_cc_in`].
+    #[inline]
+    pub fn new_in<F>(fun: F, jit_alloc: A) -> Self
+    where
+        F: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        Self::with_cc_in(B::CC::default(), fun, jit_alloc)
+    }
+
+    /// Replaces the closure invoked by the bare function with `fun`, which is wrapped using the
+    /// calling convention of `B`.
+    ///
+    /// Calls made through the bare function after this returns invoke `fun`. Calls to the
+    /// previous closure which are still in progress on other threads are not interrupted. The
+    /// previous closure is dropped by the first call to this method made while no call is in
+    /// progress, or when `self` is dropped.
+    ///
+    /// # Panics
+    /// If the thunk cannot be created, e.g. because the JIT allocator fails to allocate memory.
+    /// For a non-panicking version, see [`Self::try_replace`].
+    #[inline]
+    pub fn replace<F>(&mut self, fun: F)
+    where
+        F: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        self.try_replace(fun).unwrap()
+    }
+
+    /// Replaces the closure invoked by the bare function with `fun`. See [`Self::replace`].
+    ///
+    /// # Errors
+    /// If the JIT allocator fails to allocate memory, or if the thunk template prologue
+    /// cannot be relocated. See [`ThunkError`].
+    pub fn try_replace<F>(&mut self, fun: F) -> Result<(), ThunkError>
+    where
+        F: ToBoxedDyn<S>,
+        (B::CC, F): FnThunk<B>,
+    {
+        let new = BareFnAny::try_with_cc_in(B::CC::default(), fun, self.jit_alloc.clone())?;
+        self.target.current.store(new.bare().to_ptr().cast_mut(), Ordering::SeqCst);
+        self.retired.push(core::mem::replace(&mut self.current, new));
+
+        // Calls starting after this point load the new closure, so the retired ones are unused
+        // if no call is in progress
+        if self.target.in_flight.load(Ordering::SeqCst) == 0 {
+            self.retired.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<B: FnPtr, S: ?Sized, A: JitAlloc> RetargetableBareFnAny<B, S, A> {
+    /// Return a bare function pointer that invokes the current closure.
+    ///
+    /// The returned pointer stays the same when the closure is replaced.
+    ///
+    /// # Safety
+    /// While this method is safe, the returned function pointer is not. In particular, it
+    /// must not be called when:
+    /// - The lifetime of `self` has expired, or `self` has been dropped.
+    /// - The closure is not `Sync`, if calling from a different thread than the current one.
+    #[inline]
+    pub fn bare(&self) -> B {
+        // SAFETY: The stable thunk has signature B
+        unsafe { B::from_ptr(self.stable.thunk_ptr()) }
+    }
+
+    /// Returns the number of replaced closures which could not be dropped yet because a call was
+    /// in progress when they were replaced.
+    #[inline]
+    pub fn retired_count(&self) -> usize {
+        self.retired.len()
+    }
+}
+
+#[cfg(feature = "global_jit_alloc")]
+#[cfg_attr(docsrs, doc(cfg(all())))]
+/// Type alias for a [`RetargetableBareFnAny`] that imposes no thread safety constraints on the
+/// closures.
+pub type RetargetableBareFn<'a, B, A = GlobalJitAlloc> = RetargetableBareFnAny<B, dyn Any + 'a, A>;
+
+#[cfg(not(feature = "global_jit_alloc"))]
+/// Type alias for a [`RetargetableBareFnAny`] that imposes no thread safety constraints on the
+/// closures.
+pub type RetargetableBareFn<'a, B, A> = RetargetableBareFnAny<B, dyn Any + 'a, A>;
+
+#[cfg(feature = "global_jit_alloc")]
+#[cfg_attr(docsrs, doc(cfg(all())))]
+/// Type alias for a [`RetargetableBareFnAny`] whose closures must be [`Send`] and [`Sync`], so that
+/// they can be called and replaced from other threads.
+pub type RetargetableBareFnSync<'a, B, A = GlobalJitAlloc> =
+    RetargetableBareFnAny<B, dyn Send + Sync + 'a, A>;
+
+#[cfg(not(feature = "global_jit_alloc"))]
+/// Type alias for a [`RetargetableBareFnAny`] whose closures must be [`Send`] and [`Sync`], so that
+/// they can be called and replaced from other threads.
+pub type RetargetableBareFnSync<'a, B, A> = RetargetableBareFnAny<B, dyn Send + Sync + 'a, A>;
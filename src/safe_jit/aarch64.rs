@@ -7,17 +7,27 @@ use capstone::{
 
 use crate::safe_jit::{
     arm_util::{
-        encoding::{Adr, Branch, LdrImm, LdrImmOpc, LdrOfs},
+        encoding_aarch64::{Adr, Branch, BranchLink, LdrImm, LdrImmOpc, LdrOfs},
         has_unsupported_insn_group, CowBuffer,
     },
-    JitError, RelocThunk,
+    InstructionBoundary, JitError, JitFailure, RelocThunk, Relocation,
 };
 
+/// `BR x16`
+const BR_X16: u32 = 0xD61F_0200;
+
+/// `BLR x16`
+const BLR_X16: u32 = 0xD63F_0200;
+
+/// x16 (IP0) may be clobbered across branches according to the AAPCS64, so it is used to hold the
+/// target of relocated branches.
+const IP0: u32 = 16;
+
 pub fn try_reloc_thunk_template<'a>(
     thunk_template: &'a [u8],
     pc: usize,
     magic_offset: usize,
-) -> Result<RelocThunk<'a>, JitError> {
+) -> Result<RelocThunk<'a>, JitFailure> {
     let thunk_template_end = thunk_template.len() + pc;
     let cs = Capstone::new().arm64().mode(ArchMode::Arm).detail(true).build().unwrap();
     let mut disasm_iter = cs.disasm_iter(thunk_template, pc as u64).unwrap();
@@ -52,7 +62,8 @@ pub fn try_reloc_thunk_template<'a>(
             new_magic_offset += 4;
 
             // replace the original instruction with LDR reg, [reg]
-            let ldr64 = LdrOfs::new(ldr.opc(), ldr.reg(), ldr.reg(), 0)?;
+            let ldr64 = LdrOfs::new(ldr.opc(), ldr.reg(), ldr.reg(), 0)
+                .map_err(|e| JitError::from(e).at(offset, 4))?;
             cow_buf.replace(offset, &ldr64.to_raw().to_ne_bytes());
         }
         // ADR/ADRP reg, label
@@ -71,22 +82,23 @@ pub fn try_reloc_thunk_template<'a>(
                 disasm_iter.reset(&thunk_template[target - pc..], target as u64);
             }
             else {
-                return Err(JitError::UnsupportedInstruction);
+                return Err(JitError::UnsupportedInstruction.at(offset, 4));
             }
         }
         else if has_unsupported_insn_group(cs.insn_detail(&instr).unwrap().groups()) {
-            return Err(JitError::UnsupportedInstruction);
+            return Err(JitError::UnsupportedInstruction.at(offset, 4));
         }
     }
 
     if !has_thunk_asm {
-        return Err(JitError::NoThunkAsm);
+        return Err(JitError::NoThunkAsm.into());
     }
 
     // emit the extra LDR instructions using a post-thunk literal pool
     if !extra_ldrs.is_empty() {
         // copy the rest of the thunk template over
         cow_buf.copy_up_to(thunk_template.len());
+        let pool_start = cow_buf.new_bytes().len();
         let new_bytes = cow_buf.new_bytes_mut();
 
         // the contract is that the magic offset is at least pointer-aligned.
@@ -99,16 +111,206 @@ pub fn try_reloc_thunk_template<'a>(
         // referring to them.
         for (instr_offset, reg, addr) in extra_ldrs {
             let pc_offset = new_bytes.len() - instr_offset;
-            let ldr = LdrImm::new(LdrImmOpc::Load64, reg, pc_offset as i32 / 4)?;
+            let ldr = LdrImm::new(LdrImmOpc::Load64, reg, pc_offset as i32 / 4)
+                .map_err(JitError::from)?;
             let ldr_bytes = &ldr.to_raw().to_ne_bytes();
 
             new_bytes.extend_from_slice(&addr.to_ne_bytes());
             new_bytes[instr_offset..instr_offset + 4].copy_from_slice(ldr_bytes);
         }
+        cow_buf.mark_rewritten(pool_start..cow_buf.new_bytes().len());
     }
 
+    let rewritten = cow_buf.take_rewritten();
     Ok(RelocThunk {
         thunk: cow_buf.into_bytes(),
         magic_offset: new_magic_offset,
+        rewritten,
+        rel32_fixups: Vec::new(),
+        far_error: None,
     })
 }
+
+/// Relocates the instructions in the first `len` bytes of `code` from `old_pc` to `new_pc`.
+///
+/// PC-relative loads and address computations, as well as branches and calls out of the range, are
+/// rewritten to load their target from a literal pool placed after the relocated instructions.
+pub fn relocate(
+    code: &[u8],
+    old_pc: usize,
+    new_pc: usize,
+    len: usize,
+) -> Result<Relocation, JitError> {
+    let len = len.next_multiple_of(4);
+    if code.len() < len {
+        return Err(JitError::InvalidInstruction);
+    }
+    let cs = Capstone::new().arm64().mode(ArchMode::Arm).detail(true).build().unwrap();
+
+    let mut new_bytes = Vec::with_capacity(len);
+    let mut boundaries = Vec::with_capacity(len / 4);
+    let mut literals = Vec::new();
+
+    for (offset, bytes) in code[..len].chunks_exact(4).enumerate().map(|(i, b)| (4 * i, b)) {
+        let instr_pc = old_pc + offset;
+        let instr_u32 = u32::from_ne_bytes(bytes.try_into().unwrap());
+
+        boundaries.push(InstructionBoundary {
+            old_offset: offset,
+            new_offset: new_bytes.len(),
+        });
+
+        // LDR/LDRW/LDRSW reg, label => LDR reg, =abs_address; LDR reg, [reg]
+        if let Ok(ldr) = LdrImm::try_from_raw(instr_u32) {
+            literals.push((new_bytes.len(), ldr.reg(), ldr.target_pc(instr_pc)));
+            new_bytes.extend_from_slice(&[0; 4]);
+
+            let ldr64 = LdrOfs::new(ldr.opc(), ldr.reg(), ldr.reg(), 0)?;
+            new_bytes.extend_from_slice(&ldr64.to_raw().to_ne_bytes());
+        }
+        // ADR/ADRP reg, label => LDR reg, =abs_address
+        else if let Ok(adr) = Adr::try_from_raw(instr_u32) {
+            literals.push((new_bytes.len(), adr.reg(), adr.target_pc(instr_pc)));
+            new_bytes.extend_from_slice(&[0; 4]);
+        }
+        // B/BL label => LDR x16, =label; BR/BLR x16
+        else if let Ok((target, branch_reg)) = Branch::try_from_raw(instr_u32)
+            .map(|b| (b.target_pc(instr_pc), BR_X16))
+            .or_else(|_| {
+                BranchLink::try_from_raw(instr_u32).map(|b| (b.target_pc(instr_pc), BLR_X16))
+            })
+        {
+            if (old_pc..old_pc + len).contains(&target) {
+                return Err(JitError::UnsupportedControlFlow);
+            }
+
+            literals.push((new_bytes.len(), IP0, target));
+            new_bytes.extend_from_slice(&[0; 4]);
+            new_bytes.extend_from_slice(&branch_reg.to_ne_bytes());
+        }
+        else {
+            // LDR (literal, SIMD&FP)
+            if instr_u32 & 0x3F00_0000 == 0x1C00_0000 {
+                return Err(JitError::UnsupportedInstruction);
+            }
+
+            let instrs = cs
+                .disasm_count(bytes, instr_pc as u64, 1)
+                .map_err(|_| JitError::InvalidInstruction)?;
+            let instr = instrs.iter().next().ok_or(JitError::InvalidInstruction)?;
+            if has_unsupported_insn_group(cs.insn_detail(instr).unwrap().groups()) {
+                return Err(JitError::UnsupportedInstruction);
+            }
+
+            new_bytes.extend_from_slice(bytes);
+        }
+    }
+
+    // emit the literal pool after the relocated instructions and branch over it
+    if !literals.is_empty() {
+        let branch_offset = new_bytes.len();
+        new_bytes.extend_from_slice(&[0; 4]);
+
+        if !(new_pc + new_bytes.len()).is_multiple_of(8) {
+            new_bytes.extend_from_slice(&[0; 4]);
+        }
+
+        for (instr_offset, reg, addr) in literals {
+            let addr_pc = new_pc + new_bytes.len();
+            let ldr = LdrImm::new_at(new_pc + instr_offset, LdrImmOpc::Load64, reg, addr_pc)?;
+
+            new_bytes.extend_from_slice(&(addr as u64).to_ne_bytes());
+            new_bytes[instr_offset..instr_offset + 4].copy_from_slice(&ldr.to_raw().to_ne_bytes());
+        }
+
+        let branch = Branch::new_at(new_pc + branch_offset, new_pc + new_bytes.len())?;
+        new_bytes[branch_offset..branch_offset + 4].copy_from_slice(&branch.to_raw().to_ne_bytes());
+    }
+
+    Ok(Relocation {
+        code: new_bytes,
+        boundaries,
+        old_len: len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(code: &[u8]) -> Vec<u32> {
+        code.chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect()
+    }
+
+    fn literal(code: &[u8], pc: usize, ldr: u32) -> u64 {
+        let offset = LdrImm::try_from_raw(ldr).unwrap().target_pc(pc);
+        u64::from_le_bytes(code[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn aarch64_reloc() {
+        let code = [
+            0xA9BF7BFD_u32, // stp x29, x30, [sp, #-16]!
+            0x910003FD,     // mov x29, sp
+            0x10000040,     // adr x0, #8
+            0x58000081,     // ldr x1, #16
+            0x94000040,     // bl #0x100
+        ];
+        let code: Vec<u8> = code.iter().flat_map(|w| w.to_le_bytes()).collect();
+
+        let (old_pc, new_pc) = (0x10000, 0x20000);
+        let reloc = relocate(&code, old_pc, new_pc, code.len()).unwrap();
+        let new = words(reloc.code());
+
+        assert_eq!(reloc.old_len(), 20);
+        let new_offsets: Vec<_> = reloc.boundaries().iter().map(|b| b.new_offset).collect();
+        assert_eq!(new_offsets, [0, 4, 8, 12, 20]);
+
+        assert_eq!(new[..2], words(&code)[..2]);
+        assert_eq!(literal(reloc.code(), 8, new[2]), 0x10010);
+        assert_eq!(LdrImm::try_from_raw(new[3]).unwrap().reg(), 1);
+        assert_eq!(literal(reloc.code(), 12, new[3]), 0x1001C);
+        assert_eq!(
+            new[4],
+            LdrOfs::new(LdrImmOpc::Load64, 1, 1, 0).unwrap().to_raw()
+        );
+        assert_eq!(LdrImm::try_from_raw(new[5]).unwrap().reg(), IP0);
+        assert_eq!(literal(reloc.code(), 20, new[5]), 0x10110);
+        assert_eq!(new[6], BLR_X16);
+
+        // the literal pool is branched over
+        let branch = Branch::try_from_raw(new[7]).unwrap();
+        assert_eq!(branch.target_pc(new_pc + 28), new_pc + reloc.code().len());
+    }
+
+    #[test]
+    fn aarch64_reloc_errors() {
+        let reloc = |code: &[u32]| {
+            let code: Vec<u8> = code.iter().flat_map(|w| w.to_le_bytes()).collect();
+            relocate(&code, 0x10000, 0x20000, code.len()).map(|r| r.into_code())
+        };
+
+        // b #4 (into the range)
+        assert_eq!(
+            reloc(&[0x14000001, 0xD503201F]).unwrap_err(),
+            JitError::UnsupportedControlFlow
+        );
+        // b.eq #8
+        assert_eq!(
+            reloc(&[0x54000040]).unwrap_err(),
+            JitError::UnsupportedInstruction
+        );
+        // ldr q0, #8
+        assert_eq!(
+            reloc(&[0x9C000040]).unwrap_err(),
+            JitError::UnsupportedInstruction
+        );
+        // truncated
+        assert_eq!(
+            relocate(&[0x1F, 0x20], 0, 0, 2).unwrap_err(),
+            JitError::InvalidInstruction
+        );
+    }
+}
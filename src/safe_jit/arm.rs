@@ -10,10 +10,10 @@ use capstone::{
 
 use crate::safe_jit::{
     arm_util::{
-        encoding::{Adr, LdrImm, LoadImm},
+        encoding_arm::{Adr, Branch, LdrImm, LoadImm},
         has_unsupported_insn_group, CowBuffer,
     },
-    JitError, RelocThunk,
+    InstructionBoundary, JitError, JitFailure, RelocThunk, Relocation,
 };
 
 #[cfg(thumb_mode)]
@@ -25,7 +25,7 @@ pub fn try_reloc_thunk_template<'a>(
     thunk_template: &'a [u8],
     pc: usize,
     magic_offset: usize,
-) -> Result<RelocThunk<'a>, JitError> {
+) -> Result<RelocThunk<'a>, JitFailure> {
     let thunk_template_end = thunk_template.len() + pc;
     let cs = Capstone::new().arm().mode(MODE).detail(true).build().unwrap();
     let mut disasm_iter = cs.disasm_iter(thunk_template, pc as u64).unwrap();
@@ -60,7 +60,7 @@ pub fn try_reloc_thunk_template<'a>(
         else if detail.regs_write().iter().any(|r| r.0 as u32 == ArmReg::ARM_REG_PC)
             || has_unsupported_insn_group(detail.groups())
         {
-            return Err(JitError::UnsupportedInstruction);
+            return Err(JitError::UnsupportedInstruction.at(offset, instr.len()));
         }
         // if the instruction doesn't read the PC, we don't care about it at this point.
         // it's OK to relocate it.
@@ -69,7 +69,7 @@ pub fn try_reloc_thunk_template<'a>(
         }
         // We don't support relocating instructions that conditionally read the PC.
         if arm_detail.cc() != ArmCC::ARM_CC_AL {
-            return Err(JitError::UnsupportedInstruction);
+            return Err(JitError::UnsupportedInstruction.at(offset, instr.len()));
         }
 
         // ADR reg, label => LDR reg, =label_address
@@ -105,17 +105,18 @@ pub fn try_reloc_thunk_template<'a>(
         }
 
         // any other pc-relative reads are unsupported
-        return Err(JitError::UnsupportedInstruction);
+        return Err(JitError::UnsupportedInstruction.at(offset, instr.len()));
     }
 
     if !has_thunk_asm {
-        return Err(JitError::NoThunkAsm);
+        return Err(JitError::NoThunkAsm.into());
     }
 
     // emit the extra LDR instructions using a post-thunk literal pool
     if !extra_ldrs.is_empty() {
         // copy the rest of the thunk template over
         cow_buf.copy_up_to(thunk_template.len());
+        let pool_start = cow_buf.new_bytes().len();
         let new_bytes = cow_buf.new_bytes_mut();
 
         // the contract is that the magic offset is at least pointer-aligned.
@@ -128,15 +129,177 @@ pub fn try_reloc_thunk_template<'a>(
         // referring to them.
         for (instr_offset, reg, addr) in extra_ldrs {
             let addr_pc = pc + new_bytes.len();
-            let ldr = LdrImm::new_lit(pc + instr_offset, reg, addr_pc)?;
+            let ldr = LdrImm::new_lit(pc + instr_offset, reg, addr_pc).map_err(JitError::from)?;
 
             new_bytes.extend_from_slice(&addr.to_ne_bytes());
             new_bytes[instr_offset..instr_offset + 4].copy_from_slice(&ldr.bytes());
         }
+        cow_buf.mark_rewritten(pool_start..cow_buf.new_bytes().len());
     }
 
+    let rewritten = cow_buf.take_rewritten();
     Ok(RelocThunk {
         thunk: cow_buf.into_bytes(),
         magic_offset: new_magic_offset,
+        rewritten,
+        rel32_fixups: Vec::new(),
+        far_error: None,
+    })
+}
+
+/// Relocates the instructions in the first `len` bytes of `code` from `old_pc` to `new_pc`.
+///
+/// PC-relative loads and address computations are rewritten to load their target from a literal
+/// pool placed after the relocated instructions. Branches are not supported.
+pub fn relocate(
+    code: &[u8],
+    old_pc: usize,
+    new_pc: usize,
+    len: usize,
+) -> Result<Relocation, JitError> {
+    let cs = Capstone::new().arm().mode(MODE).detail(true).build().unwrap();
+    let mut disasm_iter = cs.disasm_iter(code, old_pc as u64).unwrap();
+
+    let mut new_bytes = Vec::with_capacity(len);
+    let mut boundaries = Vec::new();
+    let mut literals = Vec::new();
+    let mut old_len = 0;
+
+    while old_len < len {
+        let instr = disasm_iter.next().ok_or(JitError::InvalidInstruction)?;
+        let instr_pc = instr.address() as usize;
+        let instr_id = ArmInsn::from(instr.id().0);
+        old_len = instr_pc - old_pc + instr.len();
+
+        boundaries.push(InstructionBoundary {
+            old_offset: instr_pc - old_pc,
+            new_offset: new_bytes.len(),
+        });
+
+        let detail = cs.insn_detail(&instr).unwrap();
+        let arch_detail = detail.arch_detail();
+        let arm_detail = arch_detail.arm().unwrap();
+
+        if detail.regs_write().iter().any(|r| r.0 as u32 == ArmReg::ARM_REG_PC)
+            || has_unsupported_insn_group(detail.groups())
+        {
+            return Err(JitError::UnsupportedInstruction);
+        }
+        if !detail.regs_read().iter().any(|r| r.0 as u32 == ArmReg::ARM_REG_PC) {
+            new_bytes.extend_from_slice(instr.bytes());
+            continue;
+        }
+        if arm_detail.cc() != ArmCC::ARM_CC_AL {
+            return Err(JitError::UnsupportedInstruction);
+        }
+
+        // ADR reg, label => LDR reg, =label_address
+        if let Some(adr) = Adr::try_from_raw(instr.bytes()) {
+            literals.push((new_bytes.len(), adr.dest_reg(), adr.target_pc(instr_pc)));
+            new_bytes.extend_from_slice(&[0; 4]);
+        }
+        // LDR.. reg, label => LDR reg, =label_address; LDR.. reg, [reg]
+        else if let Some((load, target)) = LoadImm::try_from_raw(instr_id, instr.bytes())
+            .and_then(|load| Some((load, load.target_pc(instr_pc)?)))
+        {
+            literals.push((new_bytes.len(), load.rt(), target));
+            new_bytes.extend_from_slice(&[0; 4]);
+            load.as_rt_load(|new| new_bytes.extend_from_slice(new));
+        }
+        // any other pc-relative reads are unsupported
+        else {
+            return Err(JitError::UnsupportedInstruction);
+        }
+    }
+
+    // emit the literal pool after the relocated instructions and branch over it
+    if !literals.is_empty() {
+        let branch_offset = new_bytes.len();
+        new_bytes.extend_from_slice(&[0; Branch::LEN]);
+
+        if !(new_pc + new_bytes.len()).is_multiple_of(4) {
+            new_bytes.extend_from_slice(&[0; 2]);
+        }
+
+        for (instr_offset, reg, addr) in literals {
+            let addr_pc = new_pc + new_bytes.len();
+            let ldr = LdrImm::new_lit(new_pc + instr_offset, reg, addr_pc)?;
+
+            new_bytes.extend_from_slice(&(addr as u32).to_ne_bytes());
+            new_bytes[instr_offset..instr_offset + 4].copy_from_slice(&ldr.bytes());
+        }
+
+        let branch = Branch::new(new_pc + branch_offset, new_pc + new_bytes.len())?;
+        new_bytes[branch_offset..branch_offset + Branch::LEN].copy_from_slice(&branch.bytes());
+    }
+
+    Ok(Relocation {
+        code: new_bytes,
+        boundaries,
+        old_len,
     })
 }
+
+#[cfg(all(test, not(thumb_mode)))]
+mod tests {
+    use super::*;
+
+    fn to_bytes(code: &[u32]) -> Vec<u8> {
+        code.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn arm_reloc() {
+        let code = to_bytes(&[
+            0xE92D4010, // push {r4, lr}
+            0xE28F0008, // adr r0, #8
+            0xE59F1008, // ldr r1, [pc, #8]
+            0xE1A02003, // mov r2, r3
+        ]);
+
+        let (old_pc, new_pc) = (0x10000, 0x20000);
+        let reloc = relocate(&code, old_pc, new_pc, code.len()).unwrap();
+
+        let expected = [
+            0xE92D4010u32.to_le_bytes(),
+            LdrImm::new_lit(0x20004, 0, 0x20018).unwrap().bytes(),
+            LdrImm::new_lit(0x20008, 1, 0x2001C).unwrap().bytes(),
+            0xE5911000u32.to_le_bytes(), // ldr r1, [r1]
+            0xE1A02003u32.to_le_bytes(),
+            0xEA000001u32.to_le_bytes(), // b over the literal pool
+            0x10014u32.to_le_bytes(),
+            0x10018u32.to_le_bytes(),
+        ];
+        assert_eq!(reloc.code(), expected.concat());
+
+        let new_offsets: Vec<_> = reloc.boundaries().iter().map(|b| b.new_offset).collect();
+        assert_eq!(new_offsets, [0, 4, 8, 16]);
+        assert_eq!(reloc.old_len(), 16);
+    }
+
+    #[test]
+    fn arm_reloc_errors() {
+        let reloc = |code: &[u32]| {
+            let code = to_bytes(code);
+            relocate(&code, 0x10000, 0x20000, code.len()).map(|r| r.into_code())
+        };
+
+        // bl #8
+        assert_eq!(
+            reloc(&[0xEB000000]).unwrap_err(),
+            JitError::UnsupportedInstruction
+        );
+        // addeq r0, pc, #8
+        assert_eq!(
+            reloc(&[0x028F0008]).unwrap_err(),
+            JitError::UnsupportedInstruction
+        );
+        // empty range
+        assert_eq!(reloc(&[]).map(|c| c.len()), Ok(0));
+        // truncated
+        assert_eq!(
+            relocate(&[0x10, 0x40], 0, 0, 2).unwrap_err(),
+            JitError::InvalidInstruction
+        );
+    }
+}
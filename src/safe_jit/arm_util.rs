@@ -1,17 +1,16 @@
 use alloc::{borrow::Cow, vec::Vec};
+use core::ops::Range;
 
 use capstone::{InsnGroupId, InsnGroupType};
 
-#[cfg_attr(target_arch = "aarch64", path = "arm_util/encoding_aarch64.rs")]
-#[cfg_attr(
-    all(target_arch = "arm", not(thumb_mode)),
-    path = "arm_util/encoding_arm.rs"
-)]
-#[cfg_attr(
-    all(target_arch = "arm", thumb_mode),
-    path = "arm_util/encoding_thumb.rs"
-)]
-pub mod encoding;
+#[cfg(any(target_arch = "aarch64", test))]
+pub mod encoding_aarch64;
+
+// Only the A32 encoding is tested on other hosts, as `thumb_mode` is only set for ARM targets
+#[cfg(any(target_arch = "arm", test))]
+#[cfg_attr(not(thumb_mode), path = "arm_util/encoding_arm.rs")]
+#[cfg_attr(thumb_mode, path = "arm_util/encoding_thumb.rs")]
+pub mod encoding_arm;
 
 /// Check if any of the instruction groups provided is unsupported by the arm/aarch64 relocator.
 pub fn has_unsupported_insn_group(groups: &[InsnGroupId]) -> bool {
@@ -54,6 +53,8 @@ pub struct CowBuffer<'a> {
     orig_bytes: &'a [u8],
     new_bytes: Vec<u8>,
     num_copied: usize,
+    /// Ranges of [`Self::new_bytes`] which were not copied from the original slice.
+    rewritten: Vec<Range<usize>>,
 }
 
 impl<'a> CowBuffer<'a> {
@@ -62,6 +63,7 @@ impl<'a> CowBuffer<'a> {
             orig_bytes,
             new_bytes: Vec::new(),
             num_copied: 0,
+            rewritten: Vec::new(),
         }
     }
 
@@ -113,9 +115,27 @@ impl<'a> CowBuffer<'a> {
         }
         let bytes_offset = self.new_bytes.len();
         self.new_bytes.extend_from_slice(bytes);
+        self.mark_rewritten(bytes_offset..self.new_bytes.len());
         bytes_offset
     }
 
+    /// Record that a range of [`Self::new_bytes`] was not copied from the original slice, e.g.
+    /// because it was added through [`Self::new_bytes_mut`].
+    pub fn mark_rewritten(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        match self.rewritten.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.rewritten.push(range),
+        }
+    }
+
+    /// Take the ranges of [`Self::new_bytes`] which were not copied from the original slice.
+    pub fn take_rewritten(&mut self) -> Vec<Range<usize>> {
+        core::mem::take(&mut self.rewritten)
+    }
+
     /// Ignore `count` bytes at `offset` in the original slice.
     ///
     /// Returns the offset at which said bytes would have been in [`Self::new_bytes`].
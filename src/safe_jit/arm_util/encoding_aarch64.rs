@@ -138,7 +138,12 @@ impl Branch {
         pc.wrapping_add_signed(self.imm() as isize * 4)
     }
 
-    #[allow(unused)]
+    pub fn new_at(pc: usize, target: usize) -> Result<Self, Error> {
+        let mut ins = Self::from_raw(0b000101 << 26);
+        ins.try_set_target_pc(pc, target)?;
+        Ok(ins)
+    }
+
     pub fn try_set_target_pc(&mut self, pc: usize, target: usize) -> Result<(), ()> {
         let diff = target as isize - pc as isize;
         if diff % 4 != 0 {
@@ -149,6 +154,30 @@ impl Branch {
     }
 }
 
+// https://developer.arm.com/documentation/ddi0602/2022-09/Base-Instructions/BL--Branch-with-Link-
+bitflags! {
+    pub struct BranchLink: u32 {
+        #[signed(i32)]
+        pub imm: 0..26,
+        fixed: 26..32,
+    }
+}
+
+impl BranchLink {
+    pub fn try_from_raw(raw: u32) -> Result<Self, ()> {
+        let ins = Self::from_raw(raw);
+        ins.assert_opcode().then_some(ins).ok_or(())
+    }
+
+    pub fn assert_opcode(&self) -> bool {
+        self.fixed() == 0b100101
+    }
+
+    pub fn target_pc(&self, pc: usize) -> usize {
+        pc.wrapping_add_signed(self.imm() as isize * 4)
+    }
+}
+
 // https://developer.arm.com/documentation/ddi0602/2022-09/Base-Instructions/LDR--immediate---Load-Register--immediate--
 bitflags! {
     pub struct LdrOfs: u32 {
@@ -4,6 +4,7 @@
 //! - Ability to emit a LDR, reg [pc, offset]
 //! - Ability to turn common PC-relative loads into reg-relative loads.
 //! - Ability to turn the ADR instruction into a LDR.
+//! - Ability to emit a B to branch over a literal pool.
 
 use capstone::arch::arm::ArmInsn;
 
@@ -180,3 +181,24 @@ impl Adr {
         }
     }
 }
+
+/// Unconditional B instruction (A1 encoding).
+///
+/// See ARMv7 manual, section A8.8.18.
+pub struct Branch(u32);
+
+impl Branch {
+    pub const LEN: usize = 4;
+
+    pub fn new(pc: usize, target: usize) -> Result<Self, EncodingError> {
+        let diff = target as isize - (pc + PC_OFFSET) as isize;
+        if diff % 4 != 0 || !(-(1 << 25)..(1 << 25)).contains(&diff) {
+            return Err(EncodingError);
+        }
+        Ok(Self(0xEA00_0000 | (diff >> 2) as u32 & 0x00FF_FFFF))
+    }
+
+    pub fn bytes(self) -> [u8; 4] {
+        self.0.to_ne_bytes()
+    }
+}
@@ -4,6 +4,7 @@
 //! - Ability to emit a LDR, reg [pc, offset]
 //! - Ability to turn common PC-relative loads into reg-relative loads.
 //! - Ability to turn the ADR instruction into a LDR.
+//! - Ability to emit a B to branch over a literal pool.
 
 use capstone::arch::arm::ArmInsn;
 
@@ -275,3 +276,24 @@ impl Adr {
         }
     }
 }
+
+/// Unconditional B instruction (T2 encoding).
+///
+/// See ARMv7 manual, section A8.8.18.
+pub struct Branch(u16);
+
+impl Branch {
+    pub const LEN: usize = 2;
+
+    pub fn new(pc: usize, target: usize) -> Result<Self, EncodingError> {
+        let diff = target as isize - (pc + PC_OFFSET) as isize;
+        if diff % 2 != 0 || !(-2048..2048).contains(&diff) {
+            return Err(EncodingError);
+        }
+        Ok(Self(0xE000 | (diff >> 1) as u16 & 0x7FF))
+    }
+
+    pub fn bytes(self) -> [u8; 2] {
+        self.0.to_ne_bytes()
+    }
+}
@@ -0,0 +1,586 @@
+//! Call frame information of thunk prologues, describing how to unwind the stack from any of their
+//! instructions.
+//!
+//! Prologues are simulated instruction by instruction to track how far the stack pointer is from
+//! the Canonical Frame Address (CFA), i.e. the value of the stack pointer before the call to the
+//! thunk, and where callee-saved registers are stored. Anything that cannot be followed, such as a
+//! stack pointer write of unknown size while the CFA is based on it, aborts the analysis.
+
+use alloc::vec::Vec;
+
+/// A change to the unwinding rules, in terms of DWARF register numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CfiRule {
+    /// The CFA is now this many bytes above the register it is based on.
+    CfaOffset(u32),
+    /// The CFA is now `offset` bytes above `reg`.
+    Cfa { reg: u16, offset: u32 },
+    /// The caller's value of `reg` is saved `cfa_offset` bytes below the CFA.
+    Saved { reg: u16, cfa_offset: u32 },
+    /// The return address was signed with pointer authentication, or authenticated.
+    #[cfg_attr(not(any(target_arch = "aarch64", test)), allow(dead_code))]
+    NegateRaState,
+}
+
+/// A [`CfiRule`] which applies to all instructions from `offset` onwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CfiOp {
+    pub offset: usize,
+    pub rule: CfiRule,
+}
+
+/// Computes the unwinding rules of a thunk prologue for the target architecture. `code` must
+/// start at the thunk and include its final jump back to the template.
+///
+/// Returns `None` if the prologue could not be analyzed.
+#[cfg(feature = "unwind_info")]
+pub(crate) fn thunk_cfi(code: &[u8], ip: usize) -> Option<Vec<CfiOp>> {
+    #[cfg(target_arch = "x86_64")]
+    return x86::frame_ops(code, ip as u64, 64);
+    #[cfg(target_arch = "x86")]
+    return x86::frame_ops(code, ip as u64, 32);
+    #[cfg(target_arch = "aarch64")]
+    return aarch64::frame_ops(code, ip as u64);
+    #[cfg(target_arch = "arm")]
+    return None;
+}
+
+/// Follows the changes to the stack and frame pointers made by a prologue.
+struct FrameTracker {
+    ops: Vec<CfiOp>,
+    /// Distance from the stack pointer to the CFA, if it is still known.
+    sp_offset: Option<i64>,
+    /// Whether the CFA was moved to the frame pointer.
+    cfa_on_fp: bool,
+    saved: Vec<u16>,
+}
+
+impl FrameTracker {
+    fn new(sp_offset: i64) -> Self {
+        Self {
+            ops: Vec::new(),
+            sp_offset: Some(sp_offset),
+            cfa_on_fp: false,
+            saved: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, offset: usize, rule: CfiRule) {
+        self.ops.push(CfiOp { offset, rule });
+    }
+
+    /// Records that the stack grew by `amount` bytes (or shrunk, if negative).
+    fn grow_stack(&mut self, offset: usize, amount: i64) -> Option<()> {
+        self.sp_offset = self.sp_offset.map(|o| o + amount);
+        if !self.cfa_on_fp {
+            let cfa_offset = u32::try_from(self.sp_offset?).ok()?;
+            self.push(offset, CfiRule::CfaOffset(cfa_offset));
+        }
+        Some(())
+    }
+
+    /// Records a write of unknown effect to the stack pointer, which is only supported once the CFA
+    /// is based on the frame pointer.
+    fn clobber_sp(&mut self) -> Option<()> {
+        self.sp_offset = None;
+        self.cfa_on_fp.then_some(())
+    }
+
+    /// Records that the frame pointer `fp` was set to the stack pointer plus `sp_relative`, and
+    /// bases the CFA on it.
+    fn set_fp(&mut self, offset: usize, fp: u16, sp_relative: i64) -> Option<()> {
+        if self.cfa_on_fp {
+            return None;
+        }
+        let cfa_offset = u32::try_from(self.sp_offset? - sp_relative).ok()?;
+        self.push(
+            offset,
+            CfiRule::Cfa {
+                reg: fp,
+                offset: cfa_offset,
+            },
+        );
+        self.cfa_on_fp = true;
+        Some(())
+    }
+
+    /// Records that `reg` was stored at the stack pointer plus `sp_relative`. Only the first store
+    /// of a register is assumed to save the caller's value.
+    fn save(&mut self, offset: usize, reg: u16, sp_relative: i64) -> Option<()> {
+        if self.saved.contains(&reg) {
+            return Some(());
+        }
+        let cfa_offset = u32::try_from(self.sp_offset? - sp_relative).ok()?;
+        self.push(offset, CfiRule::Saved { reg, cfa_offset });
+        self.saved.push(reg);
+        Some(())
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", test))]
+#[cfg_attr(
+    not(any(target_arch = "x86", target_arch = "x86_64")),
+    allow(dead_code)
+)]
+mod x86 {
+    use alloc::vec::Vec;
+
+    use iced_x86::{
+        Decoder, DecoderOptions, FlowControl, InstructionInfoFactory, Mnemonic, OpAccess, OpKind,
+        Register,
+    };
+
+    use super::{CfiOp, FrameTracker};
+
+    /// DWARF number of a register whose value must be preserved across calls.
+    fn callee_saved(reg: Register, bitness: u32) -> Option<u16> {
+        match (bitness, reg) {
+            (64, Register::RBX) => Some(3),
+            (64, Register::RBP) => Some(6),
+            (64, Register::R12) => Some(12),
+            (64, Register::R13) => Some(13),
+            (64, Register::R14) => Some(14),
+            (64, Register::R15) => Some(15),
+            (32, Register::EBX) => Some(3),
+            (32, Register::EBP) => Some(5),
+            (32, Register::ESI) => Some(6),
+            (32, Register::EDI) => Some(7),
+            _ => None,
+        }
+    }
+
+    pub fn frame_ops(code: &[u8], ip: u64, bitness: u32) -> Option<Vec<CfiOp>> {
+        let (sp, fp) = match bitness {
+            64 => (Register::RSP, Register::RBP),
+            _ => (Register::ESP, Register::EBP),
+        };
+        let fp_dwarf = callee_saved(fp, bitness)?;
+
+        // The return address was just pushed by the call
+        let mut frame = FrameTracker::new(bitness as i64 / 8);
+        let mut info_factory = InstructionInfoFactory::new();
+        let mut decoder = Decoder::with_ip(bitness, code, ip, DecoderOptions::NONE);
+
+        for instr in &mut decoder {
+            if instr.is_invalid() {
+                return None;
+            }
+            let offset = (instr.next_ip() - ip) as usize;
+
+            match instr.flow_control() {
+                FlowControl::Next => {}
+                // Used to get the instruction pointer on x86
+                FlowControl::Call if instr.near_branch_target() == instr.next_ip() => {}
+                // The jump back to the template
+                FlowControl::UnconditionalBranch | FlowControl::IndirectBranch => {
+                    return Some(frame.ops)
+                }
+                _ => return None,
+            }
+
+            // PUSH, POP and the CALL above
+            let increment = instr.stack_pointer_increment();
+            if increment != 0 {
+                frame.grow_stack(offset, -increment as i64)?;
+                if instr.mnemonic() == Mnemonic::Push && instr.op0_kind() == OpKind::Register {
+                    if let Some(reg) = callee_saved(instr.op0_register(), bitness) {
+                        frame.save(offset, reg, 0)?;
+                    }
+                }
+                continue;
+            }
+
+            let op0 = (instr.op_count() > 0 && instr.op0_kind() == OpKind::Register)
+                .then(|| instr.op0_register());
+            let immediate = || {
+                matches!(
+                    instr.op1_kind(),
+                    OpKind::Immediate8to64
+                        | OpKind::Immediate32to64
+                        | OpKind::Immediate8to32
+                        | OpKind::Immediate32
+                        | OpKind::Immediate8
+                )
+            };
+            let sp_memory = || {
+                instr.op1_kind() == OpKind::Memory
+                    && instr.memory_base() == sp
+                    && instr.memory_index() == Register::None
+            };
+
+            match (instr.mnemonic(), op0) {
+                (Mnemonic::Sub, Some(r)) if r == sp && immediate() => {
+                    frame.grow_stack(offset, instr.immediate(1) as i64)?
+                }
+                (Mnemonic::Add, Some(r)) if r == sp && immediate() => {
+                    frame.grow_stack(offset, -(instr.immediate(1) as i64))?
+                }
+                (Mnemonic::Lea, Some(r)) if r == sp && sp_memory() => {
+                    frame.grow_stack(offset, -(instr.memory_displacement64() as i64))?
+                }
+                (Mnemonic::Mov, Some(r))
+                    if r == fp
+                        && instr.op1_kind() == OpKind::Register
+                        && instr.op1_register() == sp =>
+                {
+                    frame.set_fp(offset, fp_dwarf, 0)?
+                }
+                (Mnemonic::Lea, Some(r)) if r == fp && sp_memory() => {
+                    frame.set_fp(offset, fp_dwarf, instr.memory_displacement64() as i64)?
+                }
+                _ => {
+                    let info = info_factory.info(&instr);
+                    for used in info.used_registers() {
+                        let written = matches!(
+                            used.access(),
+                            OpAccess::Write
+                                | OpAccess::CondWrite
+                                | OpAccess::ReadWrite
+                                | OpAccess::ReadCondWrite
+                        );
+                        let reg = used.register().full_register();
+                        if written && reg == sp.full_register() {
+                            frame.clobber_sp()?;
+                        }
+                        if written && reg == fp.full_register() && frame.cfa_on_fp {
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", test))]
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+mod aarch64 {
+    use alloc::vec::Vec;
+
+    use capstone::{
+        arch::{
+            arm64::{ArchMode, Arm64Reg},
+            BuildsCapstone,
+        },
+        Capstone, RegId,
+    };
+
+    use super::{CfiOp, CfiRule, FrameTracker};
+    use crate::safe_jit::arm_util::has_unsupported_insn_group;
+
+    const SP: u32 = 31;
+    const FP: u16 = 29;
+
+    /// DWARF number of a register whose value must be preserved across calls, given its number in
+    /// the encoding of a general purpose (`simd == false`) or SIMD load/store.
+    fn callee_saved(reg: u32, simd: bool) -> Option<u16> {
+        match (simd, reg) {
+            (false, 19..=30) => Some(reg as u16),
+            // The lower halves of v8-v15
+            (true, 8..=15) => Some(64 + reg as u16),
+            _ => None,
+        }
+    }
+
+    fn sign_extend(value: u32, bits: u32) -> i64 {
+        ((value << (32 - bits)) as i32 >> (32 - bits)) as i64
+    }
+
+    pub fn frame_ops(code: &[u8], pc: u64) -> Option<Vec<CfiOp>> {
+        let cs = Capstone::new().arm64().mode(ArchMode::Arm).detail(true).build().ok()?;
+        let instructions = cs.disasm_all(code, pc).ok()?;
+
+        // The return address is in x30
+        let mut frame = FrameTracker::new(0);
+
+        for instr in instructions.iter() {
+            let offset = (instr.address() - pc) as usize + 4;
+            let raw = u32::from_le_bytes(instr.bytes().try_into().ok()?);
+            let (rd, rn, rt2) = (raw & 31, (raw >> 5) & 31, (raw >> 10) & 31);
+
+            match raw {
+                // BR, the jump back to the template
+                _ if raw & 0xFFFF_FC1F == 0xD61F_0000 => return Some(frame.ops),
+                // B
+                _ if raw & 0xFC00_0000 == 0x1400_0000 => return Some(frame.ops),
+                // PACIASP, PACIBSP
+                0xD503_233F | 0xD503_237F => frame.push(offset, CfiRule::NegateRaState),
+                // ADD/SUB (immediate), 64-bit
+                _ if raw & 0xBF80_0000 == 0x9100_0000 && rn == SP => {
+                    let shift = if raw & (1 << 22) != 0 { 12 } else { 0 };
+                    let mut imm = (((raw >> 10) & 0xFFF) << shift) as i64;
+                    if raw & (1 << 30) != 0 {
+                        imm = -imm;
+                    }
+                    match rd {
+                        SP => frame.grow_stack(offset, -imm)?,
+                        29 => frame.set_fp(offset, FP, imm)?,
+                        _ => {}
+                    }
+                }
+                // STP (64-bit general purpose or SIMD), pre-indexed or signed offset
+                _ if matches!(
+                    raw & 0xFFC0_0000,
+                    0xA980_0000 | 0xA900_0000 | 0x6D80_0000 | 0x6D00_0000
+                ) && rn == SP =>
+                {
+                    let simd = raw & (1 << 26) != 0;
+                    let imm = sign_extend((raw >> 15) & 0x7F, 7) * 8;
+                    let sp_relative = match raw & (1 << 23) != 0 {
+                        true => {
+                            frame.grow_stack(offset, -imm)?;
+                            0
+                        }
+                        false => imm,
+                    };
+                    if let Some(reg) = callee_saved(rd, simd) {
+                        frame.save(offset, reg, sp_relative)?;
+                    }
+                    if let Some(reg) = callee_saved(rt2, simd) {
+                        frame.save(offset, reg, sp_relative + 8)?;
+                    }
+                }
+                // STR (64-bit general purpose or SIMD), pre-indexed
+                _ if raw & 0xBBE0_0C00 == 0xB800_0C00 && raw >> 30 == 3 && rn == SP => {
+                    let imm = sign_extend((raw >> 12) & 0x1FF, 9);
+                    frame.grow_stack(offset, -imm)?;
+                    if let Some(reg) = callee_saved(rd, raw & (1 << 26) != 0) {
+                        frame.save(offset, reg, 0)?;
+                    }
+                }
+                // STR (64-bit general purpose or SIMD), unsigned offset
+                _ if raw & 0xFBC0_0000 == 0xF900_0000 && rn == SP => {
+                    let imm = (((raw >> 10) & 0xFFF) * 8) as i64;
+                    if let Some(reg) = callee_saved(rd, raw & (1 << 26) != 0) {
+                        frame.save(offset, reg, imm)?;
+                    }
+                }
+                _ => {
+                    let detail = cs.insn_detail(instr).ok()?;
+                    // Calls and any other branch
+                    if has_unsupported_insn_group(detail.groups()) {
+                        return None;
+                    }
+                    let writes = |reg: u32| detail.regs_write().contains(&RegId(reg as _));
+                    if writes(Arm64Reg::ARM64_REG_SP) || writes(Arm64Reg::ARM64_REG_WSP) {
+                        frame.clobber_sp()?;
+                    }
+                    if writes(Arm64Reg::ARM64_REG_X29) && frame.cfa_on_fp {
+                        return None;
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{
+        CfiOp,
+        CfiRule::{self, *},
+    };
+
+    fn ops(rules: &[(usize, CfiRule)]) -> Vec<CfiOp> {
+        rules.iter().map(|&(offset, rule)| CfiOp { offset, rule }).collect()
+    }
+
+    fn aarch64_code(instructions: &[u32]) -> Vec<u8> {
+        instructions.iter().flat_map(|i| i.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn x86_64_frame_pointer() {
+        let code = [
+            0x55, // push rbp
+            0x48, 0x89, 0xE5, // mov rbp, rsp
+            0x53, // push rbx
+            0x48, 0x83, 0xE4, 0xF0, // and rsp, -16
+            0x48, 0x83, 0xEC, 0x18, // sub rsp, 0x18
+            0x48, 0x8B, 0x05, 0, 0, 0, 0, // mov rax, [rip]
+            0xFF, 0x25, 0, 0, 0, 0, // jmp [rip]
+        ];
+        let expected = ops(&[
+            (1, CfaOffset(16)),
+            (
+                1,
+                Saved {
+                    reg: 6,
+                    cfa_offset: 16,
+                },
+            ),
+            (4, Cfa { reg: 6, offset: 16 }),
+            (
+                5,
+                Saved {
+                    reg: 3,
+                    cfa_offset: 24,
+                },
+            ),
+        ]);
+        assert_eq!(super::x86::frame_ops(&code, 0x1000, 64), Some(expected));
+    }
+
+    #[test]
+    fn x86_64_stack_pointer() {
+        let code = [
+            0x41, 0x57, // push r15
+            0x48, 0x81, 0xEC, 0x00, 0x01, 0, 0, // sub rsp, 0x100
+            0x48, 0x89, 0x7C, 0x24, 0x10, // mov [rsp+0x10], rdi
+            0x48, 0x8D, 0x64, 0x24, 0x08, // lea rsp, [rsp+8]
+            0xFF, 0x25, 0, 0, 0, 0, // jmp [rip]
+        ];
+        let expected = ops(&[
+            (2, CfaOffset(16)),
+            (
+                2,
+                Saved {
+                    reg: 15,
+                    cfa_offset: 16,
+                },
+            ),
+            (9, CfaOffset(0x110)),
+            (19, CfaOffset(0x108)),
+        ]);
+        assert_eq!(super::x86::frame_ops(&code, 0x1000, 64), Some(expected));
+
+        // and rsp, -16 without a frame pointer
+        let code = [0x48, 0x83, 0xE4, 0xF0, 0xFF, 0x25, 0, 0, 0, 0];
+        assert_eq!(super::x86::frame_ops(&code, 0x1000, 64), None);
+
+        // No jump back to the template
+        assert_eq!(super::x86::frame_ops(&[0x55], 0x1000, 64), None);
+    }
+
+    #[test]
+    fn x86_frame_ops() {
+        let code = [
+            0x55, // push ebp
+            0x53, // push ebx
+            0xE8, 0, 0, 0, 0,    // call $+5
+            0x58, // pop eax
+            0xFF, 0xE0, // jmp eax
+        ];
+        let expected = ops(&[
+            (1, CfaOffset(8)),
+            (
+                1,
+                Saved {
+                    reg: 5,
+                    cfa_offset: 8,
+                },
+            ),
+            (2, CfaOffset(12)),
+            (
+                2,
+                Saved {
+                    reg: 3,
+                    cfa_offset: 12,
+                },
+            ),
+            (7, CfaOffset(16)),
+            (8, CfaOffset(12)),
+        ]);
+        assert_eq!(super::x86::frame_ops(&code, 0x1000, 32), Some(expected));
+
+        // A call to another function
+        let code = [0xE8, 0x10, 0, 0, 0, 0xFF, 0xE0];
+        assert_eq!(super::x86::frame_ops(&code, 0x1000, 32), None);
+    }
+
+    #[test]
+    fn aarch64_frame_pointer() {
+        let code = aarch64_code(&[
+            0xD503233F, // paciasp
+            0xA9BE7BFD, // stp x29, x30, [sp, #-32]!
+            0x910003FD, // mov x29, sp
+            0xF9000BF3, // str x19, [sp, #16]
+            0xD10083FF, // sub sp, sp, #32
+            0x58000040, // ldr x0, #8
+            0xD61F0200, // br x16
+        ]);
+        let expected = ops(&[
+            (4, NegateRaState),
+            (8, CfaOffset(32)),
+            (
+                8,
+                Saved {
+                    reg: 29,
+                    cfa_offset: 32,
+                },
+            ),
+            (
+                8,
+                Saved {
+                    reg: 30,
+                    cfa_offset: 24,
+                },
+            ),
+            (
+                12,
+                Cfa {
+                    reg: 29,
+                    offset: 32,
+                },
+            ),
+            (
+                16,
+                Saved {
+                    reg: 19,
+                    cfa_offset: 16,
+                },
+            ),
+        ]);
+        assert_eq!(super::aarch64::frame_ops(&code, 0x1000), Some(expected));
+    }
+
+    #[test]
+    fn aarch64_stack_pointer() {
+        let code = aarch64_code(&[
+            0xD10183FF, // sub sp, sp, #96
+            0x6D0127E8, // stp d8, d9, [sp, #16]
+            0xF81F0FFE, // str x30, [sp, #-16]!
+            0xF90007E0, // str x0, [sp, #8]
+            0x58000040, // ldr x0, #8
+            0xD61F0200, // br x16
+        ]);
+        let expected = ops(&[
+            (4, CfaOffset(96)),
+            (
+                8,
+                Saved {
+                    reg: 72,
+                    cfa_offset: 80,
+                },
+            ),
+            (
+                8,
+                Saved {
+                    reg: 73,
+                    cfa_offset: 72,
+                },
+            ),
+            (12, CfaOffset(112)),
+            (
+                12,
+                Saved {
+                    reg: 30,
+                    cfa_offset: 112,
+                },
+            ),
+        ]);
+        assert_eq!(super::aarch64::frame_ops(&code, 0x1000), Some(expected));
+
+        // mov sp, x0 without a frame pointer
+        let code = aarch64_code(&[0x9100001F, 0xD61F0200]);
+        assert_eq!(super::aarch64::frame_ops(&code, 0x1000), None);
+
+        // bl #0x100
+        let code = aarch64_code(&[0x94000040, 0xD61F0200]);
+        assert_eq!(super::aarch64::frame_ops(&code, 0x1000), None);
+    }
+}
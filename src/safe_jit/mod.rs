@@ -1,55 +1,257 @@
-use alloc::borrow::Cow;
+//! Relocation of machine code, as used by the `safe_jit` feature to move thunk prologues.
+//!
+//! [`relocate`] exposes the underlying relocator for the target architecture so that it can be used
+//! on arbitrary code, e.g. to copy the first instructions of a function to a trampoline.
 
-#[cfg(target_arch = "x86_64")]
+use alloc::{borrow::Cow, vec::Vec};
+use core::{fmt, ops::Range};
+
+use crate::error::PrologueError;
+
+// The relocators of all architectures are built when testing so that they can be unit tested on
+// any host.
+
+#[cfg(any(target_arch = "x86_64", test))]
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
 mod x86_64;
 #[cfg(target_arch = "x86_64")]
 use x86_64::try_reloc_thunk_template;
 
-#[cfg(target_arch = "x86")]
+#[cfg(any(target_arch = "x86", test))]
+#[cfg_attr(not(target_arch = "x86"), allow(dead_code))]
 mod x86;
 #[cfg(target_arch = "x86")]
 use x86::try_reloc_thunk_template;
 
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", test))]
+mod x86_util;
+
+#[cfg(any(target_arch = "aarch64", test))]
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
 mod aarch64;
 #[cfg(target_arch = "aarch64")]
 use aarch64::try_reloc_thunk_template;
 
-#[cfg(target_arch = "arm")]
+#[cfg(any(target_arch = "arm", test))]
+#[cfg_attr(not(target_arch = "arm"), allow(dead_code))]
 mod arm;
 #[cfg(target_arch = "arm")]
 use arm::try_reloc_thunk_template;
 
-#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+#[cfg(any(target_arch = "arm", target_arch = "aarch64", test))]
+#[cfg_attr(
+    not(any(target_arch = "arm", target_arch = "aarch64")),
+    allow(dead_code)
+)]
 mod arm_util;
 
-#[derive(Clone, Copy, Debug)]
+#[cfg(all(feature = "detour", any(target_arch = "x86", target_arch = "x86_64")))]
+pub(crate) mod x86_detour;
+
+#[cfg(any(feature = "unwind_info", test))]
+#[cfg_attr(not(feature = "unwind_info"), allow(dead_code))]
+pub(crate) mod cfi;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[allow(unused)]
-enum JitError {
+pub(crate) enum JitError {
     InvalidInstruction,
     UnsupportedInstruction,
     UnsupportedControlFlow,
     NoAvailableRegister,
     EncodingError,
     NoThunkAsm,
+    PrologueTooSmall,
+}
+
+impl JitError {
+    /// Attaches the offset and length of the instruction which caused the error.
+    pub(crate) fn at(self, offset: usize, len: usize) -> JitFailure {
+        JitFailure {
+            error: self,
+            instruction: Some((offset, len)),
+        }
+    }
 }
 
-pub struct RelocThunk<'a> {
+/// A [`JitError`], along with the location of the instruction which caused it if there is one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct JitFailure {
+    pub error: JitError,
+    /// Offset and length of the offending instruction in the relocated code.
+    pub instruction: Option<(usize, usize)>,
+}
+
+impl From<JitError> for JitFailure {
+    fn from(value: JitError) -> Self {
+        Self {
+            error: value,
+            instruction: None,
+        }
+    }
+}
+
+pub(crate) struct RelocThunk<'a> {
     pub thunk: Cow<'a, [u8]>,
     pub magic_offset: usize,
+    /// Ranges of `thunk` which were rewritten, i.e. not copied verbatim from the template.
+    pub rewritten: Vec<Range<usize>>,
+    /// Offsets of the 32-bit IP-relative displacements in the unrelocated prologue. The template
+    /// can be copied verbatim to a thunk close enough to it by adjusting these.
+    pub rel32_fixups: Vec<usize>,
+    /// Set if the prologue could only be used by a thunk close enough to the template, in which
+    /// case `thunk` is the unrelocated prologue.
+    pub far_error: Option<PrologueError>,
 }
 
 /// Relocates the prologue including the thunk_asm, doing sanity checks on the code.
 ///
-/// # Panics
+/// # Errors
 /// If the relocation would lead to broken code.
-pub fn reloc_thunk_template<'a>(
+pub(crate) fn reloc_thunk_template<'a>(
     prologue: &'a [u8],
     ip: usize,
     magic_offset: usize,
-) -> RelocThunk<'a> {
-    try_reloc_thunk_template(prologue, ip, magic_offset).expect(
-        "failed to relocate thunk template prologue. \
-        This is a bug, please report it and include your binary with debug info if possible",
-    )
+) -> Result<RelocThunk<'a>, PrologueError> {
+    try_reloc_thunk_template(prologue, ip, magic_offset)
+        .map_err(|failure| prologue_error(failure, prologue, ip))
+}
+
+/// Converts a failure to relocate the prologue of the thunk template at `ip` into a
+/// [`PrologueError`].
+pub(crate) fn prologue_error(failure: JitFailure, prologue: &[u8], ip: usize) -> PrologueError {
+    let instruction = failure.instruction.map(|(offset, len)| {
+        let end = prologue.len().min(offset + len);
+        (offset, &prologue[offset.min(end)..end])
+    });
+    PrologueError::new(failure.error.into(), ip, instruction)
+}
+
+/// Error returned by [`relocate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RelocError {
+    /// The code contains an invalid instruction, or ends in the middle of an instruction.
+    InvalidInstruction,
+    /// An instruction in the range depends on its address in a way that cannot be relocated.
+    UnsupportedInstruction,
+    /// A branch in the range cannot be relocated, e.g. because it targets the middle of an
+    /// instruction in the range.
+    UnsupportedControlFlow,
+    /// A relocated instruction could not be encoded, e.g. because its target is out of range.
+    EncodingError,
+}
+
+impl fmt::Display for RelocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidInstruction => "invalid or truncated instruction",
+            Self::UnsupportedInstruction => "instruction cannot be relocated",
+            Self::UnsupportedControlFlow => "branch cannot be relocated",
+            Self::EncodingError => "failed to encode relocated instruction",
+        })
+    }
+}
+
+impl core::error::Error for RelocError {}
+
+impl From<JitError> for RelocError {
+    fn from(value: JitError) -> Self {
+        match value {
+            JitError::InvalidInstruction | JitError::PrologueTooSmall => Self::InvalidInstruction,
+            JitError::UnsupportedControlFlow => Self::UnsupportedControlFlow,
+            JitError::EncodingError => Self::EncodingError,
+            JitError::UnsupportedInstruction
+            | JitError::NoAvailableRegister
+            | JitError::NoThunkAsm => Self::UnsupportedInstruction,
+        }
+    }
+}
+
+/// Offsets of a relocated instruction in the original and relocated code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstructionBoundary {
+    /// Offset of the instruction in the original code.
+    pub old_offset: usize,
+    /// Offset of the instruction's replacement in the relocated code.
+    pub new_offset: usize,
+}
+
+/// Code relocated by [`relocate`].
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    code: Vec<u8>,
+    boundaries: Vec<InstructionBoundary>,
+    old_len: usize,
+}
+
+impl Relocation {
+    /// The relocated code.
+    ///
+    /// Execution falls through at the end of it, so the code to run after the relocated
+    /// instructions can be placed right after it. On ARM targets, the relocated instructions may
+    /// be followed by a literal pool, which they branch over.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Returns the relocated code.
+    pub fn into_code(self) -> Vec<u8> {
+        self.code
+    }
+
+    /// The boundaries of the relocated instructions, in order.
+    pub fn boundaries(&self) -> &[InstructionBoundary] {
+        &self.boundaries
+    }
+
+    /// The number of bytes of the original code which were relocated. This is the `len` passed to
+    /// [`relocate`], rounded up to the next instruction boundary.
+    pub fn old_len(&self) -> usize {
+        self.old_len
+    }
+
+    /// Returns the offset in the relocated code of the instruction at `old_offset` in the original
+    /// code, or `None` if `old_offset` is not an instruction boundary.
+    pub fn new_offset(&self, old_offset: usize) -> Option<usize> {
+        self.boundaries
+            .binary_search_by_key(&old_offset, |b| b.old_offset)
+            .ok()
+            .map(|i| self.boundaries[i].new_offset)
+    }
+}
+
+/// Relocates the instructions at the start of `code` so that they can be executed at `new_ip`
+/// instead of `old_ip`.
+///
+/// Whole instructions are relocated until at least `len` bytes are covered, so `code` may need to
+/// extend past `len` for the last instruction to be decoded. Instructions which depend on their
+/// address, such as relative branches or IP-relative loads, are rewritten to use the same absolute
+/// target as in the original code. Branches to an instruction within the relocated range are
+/// redirected to its relocated copy.
+///
+/// # Errors
+/// If the range contains instructions that cannot be relocated. The supported instructions depend
+/// on the target architecture:
+/// - On x86 and x86_64, all relative branches except `loop`, `jcxz` and `xbegin` are supported.
+///   IP-relative memory operands are supported if the relocated code is within 2GB of the accessed
+///   address, or for `lea` and `mov` into a general purpose register.
+/// - On ARM and AArch64, PC-relative loads and address computations are supported, as well as
+///   unconditional branches and calls out of the range on AArch64. Other branches are not.
+pub fn relocate(
+    code: &[u8],
+    old_ip: usize,
+    new_ip: usize,
+    len: usize,
+) -> Result<Relocation, RelocError> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let result = x86_util::relocate(usize::BITS, code, old_ip, new_ip, len);
+
+    #[cfg(target_arch = "aarch64")]
+    let result = aarch64::relocate(code, old_ip, new_ip, len);
+
+    #[cfg(target_arch = "arm")]
+    let result = arm::relocate(code, old_ip, new_ip, len);
+
+    result.map_err(RelocError::from)
 }
@@ -2,8 +2,15 @@ use alloc::vec::Vec;
 
 use iced_x86::{Code, Decoder, DecoderOptions, Encoder, FlowControl, Instruction, Register};
 
-use super::{JitError, RelocThunk};
-use crate::arch::consts;
+use super::{JitError, JitFailure, RelocThunk};
+
+/// Offset of the thunk asm code from the magic constant in the x86 thunk asm.
+///
+/// Mirrors `arch::consts::THUNK_CODE_OFFSET`, which only exists when targeting x86.
+const THUNK_CODE_OFFSET: isize = -16;
+
+#[cfg(target_arch = "x86")]
+const _: () = assert!(THUNK_CODE_OFFSET == crate::arch::consts::THUNK_CODE_OFFSET);
 
 struct CallPop {
     offset: usize,
@@ -16,7 +23,7 @@ pub fn try_reloc_thunk_template<'a>(
     thunk_template: &'a [u8],
     ip: usize,
     magic_offset: usize,
-) -> Result<RelocThunk<'a>, JitError> {
+) -> Result<RelocThunk<'a>, JitFailure> {
     let mut decoder = Decoder::with_ip(32, thunk_template, ip as u64, DecoderOptions::NONE);
 
     let mut instruction = Instruction::default();
@@ -25,14 +32,14 @@ pub fn try_reloc_thunk_template<'a>(
 
     while decoder.can_decode() {
         let offset = decoder.position();
-        if offset == magic_offset.wrapping_add_signed(consts::THUNK_CODE_OFFSET) {
+        if offset == magic_offset.wrapping_add_signed(THUNK_CODE_OFFSET) {
             reached_thunk_asm = true;
             break;
         }
 
         decoder.decode_out(&mut instruction);
         if instruction.is_invalid() {
-            return Err(JitError::InvalidInstruction);
+            return Err(JitError::InvalidInstruction.at(offset, instruction.len()));
         }
 
         if instruction.flow_control() != FlowControl::Next {
@@ -53,18 +60,21 @@ pub fn try_reloc_thunk_template<'a>(
                 }
             }
 
-            return Err(JitError::UnsupportedInstruction);
+            return Err(JitError::UnsupportedInstruction.at(offset, instruction.len()));
         }
     }
 
     if !reached_thunk_asm {
-        return Err(JitError::NoThunkAsm);
+        return Err(JitError::NoThunkAsm.into());
     }
 
     if call_pops.is_empty() {
         return Ok(RelocThunk {
             thunk: thunk_template.into(),
             magic_offset,
+            rewritten: Vec::new(),
+            rel32_fixups: Vec::new(),
+            far_error: None,
         });
     }
 
@@ -76,18 +86,23 @@ pub fn try_reloc_thunk_template<'a>(
 
     let mut offset = 0;
     let mut encoder = Encoder::try_with_capacity(32, required_mem).unwrap();
+    let mut rewritten = Vec::with_capacity(call_pops.len());
 
     for call_pop in call_pops {
         // copy the instruction slice between the call pops
         let mut buf = encoder.take_buffer();
         buf.extend_from_slice(&thunk_template[offset..call_pop.offset]);
+        let new_offset = buf.len();
         encoder.set_buffer(buf);
         offset = call_pop.offset + call_pop.len;
 
         // can't panic (register is from a pop r32 and is thus a 32-bit gpr)
         let mov =
             Instruction::with2(Code::Mov_r32_imm32, call_pop.register, call_pop.target_ip).unwrap();
-        encoder.encode(&mov, 0).map_err(|_| JitError::EncodingError)?;
+        let mov_len = encoder
+            .encode(&mov, 0)
+            .map_err(|_| JitError::EncodingError.at(call_pop.offset, call_pop.len))?;
+        rewritten.push(new_offset..new_offset + mov_len);
     }
 
     // write the remaining slice
@@ -97,5 +112,8 @@ pub fn try_reloc_thunk_template<'a>(
     Ok(RelocThunk {
         magic_offset: magic_offset + new_bytes.len() - thunk_template.len(),
         thunk: new_bytes.into(),
+        rewritten,
+        rel32_fixups: Vec::new(),
+        far_error: None,
     })
 }
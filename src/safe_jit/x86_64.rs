@@ -1,18 +1,26 @@
 use alloc::vec::Vec;
+use core::ops::Range;
 
 use iced_x86::{
     Code, Decoder, DecoderOptions, Encoder, FlowControl, Instruction, InstructionInfoFactory,
     InstructionInfoOptions, OpAccess, Register,
 };
 
-use super::{JitError, RelocThunk};
-use crate::arch::consts;
+use super::{prologue_error, JitError, JitFailure, RelocThunk};
+
+/// Offset of the closure address from the magic constant in the x86_64 thunk asm.
+///
+/// Mirrors `arch::consts::CLOSURE_ADDR_OFFSET`, which only exists when targeting x86_64.
+const CLOSURE_ADDR_OFFSET: isize = 0;
+
+#[cfg(target_arch = "x86_64")]
+const _: () = assert!(CLOSURE_ADDR_OFFSET == crate::arch::consts::CLOSURE_ADDR_OFFSET);
 
 pub fn try_reloc_thunk_template<'a>(
     thunk_template: &'a [u8],
     ip: usize,
     magic_offset: usize,
-) -> Result<RelocThunk<'a>, JitError> {
+) -> Result<RelocThunk<'a>, JitFailure> {
     let ip = ip as u64;
 
     let mut decoder = Decoder::with_ip(64, thunk_template, ip, DecoderOptions::NONE);
@@ -21,14 +29,16 @@ pub fn try_reloc_thunk_template<'a>(
     let mut instruction = Instruction::default();
 
     let mut max_jcc_offset = 0;
-    let mut num_ip_rel_reloc = 0;
+    let mut max_jcc_instruction = None;
+    let mut rel32_fixups = Vec::new();
     let mut thunk_asm_offset = None;
 
     while decoder.can_decode() {
         decoder.decode_out(&mut instruction);
+        let offset = (instruction.ip() - ip) as usize;
 
         if instruction.is_invalid() {
-            return Err(JitError::InvalidInstruction);
+            return Err(JitError::InvalidInstruction.at(offset, instruction.len()));
         }
 
         match instruction.flow_control() {
@@ -38,21 +48,27 @@ pub fn try_reloc_thunk_template<'a>(
             // xmm registers to the stack. So we allow conditional branches, so long as their
             // target is within the prologue (tbd after finding the thunk asm offset).
             FlowControl::ConditionalBranch if instruction.is_jcc_short_or_near() => {
-                let offset = instruction.near_branch_target().wrapping_sub(ip);
-                max_jcc_offset = max_jcc_offset.max(offset);
+                let target_offset = instruction.near_branch_target().wrapping_sub(ip);
+                if target_offset > max_jcc_offset {
+                    max_jcc_offset = target_offset;
+                    max_jcc_instruction = Some((offset, instruction.len()));
+                }
             }
-            _ => return Err(JitError::UnsupportedInstruction),
+            _ => return Err(JitError::UnsupportedInstruction.at(offset, instruction.len())),
         };
 
         let needs_reloc = instruction.is_ip_rel_memory_operand();
         if needs_reloc {
-            let closure_ptr_offset = magic_offset.wrapping_add_signed(consts::CLOSURE_ADDR_OFFSET);
+            let closure_ptr_offset = magic_offset.wrapping_add_signed(CLOSURE_ADDR_OFFSET);
             if (instruction.memory_displacement64() - ip) as usize == closure_ptr_offset {
-                thunk_asm_offset = Some(decoder.position() - instruction.len());
+                thunk_asm_offset = Some(offset);
                 break;
             }
 
-            num_ip_rel_reloc += 1;
+            // RIP-relative operands always have a 32-bit displacement
+            let displacement_offset =
+                decoder.get_constant_offsets(&instruction).displacement_offset();
+            rel32_fixups.push(offset + displacement_offset);
         }
 
         instructions.push((instruction, needs_reloc));
@@ -62,16 +78,61 @@ pub fn try_reloc_thunk_template<'a>(
 
     // Make sure all JCC instructions in the prologue have targets inside of it
     if max_jcc_offset > thunk_asm_offset as u64 {
-        return Err(JitError::UnsupportedControlFlow);
+        return Err(JitFailure {
+            error: JitError::UnsupportedControlFlow,
+            instruction: max_jcc_instruction,
+        });
     }
 
-    if num_ip_rel_reloc == 0 {
+    if rel32_fixups.is_empty() {
         return Ok(RelocThunk {
             thunk: thunk_template.into(),
             magic_offset,
+            rewritten: Vec::new(),
+            rel32_fixups,
+            far_error: None,
         });
     }
 
+    // The template can still be copied verbatim to a thunk close enough to it, so only record the
+    // failure to relocate it anywhere else
+    match reloc_ip_rel_operands(
+        thunk_template,
+        ip,
+        instructions,
+        &instruction,
+        thunk_asm_offset,
+    ) {
+        Ok((new_bytes, rewritten)) => Ok(RelocThunk {
+            magic_offset: magic_offset + new_bytes.len() - thunk_template.len(),
+            thunk: new_bytes.into(),
+            rewritten,
+            rel32_fixups,
+            far_error: None,
+        }),
+        Err(failure) => Ok(RelocThunk {
+            thunk: thunk_template.into(),
+            magic_offset,
+            rewritten: Vec::new(),
+            rel32_fixups,
+            far_error: Some(prologue_error(failure, thunk_template, ip as usize)),
+        }),
+    }
+}
+
+/// Rewrites the RIP-relative operands of the prologue `instructions` to use absolute addresses
+/// loaded in scratch registers, so that the thunk can be placed anywhere. Returns the new code and
+/// the ranges of it which were rewritten.
+///
+/// `ip` is the address of the thunk template, and `thunk_asm` the closure address load found at
+/// `thunk_asm_offset`.
+fn reloc_ip_rel_operands(
+    thunk_template: &[u8],
+    ip: u64,
+    instructions: Vec<(Instruction, bool)>,
+    thunk_asm: &Instruction,
+    thunk_asm_offset: usize,
+) -> Result<(Vec<u8>, Vec<Range<usize>>), JitFailure> {
     // go through the instructions backwards and track the last visible read/write
     // operation on general purpose registers
     //
@@ -85,12 +146,12 @@ pub fn try_reloc_thunk_template<'a>(
     }
 
     // grab the register used to read the closure address the thunk asm
-    decoder.set_position(thunk_asm_offset).unwrap();
-    let cl_read_reg = decoder.decode().op0_register();
+    let cl_read_reg = thunk_asm.op0_register();
 
     let mut gpr_ops = [GprOp::None; 16];
-    gpr_ops[cl_read_reg as usize - Register::RAX as usize] = GprOp::Write(instruction.len());
+    gpr_ops[cl_read_reg as usize - Register::RAX as usize] = GprOp::Write(thunk_asm.len());
 
+    let num_ip_rel_reloc = instructions.iter().filter(|(_, needs_reloc)| *needs_reloc).count();
     let mut info_factory = InstructionInfoFactory::new();
     let mut chosen_registers = Vec::with_capacity(num_ip_rel_reloc);
     for (i, &(instr, needs_reloc)) in instructions.iter().enumerate().rev() {
@@ -115,7 +176,9 @@ pub fn try_reloc_thunk_template<'a>(
             let Some(i_avail_gpr) =
                 gpr_ops.iter().position(|&op| matches!(op, GprOp::Write(w) if w >= i))
             else {
-                return Err(JitError::NoAvailableRegister);
+                return Err(
+                    JitError::NoAvailableRegister.at((instr.ip() - ip) as usize, instr.len())
+                );
             };
             chosen_registers.push(Register::RAX + i_avail_gpr as u32)
         }
@@ -128,25 +191,34 @@ pub fn try_reloc_thunk_template<'a>(
     let mut encoder = Encoder::try_with_capacity(64, min_new_size).unwrap();
 
     let mut offset = 0;
+    let mut new_offset = 0;
+    let mut rewritten = Vec::with_capacity(num_ip_rel_reloc);
     for (mut instr, needs_reloc) in instructions {
         if needs_reloc {
+            let instr_len = instr.len();
+            let encoding_error = |_| JitError::EncodingError.at(offset, instr_len);
+
             // cannot fail as one was pushed for each instr that needs a reloc
             let register = chosen_registers.pop().unwrap();
             let address = instr.memory_displacement64();
 
             // cannot fail as register is a 64-bit gpr
             let mov = Instruction::with2(Code::Mov_r64_imm64, register, address).unwrap();
-            encoder.encode(&mov, 0).map_err(|_| JitError::EncodingError)?;
+            let mov_len = encoder.encode(&mov, 0).map_err(encoding_error)?;
 
             instr.set_memory_base(register);
             instr.set_memory_index(Register::None); // shouldn't be necessary
             instr.set_memory_displacement64(0);
-            encoder.encode(&instr, 0).map_err(|_| JitError::EncodingError)?;
+            let new_len = mov_len + encoder.encode(&instr, 0).map_err(encoding_error)?;
+
+            rewritten.push(new_offset..new_offset + new_len);
+            new_offset += new_len;
         }
         else {
             let mut buffer = encoder.take_buffer();
             buffer.extend_from_slice(&thunk_template[offset..offset + instr.len()]);
             encoder.set_buffer(buffer);
+            new_offset += instr.len();
         }
 
         offset += instr.len();
@@ -155,9 +227,77 @@ pub fn try_reloc_thunk_template<'a>(
     // add the part that includes the thunk_asm block
     let mut new_bytes = encoder.take_buffer();
     new_bytes.extend_from_slice(&thunk_template[thunk_asm_offset..]);
+    Ok((new_bytes, rewritten))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(RelocThunk {
-        magic_offset: magic_offset + new_bytes.len() - thunk_template.len(),
-        thunk: new_bytes.into(),
-    })
+    #[test]
+    fn x86_64_reloc_error_location() {
+        // push rbp; call $+5; mov rax, [rip]
+        let code = [0x55, 0xE8, 0, 0, 0, 0, 0x48, 0x8B, 0x05, 0, 0, 0, 0];
+        let failure = try_reloc_thunk_template(&code, 0x1000, code.len()).err().unwrap();
+        assert_eq!(failure, JitError::UnsupportedInstruction.at(1, 5));
+
+        // push rbp; nop
+        let failure = try_reloc_thunk_template(&[0x55, 0x90], 0x1000, 16).err().unwrap();
+        assert_eq!(failure, JitError::NoThunkAsm.into());
+    }
+
+    #[test]
+    fn x86_64_rel32_fixups() {
+        // mov rcx, [rip + 0x10]; mov rax, [rip]
+        let code = [
+            0x48, 0x8B, 0x0D, 0x10, 0, 0, 0, 0x48, 0x8B, 0x05, 0, 0, 0, 0,
+        ];
+        let reloc = try_reloc_thunk_template(&code, 0x1000, code.len()).unwrap();
+        assert_eq!(reloc.rel32_fixups, [3]);
+        assert!(reloc.far_error.is_none());
+        assert!(reloc.thunk.len() > code.len());
+
+        // Only the first instruction is rewritten, the second being the thunk asm
+        let rewritten_len = reloc.thunk.len() - (code.len() - 7);
+        assert_eq!(reloc.rewritten, alloc::vec![0..rewritten_len]);
+        assert_eq!(&reloc.thunk[rewritten_len..], &code[7..]);
+    }
+
+    #[test]
+    fn x86_64_near_only_prologue() {
+        use crate::error::PrologueErrorKind;
+
+        // cmp qword ptr [rip + 0x10], 0; add rax, rax; mov rax, [rip]
+        let code = [
+            0x48, 0x83, 0x3D, 0x10, 0, 0, 0, 0, 0x48, 0x01, 0xC0, 0x48, 0x8B, 0x05, 0, 0, 0, 0,
+        ];
+        let reloc = try_reloc_thunk_template(&code, 0x1000, code.len()).unwrap();
+        assert_eq!(reloc.rel32_fixups, [3]);
+        assert_eq!(reloc.thunk, &code[..]);
+        assert_eq!(reloc.magic_offset, code.len());
+
+        let error = reloc.far_error.unwrap();
+        assert_eq!(error.kind(), PrologueErrorKind::NoAvailableRegister);
+        assert_eq!(error.offset(), Some(0));
+        assert_eq!(error.instruction_bytes(), Some(&code[..8]));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn x86_64_prologue_error() {
+        use crate::error::PrologueErrorKind;
+
+        // push rbp; call $+5; mov rax, [rip]
+        let code = [0x55, 0xE8, 0, 0, 0, 0, 0x48, 0x8B, 0x05, 0, 0, 0, 0];
+        let error = super::super::reloc_thunk_template(&code, 0x1000, code.len()).err().unwrap();
+        assert_eq!(error.kind(), PrologueErrorKind::UnsupportedInstruction);
+        assert_eq!(error.template_addr(), 0x1000);
+        assert_eq!(error.offset(), Some(1));
+        assert_eq!(error.instruction_bytes(), Some(&code[1..6]));
+        assert_eq!(
+            alloc::format!("{error}"),
+            "failed to relocate thunk template prologue at 0x1000: unsupported instruction at \
+            offset 0x1 (bytes: e8 00 00 00 00). This is a bug, please report it"
+        );
+    }
 }
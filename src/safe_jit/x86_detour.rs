@@ -0,0 +1,144 @@
+use alloc::vec::Vec;
+
+use iced_x86::{Decoder, DecoderOptions, FlowControl, Instruction};
+
+use super::{x86_util, JitError};
+
+const BITNESS: u32 = usize::BITS;
+
+/// Size of a `jmp rel32` instruction.
+const JMP_REL32_SIZE: usize = 5;
+
+/// Size of a `jmp qword ptr [rip]` instruction followed by the absolute target address.
+#[cfg(target_arch = "x86_64")]
+const JMP_ABS64_SIZE: usize = 14;
+
+/// Maximum size of an x86 instruction.
+const MAX_INSTRUCTION_SIZE: usize = 15;
+
+/// Decodes the instruction at the start of `code`, located at `ip`.
+fn decode_one(code: &[u8], ip: usize) -> Result<Instruction, JitError> {
+    let mut decoder = Decoder::with_ip(BITNESS, code, ip as u64, DecoderOptions::NONE);
+    let instruction = decoder.decode();
+    match instruction.is_invalid() {
+        true => Err(JitError::InvalidInstruction),
+        false => Ok(instruction),
+    }
+}
+
+/// Instructions stolen from the start of a function to make room for a jump to a hook.
+pub struct StolenPrologue {
+    instructions: Vec<Instruction>,
+    code: Vec<u8>,
+    ip: usize,
+    len: usize,
+}
+
+/// Returns the number of bytes [`encode_jump`] will emit for a jump from `from` to `to`.
+pub fn jump_size(from: usize, to: usize) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    if i32::try_from(to.wrapping_sub(from + JMP_REL32_SIZE) as isize).is_err() {
+        return JMP_ABS64_SIZE;
+    }
+    let _ = (from, to);
+    JMP_REL32_SIZE
+}
+
+/// Encodes an unconditional jump from `from` to `to`.
+pub fn encode_jump(from: usize, to: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(jump_size(from, to));
+
+    #[cfg(target_arch = "x86_64")]
+    if jump_size(from, to) == JMP_ABS64_SIZE {
+        // jmp qword ptr [rip]
+        bytes.extend_from_slice(&[0xFF, 0x25, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&(to as u64).to_le_bytes());
+        return bytes;
+    }
+
+    let rel = to.wrapping_sub(from + JMP_REL32_SIZE) as u32;
+    bytes.push(0xE9);
+    bytes.extend_from_slice(&rel.to_le_bytes());
+    bytes
+}
+
+impl StolenPrologue {
+    /// Decodes the instructions of the function at `ip` one at a time, until at least `min_len`
+    /// bytes are covered.
+    ///
+    /// `read(addr, len)` must return the `len` bytes of code at `addr`. To avoid reading past the
+    /// end of the function's mapping, only the bytes up to the end of the page of size
+    /// `page_size` containing an instruction are read, unless the instruction is cut off by the
+    /// page boundary, in which case the function continues on the next page.
+    pub fn decode<'a>(
+        ip: usize,
+        min_len: usize,
+        page_size: usize,
+        mut read: impl FnMut(usize, usize) -> &'a [u8],
+    ) -> Result<Self, JitError> {
+        let mut instructions = Vec::new();
+        let mut code = Vec::new();
+        let mut len = 0;
+        while len < min_len {
+            let addr = ip + len;
+            let to_page_end = page_size - addr % page_size;
+
+            let mut instruction =
+                decode_one(read(addr, MAX_INSTRUCTION_SIZE.min(to_page_end)), addr);
+            if instruction.is_err() && to_page_end < MAX_INSTRUCTION_SIZE {
+                instruction = decode_one(read(addr, MAX_INSTRUCTION_SIZE), addr);
+            }
+            let instruction = instruction?;
+
+            code.extend_from_slice(&read(addr, instruction.len())[..instruction.len()]);
+            len += instruction.len();
+
+            match instruction.flow_control() {
+                FlowControl::Next | FlowControl::ConditionalBranch | FlowControl::Call
+                    if !instruction.is_call_far() => {}
+                // The function ends here, so the bytes that follow may not belong to it
+                FlowControl::UnconditionalBranch | FlowControl::IndirectBranch
+                    if !instruction.is_jmp_far() && !instruction.is_jmp_far_indirect() =>
+                {
+                    if len < min_len {
+                        return Err(JitError::PrologueTooSmall);
+                    }
+                }
+                FlowControl::Return | FlowControl::Interrupt => {
+                    return Err(JitError::PrologueTooSmall)
+                }
+                _ => return Err(JitError::UnsupportedInstruction),
+            }
+
+            instructions.push(instruction);
+        }
+
+        Ok(Self {
+            instructions,
+            code,
+            ip,
+            len,
+        })
+    }
+
+    /// The bytes of the stolen instructions.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// The number of bytes stolen from the function.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Encodes a trampoline which executes the stolen instructions and jumps back to the rest of
+    /// the function, to be placed at `new_ip`.
+    pub fn encode_trampoline(&self, new_ip: usize) -> Result<Vec<u8>, JitError> {
+        let mut trampoline =
+            x86_util::encode(BITNESS, &self.code, &self.instructions, new_ip)?.into_code();
+
+        let jmp_ip = new_ip + trampoline.len();
+        trampoline.extend_from_slice(&encode_jump(jmp_ip, self.ip + self.len));
+        Ok(trampoline)
+    }
+}
@@ -0,0 +1,423 @@
+use alloc::vec::Vec;
+
+use iced_x86::{Code, Decoder, DecoderOptions, Encoder, FlowControl, Instruction, Register};
+
+use super::{InstructionBoundary, JitError, Relocation};
+
+/// `jmp qword ptr [rip]`, to be followed by the absolute target address.
+const JMP_ABS64: [u8; 6] = [0xFF, 0x25, 0, 0, 0, 0];
+
+/// `call qword ptr [rip+2]; jmp $+10`, to be followed by the absolute target address.
+const CALL_ABS64: [u8; 8] = [0xFF, 0x15, 2, 0, 0, 0, 0xEB, 8];
+
+/// Upper bound on the size of a relocated instruction, used to check if IP-relative operands will
+/// still be reachable from the relocated code.
+const MAX_RELOCATED_SIZE: u64 = 32;
+
+/// How a single instruction is relocated.
+#[derive(Clone, Copy)]
+enum Reloc {
+    /// The instruction does not depend on its address and is copied as-is.
+    Copy,
+    /// The instruction has an IP-relative operand which is still reachable, and is re-encoded at
+    /// its new address.
+    Reencode,
+    /// Near branch to the relocated instruction at the given index.
+    BranchInternal(usize),
+    /// Near branch to an address outside of the relocated range.
+    BranchNear,
+    /// Branch to an address outside of the relocated range which is more than 2GB away from the
+    /// relocated code (x86_64 only).
+    BranchFar,
+    /// `call $+len`, the "get pc" idiom of x86 code. Replaced by a push of the original return
+    /// address.
+    PushReturn,
+    /// IP-relative `lea` or `mov` into a general purpose register whose operand is more than 2GB
+    /// away from the relocated code. Replaced by a load through an absolute address.
+    AbsoluteLoad,
+}
+
+/// Decodes the instructions at the start of `code`, located at `ip`, until at least `len` bytes
+/// are covered.
+pub fn decode(
+    bitness: u32,
+    code: &[u8],
+    ip: usize,
+    len: usize,
+) -> Result<Vec<Instruction>, JitError> {
+    let mut decoder = Decoder::with_ip(bitness, code, ip as u64, DecoderOptions::NONE);
+
+    let mut instructions = Vec::new();
+    while decoder.position() < len {
+        if !decoder.can_decode() {
+            return Err(JitError::InvalidInstruction);
+        }
+
+        let instruction = decoder.decode();
+        if instruction.is_invalid() {
+            return Err(JitError::InvalidInstruction);
+        }
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+/// Relocates the instructions at the start of `code`, located at `old_ip`, until at least `len`
+/// bytes are covered.
+pub fn relocate(
+    bitness: u32,
+    code: &[u8],
+    old_ip: usize,
+    new_ip: usize,
+    len: usize,
+) -> Result<Relocation, JitError> {
+    let instructions = decode(bitness, code, old_ip, len)?;
+    encode(bitness, code, &instructions, new_ip)
+}
+
+/// Encodes `instructions`, which were decoded from `code`, so that they can be executed at
+/// `new_ip`.
+pub fn encode(
+    bitness: u32,
+    code: &[u8],
+    instructions: &[Instruction],
+    new_ip: usize,
+) -> Result<Relocation, JitError> {
+    let (Some(first), Some(last)) = (instructions.first(), instructions.last())
+    else {
+        return Ok(Relocation {
+            code: Vec::new(),
+            boundaries: Vec::new(),
+            old_len: 0,
+        });
+    };
+
+    let old_range = first.ip()..last.next_ip();
+    let new_ip = new_ip as u64;
+
+    // Be conservative and check that the target is reachable from anywhere in the relocated code
+    let new_end = new_ip + MAX_RELOCATED_SIZE * instructions.len() as u64;
+    let is_near =
+        |target: u64| bitness != 64 || (fits_rel32(new_ip, target) && fits_rel32(new_end, target));
+
+    let mut relocs = Vec::with_capacity(instructions.len());
+    for instruction in instructions {
+        let reloc = match instruction.flow_control() {
+            FlowControl::UnconditionalBranch
+            | FlowControl::ConditionalBranch
+            | FlowControl::Call => {
+                if !instruction.is_jmp_short_or_near()
+                    && !instruction.is_jcc_short_or_near()
+                    && !instruction.is_call_near()
+                {
+                    return Err(JitError::UnsupportedControlFlow);
+                }
+
+                let target = instruction.near_branch_target();
+                if bitness == 32 && instruction.is_call_near() && target == instruction.next_ip() {
+                    Reloc::PushReturn
+                }
+                else if old_range.contains(&target) {
+                    instructions
+                        .binary_search_by_key(&target, |i| i.ip())
+                        .map(Reloc::BranchInternal)
+                        .map_err(|_| JitError::UnsupportedControlFlow)?
+                }
+                else if is_near(target) {
+                    Reloc::BranchNear
+                }
+                else {
+                    Reloc::BranchFar
+                }
+            }
+            FlowControl::XbeginXabortXend
+                if matches!(instruction.code(), Code::Xbegin_rel16 | Code::Xbegin_rel32) =>
+            {
+                return Err(JitError::UnsupportedControlFlow);
+            }
+            _ if instruction.is_ip_rel_memory_operand() => {
+                if is_near(instruction.ip_rel_memory_address()) {
+                    Reloc::Reencode
+                }
+                else if matches!(
+                    instruction.code(),
+                    Code::Lea_r64_m | Code::Mov_r64_rm64 | Code::Mov_r32_rm32
+                ) {
+                    Reloc::AbsoluteLoad
+                }
+                else {
+                    return Err(JitError::UnsupportedInstruction);
+                }
+            }
+            _ => Reloc::Copy,
+        };
+        relocs.push(reloc);
+    }
+
+    // The size of each relocated instruction does not depend on its branch target, so we can
+    // compute the new offsets with placeholder targets first
+    let mut new_offsets = Vec::with_capacity(instructions.len());
+    let mut buffer = Vec::new();
+    for (instruction, &reloc) in instructions.iter().zip(&relocs) {
+        new_offsets.push(buffer.len());
+        let rip = new_ip + buffer.len() as u64;
+        emit(
+            bitness,
+            code,
+            first.ip(),
+            instruction,
+            reloc,
+            rip,
+            rip,
+            &mut buffer,
+        )?;
+    }
+
+    let mut new_code = Vec::with_capacity(buffer.len());
+    let mut boundaries = Vec::with_capacity(instructions.len());
+    for (i, (instruction, &reloc)) in instructions.iter().zip(&relocs).enumerate() {
+        if new_code.len() != new_offsets[i] {
+            return Err(JitError::EncodingError);
+        }
+
+        boundaries.push(InstructionBoundary {
+            old_offset: (instruction.ip() - first.ip()) as usize,
+            new_offset: new_code.len(),
+        });
+
+        let rip = new_ip + new_code.len() as u64;
+        let internal_target = match reloc {
+            Reloc::BranchInternal(index) => new_ip + new_offsets[index] as u64,
+            _ => rip,
+        };
+        emit(
+            bitness,
+            code,
+            first.ip(),
+            instruction,
+            reloc,
+            rip,
+            internal_target,
+            &mut new_code,
+        )?;
+    }
+
+    Ok(Relocation {
+        code: new_code,
+        boundaries,
+        old_len: (old_range.end - old_range.start) as usize,
+    })
+}
+
+/// Returns `true` if `to` can be reached by a 32-bit displacement relative to `from`.
+fn fits_rel32(from: u64, to: u64) -> bool {
+    i32::try_from(to.wrapping_sub(from) as i64).is_ok()
+}
+
+/// Writes the relocated version of `instruction` at `rip` to `out`.
+#[allow(clippy::too_many_arguments)]
+fn emit(
+    bitness: u32,
+    code: &[u8],
+    code_ip: u64,
+    instruction: &Instruction,
+    reloc: Reloc,
+    rip: u64,
+    internal_target: u64,
+    out: &mut Vec<u8>,
+) -> Result<(), JitError> {
+    let encode = |instruction: &Instruction, rip: u64, out: &mut Vec<u8>| {
+        let mut encoder = Encoder::new(bitness);
+        encoder.encode(instruction, rip).map_err(|_| JitError::EncodingError)?;
+        out.extend_from_slice(&encoder.take_buffer());
+        Ok::<_, JitError>(())
+    };
+    let branch = |code: Code, target: u64| {
+        Instruction::with_branch(code, target).map_err(|_| JitError::EncodingError)
+    };
+
+    match reloc {
+        Reloc::Copy => {
+            let offset = (instruction.ip() - code_ip) as usize;
+            out.extend_from_slice(&code[offset..offset + instruction.len()]);
+        }
+        Reloc::Reencode => encode(instruction, rip, out)?,
+        Reloc::BranchInternal(_) | Reloc::BranchNear => {
+            let target = match reloc {
+                Reloc::BranchInternal(_) => internal_target,
+                _ => instruction.near_branch_target(),
+            };
+            let mut near = *instruction;
+            near.as_near_branch();
+            encode(&branch(near.code(), target)?, rip, out)?;
+        }
+        Reloc::BranchFar => {
+            if instruction.is_call_near() {
+                out.extend_from_slice(&CALL_ABS64);
+            }
+            else {
+                if instruction.is_jcc_short_or_near() {
+                    // Skip over the absolute jump if the condition is false
+                    let mut skip = *instruction;
+                    skip.negate_condition_code();
+                    skip.as_short_branch();
+                    let skip_len = 2 + JMP_ABS64.len() + 8;
+                    encode(&branch(skip.code(), rip + skip_len as u64)?, rip, out)?;
+                }
+                out.extend_from_slice(&JMP_ABS64);
+            }
+            out.extend_from_slice(&instruction.near_branch_target().to_le_bytes());
+        }
+        Reloc::PushReturn => {
+            let push = Instruction::with1(Code::Pushd_imm32, instruction.next_ip32())
+                .map_err(|_| JitError::EncodingError)?;
+            encode(&push, rip, out)?;
+        }
+        Reloc::AbsoluteLoad => {
+            let register = instruction.op0_register().full_register();
+            let mov = Instruction::with2(
+                Code::Mov_r64_imm64,
+                register,
+                instruction.ip_rel_memory_address(),
+            )
+            .map_err(|_| JitError::EncodingError)?;
+            encode(&mov, rip, out)?;
+
+            if instruction.code() != Code::Lea_r64_m {
+                let mut load = *instruction;
+                load.set_memory_base(register);
+                load.set_memory_index(Register::None);
+                load.set_memory_displacement64(0);
+                encode(&load, rip, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bitness: u32, code: &[u8], ip: usize) -> Vec<Instruction> {
+        Decoder::with_ip(bitness, code, ip as u64, DecoderOptions::NONE)
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn x86_64_reloc_copy() {
+        // push rbp; mov rbp, rsp; sub rsp, 0x20; int3
+        let code = b"\x55\x48\x89\xe5\x48\x83\xec\x20\xcc";
+        let reloc = relocate(64, code, 0x1000, 0x7000_0000_0000, 5).unwrap();
+
+        assert_eq!(reloc.code(), &code[..8]);
+        assert_eq!(reloc.old_len(), 8);
+        let old_offsets: Vec<_> = reloc.boundaries().iter().map(|b| b.old_offset).collect();
+        assert_eq!(old_offsets, [0, 1, 4]);
+        assert_eq!(reloc.new_offset(4), Some(4));
+        assert_eq!(reloc.new_offset(2), None);
+    }
+
+    #[test]
+    fn x86_64_reloc_ip_relative() {
+        // lea rax, [rip+0x100]; mov ecx, [rip+0x200]
+        let code = b"\x48\x8d\x05\x00\x01\x00\x00\x8b\x0d\x00\x02\x00\x00";
+        let old_ip = 0x1000;
+
+        // Near: re-encoded with a new displacement
+        let reloc = relocate(64, code, old_ip, 0x5000, code.len()).unwrap();
+        let new = decode_all(64, reloc.code(), 0x5000);
+        assert_eq!(new.len(), 2);
+        assert_eq!(new[0].code(), Code::Lea_r64_m);
+        assert_eq!(new[0].ip_rel_memory_address(), 0x1107);
+        assert_eq!(new[1].code(), Code::Mov_r32_rm32);
+        assert_eq!(new[1].ip_rel_memory_address(), 0x120d);
+
+        // Far: loaded through an absolute address
+        let new_ip = 0x7000_0000_0000;
+        let reloc = relocate(64, code, old_ip, new_ip, code.len()).unwrap();
+        let new = decode_all(64, reloc.code(), new_ip);
+        assert_eq!(new.len(), 3);
+        assert_eq!(new[0].code(), Code::Mov_r64_imm64);
+        assert_eq!(new[0].op0_register(), Register::RAX);
+        assert_eq!(new[0].immediate64(), 0x1107);
+        assert_eq!(new[1].code(), Code::Mov_r64_imm64);
+        assert_eq!(new[1].op0_register(), Register::RCX);
+        assert_eq!(new[1].immediate64(), 0x120d);
+        assert_eq!(new[2].code(), Code::Mov_r32_rm32);
+        assert_eq!(new[2].memory_base(), Register::RCX);
+        assert_eq!(reloc.new_offset(7), Some(10));
+    }
+
+    #[test]
+    fn x86_64_reloc_branches() {
+        // 0: test eax, eax
+        // 2: je 0x10 (internal)
+        // 4: jne 0x2000 (external)
+        // 10: call 0x3000
+        // 15: jmp 2 (internal)
+        // 17: nop
+        let code = b"\x85\xc0\x74\x0d\x0f\x85\xf6\x0f\x00\x00\xe8\xf1\x1f\x00\x00\xeb\xf1\x90";
+        let old_ip = 0x1000;
+
+        let new_ip = 0x4000;
+        let reloc = relocate(64, code, old_ip, new_ip, code.len()).unwrap();
+        let new = decode_all(64, reloc.code(), new_ip);
+        assert_eq!(new.len(), 6);
+        assert_eq!(
+            new[1].near_branch_target(),
+            (new_ip + reloc.new_offset(0x11).unwrap()) as u64
+        );
+        assert_eq!(new[2].near_branch_target(), 0x2000);
+        assert_eq!(new[3].near_branch_target(), 0x3000);
+        assert_eq!(
+            new[4].near_branch_target(),
+            (new_ip + reloc.new_offset(2).unwrap()) as u64
+        );
+
+        // Far targets use absolute jumps
+        let new_ip = 0x7000_0000_0000;
+        let reloc = relocate(64, code, old_ip, new_ip, code.len()).unwrap();
+        let jne = reloc.new_offset(4).unwrap();
+        let call = reloc.new_offset(10).unwrap();
+        assert_eq!(reloc.code()[jne], 0x74); // je (inverted jne) over the absolute jump
+        assert_eq!(reloc.code()[jne + 2..jne + 8], JMP_ABS64);
+        assert_eq!(reloc.code()[jne + 8..jne + 16], 0x2000u64.to_le_bytes());
+        assert_eq!(reloc.code()[call..call + 8], CALL_ABS64);
+        assert_eq!(reloc.code()[call + 8..call + 16], 0x3000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn x86_reloc_get_pc() {
+        // call $+5; pop eax; add eax, 0x10
+        let code = b"\xe8\x00\x00\x00\x00\x58\x83\xc0\x10";
+        let reloc = relocate(32, code, 0x1000, 0x8000, code.len()).unwrap();
+        let new = decode_all(32, reloc.code(), 0x8000);
+
+        assert_eq!(new[0].code(), Code::Pushd_imm32);
+        assert_eq!(new[0].immediate32(), 0x1005);
+        assert_eq!(new[1].code(), Code::Pop_r32);
+        assert_eq!(reloc.code()[5..], code[5..]);
+    }
+
+    #[test]
+    fn x86_reloc_errors() {
+        // truncated mov eax, imm32
+        assert_eq!(
+            relocate(32, b"\xb8\x00\x00", 0, 0, 1).unwrap_err(),
+            JitError::InvalidInstruction
+        );
+        // loop $
+        assert_eq!(
+            relocate(32, b"\xe2\xfe", 0, 0, 1).unwrap_err(),
+            JitError::UnsupportedControlFlow
+        );
+        // jmp into the middle of the following instruction
+        let code = b"\xeb\x01\xb8\x00\x00\x00\x00";
+        assert_eq!(
+            relocate(32, code, 0, 0, code.len()).unwrap_err(),
+            JitError::UnsupportedControlFlow
+        );
+    }
+}
@@ -1,7 +1,32 @@
 //! Provides factory functions for creating [`FnThunk`] implementations from a closure while
 //! preserving its [Sync]/[Send]ness.
+//!
+//! Also provides combinators which adapt an existing [`FnThunk`] implementation to another bare
+//! function signature: [`bind_first`] and [`bind_last`] fix an argument, [`ignore_arg`] adds an
+//! unused one, and [`map_args`] and [`map_ret`] convert the arguments or return value.
+//!
+//! [`ThunkLayer`]s wrap an existing [`FnThunk`] implementation with hooks run before and after
+//! each call, and compose into stacks. [`layer`] applies one, and [`layer_exclusive`] turns a
+//! [`FnMutThunk`] implementation into a [`FnThunk`] one using an [`ExclusiveLayer`]. Provided
+//! layers are [`CountCalls`], and with the `std` feature [`Synchronized`] and [`TimeCalls`].
+//!
+//! With the `std` feature, also provides the [`catch_panic`] family of combinators which wrap an
+//! existing [`FnThunk`] implementation to apply a [`PanicPolicy`] to panics escaping from it, and
+//! [`make_mut_checked`] which applies a [`ReentrancyPolicy`] to recursive or concurrent calls to
+//! a [`FnMutThunk`] implementation. [`thread_bound`] similarly applies a [`ThreadAffinityPolicy`]
+//! to calls made from another thread than the one which created the thunk.
 
-use crate::traits::{FnMutThunk, FnOnceThunk, FnPtr, FnThunk, PackedFn, PackedFnMut, PackedFnOnce};
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::traits::{
+    FnMutThunk, FnOnceThunk, FnPtr, FnThunk, PackedFn, PackedFnMut, PackedFnOnce, TupleRemove,
+    TupleRemoveLast,
+};
 
 // SAFETY: Using the `SendSyncWrapper` type below is only unsound if `FnPtr::make_thunk` and friends
 // do not preserve the send/syncness of `fun` in the generated thunks. Since the opaque thunk impl
@@ -142,6 +167,1164 @@ where
     SendSyncWrapper(thunk)
 }
 
+/// Trait alias for [`Fn(B2::Args<'a, 'b, 'c>) -> B::Args<'a, 'b, 'c>`](Fn).
+///
+/// This is necessary to express the bounds of [`map_args`].
+pub trait ArgsMapper<'a, 'b, 'c, B2: FnPtr, B: FnPtr>:
+    Fn(B2::Args<'a, 'b, 'c>) -> B::Args<'a, 'b, 'c>
+{
+}
+
+impl<'a, 'b, 'c, B2: FnPtr, B: FnPtr, F> ArgsMapper<'a, 'b, 'c, B2, B> for F where
+    F: Fn(B2::Args<'a, 'b, 'c>) -> B::Args<'a, 'b, 'c>
+{
+}
+
+/// Trait alias for [`Fn(B::Ret<'a, 'b, 'c>) -> B2::Ret<'a, 'b, 'c>`](Fn).
+///
+/// This is necessary to express the bounds of [`map_ret`].
+pub trait RetMapper<'a, 'b, 'c, B: FnPtr, B2: FnPtr>:
+    Fn(B::Ret<'a, 'b, 'c>) -> B2::Ret<'a, 'b, 'c>
+{
+}
+
+impl<'a, 'b, 'c, B: FnPtr, B2: FnPtr, F> RetMapper<'a, 'b, 'c, B, B2> for F where
+    F: Fn(B::Ret<'a, 'b, 'c>) -> B2::Ret<'a, 'b, 'c>
+{
+}
+
+/// Wraps a [`FnThunk`] implementation for `B` into one for `B2`, which takes the same arguments
+/// except for the first, always passing a clone of `value` in its place.
+///
+/// The bare function types cannot be inferred from the returned implementation, so they must be
+/// specified. They may use different calling conventions.
+///
+/// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `value` are.
+///
+/// ```
+/// # #[cfg(feature = "default_jit_alloc")] {
+/// use closure_ffi::{cc, thunk_factory::bind_first, BareFn};
+///
+/// struct Context {
+///     scale: u32,
+/// }
+///
+/// let thunk = bind_first::<
+///     unsafe extern "C" fn(&'static Context, u32) -> u32,
+///     unsafe extern "C" fn(u32) -> u32,
+///     _,
+///     _,
+/// >(
+///     (cc::C, |ctx: &Context, n: u32| ctx.scale * n),
+///     &Context { scale: 3 },
+/// );
+/// let bare_closure = BareFn::with_thunk(thunk);
+///
+/// assert_eq!(unsafe { bare_closure.bare()(5) }, 15);
+/// # }
+/// ```
+#[inline(always)]
+pub fn bind_first<B: FnPtr, B2, T, A>(thunk: T, value: A) -> impl FnThunk<B2>
+where
+    T: FnThunk<B>,
+    A: Clone,
+    for<'a, 'b, 'c> B::Args<'a, 'b, 'c>: TupleRemove<0, B2::Args<'a, 'b, 'c>, Removed = A>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_thunk(move |args| unsafe {
+        thunk.call(TupleRemove::<0, _>::insert(args, value.clone()))
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, A)>)
+}
+
+/// Same as [`bind_first`], for [`FnMutThunk`] implementations.
+#[inline(always)]
+pub fn bind_first_mut<B: FnPtr, B2, T, A>(mut thunk: T, value: A) -> impl FnMutThunk<B2>
+where
+    T: FnMutThunk<B>,
+    A: Clone,
+    for<'a, 'b, 'c> B::Args<'a, 'b, 'c>: TupleRemove<0, B2::Args<'a, 'b, 'c>, Removed = A>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_mut_thunk(move |args| unsafe {
+        thunk.call_mut(TupleRemove::<0, _>::insert(args, value.clone()))
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, A)>)
+}
+
+/// Same as [`bind_first`], for [`FnOnceThunk`] implementations. `value` is moved into the call,
+/// so it does not need to be [`Clone`].
+#[inline(always)]
+pub fn bind_first_once<B: FnPtr, B2, T, A>(thunk: T, value: A) -> impl FnOnceThunk<B2>
+where
+    T: FnOnceThunk<B>,
+    for<'a, 'b, 'c> B::Args<'a, 'b, 'c>: TupleRemove<0, B2::Args<'a, 'b, 'c>, Removed = A>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_once_thunk(move |args| unsafe {
+        thunk.call_once(TupleRemove::<0, _>::insert(args, value))
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, A)>)
+}
+
+/// Wraps a [`FnThunk`] implementation for `B` into one for `B2`, which takes the same arguments
+/// except for the last, always passing a clone of `value` in its place.
+///
+/// This is [`bind_first`] for the last argument. The same remarks apply.
+#[inline(always)]
+pub fn bind_last<B: FnPtr, B2, T, A>(thunk: T, value: A) -> impl FnThunk<B2>
+where
+    T: FnThunk<B>,
+    A: Clone,
+    for<'a, 'b, 'c> B::Args<'a, 'b, 'c>: TupleRemoveLast<B2::Args<'a, 'b, 'c>, Removed = A>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_thunk(move |args| unsafe {
+        thunk.call(TupleRemoveLast::insert_last(args, value.clone()))
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, A)>)
+}
+
+/// Same as [`bind_last`], for [`FnMutThunk`] implementations.
+#[inline(always)]
+pub fn bind_last_mut<B: FnPtr, B2, T, A>(mut thunk: T, value: A) -> impl FnMutThunk<B2>
+where
+    T: FnMutThunk<B>,
+    A: Clone,
+    for<'a, 'b, 'c> B::Args<'a, 'b, 'c>: TupleRemoveLast<B2::Args<'a, 'b, 'c>, Removed = A>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_mut_thunk(move |args| unsafe {
+        thunk.call_mut(TupleRemoveLast::insert_last(args, value.clone()))
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, A)>)
+}
+
+/// Same as [`bind_last`], for [`FnOnceThunk`] implementations. `value` is moved into the call,
+/// so it does not need to be [`Clone`].
+#[inline(always)]
+pub fn bind_last_once<B: FnPtr, B2, T, A>(thunk: T, value: A) -> impl FnOnceThunk<B2>
+where
+    T: FnOnceThunk<B>,
+    for<'a, 'b, 'c> B::Args<'a, 'b, 'c>: TupleRemoveLast<B2::Args<'a, 'b, 'c>, Removed = A>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_once_thunk(move |args| unsafe {
+        thunk.call_once(TupleRemoveLast::insert_last(args, value))
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, A)>)
+}
+
+/// Wraps a [`FnThunk`] implementation for `B` into one for `B2`, which takes an additional
+/// argument at index `N`. The argument is dropped, and the others are passed to `thunk`.
+///
+/// This is useful to implement a C prototype with parameters the closure does not need, such as
+/// a user data pointer:
+///
+/// ```
+/// # #[cfg(feature = "default_jit_alloc")] {
+/// use core::ffi::c_void;
+///
+/// use closure_ffi::{cc, thunk_factory::ignore_arg, BareFn};
+///
+/// let offset = 10;
+/// let thunk = ignore_arg::<
+///     1,
+///     unsafe extern "C" fn(u32) -> u32,
+///     unsafe extern "C" fn(u32, *mut c_void) -> u32,
+///     _,
+/// >((cc::C, move |n: u32| n + offset));
+/// let bare_closure = BareFn::with_thunk(thunk);
+///
+/// assert_eq!(unsafe { bare_closure.bare()(5, core::ptr::null_mut()) }, 15);
+/// # }
+/// ```
+///
+/// The returned implementation is [`Send`] and [`Sync`] if `thunk` is.
+#[inline(always)]
+pub fn ignore_arg<const N: usize, B: FnPtr, B2, T>(thunk: T) -> impl FnThunk<B2>
+where
+    T: FnThunk<B>,
+    for<'a, 'b, 'c> B2::Args<'a, 'b, 'c>: TupleRemove<N, B::Args<'a, 'b, 'c>>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped =
+        B2::make_thunk(move |args| unsafe { thunk.call(TupleRemove::<N, _>::remove(args).1) });
+    InheritSendSync(wrapped, PhantomData::<T>)
+}
+
+/// Same as [`ignore_arg`], for [`FnMutThunk`] implementations.
+#[inline(always)]
+pub fn ignore_arg_mut<const N: usize, B: FnPtr, B2, T>(mut thunk: T) -> impl FnMutThunk<B2>
+where
+    T: FnMutThunk<B>,
+    for<'a, 'b, 'c> B2::Args<'a, 'b, 'c>: TupleRemove<N, B::Args<'a, 'b, 'c>>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_mut_thunk(move |args| unsafe {
+        thunk.call_mut(TupleRemove::<N, _>::remove(args).1)
+    });
+    InheritSendSync(wrapped, PhantomData::<T>)
+}
+
+/// Same as [`ignore_arg`], for [`FnOnceThunk`] implementations.
+#[inline(always)]
+pub fn ignore_arg_once<const N: usize, B: FnPtr, B2, T>(thunk: T) -> impl FnOnceThunk<B2>
+where
+    T: FnOnceThunk<B>,
+    for<'a, 'b, 'c> B2::Args<'a, 'b, 'c>: TupleRemove<N, B::Args<'a, 'b, 'c>>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_once_thunk(move |args| unsafe {
+        thunk.call_once(TupleRemove::<N, _>::remove(args).1)
+    });
+    InheritSendSync(wrapped, PhantomData::<T>)
+}
+
+/// Wraps a [`FnThunk`] implementation for `B` into one for `B2`, which converts its arguments to
+/// those of `B` with `mapper` before calling `thunk`. Both must return the same type.
+///
+/// This can reorder, convert, combine or drop arguments:
+///
+/// ```
+/// # #[cfg(feature = "default_jit_alloc")] {
+/// use closure_ffi::{cc, thunk_factory::map_args, BareFn};
+///
+/// let thunk = map_args::<
+///     unsafe extern "C" fn(&'static str, usize) -> usize,
+///     unsafe extern "C" fn(u32, &'static str) -> usize,
+///     _,
+///     _,
+/// >(
+///     (cc::C, |s: &str, n: usize| s.len() * n),
+///     |(n, s): (u32, &'static str)| (s, n as usize),
+/// );
+/// let bare_closure = BareFn::with_thunk(thunk);
+///
+/// assert_eq!(unsafe { bare_closure.bare()(2, "abc") }, 6);
+/// # }
+/// ```
+///
+/// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `mapper` are.
+#[inline(always)]
+pub fn map_args<B: FnPtr, B2, T, M>(thunk: T, mapper: M) -> impl FnThunk<B2>
+where
+    T: FnThunk<B>,
+    M: for<'a, 'b, 'c> ArgsMapper<'a, 'b, 'c, B2, B>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_thunk(move |args| unsafe { thunk.call(mapper(args)) });
+    InheritSendSync(wrapped, PhantomData::<(T, M)>)
+}
+
+/// Same as [`map_args`], for [`FnMutThunk`] implementations.
+#[inline(always)]
+pub fn map_args_mut<B: FnPtr, B2, T, M>(mut thunk: T, mapper: M) -> impl FnMutThunk<B2>
+where
+    T: FnMutThunk<B>,
+    M: for<'a, 'b, 'c> ArgsMapper<'a, 'b, 'c, B2, B>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_mut_thunk(move |args| unsafe { thunk.call_mut(mapper(args)) });
+    InheritSendSync(wrapped, PhantomData::<(T, M)>)
+}
+
+/// Same as [`map_args`], for [`FnOnceThunk`] implementations.
+#[inline(always)]
+pub fn map_args_once<B: FnPtr, B2, T, M>(thunk: T, mapper: M) -> impl FnOnceThunk<B2>
+where
+    T: FnOnceThunk<B>,
+    M: for<'a, 'b, 'c> ArgsMapper<'a, 'b, 'c, B2, B>,
+    for<'a, 'b, 'c> B2: FnPtr<Ret<'a, 'b, 'c> = B::Ret<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_once_thunk(move |args| unsafe { thunk.call_once(mapper(args)) });
+    InheritSendSync(wrapped, PhantomData::<(T, M)>)
+}
+
+/// Wraps a [`FnThunk`] implementation for `B` into one for `B2`, which takes the same arguments
+/// and converts the value returned by `thunk` with `mapper`.
+///
+/// ```
+/// # #[cfg(feature = "default_jit_alloc")] {
+/// use closure_ffi::{cc, thunk_factory::map_ret, BareFn};
+///
+/// let thunk = map_ret::<
+///     unsafe extern "C" fn(u32) -> Result<u32, ()>,
+///     unsafe extern "C" fn(u32) -> i64,
+///     _,
+///     _,
+/// >(
+///     (cc::C, |n: u32| n.checked_sub(1).ok_or(())),
+///     |res: Result<u32, ()>| res.map_or(-1, i64::from),
+/// );
+/// let bare_closure = BareFn::with_thunk(thunk);
+///
+/// assert_eq!(unsafe { bare_closure.bare()(5) }, 4);
+/// assert_eq!(unsafe { bare_closure.bare()(0) }, -1);
+/// # }
+/// ```
+///
+/// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `mapper` are.
+#[inline(always)]
+pub fn map_ret<B: FnPtr, B2, T, M>(thunk: T, mapper: M) -> impl FnThunk<B2>
+where
+    T: FnThunk<B>,
+    M: for<'a, 'b, 'c> RetMapper<'a, 'b, 'c, B, B2>,
+    for<'a, 'b, 'c> B2: FnPtr<Args<'a, 'b, 'c> = B::Args<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_thunk(move |args| mapper(unsafe { thunk.call(args) }));
+    InheritSendSync(wrapped, PhantomData::<(T, M)>)
+}
+
+/// Same as [`map_ret`], for [`FnMutThunk`] implementations.
+#[inline(always)]
+pub fn map_ret_mut<B: FnPtr, B2, T, M>(mut thunk: T, mapper: M) -> impl FnMutThunk<B2>
+where
+    T: FnMutThunk<B>,
+    M: for<'a, 'b, 'c> RetMapper<'a, 'b, 'c, B, B2>,
+    for<'a, 'b, 'c> B2: FnPtr<Args<'a, 'b, 'c> = B::Args<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_mut_thunk(move |args| mapper(unsafe { thunk.call_mut(args) }));
+    InheritSendSync(wrapped, PhantomData::<(T, M)>)
+}
+
+/// Same as [`map_ret`], for [`FnOnceThunk`] implementations.
+#[inline(always)]
+pub fn map_ret_once<B: FnPtr, B2, T, M>(thunk: T, mapper: M) -> impl FnOnceThunk<B2>
+where
+    T: FnOnceThunk<B>,
+    M: for<'a, 'b, 'c> RetMapper<'a, 'b, 'c, B, B2>,
+    for<'a, 'b, 'c> B2: FnPtr<Args<'a, 'b, 'c> = B::Args<'a, 'b, 'c>>,
+{
+    let wrapped = B2::make_once_thunk(move |args| mapper(unsafe { thunk.call_once(args) }));
+    InheritSendSync(wrapped, PhantomData::<(T, M)>)
+}
+
+/// A middleware wrapping calls to a thunk with hooks run before and after the wrapped closure,
+/// in the style of `tower` layers.
+///
+/// Layers are applied to an existing thunk implementation with [`layer`], [`layer_mut`] or
+/// [`layer_once`]. [`ExclusiveLayer`]s can also turn a [`FnMutThunk`] implementation into a
+/// [`FnThunk`] one using [`layer_exclusive`].
+///
+/// Layers compose as pairs: `(outer, inner)` runs the `before` hook of `outer` first and its
+/// `after` hook last. Nest pairs to stack more layers.
+pub trait ThunkLayer<B: FnPtr> {
+    /// State kept by the layer for the duration of a call, e.g. a lock guard or a timestamp.
+    type Guard<'s>
+    where
+        Self: 's;
+
+    /// Called with the arguments of the call before they are passed to the wrapped closure.
+    fn before<'s>(&'s self, args: &B::Args<'_, '_, '_>) -> Self::Guard<'s>;
+
+    /// Called with the value returned by the wrapped closure before it is returned from the
+    /// thunk. Not called if the closure panics, in which case `guard` is dropped while unwinding.
+    ///
+    /// The default implementation drops `guard`.
+    #[inline(always)]
+    fn after<'s>(&'s self, guard: Self::Guard<'s>, _ret: &B::Ret<'_, '_, '_>) {
+        drop(guard);
+    }
+}
+
+/// A [`ThunkLayer`] which guarantees that calls going through it are mutually exclusive.
+///
+/// # Safety
+/// While a guard returned by [`ThunkLayer::before`] is alive, other calls to `before` on the same
+/// layer must not return. They may block, panic or abort instead.
+pub unsafe trait ExclusiveLayer<B: FnPtr>: ThunkLayer<B> {}
+
+impl<B: FnPtr, L1: ThunkLayer<B>, L2: ThunkLayer<B>> ThunkLayer<B> for (L1, L2) {
+    type Guard<'s>
+        = (L1::Guard<'s>, L2::Guard<'s>)
+    where
+        Self: 's;
+
+    #[inline(always)]
+    fn before<'s>(&'s self, args: &B::Args<'_, '_, '_>) -> Self::Guard<'s> {
+        let outer = self.0.before(args);
+        (outer, self.1.before(args))
+    }
+
+    #[inline(always)]
+    fn after<'s>(&'s self, (outer, inner): Self::Guard<'s>, ret: &B::Ret<'_, '_, '_>) {
+        self.1.after(inner, ret);
+        self.0.after(outer, ret);
+    }
+}
+
+// SAFETY: The guard of the outer layer is alive for the whole call
+unsafe impl<B: FnPtr, L1: ExclusiveLayer<B>, L2: ThunkLayer<B>> ExclusiveLayer<B> for (L1, L2) {}
+
+/// [`ThunkLayer`] which counts the calls made through it.
+///
+/// Clones share the same counter, so a clone can be kept to read it after the layer has been
+/// moved into a thunk.
+#[derive(Debug, Default, Clone)]
+pub struct CountCalls(Arc<AtomicUsize>);
+
+impl CountCalls {
+    /// Creates a layer with a count of zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of calls which have entered the layer.
+    pub fn calls(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl<B: FnPtr> ThunkLayer<B> for CountCalls {
+    type Guard<'s> = ();
+
+    #[inline(always)]
+    fn before<'s>(&'s self, _args: &B::Args<'_, '_, '_>) -> Self::Guard<'s> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Wraps a [`FnThunk`] implementation so that calls to it go through `layer`.
+///
+/// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `layer` are.
+///
+/// ```
+/// # #[cfg(feature = "default_jit_alloc")] {
+/// use closure_ffi::{
+///     cc,
+///     thunk_factory::{layer, CountCalls},
+///     BareFn,
+/// };
+///
+/// let counter = CountCalls::new();
+/// let thunk = layer::<unsafe extern "C" fn(u32) -> u32, _, _>(
+///     (cc::C, |n: u32| n * 2),
+///     counter.clone(),
+/// );
+/// let bare_closure = BareFn::with_thunk(thunk);
+///
+/// assert_eq!(unsafe { bare_closure.bare()(5) }, 10);
+/// assert_eq!(unsafe { bare_closure.bare()(6) }, 12);
+/// assert_eq!(counter.calls(), 2);
+/// # }
+/// ```
+#[inline(always)]
+pub fn layer<B: FnPtr, T, L>(thunk: T, layer: L) -> impl FnThunk<B>
+where
+    T: FnThunk<B>,
+    L: ThunkLayer<B>,
+{
+    let wrapped = B::make_thunk(move |args| {
+        let guard = layer.before(&args);
+        let ret = unsafe { thunk.call(args) };
+        layer.after(guard, &ret);
+        ret
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, L)>)
+}
+
+/// Same as [`layer`], for [`FnMutThunk`] implementations.
+#[inline(always)]
+pub fn layer_mut<B: FnPtr, T, L>(mut thunk: T, layer: L) -> impl FnMutThunk<B>
+where
+    T: FnMutThunk<B>,
+    L: ThunkLayer<B>,
+{
+    let wrapped = B::make_mut_thunk(move |args| {
+        let guard = layer.before(&args);
+        let ret = unsafe { thunk.call_mut(args) };
+        layer.after(guard, &ret);
+        ret
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, L)>)
+}
+
+/// Same as [`layer`], for [`FnOnceThunk`] implementations.
+#[inline(always)]
+pub fn layer_once<B: FnPtr, T, L>(thunk: T, layer: L) -> impl FnOnceThunk<B>
+where
+    T: FnOnceThunk<B>,
+    L: ThunkLayer<B>,
+{
+    let wrapped = B::make_once_thunk(move |args| {
+        let guard = layer.before(&args);
+        let ret = unsafe { thunk.call_once(args) };
+        layer.after(guard, &ret);
+        ret
+    });
+    InheritSendSync(wrapped, PhantomData::<(T, L)>)
+}
+
+/// Wraps a [`FnMutThunk`] implementation so that calls to it go through `layer`, which
+/// serializes them. This makes it safe to call the closure through a shared reference, so the
+/// returned value implements [`FnThunk`].
+///
+/// The returned implementation is [`Send`] if both `thunk` and `layer` are, and [`Sync`] if
+/// `thunk` is [`Send`] and `layer` is [`Sync`].
+#[inline(always)]
+pub fn layer_exclusive<B: FnPtr, T, L>(thunk: T, layer: L) -> impl FnThunk<B>
+where
+    T: FnMutThunk<B>,
+    L: ExclusiveLayer<B>,
+{
+    let thunk = UnsafeCell::new(thunk);
+    let wrapped = B::make_thunk(move |args| {
+        let guard = layer.before(&args);
+        // SAFETY: No other call to the closure is in progress while the guard is alive
+        let ret = unsafe { (*thunk.get()).call_mut(args) };
+        layer.after(guard, &ret);
+        ret
+    });
+    InheritSendSync(wrapped, PhantomData::<(SyncIfSend<T>, L)>)
+}
+
+// Marker which is `Sync` if `T` is `Send`, like a `Mutex<T>`
+struct SyncIfSend<T>(PhantomData<T>);
+unsafe impl<T: Send> Sync for SyncIfSend<T> {}
+
+#[cfg(feature = "std")]
+mod std_layers {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        reentrancy_policy::{BlockOnReentry, ReentrancyGuard, ReentrancyLock},
+        ExclusiveLayer, ThunkLayer,
+    };
+    use crate::traits::FnPtr;
+
+    /// [`ExclusiveLayer`] which serializes calls made through it.
+    ///
+    /// Use it with [`layer_exclusive`](super::layer_exclusive) to turn a [`FnMut`] closure into
+    /// a [`Sync`] [`FnThunk`](crate::traits::FnThunk) implementation. This uses the same lock as
+    /// [`make_mut_checked`](super::make_mut_checked) with the
+    /// [`BlockOnReentry`](super::BlockOnReentry) policy: concurrent calls wait for the call in
+    /// progress, and recursive calls panic.
+    #[derive(Debug, Default)]
+    pub struct Synchronized(ReentrancyLock);
+
+    impl Synchronized {
+        /// Creates an unlocked layer.
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    impl<B: FnPtr> ThunkLayer<B> for Synchronized {
+        type Guard<'s> = ReentrancyGuard<'s>;
+
+        #[inline(always)]
+        fn before<'s>(&'s self, _args: &B::Args<'_, '_, '_>) -> Self::Guard<'s> {
+            match self.0.enter::<B, _>(&BlockOnReentry) {
+                Ok(guard) => guard,
+                Err(_) => unreachable!("BlockOnReentry always waits for the call in progress"),
+            }
+        }
+    }
+
+    // SAFETY: The lock is held as long as the guard is alive
+    unsafe impl<B: FnPtr> ExclusiveLayer<B> for Synchronized {}
+
+    /// [`ThunkLayer`] which measures the duration of each call made through it and passes it to
+    /// a closure.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct TimeCalls<F>(pub F);
+
+    impl<B: FnPtr, F: Fn(Duration)> ThunkLayer<B> for TimeCalls<F> {
+        type Guard<'s>
+            = Instant
+        where
+            Self: 's;
+
+        #[inline(always)]
+        fn before<'s>(&'s self, _args: &B::Args<'_, '_, '_>) -> Self::Guard<'s> {
+            Instant::now()
+        }
+
+        #[inline(always)]
+        fn after<'s>(&'s self, start: Self::Guard<'s>, _ret: &B::Ret<'_, '_, '_>) {
+            (self.0)(start.elapsed());
+        }
+    }
+}
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[doc(inline)]
+pub use std_layers::*;
+
+#[cfg(feature = "std")]
+mod panic_policy {
+    use alloc::{boxed::Box, sync::Arc};
+    use core::{any::Any, panic::AssertUnwindSafe};
+    use std::sync::RwLock;
+
+    use super::{InheritSendSync, PhantomData};
+    use crate::traits::{FnMutThunk, FnOnceThunk, FnPtr, FnThunk};
+
+    /// The payload of a panic, as returned by [`std::panic::catch_unwind`].
+    pub type PanicPayload = Box<dyn Any + Send + 'static>;
+
+    /// Determines what a thunk wrapped by [`catch_panic`] and friends does when the wrapped closure
+    /// panics.
+    ///
+    /// Unwinding out of a thunk with a non-unwinding ABI (such as `extern "C"`) aborts the process,
+    /// and unwinding into foreign code is undefined behavior. Catching the panic inside the thunk
+    /// avoids both, at the cost of having to produce a return value.
+    pub trait PanicPolicy<B: FnPtr> {
+        /// Handles a panic caught while calling the wrapped closure. The return value is returned
+        /// from the thunk.
+        fn on_panic<'a, 'b, 'c>(&self, payload: PanicPayload) -> B::Ret<'a, 'b, 'c>;
+    }
+
+    /// [`PanicPolicy`] which aborts the process when the wrapped closure panics.
+    ///
+    /// Unlike the abort caused by unwinding out of a non-unwinding ABI, this also applies to
+    /// thunks using an unwinding ABI, such as `extern "C-unwind"`.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct AbortOnPanic;
+
+    impl<B: FnPtr> PanicPolicy<B> for AbortOnPanic {
+        fn on_panic<'a, 'b, 'c>(&self, _payload: PanicPayload) -> B::Ret<'a, 'b, 'c> {
+            std::process::abort()
+        }
+    }
+
+    /// Trait alias for [`Fn(PanicPayload) -> B::Ret<'a, 'b, 'c>`](Fn).
+    ///
+    /// This is necessary to express the bounds of [`FallbackOnPanic`].
+    pub trait PanicFallback<'a, 'b, 'c, B: FnPtr>: Fn(PanicPayload) -> B::Ret<'a, 'b, 'c> {}
+
+    impl<'a, 'b, 'c, B: FnPtr, F> PanicFallback<'a, 'b, 'c, B> for F where
+        F: Fn(PanicPayload) -> B::Ret<'a, 'b, 'c>
+    {
+    }
+
+    /// [`PanicPolicy`] which returns the value computed by a closure from the panic payload when
+    /// the wrapped closure panics.
+    ///
+    /// ```
+    /// # #[cfg(feature = "default_jit_alloc")] {
+    /// use closure_ffi::{
+    ///     cc,
+    ///     thunk_factory::{catch_panic, FallbackOnPanic},
+    ///     BareFn,
+    /// };
+    ///
+    /// let thunk = catch_panic::<unsafe extern "C" fn(u32) -> u32, _, _>(
+    ///     (cc::C, |n: u32| 100 / n),
+    ///     FallbackOnPanic(|_| u32::MAX),
+    /// );
+    /// let bare_closure = BareFn::with_thunk(thunk);
+    ///
+    /// assert_eq!(unsafe { bare_closure.bare()(5) }, 20);
+    /// assert_eq!(unsafe { bare_closure.bare()(0) }, u32::MAX);
+    /// # }
+    /// ```
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct FallbackOnPanic<F>(pub F);
+
+    impl<B: FnPtr, F> PanicPolicy<B> for FallbackOnPanic<F>
+    where
+        F: for<'a, 'b, 'c> PanicFallback<'a, 'b, 'c, B>,
+    {
+        fn on_panic<'a, 'b, 'c>(&self, payload: PanicPayload) -> B::Ret<'a, 'b, 'c> {
+            (self.0)(payload)
+        }
+    }
+
+    type PanicHook = Arc<dyn Fn(PanicPayload) + Send + Sync + 'static>;
+
+    static PANIC_HOOK: RwLock<Option<PanicHook>> = RwLock::new(None);
+
+    /// Registers the global hook called by the [`HookOnPanic`] policy, replacing the previous one.
+    ///
+    /// Note that the standard library's panic hook (see [`std::panic::set_hook`]) still runs before
+    /// the panic is caught.
+    pub fn set_panic_hook(hook: impl Fn(PanicPayload) + Send + Sync + 'static) {
+        *PANIC_HOOK.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(hook));
+    }
+
+    /// Unregisters the global hook called by the [`HookOnPanic`] policy, returning it.
+    pub fn take_panic_hook() -> Option<PanicHook> {
+        PANIC_HOOK.write().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    /// [`PanicPolicy`] which passes the panic payload to the global hook registered with
+    /// [`set_panic_hook`] and returns the default value of the return type when the wrapped closure
+    /// panics.
+    ///
+    /// If no hook is registered, the payload is dropped. The hook is called without holding any
+    /// lock, so it may itself call [`set_panic_hook`] or [`take_panic_hook`]. Panics escaping from
+    /// the hook are caught and ignored.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct HookOnPanic;
+
+    impl<B: FnPtr> PanicPolicy<B> for HookOnPanic
+    where
+        for<'a, 'b, 'c> B::Ret<'a, 'b, 'c>: Default,
+    {
+        fn on_panic<'a, 'b, 'c>(&self, payload: PanicPayload) -> B::Ret<'a, 'b, 'c> {
+            let hook = PANIC_HOOK.read().unwrap_or_else(|e| e.into_inner()).clone();
+            if let Some(hook) = hook {
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| hook(payload)));
+            }
+            Default::default()
+        }
+    }
+
+    /// Wraps a [`FnOnceThunk`] implementation so that panics escaping from it are handled by
+    /// `policy` instead of unwinding out of the thunk.
+    ///
+    /// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `policy` are.
+    #[inline(always)]
+    pub fn catch_panic_once<B: FnPtr, T, P>(thunk: T, policy: P) -> impl FnOnceThunk<B>
+    where
+        T: FnOnceThunk<B>,
+        P: PanicPolicy<B>,
+    {
+        let wrapped = B::make_once_thunk(move |args| {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| unsafe { thunk.call_once(args) })) {
+                Ok(ret) => ret,
+                Err(payload) => policy.on_panic(payload),
+            }
+        });
+        InheritSendSync(wrapped, PhantomData::<(T, P)>)
+    }
+
+    /// Wraps a [`FnMutThunk`] implementation so that panics escaping from it are handled by
+    /// `policy` instead of unwinding out of the thunk.
+    ///
+    /// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `policy` are.
+    #[inline(always)]
+    pub fn catch_panic_mut<B: FnPtr, T, P>(mut thunk: T, policy: P) -> impl FnMutThunk<B>
+    where
+        T: FnMutThunk<B>,
+        P: PanicPolicy<B>,
+    {
+        let wrapped = B::make_mut_thunk(move |args| {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| unsafe { thunk.call_mut(args) })) {
+                Ok(ret) => ret,
+                Err(payload) => policy.on_panic(payload),
+            }
+        });
+        InheritSendSync(wrapped, PhantomData::<(T, P)>)
+    }
+
+    /// Wraps a [`FnThunk`] implementation so that panics escaping from it are handled by `policy`
+    /// instead of unwinding out of the thunk.
+    ///
+    /// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `policy` are.
+    #[inline(always)]
+    pub fn catch_panic<B: FnPtr, T, P>(thunk: T, policy: P) -> impl FnThunk<B>
+    where
+        T: FnThunk<B>,
+        P: PanicPolicy<B>,
+    {
+        let wrapped = B::make_thunk(move |args| {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| unsafe { thunk.call(args) })) {
+                Ok(ret) => ret,
+                Err(payload) => policy.on_panic(payload),
+            }
+        });
+        InheritSendSync(wrapped, PhantomData::<(T, P)>)
+    }
+}
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[doc(inline)]
+pub use panic_policy::*;
+
+#[cfg(feature = "std")]
+mod reentrancy_policy {
+    use core::{
+        cell::UnsafeCell,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use std::sync::{Mutex, MutexGuard, TryLockError};
+
+    use super::{InheritSendSync, PhantomData};
+    use crate::traits::{FnMutThunk, FnPtr, FnThunk};
+
+    /// The kind of conflicting call detected by [`make_mut_checked`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Reentry {
+        /// The thunk was called again on the thread that is currently running it, e.g. by a C
+        /// library invoking the callback from within the call that was made to it.
+        Recursive,
+        /// The thunk was called from another thread while it is running.
+        Concurrent,
+    }
+
+    /// Determines what a thunk wrapped by [`make_mut_checked`] does when it is entered while a
+    /// call to it is in progress.
+    pub trait ReentrancyPolicy<B: FnPtr> {
+        /// Handles a conflicting call to the wrapped closure.
+        ///
+        /// If this returns `Some`, the value is returned from the thunk without calling the
+        /// closure. If it returns `None`, the thunk waits for the call in progress to complete
+        /// before calling the closure. Since a [`Reentry::Recursive`] call cannot wait for itself,
+        /// the thunk panics in that case.
+        fn on_reentry<'a, 'b, 'c>(&self, reentry: Reentry) -> Option<B::Ret<'a, 'b, 'c>>;
+    }
+
+    /// [`ReentrancyPolicy`] which panics with a diagnostic message on conflicting calls.
+    ///
+    /// Panicking out of a thunk with a non-unwinding ABI (such as `extern "C"`) aborts the
+    /// process after printing the message.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct PanicOnReentry;
+
+    impl<B: FnPtr> ReentrancyPolicy<B> for PanicOnReentry {
+        fn on_reentry<'a, 'b, 'c>(&self, reentry: Reentry) -> Option<B::Ret<'a, 'b, 'c>> {
+            match reentry {
+                Reentry::Recursive => panic!(
+                    "recursive call to a FnMut thunk of type {}",
+                    core::any::type_name::<B>()
+                ),
+                Reentry::Concurrent => panic!(
+                    "concurrent call to a FnMut thunk of type {}",
+                    core::any::type_name::<B>()
+                ),
+            }
+        }
+    }
+
+    /// Trait alias for [`Fn(Reentry) -> B::Ret<'a, 'b, 'c>`](Fn).
+    ///
+    /// This is necessary to express the bounds of [`FallbackOnReentry`].
+    pub trait ReentryFallback<'a, 'b, 'c, B: FnPtr>: Fn(Reentry) -> B::Ret<'a, 'b, 'c> {}
+
+    impl<'a, 'b, 'c, B: FnPtr, F> ReentryFallback<'a, 'b, 'c, B> for F where
+        F: Fn(Reentry) -> B::Ret<'a, 'b, 'c>
+    {
+    }
+
+    /// [`ReentrancyPolicy`] which returns the value computed by a closure from the kind of
+    /// conflicting call, without calling the wrapped closure.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct FallbackOnReentry<F>(pub F);
+
+    impl<B: FnPtr, F> ReentrancyPolicy<B> for FallbackOnReentry<F>
+    where
+        F: for<'a, 'b, 'c> ReentryFallback<'a, 'b, 'c, B>,
+    {
+        fn on_reentry<'a, 'b, 'c>(&self, reentry: Reentry) -> Option<B::Ret<'a, 'b, 'c>> {
+            Some((self.0)(reentry))
+        }
+    }
+
+    /// [`ReentrancyPolicy`] which makes concurrent calls wait for the call in progress to
+    /// complete, like a [`Mutex`]. Recursive calls panic.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct BlockOnReentry;
+
+    impl<B: FnPtr> ReentrancyPolicy<B> for BlockOnReentry {
+        fn on_reentry<'a, 'b, 'c>(&self, _reentry: Reentry) -> Option<B::Ret<'a, 'b, 'c>> {
+            None
+        }
+    }
+
+    // Returns an identifier of the current thread which is unique among running threads and
+    // never zero
+    fn current_thread_id() -> usize {
+        std::thread_local!(static ID: u8 = const { 0 });
+        ID.with(|id| id as *const u8 as usize)
+    }
+
+    struct ResetOwner<'a>(&'a AtomicUsize);
+
+    impl Drop for ResetOwner<'_> {
+        fn drop(&mut self) {
+            self.0.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Mutual exclusion which detects recursive and concurrent calls. This is the mechanism
+    /// behind both [`make_mut_checked`] and [`Synchronized`](super::Synchronized).
+    #[derive(Debug, Default)]
+    pub(super) struct ReentrancyLock {
+        lock: Mutex<()>,
+        /// Id of the thread holding the lock, or zero.
+        owner: AtomicUsize,
+    }
+
+    /// Guard of a call made through [`make_mut_checked`] or [`Synchronized`](super::Synchronized).
+    /// No other call can proceed while it is alive.
+    pub struct ReentrancyGuard<'a> {
+        // Declared first so that the owner is reset before the lock is released
+        _reset_owner: ResetOwner<'a>,
+        _guard: MutexGuard<'a, ()>,
+    }
+
+    impl core::fmt::Debug for ReentrancyGuard<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("ReentrancyGuard").finish_non_exhaustive()
+        }
+    }
+
+    impl ReentrancyLock {
+        /// Acquires the lock, letting `policy` handle the conflict if it is already held. Returns
+        /// the value provided by the policy as an error if the call should not proceed.
+        pub(super) fn enter<'a, 'b, 'c, B: FnPtr, P: ReentrancyPolicy<B>>(
+            &self,
+            policy: &P,
+        ) -> Result<ReentrancyGuard<'_>, B::Ret<'a, 'b, 'c>> {
+            let this_thread = current_thread_id();
+            let guard = match self.lock.try_lock() {
+                Ok(guard) => guard,
+                // A panic unwound out of the closure, but we leave it up to the closure to
+                // decide whether its state is still consistent
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    // Only this thread writes its id to `owner`, which it does while holding the
+                    // lock
+                    let reentry = if self.owner.load(Ordering::Relaxed) == this_thread {
+                        Reentry::Recursive
+                    }
+                    else {
+                        Reentry::Concurrent
+                    };
+                    match policy.on_reentry(reentry) {
+                        Some(ret) => return Err(ret),
+                        None if reentry == Reentry::Recursive => panic!(
+                            "recursive call to a FnMut thunk of type {} cannot wait for itself",
+                            core::any::type_name::<B>()
+                        ),
+                        None => self.lock.lock().unwrap_or_else(|e| e.into_inner()),
+                    }
+                }
+            };
+
+            self.owner.store(this_thread, Ordering::Relaxed);
+            Ok(ReentrancyGuard {
+                _reset_owner: ResetOwner(&self.owner),
+                _guard: guard,
+            })
+        }
+    }
+
+    /// Wraps a [`FnMutThunk`] implementation so that recursive or concurrent calls to it are
+    /// detected at runtime and handled by `policy`, instead of being undefined behavior.
+    ///
+    /// Since it guards the wrapped closure itself, the returned value implements [`FnThunk`] and
+    /// should be used with [`BareFn`](crate::BareFn) or [`BareFnSync`](crate::BareFnSync) rather
+    /// than [`BareFnMut`](crate::BareFnMut), whose safety requirements it lifts.
+    ///
+    /// The returned implementation is [`Send`] if both `thunk` and `policy` are, and [`Sync`] if
+    /// `thunk` is [`Send`] and `policy` is [`Sync`].
+    ///
+    /// ```
+    /// # #[cfg(feature = "default_jit_alloc")] {
+    /// use core::cell::Cell;
+    ///
+    /// use closure_ffi::{
+    ///     cc,
+    ///     thunk_factory::{make_mut_checked, FallbackOnReentry},
+    ///     BareFn,
+    /// };
+    ///
+    /// type Callback = unsafe extern "C" fn(u32) -> u32;
+    ///
+    /// let reentrant_cb = Cell::new(None::<Callback>);
+    /// let mut total = 0;
+    /// let thunk = make_mut_checked::<Callback, _, _>(
+    ///     (cc::C, |n: u32| {
+    ///         total += n;
+    ///         // A C library calling back into us from within the callback
+    ///         total + unsafe { reentrant_cb.get().unwrap()(n) }
+    ///     }),
+    ///     FallbackOnReentry(|_| 1000),
+    /// );
+    /// let bare_closure = BareFn::with_thunk(thunk);
+    /// reentrant_cb.set(Some(bare_closure.bare()));
+    ///
+    /// assert_eq!(unsafe { bare_closure.bare()(1) }, 1001);
+    /// # }
+    /// ```
+    #[inline(always)]
+    pub fn make_mut_checked<B: FnPtr, T, P>(thunk: T, policy: P) -> impl FnThunk<B>
+    where
+        T: FnMutThunk<B>,
+        P: ReentrancyPolicy<B>,
+    {
+        let thunk = UnsafeCell::new(thunk);
+        let lock = ReentrancyLock::default();
+
+        let wrapped = B::make_thunk(move |args| {
+            let _guard = match lock.enter::<B, P>(&policy) {
+                Ok(guard) => guard,
+                Err(ret) => return ret,
+            };
+            // SAFETY: Holding the lock guarantees that no other call to the closure is in progress
+            unsafe { (*thunk.get()).call_mut(args) }
+        });
+        InheritSendSync(wrapped, PhantomData::<(Mutex<T>, P)>)
+    }
+}
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[doc(inline)]
+pub use reentrancy_policy::*;
+
+#[cfg(feature = "std")]
+mod thread_affinity {
+    use std::thread::{self, ThreadId};
+
+    use super::{InheritSendSync, PhantomData};
+    use crate::traits::{FnPtr, FnThunk};
+
+    std::thread_local! {
+        static THREAD_ID: ThreadId = thread::current().id();
+    }
+
+    /// Returns the id of the current thread without cloning its [`Thread`](thread::Thread) handle.
+    fn current_thread_id() -> ThreadId {
+        // The thread local may already be destroyed if called from the destructor of another one
+        THREAD_ID.try_with(|id| *id).unwrap_or_else(|_| thread::current().id())
+    }
+
+    /// Determines what a thunk wrapped by [`thread_bound`] does when it is called from another
+    /// thread than the one it is bound to.
+    ///
+    /// The policy is invoked on the calling thread, so it must not use the wrapped closure.
+    pub trait ThreadAffinityPolicy<B: FnPtr> {
+        /// Handles a call made from another thread than `owner`, the thread the thunk is bound
+        /// to. The return value is returned from the thunk.
+        fn on_foreign_thread<'a, 'b, 'c>(
+            &self,
+            owner: ThreadId,
+            args: B::Args<'a, 'b, 'c>,
+        ) -> B::Ret<'a, 'b, 'c>;
+    }
+
+    /// [`ThreadAffinityPolicy`] which prints a diagnostic message to the standard error and
+    /// aborts the process.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct AbortOnForeignThread;
+
+    impl<B: FnPtr> ThreadAffinityPolicy<B> for AbortOnForeignThread {
+        fn on_foreign_thread<'a, 'b, 'c>(
+            &self,
+            owner: ThreadId,
+            _args: B::Args<'a, 'b, 'c>,
+        ) -> B::Ret<'a, 'b, 'c> {
+            std::eprintln!(
+                "thunk of type {} bound to thread {owner:?} was called from thread {:?}",
+                core::any::type_name::<B>(),
+                thread::current().id()
+            );
+            std::process::abort()
+        }
+    }
+
+    /// [`ThreadAffinityPolicy`] which returns the default value of the return type.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct DefaultOnForeignThread;
+
+    impl<B: FnPtr> ThreadAffinityPolicy<B> for DefaultOnForeignThread
+    where
+        for<'a, 'b, 'c> B::Ret<'a, 'b, 'c>: Default,
+    {
+        fn on_foreign_thread<'a, 'b, 'c>(
+            &self,
+            _owner: ThreadId,
+            _args: B::Args<'a, 'b, 'c>,
+        ) -> B::Ret<'a, 'b, 'c> {
+            Default::default()
+        }
+    }
+
+    /// Trait alias for [`Fn(ThreadId, B::Args<'a, 'b, 'c>) -> B::Ret<'a, 'b, 'c>`](Fn).
+    ///
+    /// This is necessary to express the bounds of [`HandleOnForeignThread`].
+    pub trait ForeignThreadHandler<'a, 'b, 'c, B: FnPtr>:
+        Fn(ThreadId, B::Args<'a, 'b, 'c>) -> B::Ret<'a, 'b, 'c>
+    {
+    }
+
+    impl<'a, 'b, 'c, B: FnPtr, F> ForeignThreadHandler<'a, 'b, 'c, B> for F where
+        F: Fn(ThreadId, B::Args<'a, 'b, 'c>) -> B::Ret<'a, 'b, 'c>
+    {
+    }
+
+    /// [`ThreadAffinityPolicy`] which passes the owning thread and the arguments of the call to
+    /// a closure, which is responsible for producing the return value.
+    ///
+    /// The closure runs on the calling thread. To run the call on the owning thread instead, it
+    /// must forward it there itself, e.g. by posting it to the owning thread's event loop and
+    /// waiting for the result.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct HandleOnForeignThread<F>(pub F);
+
+    impl<B: FnPtr, F> ThreadAffinityPolicy<B> for HandleOnForeignThread<F>
+    where
+        F: for<'a, 'b, 'c> ForeignThreadHandler<'a, 'b, 'c, B>,
+    {
+        fn on_foreign_thread<'a, 'b, 'c>(
+            &self,
+            owner: ThreadId,
+            args: B::Args<'a, 'b, 'c>,
+        ) -> B::Ret<'a, 'b, 'c> {
+            (self.0)(owner, args)
+        }
+    }
+
+    /// Wraps a [`FnThunk`] implementation so that it may only be called from the current thread.
+    /// Calls from other threads are handled by `policy` instead, which never touches the wrapped
+    /// closure.
+    ///
+    /// This makes calling the bare function of a non-[`Send`] closure from the wrong thread a
+    /// detectable error rather than undefined behavior. To bind a [`FnMut`] closure, first wrap
+    /// it with [`make_mut_checked`](super::make_mut_checked).
+    ///
+    /// The returned implementation is [`Send`] and [`Sync`] if both `thunk` and `policy` are.
+    ///
+    /// ```
+    /// # #[cfg(feature = "default_jit_alloc")] {
+    /// use std::rc::Rc;
+    ///
+    /// use closure_ffi::{
+    ///     cc,
+    ///     thunk_factory::{thread_bound, DefaultOnForeignThread},
+    ///     BareFn,
+    /// };
+    ///
+    /// let rc = Rc::new(42);
+    /// let thunk = thread_bound::<unsafe extern "C" fn() -> u32, _, _>(
+    ///     (cc::C, move || *rc),
+    ///     DefaultOnForeignThread,
+    /// );
+    /// let bare_closure = BareFn::with_thunk(thunk);
+    /// let bare = bare_closure.bare();
+    ///
+    /// assert_eq!(unsafe { bare() }, 42);
+    /// assert_eq!(std::thread::spawn(move || unsafe { bare() }).join().unwrap(), 0);
+    /// # }
+    /// ```
+    #[inline(always)]
+    pub fn thread_bound<B: FnPtr, T, P>(thunk: T, policy: P) -> impl FnThunk<B>
+    where
+        T: FnThunk<B>,
+        P: ThreadAffinityPolicy<B> + Sync,
+    {
+        let owner = current_thread_id();
+        let wrapped = B::make_thunk(move |args| {
+            if current_thread_id() == owner {
+                // SAFETY: The closure is only ever called from the thread it is bound to
+                unsafe { thunk.call(args) }
+            }
+            else {
+                policy.on_foreign_thread(owner, args)
+            }
+        });
+        InheritSendSync(wrapped, PhantomData::<(T, P)>)
+    }
+}
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[doc(inline)]
+pub use thread_affinity::*;
+
 #[repr(transparent)]
 struct SendSyncWrapper<T>(T);
 unsafe impl<T> Send for SendSyncWrapper<T> {}
@@ -173,3 +1356,39 @@ unsafe impl<B: FnPtr, T: FnThunk<B>> FnThunk<B> for SendSyncWrapper<T> {
         self.0.call(args)
     }
 }
+
+/// Wrapper making a thunk implementation [`Send`]/[`Sync`] if `M` is.
+///
+/// Used to forward the marker traits of the captured values through the opaque thunk types
+/// returned by [`FnPtr::make_thunk`] and friends.
+#[repr(transparent)]
+struct InheritSendSync<T, M>(T, PhantomData<M>);
+unsafe impl<T, M: Send> Send for InheritSendSync<T, M> {}
+unsafe impl<T, M: Sync> Sync for InheritSendSync<T, M> {}
+unsafe impl<B: FnPtr, T: FnOnceThunk<B>, M> FnOnceThunk<B> for InheritSendSync<T, M> {
+    const THUNK_TEMPLATE_ONCE: *const u8 = T::THUNK_TEMPLATE_ONCE;
+    unsafe fn call_once<'a, 'b, 'c>(
+        self,
+        args: <B as FnPtr>::Args<'a, 'b, 'c>,
+    ) -> <B as FnPtr>::Ret<'a, 'b, 'c> {
+        self.0.call_once(args)
+    }
+}
+unsafe impl<B: FnPtr, T: FnMutThunk<B>, M> FnMutThunk<B> for InheritSendSync<T, M> {
+    const THUNK_TEMPLATE_MUT: *const u8 = T::THUNK_TEMPLATE_MUT;
+    unsafe fn call_mut<'a, 'b, 'c>(
+        &mut self,
+        args: <B as FnPtr>::Args<'a, 'b, 'c>,
+    ) -> <B as FnPtr>::Ret<'a, 'b, 'c> {
+        self.0.call_mut(args)
+    }
+}
+unsafe impl<B: FnPtr, T: FnThunk<B>, M> FnThunk<B> for InheritSendSync<T, M> {
+    const THUNK_TEMPLATE: *const u8 = T::THUNK_TEMPLATE;
+    unsafe fn call<'a, 'b, 'c>(
+        &self,
+        args: <B as FnPtr>::Args<'a, 'b, 'c>,
+    ) -> <B as FnPtr>::Ret<'a, 'b, 'c> {
+        self.0.call(args)
+    }
+}
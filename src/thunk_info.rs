@@ -0,0 +1,245 @@
+//! Introspection of the machine code emitted for bare closure thunks, to debug relocation issues
+//! or crashes inside a thunk.
+//!
+//! See [`ThunkInfo`] for more information.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// How the code of a thunk was produced from its thunk template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ThunkKind {
+    /// The closure is zero-sized, so the thunk template is used as the thunk and no code was
+    /// emitted.
+    Template,
+    /// The thunk template prologue was copied verbatim, as it does not depend on its address.
+    Copied,
+    /// The thunk template prologue was relocated by the `safe_jit` feature.
+    Relocated,
+    /// The thunk template prologue was copied close to the template, with its 32-bit IP-relative
+    /// displacements adjusted. See
+    /// [`JitAlloc::alloc_near`](crate::jit_alloc::JitAlloc::alloc_near).
+    Near,
+}
+
+/// Debug information about the code of a bare closure thunk, returned by the `thunk_info` methods
+/// of the bare closure types.
+///
+/// Offsets are relative to the start of the thunk's code.
+#[derive(Debug, Clone)]
+pub struct ThunkInfo<'a> {
+    pub(crate) kind: ThunkKind,
+    pub(crate) thunk_addr: usize,
+    pub(crate) template_addr: usize,
+    pub(crate) code: &'a [u8],
+    pub(crate) magic_offset: Option<usize>,
+    pub(crate) closure_ptr_offset: Option<usize>,
+    pub(crate) return_addr_offset: Option<usize>,
+    pub(crate) rewritten: Vec<Range<usize>>,
+}
+
+impl<'a> ThunkInfo<'a> {
+    /// How the code of the thunk was produced from its template.
+    pub fn kind(&self) -> ThunkKind {
+        self.kind
+    }
+
+    /// The address of the thunk, i.e. of the bare function pointer.
+    pub fn thunk_addr(&self) -> usize {
+        self.thunk_addr
+    }
+
+    /// The address of the compiler-generated thunk template the thunk was created from.
+    pub fn template_addr(&self) -> usize {
+        self.template_addr
+    }
+
+    /// The code emitted for the thunk, up to the end of the data following the `asm!` block. On ARM
+    /// targets, this includes the literal pool of a relocated prologue.
+    ///
+    /// Empty for [`ThunkKind::Template`] thunks, as no code is emitted for them.
+    pub fn code(&self) -> &'a [u8] {
+        self.code
+    }
+
+    /// Offset of the magic number which marks the end of the `asm!` block in the thunk template.
+    /// The closure pointer and return address slots are located relative to it.
+    pub fn magic_offset(&self) -> Option<usize> {
+        self.magic_offset
+    }
+
+    /// Offset of the pointer-sized slot holding the closure pointer.
+    pub fn closure_ptr_offset(&self) -> Option<usize> {
+        self.closure_ptr_offset
+    }
+
+    /// Offset of the pointer-sized slot holding the address the thunk jumps back to in the thunk
+    /// template.
+    pub fn return_addr_offset(&self) -> Option<usize> {
+        self.return_addr_offset
+    }
+
+    /// Ranges of [`ThunkInfo::code`] which are not a verbatim copy of the thunk template, because
+    /// they were rewritten by the relocator or adjusted for [`ThunkKind::Near`] placement. The
+    /// closure pointer and return address slots are not included.
+    pub fn rewritten(&self) -> &[Range<usize>] {
+        &self.rewritten
+    }
+
+    /// Returns `true` if any byte of `range` in [`ThunkInfo::code`] was rewritten.
+    pub fn is_rewritten(&self, range: Range<usize>) -> bool {
+        self.rewritten.iter().any(|r| r.start < range.end && range.start < r.end)
+    }
+
+    /// Formats an annotated disassembly of the thunk.
+    ///
+    /// Each instruction is listed with its offset and bytes, and rewritten instructions are marked
+    /// with a `*`. The data following the code is listed in pointer-sized words, with the closure
+    /// pointer and return address slots labeled. On x86, where these slots are immediates of the
+    /// `asm!` block instructions, the instructions are labeled instead.
+    #[cfg(feature = "thunk_disasm")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "thunk_disasm")))]
+    pub fn disassembly(&self) -> alloc::string::String {
+        use core::fmt::Write;
+
+        let mut out = alloc::string::String::new();
+        let _ = writeln!(
+            out,
+            "thunk at {:#x} ({:?}, template at {:#x})",
+            self.thunk_addr, self.kind, self.template_addr
+        );
+        let Some(magic_offset) = self.magic_offset
+        else {
+            out.push_str("  <no code emitted>\n");
+            return out;
+        };
+
+        let code_addr = self.code.as_ptr() as u64;
+        let instructions = disasm::disassemble(&self.code[..magic_offset], code_addr);
+        let bytes_width = instructions.iter().map(|i| 3 * i.len).max().unwrap_or(0).max(3 * 8);
+
+        let mut offset = 0;
+        for instr in &instructions {
+            let range = offset..offset + instr.len;
+            self.write_line(&mut out, range.clone(), bytes_width, &instr.text);
+            offset = range.end;
+        }
+
+        // Data following the code, and anything the disassembler could not decode
+        const WORD: usize = size_of::<usize>();
+        while offset < self.code.len() {
+            let len = (WORD - offset % WORD).min(self.code.len() - offset);
+            let mut bytes = [0; WORD];
+            bytes[..len].copy_from_slice(&self.code[offset..offset + len]);
+            let text = match len {
+                1 => alloc::format!(".byte {:#x}", bytes[0]),
+                _ => alloc::format!(".{len}byte {:#x}", usize::from_le_bytes(bytes)),
+            };
+            self.write_line(&mut out, offset..offset + len, bytes_width, &text);
+            offset += len;
+        }
+        out
+    }
+
+    #[cfg(feature = "thunk_disasm")]
+    fn write_line(
+        &self,
+        out: &mut alloc::string::String,
+        range: Range<usize>,
+        bytes_width: usize,
+        text: &str,
+    ) {
+        use core::fmt::Write;
+
+        let marker = if self.is_rewritten(range.clone()) { '*' } else { ' ' };
+        let mut bytes = alloc::string::String::new();
+        for byte in &self.code[range.clone()] {
+            let _ = write!(bytes, "{byte:02x} ");
+        }
+        let _ = write!(
+            out,
+            "{marker} {:04x}  {bytes:bytes_width$} {text}",
+            range.start
+        );
+
+        let slots = [
+            (self.closure_ptr_offset, "closure pointer"),
+            (self.return_addr_offset, "return address"),
+        ];
+        for (slot, label) in slots {
+            if slot.is_some_and(|s| range.contains(&s)) {
+                let _ = write!(out, " ; {label}");
+            }
+        }
+        out.push('\n');
+    }
+}
+
+#[cfg(feature = "thunk_disasm")]
+mod disasm {
+    use alloc::{string::String, vec::Vec};
+
+    /// A disassembled instruction.
+    pub struct Instruction {
+        pub len: usize,
+        pub text: String,
+    }
+
+    /// Disassembles `code` located at `ip`, stopping at the first invalid instruction.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn disassemble(code: &[u8], ip: u64) -> Vec<Instruction> {
+        use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+
+        let mut decoder = Decoder::with_ip(usize::BITS, code, ip, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        let mut instructions = Vec::new();
+        for instr in &mut decoder {
+            if instr.is_invalid() {
+                break;
+            }
+            let mut text = String::new();
+            formatter.format(&instr, &mut text);
+            instructions.push(Instruction {
+                len: instr.len(),
+                text,
+            });
+        }
+        instructions
+    }
+
+    /// Disassembles `code` located at `pc`, stopping at the first invalid instruction.
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn disassemble(code: &[u8], pc: u64) -> Vec<Instruction> {
+        use capstone::prelude::*;
+
+        #[cfg(target_arch = "aarch64")]
+        let cs = Capstone::new().arm64().mode(arch::arm64::ArchMode::Arm).build();
+        #[cfg(all(target_arch = "arm", thumb_mode))]
+        let cs = Capstone::new().arm().mode(arch::arm::ArchMode::Thumb).build();
+        #[cfg(all(target_arch = "arm", not(thumb_mode)))]
+        let cs = Capstone::new().arm().mode(arch::arm::ArchMode::Arm).build();
+
+        let Ok(cs) = cs
+        else {
+            return Vec::new();
+        };
+        let Ok(instructions) = cs.disasm_all(code, pc)
+        else {
+            return Vec::new();
+        };
+        instructions
+            .iter()
+            .map(|i| Instruction {
+                len: i.len(),
+                text: alloc::format!(
+                    "{} {}",
+                    i.mnemonic().unwrap_or("?"),
+                    i.op_str().unwrap_or("")
+                )
+                .trim_end()
+                .into(),
+            })
+            .collect()
+    }
+}
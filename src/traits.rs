@@ -72,7 +72,8 @@ where
     }
 }
 
-/// Trait implemented by unsafe function pointer types of up to 12 arguments.
+/// Trait implemented by unsafe function pointer types of up to 12 arguments (24 with the
+/// `extended_arity` feature).
 ///
 /// Allows introspection of the function's calling convention, arguments, return type, and provides
 /// a `call` method for invoking the function.
@@ -283,3 +284,126 @@ impl<'a, 'b, 'c, B: FnPtr, F> PackedFn<'a, 'b, 'c, B> for F where
     F: Fn(B::Args<'a, 'b, 'c>) -> B::Ret<'a, 'b, 'c>
 {
 }
+
+/// Tuples from which the element at index `N` can be removed, leaving the tuple `Rest`.
+///
+/// Implemented for tuples of up to 12 elements (24 with the `extended_arity` feature). This is used
+/// by the [`thunk_factory`](crate::thunk_factory) combinators to relate the [`Args`](FnPtr::Args)
+/// of two bare functions. `Rest` is a parameter rather than an associated type so that it can refer
+/// to the lifetimes of a higher-ranked bound.
+pub trait TupleRemove<const N: usize, Rest> {
+    /// The element at index `N`.
+    type Removed;
+
+    /// Splits the tuple into the element at index `N` and the remaining elements.
+    fn remove(self) -> (Self::Removed, Rest);
+
+    /// Inserts `removed` at index `N` of `rest`.
+    fn insert(rest: Rest, removed: Self::Removed) -> Self;
+}
+
+/// Tuples from which the last element can be removed, leaving the tuple `Rest`.
+///
+/// Implemented for tuples of up to 12 elements (24 with the `extended_arity` feature).
+pub trait TupleRemoveLast<Rest> {
+    /// The last element.
+    type Removed;
+
+    /// Splits the tuple into its last element and the remaining elements.
+    fn remove_last(self) -> (Self::Removed, Rest);
+
+    /// Appends `removed` to `rest`.
+    fn insert_last(rest: Rest, removed: Self::Removed) -> Self;
+}
+
+macro_rules! tuple_remove_impl_recursive {
+    // Case 1: Remove the first element of the suffix
+    (
+        [$($pre_v:ident: $pre_t:ident,)*]
+        [$v:ident: $t:ident, $($post_v:ident: $post_t:ident,)*]
+        [$idx:tt, $($tail_idx:tt,)*]
+    ) => {
+        impl<$($pre_t,)* $t, $($post_t,)*> TupleRemove<$idx, ($($pre_t,)* $($post_t,)*)>
+            for ($($pre_t,)* $t, $($post_t,)*)
+        {
+            type Removed = $t;
+
+            #[inline(always)]
+            fn remove(self) -> (Self::Removed, ($($pre_t,)* $($post_t,)*)) {
+                let ($($pre_v,)* $v, $($post_v,)*) = self;
+                ($v, ($($pre_v,)* $($post_v,)*))
+            }
+
+            #[inline(always)]
+            fn insert(
+                ($($pre_v,)* $($post_v,)*): ($($pre_t,)* $($post_t,)*),
+                $v: Self::Removed,
+            ) -> Self {
+                ($($pre_v,)* $v, $($post_v,)*)
+            }
+        }
+
+        tuple_remove_impl_recursive!(
+            [$($pre_v: $pre_t,)* $v: $t,]
+            [$($post_v: $post_t,)*]
+            [$($tail_idx,)*]
+        );
+    };
+
+    // Case 2: Exhausted suffix
+    ([$($pre_v:ident: $pre_t:ident,)*] [] [$($tail_idx:tt,)*]) => {};
+}
+
+macro_rules! tuple_impl_recursive {
+    // Case 1: Implement for the tuple made of the prefix and the next element
+    (
+        [$($v:ident: $t:ident,)*]
+        [$next_v:ident: $next_t:ident, $($tail_v:ident: $tail_t:ident,)*]
+        $indices:tt
+    ) => {
+        tuple_remove_impl_recursive!([] [$($v: $t,)* $next_v: $next_t,] $indices);
+
+        impl<$($t,)* $next_t> TupleRemoveLast<($($t,)*)> for ($($t,)* $next_t,) {
+            type Removed = $next_t;
+
+            #[inline(always)]
+            fn remove_last(self) -> (Self::Removed, ($($t,)*)) {
+                let ($($v,)* $next_v,) = self;
+                ($next_v, ($($v,)*))
+            }
+
+            #[inline(always)]
+            fn insert_last(($($v,)*): ($($t,)*), $next_v: Self::Removed) -> Self {
+                ($($v,)* $next_v,)
+            }
+        }
+
+        tuple_impl_recursive!(
+            [$($v: $t,)* $next_v: $next_t,]
+            [$($tail_v: $tail_t,)*]
+            $indices
+        );
+    };
+
+    // Case 2: Exhausted element list
+    ([$($v:ident: $t:ident,)*] [] $indices:tt) => {};
+}
+
+#[cfg(not(feature = "extended_arity"))]
+tuple_impl_recursive!(
+    []
+    [a0:T0,a1:T1,a2:T2,a3:T3,a4:T4,a5:T5,a6:T6,a7:T7,a8:T8,a9:T9,a10:T10,a11:T11,]
+    [0,1,2,3,4,5,6,7,8,9,10,11,]
+);
+
+// Same as above, but up to 24 elements, to match the arity of `FnPtr` implementations.
+#[cfg(feature = "extended_arity")]
+tuple_impl_recursive!(
+    []
+    [
+        a0:T0,a1:T1,a2:T2,a3:T3,a4:T4,a5:T5,a6:T6,a7:T7,a8:T8,a9:T9,a10:T10,a11:T11,
+        a12:T12,a13:T13,a14:T14,a15:T15,a16:T16,a17:T17,a18:T18,a19:T19,a20:T20,a21:T21,
+        a22:T22,a23:T23,
+    ]
+    [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,]
+);
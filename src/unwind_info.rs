@@ -0,0 +1,260 @@
+//! Registration of the call frame information of thunks with the unwinder, for the `unwind_info`
+//! feature.
+//!
+//! The closure is called from the thunk template, which has unwind information of its own. Only
+//! the code copied to JIT memory is missing it, so unwinding from inside the thunk prologue (from
+//! a signal handler, profiler or debugger) needs a CIE and FDE describing it, in the `.eh_frame`
+//! format expected by `__register_frame`.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::safe_jit::cfi::{CfiOp, CfiRule};
+
+extern "C" {
+    fn __register_frame(begin: *const u8);
+    fn __deregister_frame(begin: *const u8);
+}
+
+#[cfg(target_arch = "x86_64")]
+mod consts {
+    pub const SP: u16 = 7;
+    pub const RETURN_ADDRESS: u16 = 16;
+    pub const CODE_ALIGN: u32 = 1;
+    pub const DATA_ALIGN: i32 = -8;
+    /// Whether the return address is pushed on the stack by calls.
+    pub const RETURN_ADDRESS_ON_STACK: bool = true;
+}
+
+#[cfg(target_arch = "x86")]
+mod consts {
+    pub const SP: u16 = 4;
+    pub const RETURN_ADDRESS: u16 = 8;
+    pub const CODE_ALIGN: u32 = 1;
+    pub const DATA_ALIGN: i32 = -4;
+    /// Whether the return address is pushed on the stack by calls.
+    pub const RETURN_ADDRESS_ON_STACK: bool = true;
+}
+
+#[cfg(target_arch = "aarch64")]
+mod consts {
+    pub const SP: u16 = 31;
+    pub const RETURN_ADDRESS: u16 = 30;
+    pub const CODE_ALIGN: u32 = 4;
+    pub const DATA_ALIGN: i32 = -8;
+    /// Whether the return address is pushed on the stack by calls.
+    pub const RETURN_ADDRESS_ON_STACK: bool = false;
+}
+
+const WORD: usize = size_of::<usize>();
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET_EXTENDED: u8 = 0x05;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_AARCH64_NEGATE_RA_STATE: u8 = 0x2d;
+
+/// Pointers in the FDE are absolute and pointer-sized.
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn word(&mut self, value: usize) {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn uleb128(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.u8(byte);
+                return;
+            }
+            self.u8(byte | 0x80);
+        }
+    }
+
+    fn sleb128(&mut self, mut value: i64) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+                self.u8(byte);
+                return;
+            }
+            self.u8(byte | 0x80);
+        }
+    }
+
+    /// Writes a CIE or FDE, whose length is prepended and which is padded to the pointer size.
+    fn entry(&mut self, contents: impl FnOnce(&mut Self)) {
+        let start = self.0.len();
+        self.u32(0);
+        contents(self);
+        let padded = (self.0.len() - start).next_multiple_of(WORD);
+        self.0.resize(start + padded, DW_CFA_NOP);
+        let len = (self.0.len() - start - 4) as u32;
+        self.0[start..start + 4].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    /// Writes `DW_CFA_offset` for a register saved `cfa_offset` bytes below the CFA.
+    fn saved(&mut self, reg: u16, cfa_offset: u32) -> Option<()> {
+        let factored = cfa_offset as i64 / -consts::DATA_ALIGN as i64;
+        if factored * -consts::DATA_ALIGN as i64 != cfa_offset as i64 {
+            return None;
+        }
+        if reg < 64 {
+            self.u8(DW_CFA_OFFSET | reg as u8);
+        }
+        else {
+            self.u8(DW_CFA_OFFSET_EXTENDED);
+            self.uleb128(reg.into());
+        }
+        self.uleb128(factored as u64);
+        Some(())
+    }
+
+    fn advance_loc(&mut self, delta: usize) {
+        let delta = delta as u32 / consts::CODE_ALIGN;
+        match delta {
+            0 => {}
+            1..0x40 => self.u8(DW_CFA_ADVANCE_LOC | delta as u8),
+            0x40..0x100 => {
+                self.u8(DW_CFA_ADVANCE_LOC1);
+                self.u8(delta as u8);
+            }
+            0x100..0x10000 => {
+                self.u8(DW_CFA_ADVANCE_LOC2);
+                self.u16(delta as u16);
+            }
+            _ => {
+                self.u8(DW_CFA_ADVANCE_LOC4);
+                self.u32(delta);
+            }
+        }
+    }
+}
+
+/// Builds an `.eh_frame` section describing the `len` bytes of code at `addr`, made of a CIE,
+/// an FDE and a terminator. Returns it along with the offset of the FDE.
+///
+/// Returns `None` if the rules cannot be encoded.
+fn build_eh_frame(addr: usize, len: usize, ops: &[CfiOp]) -> Option<(Vec<u8>, usize)> {
+    let mut w = Writer::default();
+
+    w.entry(|w| {
+        w.u32(0); // CIE id
+        w.u8(1); // version
+        w.0.extend_from_slice(b"zR\0");
+        w.uleb128(consts::CODE_ALIGN.into());
+        w.sleb128(consts::DATA_ALIGN.into());
+        w.uleb128(consts::RETURN_ADDRESS.into());
+        w.uleb128(1); // augmentation data length
+        w.u8(DW_EH_PE_ABSPTR);
+
+        // The state at the first instruction of the thunk, right after the call
+        let initial_cfa_offset = if consts::RETURN_ADDRESS_ON_STACK { WORD } else { 0 };
+        w.u8(DW_CFA_DEF_CFA);
+        w.uleb128(consts::SP.into());
+        w.uleb128(initial_cfa_offset as u64);
+        if consts::RETURN_ADDRESS_ON_STACK {
+            w.saved(consts::RETURN_ADDRESS, WORD as u32).unwrap();
+        }
+    });
+
+    let fde = w.0.len();
+    let mut encoded = Some(());
+    w.entry(|w| {
+        // Distance back to the CIE
+        w.u32(w.0.len() as u32);
+        w.word(addr);
+        w.word(len);
+        w.uleb128(0); // augmentation data length
+
+        let mut location = 0;
+        for op in ops {
+            w.advance_loc(op.offset - location);
+            location = op.offset;
+            match op.rule {
+                CfiRule::CfaOffset(offset) => {
+                    w.u8(DW_CFA_DEF_CFA_OFFSET);
+                    w.uleb128(offset.into());
+                }
+                CfiRule::Cfa { reg, offset } => {
+                    w.u8(DW_CFA_DEF_CFA);
+                    w.uleb128(reg.into());
+                    w.uleb128(offset.into());
+                }
+                CfiRule::Saved { reg, cfa_offset } => {
+                    encoded = encoded.and(w.saved(reg, cfa_offset));
+                }
+                CfiRule::NegateRaState => w.u8(DW_CFA_AARCH64_NEGATE_RA_STATE),
+            }
+        }
+    });
+    encoded?;
+
+    w.u32(0);
+    Some((w.0, fde))
+}
+
+/// Call frame information registered with the unwinder, which is deregistered on drop.
+#[derive(Debug)]
+pub(crate) struct FrameRegistration {
+    eh_frame: Box<[u8]>,
+    fde: usize,
+}
+
+impl FrameRegistration {
+    /// Registers call frame information for the `len` bytes of code at `addr`, following the
+    /// rules in `ops`.
+    ///
+    /// Returns `None` if the rules cannot be encoded.
+    ///
+    /// # Safety
+    /// The registration must be dropped before the code is freed.
+    pub unsafe fn register(addr: usize, len: usize, ops: &[CfiOp]) -> Option<Self> {
+        let (eh_frame, fde) = build_eh_frame(addr, len, ops)?;
+        let registration = Self {
+            eh_frame: eh_frame.into_boxed_slice(),
+            fde,
+        };
+        __register_frame(registration.begin());
+        Some(registration)
+    }
+
+    /// The pointer passed to `__register_frame`. libgcc takes the whole `.eh_frame` section, while
+    /// LLVM's libunwind takes a single FDE.
+    fn begin(&self) -> *const u8 {
+        match cfg!(target_env = "gnu") {
+            true => self.eh_frame.as_ptr(),
+            false => self.eh_frame[self.fde..].as_ptr(),
+        }
+    }
+}
+
+impl Drop for FrameRegistration {
+    fn drop(&mut self) {
+        // SAFETY: The frame was registered with the same pointer
+        unsafe { __deregister_frame(self.begin()) };
+    }
+}
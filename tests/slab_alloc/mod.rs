@@ -41,7 +41,7 @@ impl JitAlloc for SlabAlloc {
             .fetch_update(Relaxed, Relaxed, |offset| {
                 (size + offset <= self.buf.len()).then_some(size + offset)
             })
-            .map_err(|_| JitAllocError)?;
+            .map_err(|_| JitAllocError::OutOfMemory)?;
 
         let ptr = unsafe { self.buf.as_ptr::<u8>().add(offset) };
         Ok((ptr, ptr as *mut _))
@@ -0,0 +1,121 @@
+#![cfg(feature = "default_jit_alloc")]
+// qemu-arm is unable to correctly model W^X dual-mapped memory (without software MMU),
+// so we can't test the default jit allocator on ARM
+#![cfg(not(target_arch = "arm"))]
+
+use core::{
+    future::Future,
+    pin::{pin, Pin},
+    task::{Context, Poll, Waker},
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::Wake,
+};
+
+use closure_ffi::async_callback::{CallbackFuture, CallbackStream};
+
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_callback_future() {
+    let counter = Arc::new(CountingWaker::default());
+    let waker = Waker::from(counter.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let future =
+        CallbackFuture::<unsafe extern "C" fn(*const u8, usize) -> i32, _>::new(|(ptr, len)| {
+            unsafe { core::slice::from_raw_parts(ptr, len) }.to_vec()
+        });
+    let bare = future.bare();
+    let mut future = pin!(future);
+    assert_eq!(future.as_mut().poll(&mut cx), Poll::Pending);
+
+    let data = [1u8, 2, 3];
+    let thread = std::thread::spawn(move || unsafe { bare(data.as_ptr(), data.len()) });
+    assert_eq!(thread.join().unwrap(), 0);
+
+    // Later calls are ignored
+    assert_eq!(unsafe { bare(data.as_ptr(), 1) }, 0);
+
+    assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+    assert_eq!(future.poll(&mut cx), Poll::Ready(vec![1, 2, 3]));
+}
+
+#[test]
+fn test_callback_future_drops_callback() {
+    let state = Arc::new(());
+
+    // Dropping the future releases the callback, even if it was never called
+    let state_clone = state.clone();
+    let future = CallbackFuture::<unsafe extern "C" fn(), _>::new(move |()| drop(state_clone));
+    assert_eq!(Arc::strong_count(&state), 2);
+    drop(future);
+    assert_eq!(Arc::strong_count(&state), 1);
+
+    // Calling the callback consumes the closure
+    let state_clone = state.clone();
+    let future = CallbackFuture::<unsafe extern "C" fn(), _>::new(move |()| drop(state_clone));
+    unsafe { future.bare()() };
+    assert_eq!(Arc::strong_count(&state), 1);
+    assert_eq!(
+        pin!(future).poll(&mut Context::from_waker(Waker::noop())),
+        Poll::Ready(())
+    );
+}
+
+#[test]
+fn test_callback_stream() {
+    let counter = Arc::new(CountingWaker::default());
+    let waker = Waker::from(counter.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut stream = CallbackStream::<unsafe extern "C" fn(u32, u32), _>::new(|(a, b)| a + b);
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+
+    let bare = stream.bare();
+    unsafe {
+        bare(1, 2);
+        bare(3, 4);
+    }
+    assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+
+    assert_eq!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(3))
+    );
+    assert_eq!(stream.try_recv(), Some(7));
+    assert_eq!(stream.try_recv(), None);
+    assert_eq!(pin!(stream.recv()).poll(&mut cx), Poll::Pending);
+
+    unsafe { bare(5, 6) };
+    assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+    stream.close();
+    unsafe { bare(7, 8) };
+
+    assert_eq!(pin!(stream.recv()).poll(&mut cx), Poll::Ready(Some(11)));
+    assert_eq!(pin!(stream.recv()).poll(&mut cx), Poll::Ready(None));
+}
+
+#[test]
+fn test_callback_stream_drops_callback() {
+    let state = Arc::new(());
+    let state_clone = state.clone();
+    let stream =
+        CallbackStream::<unsafe extern "C" fn(), _>::new(move |()| Arc::strong_count(&state_clone));
+    unsafe { stream.bare()() };
+    assert_eq!(Arc::strong_count(&state), 2);
+
+    drop(stream);
+    assert_eq!(Arc::strong_count(&state), 1);
+}
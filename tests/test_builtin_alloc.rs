@@ -123,3 +123,189 @@ fn test_print_fn_once() {
     let bare = bare_closure.leak();
     assert_eq!(unsafe { bare(5) }, 15);
 }
+
+#[test]
+fn test_prewarm() {
+    fn make_adder(n: usize) -> impl Fn(usize) -> usize {
+        move |x| x + n
+    }
+    fn make_counter(mut n: usize) -> impl FnMut() -> usize {
+        move || {
+            n += 1;
+            n
+        }
+    }
+    fn make_once(s: String) -> impl FnOnce() -> usize {
+        move || s.len()
+    }
+
+    fn prewarm_fn<F: Fn(usize) -> usize>(_: &F) {
+        BareFn::<unsafe extern "C" fn(usize) -> usize>::prewarm::<F>();
+    }
+    fn prewarm_fn_mut<F: FnMut() -> usize>(_: &F) {
+        BareFnMut::<unsafe extern "C" fn() -> usize>::prewarm::<F>();
+    }
+    fn prewarm_fn_once<F: FnOnce() -> usize>(_: &F) {
+        BareFnOnce::<unsafe extern "C" fn() -> usize>::prewarm::<F>();
+    }
+
+    let adder = make_adder(5);
+    prewarm_fn(&adder);
+    prewarm_fn(&adder);
+    let bare_closure = BareFn::new_c(adder);
+    assert_eq!(unsafe { bare_closure.bare()(5) }, 10);
+
+    let counter = make_counter(0);
+    prewarm_fn_mut(&counter);
+    let bare_closure = BareFnMut::new_c(counter);
+    let bare = bare_closure.bare();
+    unsafe {
+        assert_eq!(bare(), 1);
+        assert_eq!(bare(), 2);
+    }
+
+    let once = make_once("hello".to_owned());
+    prewarm_fn_once(&once);
+    let bare = BareFnOnce::new_c(once).bare();
+    assert_eq!(unsafe { bare() }, 5);
+
+    // Zero-sized closures don't use a JIT thunk, so there is nothing to relocate
+    let doubler = |x: usize| 2 * x;
+    prewarm_fn(&doubler);
+    let bare_closure = BareFn::new_c(doubler);
+    assert_eq!(unsafe { bare_closure.bare()(5) }, 10);
+}
+
+#[test]
+fn test_from_fn_with_data() {
+    use core::ffi::c_void;
+
+    use closure_ffi::BareFnSync;
+
+    unsafe extern "C" fn add(user: *mut c_void, a: u32, b: u32) -> u32 {
+        *user.cast::<u32>() + a + b
+    }
+    unsafe extern "C" fn scale(factor: usize, n: usize) -> usize {
+        factor * n
+    }
+
+    let mut offset = 5u32;
+    let user = (&raw mut offset).cast::<c_void>();
+    let bare_closure = BareFn::<unsafe extern "C" fn(u32, u32) -> u32>::from_fn_with_data(
+        add as unsafe extern "C" fn(*mut c_void, u32, u32) -> u32,
+        user,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(1, 2) }, 8);
+    unsafe { user.cast::<u32>().write(10) };
+    assert_eq!(unsafe { bare_closure.bare()(1, 2) }, 13);
+
+    let bare_closures: Vec<_> = (0..10)
+        .map(|n| {
+            BareFnSync::<unsafe extern "C" fn(usize) -> usize>::from_fn_with_data(
+                scale as unsafe extern "C" fn(usize, usize) -> usize,
+                n,
+            )
+        })
+        .collect();
+    std::thread::spawn(move || {
+        for (n, bare_closure) in bare_closures.iter().enumerate() {
+            assert_eq!(unsafe { bare_closure.bare()(3) }, 3 * n);
+        }
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_shared_bare_fn() {
+    use std::{rc::Rc, sync::Arc};
+
+    use closure_ffi::{SharedBareFn, SharedBareFnSync};
+
+    let rc = Rc::new(5);
+    let rc_clone = rc.clone();
+    let shared = SharedBareFn::new_c(move |x: usize| x + *rc_clone);
+    let shared_clone = shared.clone();
+    assert!(SharedBareFn::ptr_eq(&shared, &shared_clone));
+    assert_eq!(SharedBareFn::strong_count(&shared), 2);
+    assert_eq!(shared.bare() as usize, shared_clone.bare() as usize);
+
+    drop(shared);
+    assert_eq!(unsafe { shared_clone.bare()(1) }, 6);
+    assert_eq!(Rc::strong_count(&rc), 2);
+
+    let bare_closure = SharedBareFn::try_unwrap(shared_clone).ok().unwrap();
+    assert_eq!(unsafe { bare_closure.bare()(2) }, 7);
+    drop(bare_closure);
+    assert_eq!(Rc::strong_count(&rc), 1);
+
+    let arc = Arc::new(10);
+    let arc_clone = arc.clone();
+    let shared = SharedBareFnSync::new_c(move |x: usize| x * *arc_clone);
+    let handles: Vec<_> = (0..4)
+        .map(|n| {
+            let shared = shared.clone();
+            std::thread::spawn(move || unsafe { shared.bare()(n) })
+        })
+        .collect();
+    drop(shared);
+
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results, [0, 10, 20, 30]);
+    assert_eq!(Arc::strong_count(&arc), 1);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_alloc_near() {
+    use closure_ffi::jit_alloc::{GlobalJitAlloc, JitAlloc, JitAllocError, ProtectJitAccess};
+
+    /// Returns the permissions of the mapping containing `ptr`, as listed in `/proc/self/maps`.
+    #[cfg(target_os = "linux")]
+    fn mapping_perms(ptr: *const u8) -> Option<String> {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines().find_map(|line| {
+            let (range, rest) = line.split_once(' ')?;
+            let (start, end) = range.split_once('-')?;
+            let range =
+                usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?;
+            range
+                .contains(&(ptr as usize))
+                .then(|| rest.split(' ').next().unwrap().to_owned())
+        })
+    }
+
+    const RANGE: usize = 1 << 30;
+    const SIZE: usize = 200;
+
+    let hint = test_alloc_near as *const u8;
+    let allocs: Vec<_> = (0..100)
+        .map(|_| GlobalJitAlloc.alloc_near(hint, RANGE, SIZE).unwrap())
+        .collect();
+
+    for &(rx, rw) in &allocs {
+        assert!((rx as usize).abs_diff(hint as usize) <= RANGE);
+        assert!((rx as usize + SIZE).abs_diff(hint as usize) <= RANGE);
+        unsafe {
+            GlobalJitAlloc.protect_jit_memory(rx, SIZE, ProtectJitAccess::ReadWrite);
+            // ret
+            rw.write(0xC3);
+            GlobalJitAlloc.protect_jit_memory(rx, SIZE, ProtectJitAccess::ReadExecute);
+            GlobalJitAlloc.flush_instruction_cache(rx, SIZE);
+            core::mem::transmute::<*const u8, extern "C" fn()>(rx)();
+        }
+        // The memory is never writable and executable at the same time
+        #[cfg(target_os = "linux")]
+        assert_eq!(mapping_perms(rx).as_deref(), Some("r-xp"));
+    }
+
+    for (rx, _) in allocs {
+        unsafe {
+            assert_eq!(GlobalJitAlloc.release(rx), Ok(()));
+            assert_eq!(
+                GlobalJitAlloc.release(rx),
+                Err(JitAllocError::InvalidPointer)
+            );
+        }
+    }
+}
@@ -0,0 +1,187 @@
+#![cfg(feature = "default_jit_alloc")]
+// qemu-arm is unable to correctly model W^X dual-mapped memory (without software MMU),
+// so we can't test the default jit allocator on ARM
+#![cfg(not(target_arch = "arm"))]
+
+use core::{cell::Cell, ffi::c_void};
+use std::rc::Rc;
+
+use closure_ffi::com::{
+    ComObject, ComThis, ComVtable, Guid, E_NOINTERFACE, E_POINTER, IID_IUNKNOWN, S_OK,
+};
+
+const IID_ICALC: Guid = Guid::new(0x6a3f1d2e, 0x1b2c, 0x4d5e, [0x8f, 0, 1, 2, 3, 4, 5, 6]);
+const IID_IOTHER: Guid = Guid::new(0x6a3f1d2f, 0x1b2c, 0x4d5e, [0x8f, 0, 1, 2, 3, 4, 5, 6]);
+
+#[repr(C)]
+struct ICalcVtbl {
+    add: unsafe extern "C" fn(*mut c_void, i32) -> i32,
+    this_ptr: unsafe extern "C" fn(*mut c_void) -> *mut c_void,
+}
+
+type Calc<'a> = ComObject<'a, ICalcVtbl>;
+
+fn make_calc(total: Rc<Cell<i32>>) -> Calc<'static> {
+    ComObject::new(&[IID_ICALC], move |thunks| {
+        Ok(ICalcVtbl {
+            add: thunks.thunk(move |_this: *mut c_void, n: i32| {
+                total.set(total.get() + n);
+                total.get()
+            })?,
+            this_ptr: thunks.thunk(|this: *mut c_void| this)?,
+        })
+    })
+}
+
+unsafe fn vtable<'a>(this: *mut c_void) -> &'a ComVtable<ICalcVtbl> {
+    unsafe { &**this.cast::<*const ComVtable<ICalcVtbl>>() }
+}
+
+#[test]
+fn test_com_methods() {
+    let total = Rc::new(Cell::new(0));
+    let calc = make_calc(total.clone());
+    let this = calc.as_raw();
+
+    unsafe {
+        let vtable = vtable(this);
+        assert_eq!((vtable.methods.add)(this, 2), 2);
+        assert_eq!((vtable.methods.add)(this, 5), 7);
+        // Methods receive the object pointer passed by the caller
+        assert_eq!((vtable.methods.this_ptr)(this), this);
+    }
+    assert_eq!(total.get(), 7);
+}
+
+#[test]
+fn test_com_query_interface() {
+    let calc = make_calc(Rc::default());
+    let this = calc.as_raw();
+
+    unsafe {
+        let unknown = &vtable(this).unknown;
+        let mut ppv = core::ptr::null_mut();
+
+        assert_eq!((unknown.query_interface)(this, &IID_ICALC, &mut ppv), S_OK);
+        assert_eq!(ppv, this);
+        assert_eq!(Calc::ref_count(&calc), 2);
+        assert_eq!((unknown.release)(ppv), 1);
+
+        assert_eq!(
+            (unknown.query_interface)(this, &IID_IUNKNOWN, &mut ppv),
+            S_OK
+        );
+        assert_eq!((unknown.release)(ppv), 1);
+
+        assert_eq!(
+            (unknown.query_interface)(this, &IID_IOTHER, &mut ppv),
+            E_NOINTERFACE
+        );
+        assert!(ppv.is_null());
+        assert_eq!(
+            (unknown.query_interface)(this, core::ptr::null(), &mut ppv),
+            E_POINTER
+        );
+        assert_eq!(
+            (unknown.query_interface)(this, &IID_ICALC, core::ptr::null_mut()),
+            E_POINTER
+        );
+    }
+    assert_eq!(Calc::ref_count(&calc), 1);
+}
+
+#[test]
+fn test_com_ref_counting() {
+    let total = Rc::new(Cell::new(0));
+    let calc = make_calc(total.clone());
+    assert_eq!(Rc::strong_count(&total), 2);
+
+    let calc2 = calc.clone();
+    assert_eq!(Calc::ref_count(&calc), 2);
+    let this = calc.into_raw();
+
+    unsafe {
+        let unknown = &vtable(this).unknown;
+        assert_eq!((unknown.add_ref)(this), 3);
+        assert_eq!((unknown.release)(this), 2);
+
+        let calc3 = Calc::clone_from_raw(this);
+        assert_eq!(Calc::ref_count(&calc3), 3);
+        drop(calc3);
+
+        assert_eq!((unknown.release)(this), 1);
+    }
+    assert_eq!(Rc::strong_count(&total), 2);
+
+    // The last release frees the closures and their state
+    drop(calc2);
+    assert_eq!(Rc::strong_count(&total), 1);
+}
+
+struct Account {
+    balance: Cell<i64>,
+    dropped: Rc<Cell<bool>>,
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.dropped.set(true);
+    }
+}
+
+#[repr(C)]
+struct IAccountVtbl {
+    deposit: unsafe extern "C" fn(ComThis<Account>, i64) -> i64,
+    add_ref_self: unsafe extern "C" fn(ComThis<Account>) -> u32,
+}
+
+type AccountObject<'a> =
+    ComObject<'a, IAccountVtbl, closure_ffi::jit_alloc::GlobalJitAlloc, Account>;
+
+#[test]
+fn test_com_state() {
+    let dropped = Rc::new(Cell::new(false));
+    let state = Account {
+        balance: Cell::new(10),
+        dropped: dropped.clone(),
+    };
+    let account: AccountObject = ComObject::with_state(&[IID_ICALC], state, |thunks| {
+        Ok(IAccountVtbl {
+            deposit: thunks.thunk(|this: ComThis<Account>, amount: i64| {
+                this.balance.set(this.balance.get() + amount);
+                this.balance.get()
+            })?,
+            add_ref_self: thunks.thunk(|this: ComThis<Account>| {
+                // Methods can obtain an owned reference to the object they are called on
+                let object = unsafe { AccountObject::clone_from_raw(this.as_raw()) };
+                let count = AccountObject::ref_count(&object);
+                core::mem::forget(object);
+                count
+            })?,
+        })
+    });
+    let this = account.as_raw();
+
+    unsafe {
+        let deposit = account.vtable().methods.deposit;
+        // Foreign callers pass the raw object pointer
+        let raw_deposit: unsafe extern "C" fn(*mut c_void, i64) -> i64 =
+            core::mem::transmute(deposit);
+        assert_eq!(raw_deposit(this, 5), 15);
+        assert_eq!(deposit(ComThis::from_raw(this), 7), 22);
+
+        let add_ref_self = account.vtable().methods.add_ref_self;
+        assert_eq!(add_ref_self(ComThis::from_raw(this)), 2);
+        assert_eq!((vtable_of(this).unknown.release)(this), 1);
+    }
+    assert_eq!(account.state().balance.get(), 22);
+
+    // The state is dropped along with the object
+    assert!(!dropped.get());
+    drop(account);
+    assert!(dropped.get());
+}
+
+unsafe fn vtable_of<'a>(this: *mut c_void) -> &'a ComVtable<IAccountVtbl> {
+    unsafe { &**this.cast::<*const ComVtable<IAccountVtbl>>() }
+}
@@ -0,0 +1,145 @@
+#![cfg(feature = "detour")]
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+use std::{
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use closure_ffi::{
+    cc,
+    detour::{Detour, DetourError, RawDetour},
+    jit_alloc::GlobalJitAlloc,
+};
+
+// Each test hooks its own functions, since tests run concurrently.
+
+#[inline(never)]
+extern "C" fn sum_to(n: usize) -> usize {
+    let mut sum = 0;
+    for i in 0..=black_box(n) {
+        sum += black_box(i);
+    }
+    sum
+}
+
+#[inline(never)]
+extern "C" fn mul_add(a: u64, b: u64, c: u64) -> u64 {
+    black_box(a).wrapping_mul(black_box(b)).wrapping_add(black_box(c))
+}
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[inline(never)]
+extern "C" fn load_counter() -> usize {
+    // Likely to start with an IP-relative load on x86_64
+    COUNTER.load(Ordering::Relaxed) + black_box(1)
+}
+
+#[inline(never)]
+extern "C" fn empty() {}
+
+#[inline(never)]
+extern "C" fn raw_target(x: u32) -> u32 {
+    black_box(x).wrapping_mul(black_box(3))
+}
+
+#[inline(never)]
+extern "C" fn raw_hook(x: u32) -> u32 {
+    black_box(x).wrapping_add(black_box(1000))
+}
+
+#[test]
+fn test_detour_original() {
+    let target: unsafe extern "C" fn(usize) -> usize = sum_to;
+    let calls = AtomicUsize::new(0);
+
+    let mut detour = unsafe {
+        Detour::new(target, |original| {
+            let calls = &calls;
+            move |n| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                original(n) + 1
+            }
+        })
+    }
+    .unwrap();
+
+    assert!(!detour.is_enabled());
+    assert_eq!(black_box(sum_to)(10), 55);
+    assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+    unsafe { detour.enable().unwrap() };
+    assert!(detour.is_enabled());
+    assert_eq!(black_box(sum_to)(10), 56);
+    assert_eq!(unsafe { detour.original()(10) }, 55);
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+    unsafe { detour.disable().unwrap() };
+    assert_eq!(black_box(sum_to)(10), 55);
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+    // Re-enabling must work as well
+    unsafe { detour.enable().unwrap() };
+    assert_eq!(black_box(sum_to)(4), 11);
+}
+
+#[test]
+fn test_detour_drop_restores() {
+    let mut detour = unsafe {
+        Detour::with_cc_in(
+            mul_add as unsafe extern "C" fn(u64, u64, u64) -> u64,
+            cc::C,
+            |_| |a: u64, b: u64, c: u64| a + b + c,
+            GlobalJitAlloc,
+        )
+    }
+    .unwrap();
+
+    unsafe { detour.enable().unwrap() };
+    assert_eq!(black_box(mul_add)(2, 3, 4), 9);
+
+    drop(detour);
+    assert_eq!(black_box(mul_add)(2, 3, 4), 10);
+}
+
+#[test]
+fn test_detour_ip_relative() {
+    let target: unsafe extern "C" fn() -> usize = load_counter;
+    COUNTER.store(41, Ordering::Relaxed);
+
+    let mut detour = unsafe { Detour::new(target, |original| move || original() * 2) }.unwrap();
+    unsafe { detour.enable().unwrap() };
+
+    assert_eq!(black_box(load_counter)(), 84);
+    assert_eq!(unsafe { detour.original()() }, 42);
+}
+
+#[test]
+fn test_raw_detour() {
+    let mut detour = unsafe {
+        RawDetour::new_in(
+            raw_target as *const u8,
+            raw_hook as *const u8,
+            GlobalJitAlloc,
+        )
+    }
+    .unwrap();
+    assert!(detour.patch_len() >= 5);
+
+    let original: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(detour.trampoline()) };
+
+    unsafe { detour.enable().unwrap() };
+    assert_eq!(black_box(raw_target)(5), 1005);
+    assert_eq!(original(5), 15);
+
+    unsafe { detour.disable().unwrap() };
+    assert_eq!(black_box(raw_target)(5), 15);
+}
+
+#[test]
+fn test_detour_too_small() {
+    let target: unsafe extern "C" fn() = empty;
+    let result = unsafe { Detour::new(target, |_| || ()) };
+    assert!(matches!(result, Err(DetourError::FunctionTooSmall)));
+}
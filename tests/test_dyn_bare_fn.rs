@@ -0,0 +1,171 @@
+#![cfg(all(feature = "dyn_bare_fn", feature = "default_jit_alloc"))]
+
+use core::{cell::Cell, ffi::c_void};
+use std::rc::Rc;
+
+use closure_ffi::dyn_bare_fn::{
+    DynBareFn, DynCallConv, DynSignature, DynSignatureError, DynType, DynValue,
+};
+
+#[test]
+fn test_parse_signature() {
+    let sig: DynSignature = "i32(f64, ptr, u8)".parse().unwrap();
+    assert_eq!(sig.cc(), DynCallConv::C);
+    assert_eq!(sig.args(), [DynType::F64, DynType::Ptr, DynType::U8]);
+    assert_eq!(sig.ret(), DynType::I32);
+    assert_eq!(sig.to_string(), "extern \"C\" i32(f64, ptr, u8)");
+    assert_eq!(sig.to_string().parse(), Ok(sig));
+
+    let sig: DynSignature = " extern \"sysv64\" void ( ) ".parse().unwrap();
+    assert_eq!(sig.cc(), DynCallConv::Sysv64);
+    assert_eq!(sig.args(), []);
+    assert_eq!(sig.ret(), DynType::Void);
+
+    let err = |s: &str| s.parse::<DynSignature>().unwrap_err();
+    assert_eq!(err("i32"), DynSignatureError::InvalidSyntax);
+    assert_eq!(err("i32(u8"), DynSignatureError::InvalidSyntax);
+    assert_eq!(err("i32(u8,)"), DynSignatureError::UnknownType);
+    assert_eq!(err("i128()"), DynSignatureError::UnknownType);
+    assert_eq!(
+        err("extern \"fastcall\" void()"),
+        DynSignatureError::UnknownType
+    );
+    assert_eq!(err("void(void)"), DynSignatureError::VoidArgument);
+}
+
+#[test]
+fn test_dyn_bare_fn() {
+    let sig = "i32(f64, ptr, u8)".parse().unwrap();
+    let bare_fn = DynBareFn::new(sig, |args| {
+        let [DynValue::F64(a), DynValue::Ptr(b), DynValue::U8(c)] = *args
+        else {
+            panic!("unexpected arguments {args:?}")
+        };
+        DynValue::I32(a as i32 * unsafe { *b.cast::<i32>() } - c as i32)
+    });
+
+    let bare: unsafe extern "C" fn(f64, *mut c_void, u8) -> i32 =
+        unsafe { core::mem::transmute(bare_fn.bare()) };
+    let mut n = 3;
+    assert_eq!(unsafe { bare(2.5, (&raw mut n).cast(), 10) }, -4);
+}
+
+#[test]
+fn test_dyn_bare_fn_float_ret() {
+    let sig = "f32(f32, i8)".parse().unwrap();
+    let bare_fn = DynBareFn::new(sig, |args| match *args {
+        [DynValue::F32(a), DynValue::I8(b)] => DynValue::F32(a * b as f32),
+        _ => panic!("unexpected arguments {args:?}"),
+    });
+
+    let bare: unsafe extern "C" fn(f32, i8) -> f32 =
+        unsafe { core::mem::transmute(bare_fn.bare()) };
+    assert_eq!(unsafe { bare(1.5, -2) }, -3.0);
+}
+
+#[test]
+fn test_dyn_bare_fn_void() {
+    let calls = Cell::new(0);
+    let bare_fn = DynBareFn::new("void()".parse().unwrap(), |args| {
+        assert!(args.is_empty());
+        calls.set(calls.get() + 1);
+        DynValue::Void
+    });
+
+    let bare: unsafe extern "C" fn() = unsafe { core::mem::transmute(bare_fn.bare()) };
+    unsafe {
+        bare();
+        bare();
+    }
+    drop(bare_fn);
+    assert_eq!(calls.get(), 2);
+}
+
+#[test]
+fn test_dyn_bare_fn_stack_args() {
+    type Bare = unsafe extern "C" fn(
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+        u64,
+        f64,
+    ) -> f64;
+
+    // 10 integer and 10 floating point arguments, so both kinds spill to the stack and more
+    // arguments than can be decoded without allocating are passed
+    let sig = DynSignature::new(
+        DynCallConv::C,
+        [DynType::U64, DynType::F64].repeat(10),
+        DynType::F64,
+    )
+    .unwrap();
+
+    let bare_fn = DynBareFn::new(sig, |args| {
+        let sum = args
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| match *arg {
+                DynValue::U64(n) if i % 2 == 0 => n as f64 * 10f64.powi(i as i32 / 2),
+                DynValue::F64(x) if i % 2 == 1 => x,
+                _ => panic!("unexpected argument {arg:?}"),
+            })
+            .sum();
+        DynValue::F64(sum)
+    });
+
+    let bare: Bare = unsafe { core::mem::transmute(bare_fn.bare()) };
+    let sum = unsafe {
+        bare(
+            1,
+            0.5,
+            2,
+            0.25,
+            3,
+            0.125,
+            4,
+            0.0625,
+            5,
+            0.03125,
+            6,
+            0.015625,
+            7,
+            0.0078125,
+            8,
+            0.00390625,
+            9,
+            0.001953125,
+            1,
+            0.0009765625,
+        )
+    };
+    assert_eq!(sum, 1987654321.0 + 0.9990234375);
+}
+
+#[test]
+fn test_dyn_bare_fn_drop() {
+    let rc = Rc::new(());
+    let rc_clone = rc.clone();
+    let bare_fn = DynBareFn::new("usize()".parse().unwrap(), move |_| {
+        DynValue::Usize(Rc::strong_count(&rc_clone))
+    });
+
+    let bare: unsafe extern "C" fn() -> usize = unsafe { core::mem::transmute(bare_fn.bare()) };
+    assert_eq!(unsafe { bare() }, 2);
+    drop(bare_fn);
+    assert_eq!(Rc::strong_count(&rc), 1);
+}
@@ -2,7 +2,7 @@
 use closure_ffi::{cc, BareFn, BareFnMut, BareFnOnce, UntypedBareFn};
 
 mod slab_alloc;
-use slab_alloc::SLAB;
+use slab_alloc::{SlabAlloc, SLAB};
 
 #[test]
 fn test_stateless_fn() {
@@ -305,3 +305,351 @@ fn test_wrap() {
     drop(locked_inc);
     assert_eq!(counter, 5000);
 }
+
+#[test]
+fn test_fn_once_self_free() {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst};
+
+    use closure_ffi::{jit_alloc::ProtectJitAccess, JitAlloc, JitAllocError};
+
+    // Allocator counting releases and drops of itself
+    struct CountingAlloc<'a> {
+        releases: &'a AtomicUsize,
+        drops: &'a AtomicUsize,
+    }
+    impl JitAlloc for CountingAlloc<'_> {
+        fn alloc(&self, size: usize) -> Result<(*const u8, *mut u8), JitAllocError> {
+            SLAB.alloc(size)
+        }
+
+        unsafe fn release(&self, rx_ptr: *const u8) -> Result<(), JitAllocError> {
+            self.releases.fetch_add(1, SeqCst);
+            SLAB.release(rx_ptr)
+        }
+
+        unsafe fn flush_instruction_cache(&self, rx_ptr: *const u8, size: usize) {
+            SLAB.flush_instruction_cache(rx_ptr, size);
+        }
+
+        unsafe fn protect_jit_memory(&self, ptr: *const u8, size: usize, access: ProtectJitAccess) {
+            SLAB.protect_jit_memory(ptr, size, access);
+        }
+    }
+    impl Drop for CountingAlloc<'_> {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, SeqCst);
+        }
+    }
+
+    // Use this type to verify that our closure was dropped
+    struct SetOnDrop<'a>(&'a AtomicBool);
+    impl Drop for SetOnDrop<'_> {
+        fn drop(&mut self) {
+            self.0.store(true, SeqCst);
+        }
+    }
+
+    let releases = AtomicUsize::new(0);
+    let drops = AtomicUsize::new(0);
+    let alloc = || CountingAlloc {
+        releases: &releases,
+        drops: &drops,
+    };
+
+    // Called: everything is freed by the thunk
+    let dropped = AtomicBool::new(false);
+    let check = SetOnDrop(&dropped);
+    let bare = BareFnOnce::new_c_in(
+        move |n: usize| {
+            let _ = &check;
+            3 * n
+        },
+        alloc(),
+    )
+    .bare();
+
+    assert!(!dropped.load(SeqCst));
+    assert_eq!(unsafe { bare(5) }, 15);
+    assert!(dropped.load(SeqCst));
+    assert_eq!(releases.load(SeqCst), 1);
+    assert_eq!(drops.load(SeqCst), 1);
+
+    // Never called: everything is freed on drop
+    let dropped = AtomicBool::new(false);
+    let check = SetOnDrop(&dropped);
+    let bare_closure = BareFnOnce::new_c_in(
+        move |n: usize| {
+            let _ = &check;
+            3 * n
+        },
+        alloc(),
+    );
+    drop(bare_closure);
+    assert!(dropped.load(SeqCst));
+    assert_eq!(releases.load(SeqCst), 2);
+    assert_eq!(drops.load(SeqCst), 2);
+
+    // Zero-sized closure: no executable memory, the allocator is dropped immediately
+    let bare = BareFnOnce::new_c_in(|n: usize| 2 * n, alloc()).bare();
+    assert_eq!(drops.load(SeqCst), 3);
+    assert_eq!(unsafe { bare(5) }, 10);
+    assert_eq!(releases.load(SeqCst), 2);
+}
+
+#[test]
+fn test_thunk_pool() {
+    use closure_ffi::{
+        jit_alloc::{JitAlloc, ThunkPool},
+        JitAllocError, ThunkError,
+    };
+
+    type Bare = unsafe extern "C" fn(usize) -> usize;
+
+    fn make_adder(n: usize) -> impl Fn(usize) -> usize + Copy {
+        move |x| x + n
+    }
+    fn pool_for<F: Fn(usize) -> usize>(_: &F, count: usize) -> ThunkPool<&'static SlabAlloc> {
+        let size = BareFn::<Bare, &ThunkPool<&'static SlabAlloc>>::jit_alloc_size::<F>();
+        assert_ne!(size, 0);
+        ThunkPool::new_in(count, size, &*SLAB)
+    }
+
+    let pool = pool_for(&make_adder(0), 3);
+    assert_eq!(pool.capacity(), 3);
+    assert_eq!(pool.available(), 3);
+
+    let adders: Vec<BareFn<Bare, _>> =
+        pool.write(|| (1..=3).map(|n| BareFn::new_in(make_adder(n), &pool)).collect());
+    assert_eq!(pool.available(), 0);
+    for (n, adder) in (1..=3).zip(&adders) {
+        assert_eq!(unsafe { adder.bare()(5) }, 5 + n);
+    }
+
+    // The pool is exhausted
+    assert!(matches!(
+        BareFn::<Bare, _>::try_with_cc_in(cc::C, make_adder(4), &pool),
+        Err(ThunkError::JitAlloc(JitAllocError::OutOfMemory))
+    ));
+
+    // Slots are reused once released
+    drop(adders);
+    assert_eq!(pool.available(), 3);
+    let adder: BareFn<Bare, _> = pool.write(|| BareFn::new_in(make_adder(10), &pool));
+    assert_eq!(unsafe { adder.bare()(5) }, 15);
+    assert_eq!(pool.available(), 2);
+
+    // Thunks can also be created outside of `write`
+    let adder: BareFn<Bare, _> = BareFn::new_in(make_adder(20), &pool);
+    assert_eq!(unsafe { adder.bare()(5) }, 25);
+    drop(adder);
+
+    // The reservation is made executable again when the closure panics
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        pool.write(|| {
+            let _adder: BareFn<Bare, _> = BareFn::new_in(make_adder(30), &pool);
+            panic!("thunk creation failed");
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(pool.available(), 2);
+    let adder: BareFn<Bare, _> = BareFn::new_in(make_adder(40), &pool);
+    assert_eq!(unsafe { adder.bare()(5) }, 45);
+
+    // Copy closures can be stored in the slot itself
+    fn inline_pool_for<F: Fn(usize) -> usize + Copy>(
+        _: &F,
+        count: usize,
+    ) -> ThunkPool<&'static SlabAlloc> {
+        BareFn::<Bare, &ThunkPool<&'static SlabAlloc>>::prewarm::<F>();
+        let size = BareFn::<Bare, &ThunkPool<&'static SlabAlloc>>::jit_alloc_size_inline::<F>();
+        assert!(size > BareFn::<Bare, &ThunkPool<&'static SlabAlloc>>::jit_alloc_size::<F>());
+        ThunkPool::new_in(count, size, &*SLAB)
+    }
+    let inline_pool = inline_pool_for(&make_adder(0), 2);
+    let adders: Vec<BareFn<Bare, _>> = inline_pool
+        .write(|| (1..=2).map(|n| BareFn::new_inline_in(make_adder(n), &inline_pool)).collect());
+    assert_eq!(inline_pool.available(), 0);
+    for (n, adder) in (1..=2).zip(&adders) {
+        assert_eq!(unsafe { adder.bare()(5) }, 5 + n);
+    }
+    drop(adders);
+    assert_eq!(inline_pool.available(), 2);
+
+    // Zero-sized closures do not use a slot
+    let double: BareFn<Bare, _> = BareFn::new_inline_in(|x: usize| 2 * x, &inline_pool);
+    assert_eq!(unsafe { double.bare()(5) }, 10);
+    assert_eq!(inline_pool.available(), 2);
+
+    // Requests larger than a slot are rejected, as are foreign pointers
+    assert_eq!(
+        pool.alloc(pool.slot_size() + 1),
+        Err(JitAllocError::InvalidSize)
+    );
+    assert_eq!(
+        unsafe { pool.release(pool_for(&make_adder(0), 1).alloc(1).unwrap().0) },
+        Err(JitAllocError::InvalidPointer)
+    );
+}
+
+#[cfg(feature = "extended_arity")]
+#[test]
+fn test_extended_arity() {
+    #[allow(clippy::too_many_arguments)]
+    fn sum(
+        a0: u8,
+        a1: u16,
+        a2: u32,
+        a3: u64,
+        a4: usize,
+        a5: u8,
+        a6: u16,
+        a7: u32,
+        a8: u64,
+        a9: usize,
+        a10: u8,
+        a11: u16,
+        a12: u32,
+        a13: u64,
+        a14: usize,
+        a15: u8,
+        a16: u16,
+        a17: u32,
+        a18: u64,
+        a19: usize,
+    ) -> u64 {
+        [a0 as u64, a1 as u64, a2 as u64, a3, a4 as u64]
+            .into_iter()
+            .chain([a5 as u64, a6 as u64, a7 as u64, a8, a9 as u64])
+            .chain([a10 as u64, a11 as u64, a12 as u64, a13, a14 as u64])
+            .chain([a15 as u64, a16 as u64, a17 as u64, a18, a19 as u64])
+            .sum()
+    }
+
+    let offset = 1000u64;
+    let bare_closure = BareFn::new_c_in(
+        move |a0,
+              a1,
+              a2,
+              a3,
+              a4,
+              a5,
+              a6,
+              a7,
+              a8,
+              a9,
+              a10,
+              a11,
+              a12,
+              a13,
+              a14,
+              a15,
+              a16,
+              a17,
+              a18,
+              a19| {
+            offset
+                + sum(
+                    a0, a1, a2, a3, a4, a5, a6, a7, a8, a9, a10, a11, a12, a13, a14, a15, a16, a17,
+                    a18, a19,
+                )
+        },
+        &SLAB,
+    );
+    let bare: unsafe extern "C" fn(
+        u8,
+        u16,
+        u32,
+        u64,
+        usize,
+        u8,
+        u16,
+        u32,
+        u64,
+        usize,
+        u8,
+        u16,
+        u32,
+        u64,
+        usize,
+        u8,
+        u16,
+        u32,
+        u64,
+        usize,
+    ) -> u64 = bare_closure.bare();
+
+    let ret = unsafe {
+        bare(
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+        )
+    };
+    assert_eq!(ret, 1000 + 210);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_tracking_alloc() {
+    use closure_ffi::jit_alloc::{AllocSite, JitAllocStats, TrackingJitAlloc};
+
+    type Bare = unsafe extern "C" fn(usize) -> usize;
+
+    fn make_mul(factor: usize) -> impl Fn(usize) -> usize {
+        move |x| x * factor
+    }
+
+    let tracker = TrackingJitAlloc::new(&*SLAB);
+
+    let adder: BareFn<Bare, _> = BareFn::new_in(make_mul(1), &tracker);
+    let doubler = BareFn::new_c_in(make_mul(2), tracker.for_signature::<Bare>());
+    let site = tracker.at_caller();
+    let tripler = BareFn::new_c_in(make_mul(3), site);
+    // Zero-sized closures do not allocate
+    let zst = BareFn::new_c_in(|x: usize| x, tracker.tagged("zst"));
+    assert_eq!(
+        unsafe { adder.bare()(5) + doubler.bare()(5) + tripler.bare()(5) + zst.bare()(5) },
+        35
+    );
+
+    let stats = tracker.stats();
+    assert_eq!(stats.live_allocations, 3);
+    assert_eq!(stats.total_allocations, 3);
+    assert_eq!(stats.peak_bytes, stats.live_bytes);
+
+    let AllocSite::CallSite(location) = site.site()
+    else {
+        panic!("expected a call site");
+    };
+    assert_eq!(location.file(), file!());
+
+    let sites: Vec<_> = tracker.stats_by_site().into_iter().map(|(site, _)| site).collect();
+    assert_eq!(
+        sites,
+        [
+            AllocSite::Untagged,
+            AllocSite::Label(core::any::type_name::<Bare>()),
+            site.site()
+        ]
+    );
+    let outstanding = tracker.outstanding();
+    assert_eq!(outstanding.len(), 3);
+    let doubler_addr = doubler.bare() as usize;
+    assert!(outstanding.iter().any(|a| {
+        (a.rx_ptr as usize..a.rx_ptr as usize + a.size).contains(&doubler_addr)
+            && a.site == sites[1]
+    }));
+
+    drop((adder, doubler, tripler, zst));
+    assert!(tracker.outstanding().is_empty());
+    assert_eq!(
+        tracker.stats(),
+        JitAllocStats {
+            live_allocations: 0,
+            live_bytes: 0,
+            ..stats
+        }
+    );
+    assert!(tracker
+        .stats_by_site()
+        .iter()
+        .all(|(_, s)| s.live_allocations == 0 && s.peak_allocations == 1));
+}
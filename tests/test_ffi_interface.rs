@@ -0,0 +1,95 @@
+#![cfg(all(feature = "proc_macros", feature = "default_jit_alloc"))]
+// qemu-arm is unable to correctly model W^X dual-mapped memory (without software MMU),
+// so we can't test the default jit allocator on ARM
+#![cfg(not(target_arch = "arm"))]
+
+use core::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use closure_ffi::{ffi_interface, ffi_interface::FfiInterface};
+
+#[ffi_interface]
+trait FileOps {
+    /// Opens the file with the given id.
+    fn open(&self, id: u32) -> i32;
+    fn read(&self, buf: *mut u8, len: usize) -> isize;
+    fn close(&self);
+}
+
+#[derive(Default)]
+struct MemFile {
+    opened: Cell<Option<u32>>,
+    data: RefCell<Vec<u8>>,
+}
+
+impl FileOps for MemFile {
+    fn open(&self, id: u32) -> i32 {
+        if self.opened.replace(Some(id)).is_some() {
+            return -1;
+        }
+        *self.data.borrow_mut() = id.to_le_bytes().to_vec();
+        0
+    }
+
+    fn read(&self, buf: *mut u8, len: usize) -> isize {
+        let data = self.data.borrow();
+        let len = len.min(data.len());
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), buf, len) };
+        len as isize
+    }
+
+    fn close(&self) {
+        self.opened.set(None);
+    }
+}
+
+#[test]
+fn test_ffi_interface() {
+    let interface = FfiInterface::<FileOpsVtable, _>::new(MemFile::default());
+    let vtable = *interface.vtable();
+
+    let mut buf = [0u8; 8];
+    unsafe {
+        assert_eq!((vtable.open)(0x04030201), 0);
+        assert_eq!((vtable.open)(1), -1);
+        assert_eq!((vtable.read)(buf.as_mut_ptr(), buf.len()), 4);
+        (vtable.close)();
+    }
+    assert_eq!(buf, [1, 2, 3, 4, 0, 0, 0, 0]);
+    assert_eq!(interface.implementor().opened.get(), None);
+}
+
+#[ffi_interface(vtable = RawCallbacks, abi = "system")]
+trait Callbacks {
+    fn on_event(&self, event: u64);
+    fn event_count(&self) -> usize;
+}
+
+struct EventLog(Rc<RefCell<Vec<u64>>>);
+
+impl Callbacks for EventLog {
+    fn on_event(&self, event: u64) {
+        self.0.borrow_mut().push(event);
+    }
+
+    fn event_count(&self) -> usize {
+        self.0.borrow().len()
+    }
+}
+
+#[test]
+fn test_ffi_interface_drop() {
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let interface = FfiInterface::<RawCallbacks, _>::new(EventLog(events.clone()));
+
+    let on_event: unsafe extern "system" fn(u64) = interface.vtable().on_event;
+    unsafe {
+        on_event(1);
+        on_event(2);
+        assert_eq!((interface.vtable().event_count)(), 2);
+    }
+    assert_eq!(*events.borrow(), [1, 2]);
+
+    drop(interface);
+    assert_eq!(Rc::strong_count(&events), 1);
+}
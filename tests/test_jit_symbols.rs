@@ -0,0 +1,185 @@
+#![cfg(any(feature = "perf_map", feature = "gdb_jit"))]
+
+use std::sync::Mutex;
+
+use closure_ffi::BareFn;
+
+/// Thunks are registered by all tests, so the GDB JIT list must not be walked concurrently.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(feature = "perf_map")]
+#[test]
+fn test_perf_map() {
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let offset = 5;
+    let bare_closure = BareFn::new_c(move |n: usize| n + offset);
+    let info = bare_closure.thunk_info();
+
+    let perf_map =
+        std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+    let entry = format!("{:x} {:x} ", info.thunk_addr(), info.code().len());
+    // Entries are never removed, so the memory may have been used by earlier thunks
+    let line = perf_map.lines().rfind(|l| l.starts_with(&entry)).unwrap();
+
+    assert!(line.contains("closure_ffi::thunk<"));
+    assert!(line.contains("test_perf_map::{{closure}}"));
+    assert!(line.ends_with(", unsafe extern \"C\" fn(usize) -> usize>"));
+
+    // Zero-sized closures use the template, which already has a symbol
+    let zst = BareFn::new_c(|n: usize| n + 1);
+    let entry = format!("{:x} ", zst.bare() as usize);
+    let perf_map =
+        std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+    assert!(!perf_map.lines().any(|l| l.starts_with(&entry)));
+}
+
+#[cfg(all(
+    feature = "perf_map",
+    feature = "dyn_bare_fn",
+    feature = "default_jit_alloc"
+))]
+#[test]
+fn test_perf_map_dyn_bare_fn() {
+    use closure_ffi::dyn_bare_fn::{DynBareFn, DynValue};
+
+    let offset = 5;
+    let dyn_fn = DynBareFn::new("i32(i32)".parse().unwrap(), move |args| {
+        let [DynValue::I32(n)] = *args
+        else {
+            panic!("unexpected arguments {args:?}")
+        };
+        DynValue::I32(n + offset)
+    });
+
+    let perf_map =
+        std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+    let entry = format!("{:x} ", dyn_fn.bare() as usize);
+    let line = perf_map.lines().rfind(|l| l.starts_with(&entry)).unwrap();
+    assert!(line.contains(
+        "closure_ffi::dyn_bare_fn<test_jit_symbols::test_perf_map_dyn_bare_fn::{{closure}}>"
+    ));
+}
+
+#[cfg(all(
+    feature = "perf_map",
+    feature = "detour",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+#[test]
+fn test_perf_map_detour() {
+    use closure_ffi::detour::Detour;
+
+    #[inline(never)]
+    extern "C" fn target(x: u32) -> u32 {
+        std::hint::black_box(x).wrapping_mul(std::hint::black_box(3))
+    }
+
+    let target_fn: unsafe extern "C" fn(u32) -> u32 = target;
+    let detour = unsafe { Detour::new(target_fn, |original| move |x| original(x) + 1) }.unwrap();
+
+    let trampoline = detour.as_raw().trampoline() as usize;
+    let perf_map =
+        std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+    let entry = format!("{trampoline:x} ");
+    let line = perf_map.lines().rfind(|l| l.starts_with(&entry)).unwrap();
+    assert!(line.ends_with(&format!(
+        " closure_ffi::trampoline<{:#x}>",
+        target_fn as usize
+    )));
+}
+
+#[cfg(feature = "gdb_jit")]
+mod gdb_jit {
+    use super::*;
+
+    #[repr(C)]
+    struct JitCodeEntry {
+        next_entry: *const JitCodeEntry,
+        prev_entry: *const JitCodeEntry,
+        symfile_addr: *const u8,
+        symfile_size: u64,
+    }
+
+    #[repr(C)]
+    struct JitDescriptor {
+        version: u32,
+        action_flag: u32,
+        relevant_entry: *const JitCodeEntry,
+        first_entry: *const JitCodeEntry,
+    }
+
+    extern "C" {
+        static __jit_debug_descriptor: JitDescriptor;
+    }
+
+    fn read_word(bytes: &[u8], offset: usize) -> usize {
+        usize::from_ne_bytes(bytes[offset..offset + size_of::<usize>()].try_into().unwrap())
+    }
+
+    /// Returns the ELF objects registered with the debugger whose `.text` section starts at `addr`.
+    fn symfiles_at(addr: usize) -> Vec<&'static [u8]> {
+        const WORD: usize = size_of::<usize>();
+        let shdr_size = if WORD == 8 { 64 } else { 40 };
+
+        let mut symfiles = Vec::new();
+        let descriptor = unsafe { &*core::ptr::addr_of!(__jit_debug_descriptor) };
+        assert_eq!(descriptor.version, 1);
+        assert_eq!(descriptor.action_flag, 0);
+
+        let mut entry = descriptor.first_entry;
+        while let Some(e) = unsafe { entry.as_ref() } {
+            let symfile =
+                unsafe { core::slice::from_raw_parts(e.symfile_addr, e.symfile_size as usize) };
+            assert_eq!(&symfile[..4], b"\x7fELF");
+
+            // The `.text` section follows the null section
+            let shoff = read_word(symfile, 16 + 2 + 2 + 4 + 2 * WORD);
+            let text_addr = read_word(symfile, shoff + shdr_size + 8 + WORD);
+            if text_addr == addr {
+                symfiles.push(symfile);
+            }
+            entry = e.next_entry;
+        }
+        symfiles
+    }
+
+    #[test]
+    fn test_gdb_jit() {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let offset = 5;
+        let bare_closure = BareFn::new_c(move |n: usize| n + offset);
+        let addr = bare_closure.thunk_info().thunk_addr();
+
+        let symfiles = symfiles_at(addr);
+        assert_eq!(symfiles.len(), 1);
+        let name = b"\0closure_ffi::thunk<test_jit_symbols::gdb_jit::test_gdb_jit::{{closure}}, unsafe extern \"C\" fn(usize) -> usize>\0";
+        assert!(symfiles[0].windows(name.len()).any(|w| w == name));
+
+        drop(bare_closure);
+        assert!(symfiles_at(addr).is_empty());
+    }
+
+    #[test]
+    fn test_gdb_jit_many_thunks() {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let (even, odd): (Vec<_>, Vec<_>) = (0..100)
+            .map(|n| BareFn::new_c(move |x: usize| x + n))
+            .partition(|t| unsafe { t.bare()(0) } % 2 == 0);
+        let addrs = |thunks: &[BareFn<_>]| -> Vec<usize> {
+            thunks.iter().map(|t| t.thunk_info().thunk_addr()).collect()
+        };
+        let (even_addrs, odd_addrs) = (addrs(&even), addrs(&odd));
+        assert!(even_addrs.iter().chain(&odd_addrs).all(|&a| symfiles_at(a).len() == 1));
+
+        // Unregister entries from the middle of the list
+        drop(odd);
+        assert!(odd_addrs.iter().all(|&a| symfiles_at(a).is_empty()));
+        assert!(even_addrs.iter().all(|&a| symfiles_at(a).len() == 1));
+
+        drop(even);
+        assert!(even_addrs.iter().all(|&a| symfiles_at(a).is_empty()));
+    }
+}
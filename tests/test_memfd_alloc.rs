@@ -0,0 +1,72 @@
+#![cfg(feature = "memfd_jit_alloc")]
+
+use closure_ffi::{
+    jit_alloc::{JitAlloc, MemfdJitAlloc},
+    BareFn, BareFnMut, JitAllocError,
+};
+
+static JIT_ALLOC: MemfdJitAlloc = MemfdJitAlloc::new();
+
+fn make_adder(n: usize) -> impl Fn(usize) -> usize {
+    move |x| x + n
+}
+
+#[test]
+fn test_memfd_bare_fn() {
+    let mut sum = 0;
+    let bare_closure = BareFnMut::new_c_in(|n: usize| sum += n, &JIT_ALLOC);
+    unsafe {
+        bare_closure.bare()(5);
+        bare_closure.bare()(3);
+    }
+    drop(bare_closure);
+    assert_eq!(sum, 8);
+}
+
+#[test]
+fn test_memfd_many_thunks() {
+    // Enough thunks to span multiple chunks
+    let adders: Vec<_> = (0..5000).map(|n| BareFn::new_c_in(make_adder(n), &JIT_ALLOC)).collect();
+    for (n, adder) in adders.iter().enumerate() {
+        assert_eq!(unsafe { adder.bare()(1) }, n + 1);
+    }
+    drop(adders);
+
+    let adder = BareFn::new_c_in(make_adder(7), &JIT_ALLOC);
+    assert_eq!(unsafe { adder.bare()(1) }, 8);
+}
+
+#[test]
+fn test_memfd_dual_mapping() {
+    let jit = MemfdJitAlloc::new();
+    let (rx, rw) = jit.alloc(100).unwrap();
+    assert_ne!(rx, rw.cast_const());
+
+    unsafe {
+        rw.write_bytes(0xCC, 100);
+        assert_eq!(rx.add(99).read_volatile(), 0xCC);
+    }
+
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let views: Vec<_> = maps.lines().filter(|l| l.contains("/memfd:closure-ffi")).collect();
+    assert!(views.iter().any(|l| l.contains(" rw-s ")));
+    assert!(views.iter().any(|l| l.contains(" r-xs ")));
+    assert!(!maps.lines().any(|l| l.contains("/memfd:closure-ffi") && l.contains("wx")));
+
+    // Allocations larger than a chunk get their own
+    let (large_rx, _) = jit.alloc(1 << 20).unwrap();
+
+    unsafe {
+        assert_eq!(jit.release(rx), Ok(()));
+        assert_eq!(jit.release(rx), Err(JitAllocError::InvalidPointer));
+        assert_eq!(jit.release(large_rx), Ok(()));
+    }
+}
+
+#[test]
+fn test_memfd_huge_pages() {
+    // Falls back to regular pages if no huge pages are available
+    let jit = MemfdJitAlloc::with_huge_pages();
+    let adder = BareFn::new_c_in(make_adder(1), &jit);
+    assert_eq!(unsafe { adder.bare()(1) }, 2);
+}
@@ -0,0 +1,104 @@
+#![cfg(feature = "default_jit_alloc")]
+// qemu-arm is unable to correctly model W^X dual-mapped memory (without software MMU),
+// so we can't test the default jit allocator on ARM
+#![cfg(not(target_arch = "arm"))]
+
+use std::{
+    rc::Rc,
+    sync::{mpsc, Arc, Barrier},
+};
+
+use closure_ffi::retargetable::{RetargetableBareFn, RetargetableBareFnSync};
+
+#[test]
+fn test_replace() {
+    let offset = 5;
+    let mut bare_closure = RetargetableBareFn::new_c(|x: usize| x + offset);
+    let bare = bare_closure.bare();
+    assert_eq!(unsafe { bare(1) }, 6);
+
+    let factor = 10;
+    bare_closure.replace(move |x: usize| x * factor);
+    assert_eq!(bare_closure.bare() as usize, bare as usize);
+    assert_eq!(unsafe { bare(2) }, 20);
+    assert_eq!(bare_closure.retired_count(), 0);
+
+    // Zero-sized closures don't use a JIT thunk
+    bare_closure.replace(|x: usize| x);
+    assert_eq!(unsafe { bare(3) }, 3);
+}
+
+#[test]
+fn test_replace_drops_closures() {
+    let rc = Rc::new(());
+    let rc_clone = rc.clone();
+    let mut bare_closure = RetargetableBareFn::new_c(move || Rc::strong_count(&rc_clone));
+    assert_eq!(unsafe { bare_closure.bare()() }, 2);
+
+    bare_closure.replace(|| 0);
+    assert_eq!(Rc::strong_count(&rc), 1);
+
+    let rc_clone = rc.clone();
+    bare_closure.replace(move || Rc::strong_count(&rc_clone));
+    assert_eq!(unsafe { bare_closure.bare()() }, 2);
+    drop(bare_closure);
+    assert_eq!(Rc::strong_count(&rc), 1);
+}
+
+#[test]
+fn test_replace_while_called() {
+    let entered = Arc::new(Barrier::new(2));
+    let (resume_tx, resume_rx) = mpsc::sync_channel::<()>(0);
+    let resume_rx = std::sync::Mutex::new(resume_rx);
+
+    let old_state = Arc::new(());
+    let old_state_clone = old_state.clone();
+    let entered_clone = entered.clone();
+    let mut bare_closure = RetargetableBareFnSync::new_c(move |x: u32| {
+        let _state = &old_state_clone;
+        entered_clone.wait();
+        resume_rx.lock().unwrap().recv().unwrap();
+        x + 1
+    });
+
+    let bare = bare_closure.bare();
+    let caller = std::thread::spawn(move || unsafe { bare(1) });
+
+    // Replace the closure while the first one is running on the other thread
+    entered.wait();
+    bare_closure.replace(|x: u32| x * 10);
+    assert_eq!(unsafe { bare(2) }, 20);
+    assert_eq!(bare_closure.retired_count(), 1);
+    assert_eq!(Arc::strong_count(&old_state), 2);
+
+    resume_tx.send(()).unwrap();
+    assert_eq!(caller.join().unwrap(), 2);
+
+    // The first closure can be dropped now that it is no longer running
+    bare_closure.replace(|x: u32| x * 100);
+    assert_eq!(bare_closure.retired_count(), 0);
+    assert_eq!(Arc::strong_count(&old_state), 1);
+    assert_eq!(unsafe { bare(2) }, 200);
+}
+
+#[test]
+fn test_replace_concurrent_calls() {
+    let bare_closure = std::sync::RwLock::new(RetargetableBareFnSync::new_c(|x: u64| x));
+    let bare = bare_closure.read().unwrap().bare();
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for x in 0..10000 {
+                    let y = unsafe { bare(x) };
+                    assert!(y == x || y == 2 * x || y == 3 * x);
+                }
+            });
+        }
+
+        for n in 0..100 {
+            let factor = n % 3 + 1;
+            bare_closure.write().unwrap().replace(move |x: u64| factor * x);
+        }
+    });
+}
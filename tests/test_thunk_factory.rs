@@ -0,0 +1,475 @@
+#[allow(unused_imports)]
+use closure_ffi::{cc, BareFn, BareFnMut, BareFnOnce};
+
+mod slab_alloc;
+use slab_alloc::SLAB;
+
+#[cfg(feature = "std")]
+#[test]
+fn test_catch_panic() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    };
+
+    use closure_ffi::{
+        thunk_factory::{
+            catch_panic, catch_panic_mut, catch_panic_once, set_panic_hook, take_panic_hook,
+            FallbackOnPanic, HookOnPanic,
+        },
+        BareFnSync,
+    };
+
+    type Bare = unsafe extern "C" fn(u32) -> u32;
+
+    let thunk = catch_panic::<Bare, _, _>(
+        (cc::C, |n: u32| 100 / n),
+        FallbackOnPanic(|payload: Box<dyn std::any::Any + Send>| {
+            assert!(payload.downcast_ref::<&str>().is_some());
+            u32::MAX
+        }),
+    );
+    // Send/Sync are preserved
+    let bare_closure = BareFnSync::with_thunk_in(thunk, &SLAB);
+    assert_eq!(unsafe { bare_closure.bare()(5) }, 20);
+    assert_eq!(unsafe { bare_closure.bare()(0) }, u32::MAX);
+
+    let panics = Arc::new(AtomicUsize::new(0));
+    let panics_clone = panics.clone();
+    set_panic_hook(move |payload| {
+        assert_eq!(payload.downcast_ref::<String>().unwrap(), "odd: 3");
+        panics_clone.fetch_add(1, SeqCst);
+    });
+
+    let mut sum = 0;
+    let bare_closure = BareFnMut::with_thunk_in(
+        catch_panic_mut::<Bare, _, _>(
+            (cc::C, |n: u32| {
+                assert!(n.is_multiple_of(2), "odd: {n}");
+                sum += n;
+                sum
+            }),
+            HookOnPanic,
+        ),
+        &SLAB,
+    );
+    let bare = bare_closure.bare();
+    unsafe {
+        assert_eq!(bare(2), 2);
+        assert_eq!(bare(3), 0);
+        assert_eq!(bare(4), 6);
+    }
+    assert_eq!(panics.load(SeqCst), 1);
+
+    let bare_closure = BareFnOnce::with_thunk_in(
+        catch_panic_once::<Bare, _, _>((cc::C, |n: u32| panic!("odd: {n}")), HookOnPanic),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(3) }, 0);
+    assert_eq!(panics.load(SeqCst), 2);
+
+    // The hook may replace itself, and panics escaping from it are caught
+    set_panic_hook(|_| {
+        set_panic_hook(|_| panic!("hook panicked"));
+    });
+    let bare_closure = BareFn::with_thunk_in(
+        catch_panic::<Bare, _, _>((cc::C, |n: u32| panic!("odd: {n}")), HookOnPanic),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(3) }, 0);
+    assert_eq!(unsafe { bare_closure.bare()(5) }, 0);
+
+    assert!(take_panic_hook().is_some());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_make_mut_checked() {
+    use core::cell::Cell;
+
+    use closure_ffi::{
+        thunk_factory::{
+            make_mut_checked, BlockOnReentry, FallbackOnReentry, PanicOnReentry, ReentrancyPolicy,
+            Reentry,
+        },
+        BareFnSync,
+    };
+
+    type Bare = unsafe extern "C" fn(u32) -> u32;
+    type BareUnwind = unsafe extern "C-unwind" fn(u32) -> u32;
+
+    // Recursive calls run the fallback
+    let reentrant = Cell::new(None::<Bare>);
+    let bare_closure = BareFn::with_thunk_in(
+        make_mut_checked::<Bare, _, _>(
+            (cc::C, |n: u32| match n {
+                0 => 0,
+                n => n + unsafe { reentrant.get().unwrap()(n - 1) },
+            }),
+            FallbackOnReentry(|reentry| {
+                assert_eq!(reentry, Reentry::Recursive);
+                100
+            }),
+        ),
+        &SLAB,
+    );
+    reentrant.set(Some(bare_closure.bare()));
+    assert_eq!(unsafe { bare_closure.bare()(5) }, 105);
+    assert_eq!(unsafe { bare_closure.bare()(0) }, 0);
+
+    // Concurrent calls are serialized, and Send/Sync are preserved
+    let mut count = 0;
+    let bare_closure = BareFnSync::with_thunk_in(
+        make_mut_checked::<Bare, _, _>(
+            (cc::C, move |n: u32| {
+                count += n;
+                count
+            }),
+            BlockOnReentry,
+        ),
+        &SLAB,
+    );
+    let bare = bare_closure.bare();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    unsafe { bare(1) };
+                }
+            });
+        }
+    });
+    assert_eq!(unsafe { bare(0) }, 4000);
+
+    // Recursive calls panic with the other policies
+    fn assert_recursion_panics(policy: impl ReentrancyPolicy<BareUnwind>) {
+        let reentrant = Cell::new(None::<BareUnwind>);
+        let bare_closure = BareFn::with_thunk_in(
+            make_mut_checked::<BareUnwind, _, _>(
+                (cc::CUnwind, |n: u32| match n {
+                    0 => 0,
+                    n => unsafe { reentrant.get().unwrap()(n - 1) },
+                }),
+                policy,
+            ),
+            &SLAB,
+        );
+        let bare = bare_closure.bare();
+        reentrant.set(Some(bare));
+
+        let payload = std::panic::catch_unwind(|| unsafe { bare(1) }).unwrap_err();
+        assert!(payload.downcast_ref::<String>().unwrap().starts_with("recursive call"));
+        // The closure can still be called after the panic
+        assert_eq!(unsafe { bare(0) }, 0);
+    }
+    assert_recursion_panics(PanicOnReentry);
+    assert_recursion_panics(BlockOnReentry);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_thread_bound() {
+    use core::cell::Cell;
+    use std::{rc::Rc, sync::mpsc, thread};
+
+    use closure_ffi::thunk_factory::{
+        make_mut_checked, thread_bound, DefaultOnForeignThread, HandleOnForeignThread,
+        PanicOnReentry,
+    };
+
+    type Bare = unsafe extern "C" fn(u32) -> u32;
+
+    let rc = Rc::new(10);
+    let bare_closure: BareFn<Bare, _> =
+        BareFn::new_thread_bound_in(move |n: u32| *rc + n, DefaultOnForeignThread, &SLAB);
+    let bare = bare_closure.bare();
+    assert_eq!(unsafe { bare(1) }, 11);
+    assert_eq!(thread::spawn(move || unsafe { bare(1) }).join().unwrap(), 0);
+
+    // Foreign calls are given the owning thread and the arguments
+    let (tx, rx) = mpsc::channel();
+    let owner = thread::current().id();
+    let bare_closure: BareFn<Bare, _> = BareFn::new_thread_bound_in(
+        |n: u32| n,
+        HandleOnForeignThread(move |thread, n: (u32,)| {
+            assert_eq!(thread, owner);
+            tx.send(n.0).unwrap();
+            n.0 * 2
+        }),
+        &SLAB,
+    );
+    let bare = bare_closure.bare();
+    assert_eq!(
+        thread::spawn(move || unsafe { bare(21) }).join().unwrap(),
+        42
+    );
+    assert_eq!(rx.try_recv(), Ok(21));
+    assert_eq!(unsafe { bare(21) }, 21);
+    assert!(rx.try_recv().is_err());
+
+    // FnMut closures can be bound by checking them first
+    let calls = Cell::new(0);
+    let mut sum = 0;
+    let thunk = thread_bound::<Bare, _, _>(
+        make_mut_checked::<Bare, _, _>(
+            (cc::C, |n: u32| {
+                calls.set(calls.get() + 1);
+                sum += n;
+                sum
+            }),
+            PanicOnReentry,
+        ),
+        DefaultOnForeignThread,
+    );
+    let bare_closure = BareFn::with_thunk_in(thunk, &SLAB);
+    let bare = bare_closure.bare();
+    assert_eq!(unsafe { bare(2) }, 2);
+    assert_eq!(thread::spawn(move || unsafe { bare(3) }).join().unwrap(), 0);
+    assert_eq!(unsafe { bare(4) }, 6);
+    assert_eq!(calls.get(), 2);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_thunk_layer() {
+    use core::cell::{Cell, RefCell};
+    use std::{sync::Mutex, thread};
+
+    use closure_ffi::{
+        thunk_factory::{
+            layer, layer_exclusive, layer_mut, CountCalls, Synchronized, ThunkLayer, TimeCalls,
+        },
+        traits::FnPtr,
+        BareFnSync,
+    };
+
+    type Bare = unsafe extern "C" fn(u32) -> u32;
+
+    // Hooks see the arguments and return value, and run outermost first
+    struct Record<'a>(&'static str, &'a RefCell<Vec<String>>);
+
+    impl<B: FnPtr> ThunkLayer<B> for Record<'_>
+    where
+        for<'a, 'b, 'c> B::Args<'a, 'b, 'c>: core::fmt::Debug,
+        for<'a, 'b, 'c> B::Ret<'a, 'b, 'c>: core::fmt::Debug,
+    {
+        type Guard<'s>
+            = ()
+        where
+            Self: 's;
+
+        fn before<'s>(&'s self, args: &B::Args<'_, '_, '_>) -> Self::Guard<'s> {
+            self.1.borrow_mut().push(format!("{} before {args:?}", self.0));
+        }
+
+        fn after<'s>(&'s self, _guard: Self::Guard<'s>, ret: &B::Ret<'_, '_, '_>) {
+            self.1.borrow_mut().push(format!("{} after {ret:?}", self.0));
+        }
+    }
+
+    let log = RefCell::new(Vec::new());
+    let counter = CountCalls::new();
+    let bare_closure = BareFn::with_thunk_in(
+        layer::<Bare, _, _>(
+            (cc::C, |n: u32| n + 1),
+            (
+                Record("outer", &log),
+                (counter.clone(), Record("inner", &log)),
+            ),
+        ),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(1) }, 2);
+    assert_eq!(counter.calls(), 1);
+    assert_eq!(
+        *log.borrow(),
+        [
+            "outer before (1,)",
+            "inner before (1,)",
+            "inner after 2",
+            "outer after 2"
+        ]
+    );
+
+    // FnMut closures keep their signature
+    let mut total = 0;
+    let bare_closure = BareFnMut::with_thunk_in(
+        layer_mut::<Bare, _, _>(
+            (cc::C, |n: u32| {
+                total += n;
+                total
+            }),
+            counter.clone(),
+        ),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(2) }, 2);
+    assert_eq!(unsafe { bare_closure.bare()(3) }, 5);
+    assert_eq!(counter.calls(), 3);
+    drop(bare_closure);
+
+    // Synchronized turns FnMut closures into Sync ones, and composes with the other layers
+    let counter = CountCalls::new();
+    let timings = Mutex::new(Vec::new());
+    let mut total = 0;
+    let bare_closure = BareFnSync::with_thunk_in(
+        layer_exclusive::<Bare, _, _>(
+            (cc::C, move |n: u32| {
+                total += n;
+                total
+            }),
+            (
+                Synchronized::new(),
+                (
+                    counter.clone(),
+                    TimeCalls(|duration| timings.lock().unwrap().push(duration)),
+                ),
+            ),
+        ),
+        &SLAB,
+    );
+    let bare = bare_closure.bare();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    unsafe { bare(1) };
+                }
+            });
+        }
+    });
+    assert_eq!(unsafe { bare(0) }, 4000);
+    assert_eq!(counter.calls(), 4001);
+    drop(bare_closure);
+    assert_eq!(timings.into_inner().unwrap().len(), 4001);
+
+    // Like make_mut_checked with BlockOnReentry, recursive calls panic instead of deadlocking
+    type BareUnwind = unsafe extern "C-unwind" fn(u32) -> u32;
+    let reentrant = Cell::new(None::<BareUnwind>);
+    let bare_closure = BareFn::with_thunk_in(
+        layer_exclusive::<BareUnwind, _, _>(
+            (cc::CUnwind, |n: u32| match n {
+                0 => 0,
+                n => unsafe { reentrant.get().unwrap()(n - 1) },
+            }),
+            Synchronized::new(),
+        ),
+        &SLAB,
+    );
+    let bare = bare_closure.bare();
+    reentrant.set(Some(bare));
+
+    let payload = std::panic::catch_unwind(|| unsafe { bare(1) }).unwrap_err();
+    assert!(payload.downcast_ref::<String>().unwrap().starts_with("recursive call"));
+    assert_eq!(unsafe { bare(0) }, 0);
+}
+
+#[test]
+fn test_arg_binding() {
+    use closure_ffi::{
+        thunk_factory::{
+            bind_first, bind_first_once, bind_last, bind_last_mut, ignore_arg, ignore_arg_mut,
+            map_args, map_args_once, map_ret, map_ret_mut,
+        },
+        BareFnSync,
+    };
+
+    type Sub = unsafe extern "C" fn(u32, u32) -> u32;
+    type Unary = unsafe extern "C" fn(u32) -> u32;
+    let sub = |a: u32, b: u32| a - b;
+
+    // Send/Sync are preserved
+    let bare_closure =
+        BareFnSync::with_thunk_in(bind_first::<Sub, Unary, _, _>((cc::C, sub), 10), &SLAB);
+    assert_eq!(unsafe { bare_closure.bare()(3) }, 7);
+    let bare_closure = BareFn::with_thunk_in(bind_last::<Sub, Unary, _, _>((cc::C, sub), 3), &SLAB);
+    assert_eq!(unsafe { bare_closure.bare()(10) }, 7);
+
+    // Bound values are moved into once thunks
+    let name = String::from("closure");
+    let bare_closure = BareFnOnce::with_thunk_in(
+        bind_first_once::<
+            unsafe extern "C" fn(String, usize) -> usize,
+            unsafe extern "C" fn(usize) -> usize,
+            _,
+            _,
+        >((cc::C, |s: String, n: usize| s.len() + n), name),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(1) }, 8);
+
+    let mut calls = 0;
+    let bare_closure = BareFnMut::with_thunk_in(
+        bind_last_mut::<Sub, Unary, _, _>(
+            (cc::C, |a: u32, b: u32| {
+                calls += 1;
+                a * b
+            }),
+            2,
+        ),
+        &SLAB,
+    );
+    unsafe {
+        assert_eq!(bare_closure.bare()(4), 8);
+        assert_eq!(bare_closure.bare()(5), 10);
+    }
+    drop(bare_closure);
+    assert_eq!(calls, 2);
+
+    // Arguments can be ignored at any index, including the only one
+    let bare_closure = BareFn::with_thunk_in(
+        ignore_arg::<0, Unary, Sub, _>((cc::C, |n: u32| n * 2)),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(100, 4) }, 8);
+    let bare_closure = BareFn::with_thunk_in(
+        ignore_arg::<1, Unary, Sub, _>((cc::C, |n: u32| n * 2)),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(100, 4) }, 200);
+    let mut count = 0;
+    let bare_closure = BareFnMut::with_thunk_in(
+        ignore_arg_mut::<0, unsafe extern "C" fn() -> u32, Unary, _>((cc::C, || {
+            count += 1;
+            count
+        })),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(100) }, 1);
+    assert_eq!(unsafe { bare_closure.bare()(100) }, 2);
+
+    // Swap the arguments
+    let bare_closure = BareFn::with_thunk_in(
+        map_args::<Sub, Sub, _, _>((cc::C, sub), |(a, b): (u32, u32)| (b, a)),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(3, 10) }, 7);
+    let bare_closure = BareFnOnce::with_thunk_in(
+        map_args_once::<Sub, Unary, _, _>((cc::C, sub), |(a,): (u32,)| (a, 1)),
+        &SLAB,
+    );
+    assert_eq!(unsafe { bare_closure.bare()(3) }, 2);
+
+    // Change the return type and the calling convention
+    let bare_closure = BareFn::with_thunk_in(
+        map_ret::<Sub, unsafe extern "system" fn(u32, u32) -> bool, _, _>((cc::C, sub), |n| n > 5),
+        &SLAB,
+    );
+    assert!(unsafe { bare_closure.bare()(10, 3) });
+    assert!(!unsafe { bare_closure.bare()(10, 6) });
+    let mut total = 0;
+    let bare_closure = BareFnMut::with_thunk_in(
+        map_ret_mut::<Unary, unsafe extern "C" fn(u32) -> u64, _, _>(
+            (cc::C, |n: u32| {
+                total += n;
+                total
+            }),
+            u64::from,
+        ),
+        &SLAB,
+    );
+    unsafe {
+        assert_eq!(bare_closure.bare()(1), 1);
+        assert_eq!(bare_closure.bare()(2), 3);
+    }
+}
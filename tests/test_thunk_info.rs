@@ -0,0 +1,82 @@
+use closure_ffi::{thunk_info::ThunkKind, BareFn};
+
+mod slab_alloc;
+use slab_alloc::SLAB;
+
+#[test]
+fn test_thunk_info_zst() {
+    let bare_closure = BareFn::new_c_in(|n: usize| n + 1, &SLAB);
+    let info = bare_closure.thunk_info();
+
+    assert_eq!(info.kind(), ThunkKind::Template);
+    assert_eq!(info.thunk_addr(), info.template_addr());
+    assert_eq!(info.thunk_addr(), bare_closure.bare() as usize);
+    assert!(info.code().is_empty());
+    assert_eq!(info.magic_offset(), None);
+}
+
+#[test]
+fn test_thunk_info() {
+    let offset = 5;
+    let bare_closure = BareFn::new_c_in(move |n: usize| n + offset, &SLAB);
+    let info = bare_closure.thunk_info();
+
+    assert!(matches!(
+        info.kind(),
+        ThunkKind::Copied | ThunkKind::Relocated
+    ));
+    assert_eq!(info.thunk_addr(), bare_closure.bare() as usize);
+    assert_ne!(info.thunk_addr(), info.template_addr());
+
+    let code = info.code();
+    let magic_offset = info.magic_offset().unwrap();
+    assert!(magic_offset < code.len());
+    assert!(info.rewritten().iter().all(|r| r.end <= code.len()));
+    if info.kind() == ThunkKind::Copied {
+        assert!(info.rewritten().is_empty());
+    }
+
+    // The thunk jumps back past the `asm!` block of the template
+    let read_slot = |offset: usize| {
+        usize::from_ne_bytes(code[offset..offset + size_of::<usize>()].try_into().unwrap())
+    };
+    let return_addr = read_slot(info.return_addr_offset().unwrap());
+    assert!(return_addr > info.template_addr() + magic_offset / 2);
+    assert!(return_addr < info.template_addr() + 2 * code.len());
+    assert_ne!(read_slot(info.closure_ptr_offset().unwrap()), 0);
+
+    assert_eq!(unsafe { bare_closure.bare()(1) }, 6);
+}
+
+#[cfg(feature = "thunk_disasm")]
+#[test]
+fn test_thunk_disassembly() {
+    let offset = 5;
+    let bare_closure = BareFn::new_c_in(move |n: usize| n + offset, &SLAB);
+    let info = bare_closure.thunk_info();
+    let disassembly = info.disassembly();
+
+    let mut lines = disassembly.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        format!(
+            "thunk at {:#x} ({:?}, template at {:#x})",
+            info.thunk_addr(),
+            info.kind(),
+            info.template_addr()
+        )
+    );
+    assert!(lines.clone().any(|l| l.ends_with("; closure pointer")));
+    assert!(lines.clone().any(|l| l.ends_with("; return address")));
+    // Every byte of the code is listed exactly once
+    let listed: usize = lines
+        .map(|l| l[8..].split("  ").next().unwrap().split_whitespace().count())
+        .sum();
+    assert_eq!(listed, info.code().len());
+
+    #[cfg(target_arch = "x86_64")]
+    assert!(disassembly.contains("jmp qword ptr ["));
+
+    let zst = BareFn::new_c_in(|n: usize| n, &SLAB);
+    assert!(zst.thunk_info().disassembly().contains("<no code emitted>"));
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "unwind_info")]
+
+use core::ffi::c_void;
+use std::sync::Mutex;
+
+use closure_ffi::{thunk_info::ThunkKind, BareFn};
+
+/// Freed thunks must not be checked while other tests may reuse their memory.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[repr(C)]
+struct DwarfEhBases {
+    tbase: *const c_void,
+    dbase: *const c_void,
+    func: *const c_void,
+}
+
+extern "C" {
+    fn _Unwind_Find_FDE(pc: *const c_void, bases: *mut DwarfEhBases) -> *const c_void;
+}
+
+/// Returns the start of the function covered by the FDE the unwinder finds for `pc`.
+fn fde_func(pc: usize) -> Option<usize> {
+    let mut bases = DwarfEhBases {
+        tbase: core::ptr::null(),
+        dbase: core::ptr::null(),
+        func: core::ptr::null(),
+    };
+    let fde = unsafe { _Unwind_Find_FDE(pc as *const c_void, &mut bases) };
+    (!fde.is_null()).then_some(bases.func as usize)
+}
+
+#[test]
+fn test_unwind_info() {
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let offset = 5;
+    let bare_closure = BareFn::new_c(move |n: usize| n + offset);
+    let info = bare_closure.thunk_info();
+    let addr = info.thunk_addr();
+    let len = info.magic_offset().unwrap();
+    assert_ne!(info.kind(), ThunkKind::Template);
+
+    assert_eq!(fde_func(addr), Some(addr));
+    assert_eq!(fde_func(addr + len - 1), Some(addr));
+    assert_eq!(unsafe { bare_closure.bare()(1) }, 6);
+
+    drop(bare_closure);
+    assert_eq!(fde_func(addr), None);
+}
+
+#[test]
+fn test_unwind_info_many_thunks() {
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let thunks: Vec<_> = (0..100).map(|n| BareFn::new_c(move |x: usize| x + n)).collect();
+    let addrs: Vec<_> = thunks.iter().map(|t| t.thunk_info().thunk_addr()).collect();
+    assert!(addrs.iter().all(|&a| fde_func(a) == Some(a)));
+
+    drop(thunks);
+    assert!(addrs.iter().all(|&a| fde_func(a).is_none()));
+}
+
+#[test]
+fn test_unwind_info_zst() {
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    // The thunk template is used as-is, and is covered by the binary's own unwind info
+    let bare_closure = BareFn::new_c(|n: usize| n + 1);
+    let addr = bare_closure.bare() as usize;
+    assert_eq!(bare_closure.thunk_info().kind(), ThunkKind::Template);
+    assert!(fde_func(addr).is_some_and(|f| f <= addr));
+}